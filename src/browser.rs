@@ -0,0 +1,465 @@
+use crate::errorformat::Entry;
+use crate::quickfix::ListKind;
+use crate::{Buffer, CommandState, ConfirmAction, ConfirmState, Editor, Mode};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ropey::Rope;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Lines above the entries: the directory, the sort order and the keys.
+const HEADER: usize = 3;
+
+/// The order of the entries; directories always come first.
+#[derive(Clone, Copy, Default)]
+enum SortBy {
+    #[default]
+    Name,
+    /// Newest first.
+    Time,
+    /// Largest first.
+    Size,
+}
+
+impl SortBy {
+    fn next(self) -> Self {
+        match self {
+            SortBy::Name => SortBy::Time,
+            SortBy::Time => SortBy::Size,
+            SortBy::Size => SortBy::Name,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Time => "time",
+            SortBy::Size => "size",
+        }
+    }
+}
+
+/// A file or directory in the listing.
+#[derive(Clone)]
+struct DirEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// The listing shown when a directory is opened. The buffer holds the text; this
+/// holds what it stands for. The sort order, hidden files and marks carry over when
+/// going from one directory to another.
+#[derive(Clone, Default)]
+pub struct Browser {
+    /// The directory listed, as an absolute path.
+    dir: PathBuf,
+    /// The entries in the order shown, below `../`.
+    entries: Vec<DirEntry>,
+    sort: SortBy,
+    /// Whether names starting with `.` are listed.
+    show_hidden: bool,
+    /// Files picked with `m`, to open or delete together.
+    marked: BTreeSet<PathBuf>,
+}
+
+impl Browser {
+    /// The buffer line of the first entry, where the cursor starts.
+    pub fn first_row(&self) -> usize {
+        if self.entries.is_empty() {
+            HEADER
+        } else {
+            HEADER + 1
+        }
+    }
+
+    /// The entry on a buffer line; None for the header and for `../`.
+    fn entry_at(&self, row: usize) -> Option<&DirEntry> {
+        self.entries.get(row.checked_sub(HEADER + 1)?)
+    }
+
+    /// The buffer line of the entry with the given name.
+    fn row_of(&self, name: &str) -> Option<usize> {
+        let index = self.entries.iter().position(|e| e.name == name)?;
+        Some(HEADER + 1 + index)
+    }
+
+    /// Reads the directory again, keeping the sort order and hidden files setting.
+    fn read(&mut self) -> Result<()> {
+        let mut entries = Vec::new();
+        for found in fs::read_dir(&self.dir)? {
+            let found = found?;
+            let name = found.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !self.show_hidden {
+                continue;
+            }
+            // A link to a directory is browsed like one
+            let metadata = fs::metadata(found.path()).or_else(|_| found.metadata())?;
+            entries.push(DirEntry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+        entries.sort_by(|a, b| {
+            let order = match self.sort {
+                SortBy::Name => a.name.cmp(&b.name),
+                SortBy::Time => b.modified.cmp(&a.modified),
+                SortBy::Size => b.size.cmp(&a.size),
+            };
+            b.is_dir
+                .cmp(&a.is_dir)
+                .then(order)
+                .then(a.name.cmp(&b.name))
+        });
+        self.entries = entries;
+        Ok(())
+    }
+
+    /// The text of the listing buffer. Directories end in `/`, marked files start with `*`.
+    fn listing(&self) -> String {
+        let mut text = format!(
+            "\" Directory: {}/\n\
+             \" Sorted by {}, dotfiles {}  (s: sort, gh: dotfiles, Ctrl-l: refresh)\n\
+             \" Enter: open  -: up  %: new file  d: new directory  R: rename  D: delete  m: mark\n\
+             \x20 ../",
+            self.dir.display(),
+            self.sort.name(),
+            if self.show_hidden { "shown" } else { "hidden" }
+        );
+        for entry in &self.entries {
+            let mark = if self.marked.contains(&self.dir.join(&entry.name)) {
+                '*'
+            } else {
+                ' '
+            };
+            let slash = if entry.is_dir { "/" } else { "" };
+            text.push_str(&format!("\n{} {}{}", mark, entry.name, slash));
+        }
+        text
+    }
+}
+
+/// A path as short as it can be written: relative to the working directory if
+/// it is inside it.
+fn display_path(path: &Path) -> String {
+    let cwd = env::current_dir().unwrap_or_default();
+    match path.strip_prefix(&cwd) {
+        Ok(relative) if relative.as_os_str().is_empty() => ".".to_string(),
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => path.to_string_lossy().into_owned(),
+    }
+}
+
+impl Editor {
+    /// Shows a directory as a read-only listing to browse, in place of a file.
+    pub(crate) fn open_directory(&mut self, name: &str) -> Result<()> {
+        let mut browser = self.browser.clone().unwrap_or_default();
+        browser.dir = fs::canonicalize(name)?;
+        browser.read()?;
+        self.replace_buffer(Buffer::new());
+        self.browser = Some(browser);
+        self.show_listing(None);
+        let browser = self.browser.as_ref().unwrap();
+        self.status_msg = format!(
+            "\"{}/\" {} entr{}",
+            display_path(&browser.dir),
+            browser.entries.len(),
+            if browser.entries.len() == 1 {
+                "y"
+            } else {
+                "ies"
+            }
+        );
+        Ok(())
+    }
+
+    /// Lists a directory for `:Explore`: the one given, or the current file's, with the
+    /// cursor on that file.
+    pub(crate) fn explore(&mut self, force: bool, dir: Option<&str>) {
+        let current = self.buffer.filename.as_deref().map(Path::new);
+        let (dir, focus) = match (dir, current) {
+            (Some(dir), _) => (dir.to_string(), None),
+            (None, Some(current)) => (
+                current
+                    .parent()
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(|| ".".to_string()),
+                current
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned()),
+            ),
+            (None, None) => (".".to_string(), None),
+        };
+        self.edit(force, &[&dir]);
+        if let Some(focus) = focus
+            && let Some(row) = self.browser.as_ref().and_then(|b| b.row_of(&focus))
+        {
+            self.cy = row;
+            self.scroll_check();
+        }
+    }
+
+    /// Puts the listing in the buffer, with the cursor on the entry named `focus`, or
+    /// on the line it was on.
+    fn show_listing(&mut self, focus: Option<&str>) {
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        let name = format!("{}/", display_path(&browser.dir));
+        self.buffer = Buffer::with_text(Rope::from_str(&browser.listing()), Some(name));
+        self.buffer.readonly = true;
+        self.buffer.modifiable = false;
+        match focus.and_then(|name| browser.row_of(name)) {
+            Some(row) => self.cy = row,
+            None => self.cy = self.cy.max(browser.first_row()),
+        }
+        self.cx = 0;
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+
+    /// Reads the directory again and shows it, keeping the cursor on `focus` if given,
+    /// or on the entry it was on.
+    fn refresh_listing(&mut self, focus: Option<&str>) {
+        let Some(browser) = &mut self.browser else {
+            return;
+        };
+        let current = browser.entry_at(self.cy).map(|e| e.name.clone());
+        if let Err(e) = browser.read() {
+            self.status_msg = format!("Cannot read {}: {}", browser.dir.display(), e);
+        }
+        self.show_listing(focus.or(current.as_deref()));
+    }
+
+    /// Handles the keys of the directory listing. Returns false for keys it leaves to
+    /// Normal mode, such as moving and searching.
+    pub(crate) fn process_browser_keypress(&mut self, event: KeyEvent) -> bool {
+        let Some(browser) = &mut self.browser else {
+            return false;
+        };
+        if event.modifiers.contains(KeyModifiers::CONTROL) {
+            if event.code != KeyCode::Char('l') {
+                return false;
+            }
+            self.refresh_listing(None);
+            return true;
+        }
+        let dir = display_path(&browser.dir);
+        match event.code {
+            KeyCode::Enter if !browser.marked.is_empty() => self.open_marked(),
+            KeyCode::Enter => match browser.entry_at(self.cy) {
+                Some(entry) => {
+                    let path = display_path(&browser.dir.join(&entry.name));
+                    self.edit(false, &[&path]);
+                }
+                None if self.cy == HEADER => self.browse_parent(),
+                None => {}
+            },
+            KeyCode::Char('-') => self.browse_parent(),
+            KeyCode::Char('s') => {
+                browser.sort = browser.sort.next();
+                self.refresh_listing(None);
+            }
+            KeyCode::Char('m') => {
+                let Some(entry) = browser.entry_at(self.cy) else {
+                    return true;
+                };
+                let path = browser.dir.join(&entry.name);
+                if !browser.marked.remove(&path) {
+                    browser.marked.insert(path);
+                }
+                self.cy += 1;
+                self.show_listing(None);
+            }
+            // A new file is written where `:e` is pointed; a new directory is made at once
+            KeyCode::Char('%') => {
+                let prefix = if dir == "." { String::new() } else { dir + "/" };
+                self.mode = Mode::Command(CommandState::new(format!(":e {}", prefix)));
+            }
+            KeyCode::Char('d') => {
+                self.mode = Mode::Command(CommandState::new(":Mkdir ".to_string()));
+            }
+            KeyCode::Char('R') => {
+                if let Some(entry) = browser.entry_at(self.cy) {
+                    self.mode = Mode::Command(CommandState::new(format!(":Rename {}", entry.name)));
+                }
+            }
+            KeyCode::Char('D') => self.confirm_delete(),
+            KeyCode::Char('i') => self.status_msg = "Directory listing is read-only".to_string(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// Lists the directory above the one shown, with the cursor on the one left.
+    fn browse_parent(&mut self) {
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        let Some(parent) = browser.dir.parent() else {
+            return;
+        };
+        let left = browser
+            .dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        self.edit(false, &[&display_path(parent)]);
+        if let Some(left) = left
+            && let Some(row) = self.browser.as_ref().and_then(|b| b.row_of(&left))
+        {
+            self.cy = row;
+            self.scroll_check();
+        }
+    }
+
+    /// Shows or hides names starting with `.` (`gh`).
+    pub(crate) fn toggle_hidden(&mut self) {
+        if let Some(browser) = &mut self.browser {
+            browser.show_hidden = !browser.show_hidden;
+            self.refresh_listing(None);
+        }
+    }
+
+    /// Opens the marked files: they fill the quickfix list, so `:cn` goes on to the next.
+    fn open_marked(&mut self) {
+        let Some(browser) = &mut self.browser else {
+            return;
+        };
+        let marked = std::mem::take(&mut browser.marked);
+        let entries: Vec<Entry> = marked
+            .iter()
+            .filter(|path| !path.is_dir())
+            .map(|path| Entry {
+                filename: Some(display_path(path)),
+                line: 0,
+                col: 0,
+                kind: ' ',
+                number: None,
+                text: display_path(path),
+            })
+            .collect();
+        if entries.is_empty() {
+            self.status_msg = "No marked files, only directories".to_string();
+            self.show_listing(None);
+            return;
+        }
+        self.set_quickfix_list(
+            ListKind::Quickfix,
+            entries,
+            "Marked files".to_string(),
+            true,
+        );
+    }
+
+    /// Asks before deleting the marked files, or else the entry under the cursor.
+    fn confirm_delete(&mut self) {
+        let Some(browser) = &self.browser else {
+            return;
+        };
+        let paths: Vec<PathBuf> = if browser.marked.is_empty() {
+            match browser.entry_at(self.cy) {
+                Some(entry) => vec![browser.dir.join(&entry.name)],
+                None => return,
+            }
+        } else {
+            browser.marked.iter().cloned().collect()
+        };
+        let what = match paths.as_slice() {
+            [path] => display_path(path),
+            _ => format!("{} marked files", paths.len()),
+        };
+        self.mode = Mode::Confirm(ConfirmState {
+            prompt: format!("Delete {}? [Y]es, [N]o:", what),
+            choices: "yn".to_string(),
+            cancel: 'n',
+            action: ConfirmAction::Delete(paths),
+        });
+    }
+
+    /// Carries out the answer to the delete prompt. Like netrw, only empty directories
+    /// are deleted.
+    pub(crate) fn answer_delete_prompt(&mut self, paths: Vec<PathBuf>, choice: char) {
+        if choice != 'y' {
+            return;
+        }
+        let mut deleted = 0;
+        for path in &paths {
+            let is_dir = fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+            let removed = if is_dir {
+                fs::remove_dir(path)
+            } else {
+                fs::remove_file(path)
+            };
+            if let Err(e) = removed {
+                self.status_msg = format!("Cannot delete {}: {}", display_path(path), e);
+                break;
+            }
+            if let Some(browser) = &mut self.browser {
+                browser.marked.remove(path);
+            }
+            deleted += 1;
+        }
+        if deleted == paths.len() {
+            self.status_msg = format!("Deleted {}", deleted);
+        }
+        self.refresh_listing(None);
+    }
+
+    /// Makes a directory for `:Mkdir`, in the directory listed (or the working one).
+    pub(crate) fn make_directory(&mut self, name: &str) {
+        if name.is_empty() {
+            self.status_msg = "Argument required".to_string();
+            return;
+        }
+        let path = match &self.browser {
+            Some(browser) => browser.dir.join(name),
+            None => PathBuf::from(name),
+        };
+        match fs::create_dir_all(&path) {
+            Ok(()) => {
+                self.status_msg = format!("Created {}/", display_path(&path));
+                self.refresh_listing(Some(name));
+            }
+            Err(e) => self.status_msg = format!("Cannot create {}: {}", name, e),
+        }
+    }
+
+    /// Renames the entry under the cursor for `:Rename`. The new name is relative to
+    /// the directory listed, so it can move the entry elsewhere too.
+    pub(crate) fn rename_entry(&mut self, new_name: &str) {
+        let Some(browser) = &mut self.browser else {
+            self.status_msg = "Not in a directory listing".to_string();
+            return;
+        };
+        let Some(name) = browser.entry_at(self.cy).map(|e| e.name.clone()) else {
+            self.status_msg = "No file under the cursor".to_string();
+            return;
+        };
+        if new_name.is_empty() {
+            self.status_msg = "Argument required".to_string();
+            return;
+        }
+        let from = browser.dir.join(&name);
+        let to = browser.dir.join(new_name);
+        if fs::symlink_metadata(&to).is_ok() {
+            self.status_msg = format!("{} already exists", display_path(&to));
+            return;
+        }
+        if let Err(e) = fs::rename(&from, &to) {
+            self.status_msg = format!("Cannot rename {}: {}", name, e);
+            return;
+        }
+        if browser.marked.remove(&from) {
+            browser.marked.insert(to.clone());
+        }
+        self.status_msg = format!("Renamed {} to {}", name, new_name);
+        self.refresh_listing(Some(new_name));
+    }
+}
@@ -0,0 +1,164 @@
+use crate::{ConfirmAction, ConfirmState, Editor, Mode, split_enc_arg};
+use std::fs;
+use std::io::Result;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the file is checked for changes while the editor is idle in Normal mode.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a file looked like on disk when it was last read or written.
+/// A change in any of these means another program has touched it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DiskState {
+    modified: Option<SystemTime>,
+    size: u64,
+    /// Device and inode: a file replaced by a rename (as `git checkout` does) gets a new one.
+    inode: (u64, u64),
+}
+
+impl DiskState {
+    pub fn from_metadata(meta: &fs::Metadata) -> Self {
+        Self {
+            modified: meta.modified().ok(),
+            size: meta.len(),
+            inode: inode(meta),
+        }
+    }
+
+    /// Returns the current state of a file, or None if it doesn't exist.
+    pub fn of(filename: &str) -> Option<Self> {
+        fs::metadata(filename)
+            .ok()
+            .map(|meta| Self::from_metadata(&meta))
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &fs::Metadata) -> (u64, u64) {
+    (meta.dev(), meta.ino())
+}
+
+/// Elsewhere only the time and size tell that a file changed.
+#[cfg(not(unix))]
+fn inode(_meta: &fs::Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+impl Editor {
+    /// Checks whether the file was changed on disk by another program. An unmodified
+    /// buffer is reloaded if `autoread` is set; otherwise the user is asked what to do.
+    /// `explicit` is set for `:checktime`, which also works outside Normal mode.
+    pub(crate) fn check_time(&mut self, explicit: bool) {
+        self.last_check = Instant::now();
+        // A question would get in the way of typing; it waits for Normal mode
+        if !explicit && !matches!(self.mode, Mode::Normal(_)) {
+            return;
+        }
+        if self.loader.is_some() || self.picker.is_some() || self.browser.is_some() {
+            return;
+        }
+        let Some(filename) = self.buffer.filename.clone() else {
+            return;
+        };
+        let current = DiskState::of(&filename);
+        if current == self.buffer.disk {
+            return;
+        }
+        if current.is_none() {
+            self.status_msg = format!("File \"{}\" no longer available", filename);
+            // Warn only once; writing the file records it again
+            self.buffer.disk = None;
+            return;
+        }
+        if !self.buffer.dirty && self.options.autoread {
+            self.reload();
+            return;
+        }
+        let what = match (self.buffer.disk, self.buffer.dirty) {
+            (None, _) => "has been created after editing started",
+            (Some(_), true) => "and the buffer were both changed",
+            (Some(_), false) => "has changed since editing started",
+        };
+        let prompt = format!("File \"{}\" {}! [O]K, [L]oad File:", filename, what);
+        self.mode = Mode::Confirm(ConfirmState {
+            prompt,
+            choices: "ol".to_string(),
+            cancel: 'o',
+            action: ConfirmAction::FileChanged,
+        });
+    }
+
+    /// Checks the file every `CHECK_INTERVAL`, for changes that came without a focus event.
+    pub(crate) fn poll_check_time(&mut self) {
+        if self.last_check.elapsed() >= CHECK_INTERVAL {
+            self.check_time(false);
+        }
+    }
+
+    /// Carries out the answer to the "file has changed" prompt.
+    pub(crate) fn answer_file_changed_prompt(&mut self, choice: char) {
+        match choice {
+            'l' => self.reload(),
+            // Keep the buffer, and stop asking until the file changes again
+            _ => {
+                if let Some(filename) = &self.buffer.filename {
+                    self.buffer.disk = DiskState::of(filename);
+                }
+            }
+        }
+    }
+
+    /// Reads the file again, keeping the cursor where it was.
+    fn reload(&mut self) {
+        let Some(filename) = self.buffer.filename.clone() else {
+            return;
+        };
+        match self.open_file(&filename, None, false) {
+            Ok(()) => self.status_msg = format!("\"{}\" changed on disk; reloaded", filename),
+            Err(e) => self.status_msg = format!("Cannot open {}: {}", filename, e),
+        }
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+
+    /// Whether `:w` with these arguments would overwrite the buffer's own file after
+    /// another program changed it. Writing to another name never does.
+    pub(crate) fn write_overwrites_change(&self, args: &[&str]) -> bool {
+        let Some(filename) = &self.buffer.filename else {
+            return false;
+        };
+        let (_, args) = split_enc_arg(args);
+        if args.first().is_some_and(|name| name != filename) {
+            return false;
+        }
+        // A file that is gone now is simply written again
+        DiskState::of(filename).is_some_and(|current| self.buffer.disk != Some(current))
+    }
+
+    /// Asks before `:w` overwrites a file that changed on disk since it was read.
+    pub(crate) fn confirm_overwrite(&mut self, command: &str) {
+        self.mode = Mode::Confirm(ConfirmState {
+            prompt: "The file has been changed since reading it! Write anyway? [Y]es, [N]o:"
+                .to_string(),
+            choices: "yn".to_string(),
+            cancel: 'n',
+            action: ConfirmAction::WriteChanged(command.to_string()),
+        });
+    }
+
+    /// Runs the write the overwrite prompt was about, if the answer was yes.
+    /// Returns false if the editor should quit.
+    pub(crate) fn answer_overwrite_prompt(&mut self, command: &str, choice: char) -> Result<bool> {
+        if choice != 'y' {
+            self.status_msg = "Not written".to_string();
+            return Ok(true);
+        }
+        // The change on disk is accepted as seen, so the command goes through this time
+        if let Some(filename) = &self.buffer.filename {
+            self.buffer.disk = DiskState::of(filename);
+        }
+        self.execute_command(command)
+    }
+}
@@ -0,0 +1,570 @@
+use crate::render::{Frame, Style};
+use crate::{CommandState, Editor, Mode, NormalState, complete, display, options, quickfix, range};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::Color;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Lines kept in each history, Vim's default for 'history'.
+const HISTORY_SIZE: usize = 50;
+
+/// Ex commands offered when completing a command name, besides the quickfix ones.
+const COMMANDS: &[&str] = &[
+    "CodeAction",
+    "Explore",
+    "Files",
+    "Format",
+    "LspDiagnostics",
+    "LspRename",
+    "Mkdir",
+    "Rename",
+    "buffer",
+    "checktime",
+    "edit",
+    "q",
+    "set",
+    "substitute",
+    "update",
+    "w",
+    "wq",
+];
+
+/// Commands whose arguments are file names.
+const FILE_COMMANDS: &[&str] = &["e", "edit", "w", "wq", "Ex", "Explore", "Mkdir", "Rename"];
+
+/// The lines entered on the command line, oldest first, kept apart for Ex commands
+/// and for searches.
+#[derive(Default)]
+pub struct History {
+    commands: Vec<String>,
+    searches: Vec<String>,
+}
+
+impl History {
+    /// The history for a command line starting with `kind`: `:`, `/` or `?`.
+    pub fn list(&self, kind: char) -> &[String] {
+        if kind == ':' {
+            &self.commands
+        } else {
+            &self.searches
+        }
+    }
+
+    /// Remembers an entered command line, moving a repeated one to the end.
+    pub fn add(&mut self, line: &str) {
+        let mut chars = line.chars();
+        let list = match chars.next() {
+            Some(':') => &mut self.commands,
+            Some('/' | '?') => &mut self.searches,
+            _ => return,
+        };
+        let text = chars.as_str();
+        if text.trim().is_empty() {
+            return;
+        }
+        list.retain(|old| old != text);
+        list.push(text.to_string());
+        if list.len() > HISTORY_SIZE {
+            list.remove(0);
+        }
+    }
+}
+
+/// Where Up and Down have got to in the history.
+pub struct Browse {
+    /// Index of the entry shown; the length of the history stands for the typed text.
+    index: usize,
+    /// What was typed before browsing, which the entries shown must start with.
+    typed: String,
+}
+
+/// Tab completion in progress, its matches listed in the wildmenu above the command line.
+pub struct Wildmenu {
+    /// Byte index where the completed word starts.
+    start: usize,
+    /// The word as typed, put back after the last match.
+    original: String,
+    matches: Vec<String>,
+    /// The match in the command line; None for the original word.
+    selected: Option<usize>,
+}
+
+impl CommandState {
+    /// A command line holding `text`, with the cursor at its end.
+    pub(crate) fn new(text: String) -> Self {
+        Self {
+            cursor: text.len(),
+            command_buffer: text,
+            history: None,
+            wildmenu: None,
+            ctrl_r: false,
+        }
+    }
+
+    /// Replaces the whole line, leaving the cursor at its end.
+    fn set_text(&mut self, text: String) {
+        self.cursor = text.len();
+        self.command_buffer = text;
+    }
+
+    /// Inserts text at the cursor and moves past it.
+    pub(crate) fn insert(&mut self, text: &str) {
+        self.command_buffer.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    /// The byte index of the character cluster before `index`, never before the
+    /// `:`, `/` or `?` the line starts with.
+    fn prev_boundary(&self, index: usize) -> usize {
+        self.command_buffer[..index]
+            .grapheme_indices(true)
+            .next_back()
+            .map_or(index, |(i, _)| i)
+            .max(1)
+    }
+
+    /// The byte index after the character cluster at `index`.
+    fn next_boundary(&self, index: usize) -> usize {
+        self.command_buffer[index..]
+            .graphemes(true)
+            .next()
+            .map_or(index, |g| index + g.len())
+    }
+
+    /// Where the word before the cursor starts, as Ctrl-w sees it: blanks, then a
+    /// run of keyword characters or a run of other characters.
+    fn word_start(&self) -> usize {
+        let before = &self.command_buffer[1..self.cursor];
+        let trimmed = before.trim_end();
+        let keyword = trimmed
+            .chars()
+            .next_back()
+            .is_some_and(complete::is_keyword);
+        let start = trimmed
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| c.is_whitespace() || complete::is_keyword(c) != keyword)
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        start + 1
+    }
+
+    /// Where the next word after the cursor starts, for Shift-Right.
+    fn next_word(&self) -> usize {
+        let after = &self.command_buffer[self.cursor..];
+        let word_end = after.find(char::is_whitespace).unwrap_or(after.len());
+        let next = after[word_end..]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(after.len(), |i| word_end + i);
+        self.cursor + next
+    }
+
+    /// Where the word before the cursor starts, for Shift-Left.
+    fn prev_word(&self) -> usize {
+        let before = self.command_buffer[1..self.cursor].trim_end();
+        let start = before
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| c.is_whitespace())
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        start + 1
+    }
+
+    /// Removes the text between two byte indices and puts the cursor there.
+    fn delete(&mut self, start: usize, end: usize) {
+        self.command_buffer.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    /// The part of the line to show in `width` columns, scrolled so the cursor stays
+    /// in sight, and the cursor's column in it.
+    pub(crate) fn view(&self, width: usize) -> (&str, usize) {
+        let mut start = 0;
+        let mut x = display::str_width(&self.command_buffer[..self.cursor]);
+        for g in self.command_buffer[..self.cursor].graphemes(true) {
+            if x < width {
+                break;
+            }
+            start += g.len();
+            x -= display::str_width(g);
+        }
+        (&self.command_buffer[start..], x)
+    }
+}
+
+impl Editor {
+    /// Handles the editing keys of the command line: moving the cursor, deleting,
+    /// Ctrl-r, the history and Tab completion. Enter and Esc are handled by the caller.
+    pub(crate) fn process_cmdline_keypress(&mut self, event: KeyEvent) {
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        let shift = event.modifiers.contains(KeyModifiers::SHIFT);
+        let Mode::Command(state) = &self.mode else {
+            return;
+        };
+        if state.ctrl_r {
+            let text = self.register(event);
+            if let Mode::Command(state) = &mut self.mode {
+                state.ctrl_r = false;
+                state.history = None;
+                if let Some(text) = text {
+                    state.insert(&text);
+                }
+            }
+            return;
+        }
+        // Tab and Shift-Tab step through the matches; Ctrl-n and Ctrl-p too, while
+        // the wildmenu is shown
+        let cycle = match event.code {
+            KeyCode::Tab => Some(true),
+            KeyCode::BackTab => Some(false),
+            KeyCode::Char('n') if control && state.wildmenu.is_some() => Some(true),
+            KeyCode::Char('p') if control && state.wildmenu.is_some() => Some(false),
+            _ => None,
+        };
+        if let Some(forward) = cycle {
+            if state.wildmenu.is_some() {
+                self.select_wild_match(forward);
+            } else {
+                self.start_wildmenu(forward);
+            }
+            return;
+        }
+        let Mode::Command(state) = &mut self.mode else {
+            return;
+        };
+        // Any other key keeps the match shown
+        state.wildmenu = None;
+        match event.code {
+            KeyCode::Up => return self.browse_history(true, true),
+            KeyCode::Down => return self.browse_history(false, true),
+            KeyCode::Char('p') if control => return self.browse_history(true, false),
+            KeyCode::Char('n') if control => return self.browse_history(false, false),
+            _ => {}
+        }
+        let Mode::Command(state) = &mut self.mode else {
+            return;
+        };
+        // Ctrl-h is another Backspace
+        let code = match event.code {
+            KeyCode::Char('h') if control => KeyCode::Backspace,
+            code => code,
+        };
+        match code {
+            KeyCode::Char('r') if control => state.ctrl_r = true,
+            KeyCode::Char('f') if control => {
+                let (kind, typed) = state.command_buffer.split_at(1);
+                let (kind, typed) = (kind.chars().next().unwrap_or(':'), typed.to_string());
+                self.open_cmdwin(kind, &typed);
+            }
+            KeyCode::Left if shift || control => state.cursor = state.prev_word(),
+            KeyCode::Right if shift || control => state.cursor = state.next_word(),
+            KeyCode::Left => state.cursor = state.prev_boundary(state.cursor),
+            KeyCode::Right => state.cursor = state.next_boundary(state.cursor),
+            KeyCode::Home => state.cursor = 1,
+            KeyCode::Char('b') if control => state.cursor = 1,
+            KeyCode::End => state.cursor = state.command_buffer.len(),
+            KeyCode::Char('e') if control => state.cursor = state.command_buffer.len(),
+            KeyCode::Backspace => {
+                if state.command_buffer.len() == 1 {
+                    // Deleting the `:` (or `/`) leaves the command line, as in Vim
+                    self.mode = Mode::Normal(NormalState::default());
+                    self.status_msg.clear();
+                    return;
+                }
+                let start = state.prev_boundary(state.cursor);
+                state.delete(start, state.cursor);
+                state.history = None;
+            }
+            KeyCode::Delete => {
+                let end = state.next_boundary(state.cursor);
+                state.delete(state.cursor, end);
+                state.history = None;
+            }
+            KeyCode::Char('w') if control => {
+                let start = state.word_start();
+                state.delete(start, state.cursor);
+                state.history = None;
+            }
+            KeyCode::Char('u') if control => {
+                state.delete(1, state.cursor);
+                state.history = None;
+            }
+            KeyCode::Char(c) if !control => {
+                state.insert(c.encode_utf8(&mut [0; 4]));
+                state.history = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// The text Ctrl-r inserts for the key typed after it. rim has no yank registers;
+    /// the read-only ones Vim has for names, the last search and the last command
+    /// line are there, and Ctrl-w for the word under the cursor.
+    fn register(&self, event: KeyEvent) -> Option<String> {
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        match event.code {
+            KeyCode::Char('w') if control => self.word_under_cursor(),
+            KeyCode::Char('%') => self.buffer.filename.clone(),
+            KeyCode::Char('#') => self.alternate_file.clone(),
+            KeyCode::Char('/') => self.last_search.as_ref().map(|s| s.pattern().to_string()),
+            KeyCode::Char(':') => self.history.list(':').last().cloned(),
+            _ => None,
+        }
+    }
+
+    /// The keyword under or after the cursor, as Vim's Ctrl-r Ctrl-w finds it.
+    pub(crate) fn word_under_cursor(&self) -> Option<String> {
+        let chars = self.buffer.line_chars(self.cy);
+        let start = chars
+            .iter()
+            .skip(self.cx)
+            .position(|&c| complete::is_keyword(c))
+            .map(|i| self.cx + i)?;
+        // Back to the start of a word the cursor is in the middle of
+        let start = chars[..start]
+            .iter()
+            .rposition(|&c| !complete::is_keyword(c))
+            .map_or(0, |i| i + 1);
+        Some(
+            chars[start..]
+                .iter()
+                .take_while(|&&c| complete::is_keyword(c))
+                .collect(),
+        )
+    }
+
+    /// Shows the previous (or next) line in the history of this kind of command line.
+    /// With `prefix`, only lines starting with what was typed before browsing count.
+    fn browse_history(&mut self, backward: bool, prefix: bool) {
+        let Mode::Command(state) = &mut self.mode else {
+            return;
+        };
+        let kind = state.command_buffer.chars().next().unwrap_or(':');
+        let list = self.history.list(kind);
+        let browse = state.history.get_or_insert_with(|| Browse {
+            index: list.len(),
+            typed: if prefix {
+                state.command_buffer[1..].to_string()
+            } else {
+                String::new()
+            },
+        });
+        let matches = |i: &usize| list[*i].starts_with(&browse.typed);
+        let found = if backward {
+            (0..browse.index).rev().find(matches)
+        } else {
+            (browse.index + 1..list.len()).find(matches)
+        };
+        let index = match found {
+            Some(index) => index,
+            // Down past the newest line brings back what was typed
+            None if !backward && browse.index < list.len() => list.len(),
+            None => return,
+        };
+        browse.index = index;
+        let text = list.get(index).unwrap_or(&browse.typed);
+        let line = format!("{}{}", kind, text);
+        state.set_text(line);
+    }
+
+    /// Completes the word before the cursor and lists the matches in the wildmenu.
+    fn start_wildmenu(&mut self, forward: bool) {
+        let Mode::Command(state) = &self.mode else {
+            return;
+        };
+        let Some((start, matches)) = self.completions(&state.command_buffer[..state.cursor]) else {
+            return;
+        };
+        let Mode::Command(state) = &mut self.mode else {
+            return;
+        };
+        state.history = None;
+        match matches.len() {
+            0 => {}
+            1 => {
+                state.delete(start, state.cursor);
+                state.insert(&matches[0]);
+            }
+            _ => {
+                let original = state.command_buffer[start..state.cursor].to_string();
+                state.wildmenu = Some(Wildmenu {
+                    start,
+                    original,
+                    matches,
+                    selected: None,
+                });
+                self.select_wild_match(forward);
+            }
+        }
+    }
+
+    /// Puts the next (or previous) match in the command line; past the last one comes
+    /// the word as typed, then the first again.
+    fn select_wild_match(&mut self, forward: bool) {
+        let Mode::Command(state) = &mut self.mode else {
+            return;
+        };
+        let Some(menu) = &mut state.wildmenu else {
+            return;
+        };
+        let count = menu.matches.len();
+        menu.selected = match (menu.selected, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(count - 1),
+            (Some(i), true) if i + 1 < count => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            (Some(_), _) => None,
+        };
+        let text = match menu.selected {
+            Some(i) => menu.matches[i].clone(),
+            None => menu.original.clone(),
+        };
+        let start = menu.start;
+        state.delete(start, state.cursor);
+        state.insert(&text);
+    }
+
+    /// What the word before the cursor could be completed to, and where the word
+    /// starts: a command name, an option name after `:set`, a buffer name after
+    /// `:buffer`, or a file name after a command that takes one.
+    fn completions(&self, line: &str) -> Option<(usize, Vec<String>)> {
+        let body = line.strip_prefix(':')?;
+        let last = self.buffer.len_lines().saturating_sub(1);
+        let (_, rest) = range::parse(body, self.cy, last).ok()?;
+        let rest = rest.trim_start();
+        let Some(name_end) = rest.find(char::is_whitespace) else {
+            if !rest.chars().all(|c| c.is_ascii_alphabetic()) {
+                return None;
+            }
+            let mut names: Vec<&str> = COMMANDS
+                .iter()
+                .copied()
+                .chain(quickfix::command_names())
+                .filter(|name| name.starts_with(rest))
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            let names = names.into_iter().map(String::from).collect();
+            return Some((line.len() - rest.len(), names));
+        };
+        let name = rest[..name_end].trim_end_matches('!');
+        let word = &line[line.rfind(char::is_whitespace).map_or(0, |i| i + 1)..];
+        let start = line.len() - word.len();
+        let matches = if name == "se" || name == "set" {
+            option_names(word)
+        } else if name == "b" || name == "buffer" {
+            self.buffer_names()
+                .into_iter()
+                .filter(|b| b.contains(word))
+                .collect()
+        } else if FILE_COMMANDS.contains(&name)
+            || quickfix::command_name(name)
+                .is_some_and(|c| matches!(c.action, "file" | "vimgrep" | "grep"))
+        {
+            complete::file_names(word)
+        } else {
+            return None;
+        };
+        Some((start, matches))
+    }
+
+    /// Draws the wildmenu over the row above the command line, scrolled a page at a
+    /// time to keep the selected match in sight.
+    pub(crate) fn draw_wildmenu(&self, frame: &mut Frame) {
+        let Mode::Command(CommandState {
+            wildmenu: Some(menu),
+            ..
+        }) = &self.mode
+        else {
+            return;
+        };
+        let Some(y) = self.status_row().checked_sub(1) else {
+            return;
+        };
+        let bar = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        let current = Style::DEFAULT.bg(Color::Yellow).fg(Color::Black);
+        frame.fill(0, y, self.terminal_cols, bar);
+        // Room for the `<` and `>` that say there is more on either side
+        let width = self.terminal_cols.saturating_sub(2);
+        let names: Vec<&str> = menu.matches.iter().map(|m| tail(m)).collect();
+        let selected = menu.selected.unwrap_or(0);
+        let mut first = 0;
+        let mut end = page_end(&names, first, width);
+        while end <= selected && end < names.len() {
+            first = end;
+            end = page_end(&names, first, width);
+        }
+        let mut x = 0;
+        if first > 0 {
+            frame.put_str(0, y, "<", bar);
+        }
+        x += 1;
+        for (i, name) in names.iter().enumerate().take(end).skip(first) {
+            let style = if menu.selected == Some(i) {
+                current
+            } else {
+                bar
+            };
+            x = frame.put_str(x, y, name, style) + 2;
+        }
+        if end < names.len() {
+            frame.put_str(self.terminal_cols.saturating_sub(1), y, ">", bar);
+        }
+    }
+}
+
+/// Where the page of wildmenu entries starting at `first` ends, filling `width`
+/// columns with entries two columns apart. A page always holds at least one entry.
+fn page_end(names: &[&str], first: usize, width: usize) -> usize {
+    let mut used = 0;
+    for (i, name) in names.iter().enumerate().skip(first) {
+        used += display::str_width(name) + 2;
+        if used > width + 2 && i > first {
+            return i;
+        }
+    }
+    names.len()
+}
+
+/// The last part of a path, as the wildmenu shows it, keeping a directory's `/`.
+fn tail(path: &str) -> &str {
+    let trimmed = path.strip_suffix('/').unwrap_or(path);
+    match trimmed.rfind('/') {
+        Some(i) => &path[i + 1..],
+        None => path,
+    }
+}
+
+/// Option names starting with `word`, which may have the `no` or `inv` of a
+/// boolean option in front.
+fn option_names(word: &str) -> Vec<String> {
+    if word.contains('=') {
+        return Vec::new();
+    }
+    let (negation, name) = ["no", "inv"]
+        .iter()
+        .find_map(|p| {
+            let rest = word.strip_prefix(p)?;
+            (!options::NAMES.iter().any(|n| n.starts_with(word))).then_some((*p, rest))
+        })
+        .unwrap_or(("", word));
+    options::NAMES
+        .iter()
+        .filter(|n| n.starts_with(name))
+        .map(|n| format!("{}{}", negation, n))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_moves_skip_multibyte_blanks() {
+        // An ideographic space (U+3000) and a no-break space (U+00A0)
+        let mut state = CommandState::new(":e\u{3000}ab\u{a0}cd".to_string());
+        assert_eq!(&state.command_buffer[state.prev_word()..], "cd");
+        state.cursor = state.prev_word() - '\u{a0}'.len_utf8();
+        assert_eq!(&state.command_buffer[state.prev_word()..], "ab\u{a0}cd");
+        state.cursor = 1;
+        assert_eq!(&state.command_buffer[state.next_word()..], "ab\u{a0}cd");
+    }
+}
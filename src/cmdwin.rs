@@ -0,0 +1,82 @@
+use crate::{Buffer, CommandState, Editor, Mode, NormalState};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use ropey::Rope;
+use std::io::Result;
+
+/// Text rows the command-line window gets when there is room, Vim's 'cmdwinheight'.
+const HEIGHT: usize = 7;
+
+/// What commands that would leave the command-line window are told.
+pub const INVALID: &str = "Invalid in command-line window; <CR> executes, CTRL-C quits";
+
+/// The command-line window opened with `q:`, `q/` or Ctrl-f: a window below the others
+/// on a buffer of the history, so every editing command works on it. It keeps the
+/// focus until it closes.
+pub struct CmdWindow {
+    /// `:` for Ex commands, `/` or `?` for searches.
+    kind: char,
+}
+
+impl Editor {
+    /// Opens the command-line window on the history of `kind`, with `typed` as a last
+    /// line to go on editing.
+    pub(crate) fn open_cmdwin(&mut self, kind: char, typed: &str) {
+        if !self.may_leave_window() {
+            return;
+        }
+        let mut lines = self.history.list(kind).to_vec();
+        lines.push(typed.to_string());
+        let buffer = Buffer::with_text(Rope::from_str(&lines.join("\n")), None);
+        self.quickfix.set_focus(false);
+        self.open_bottom_window(buffer, HEIGHT, "[Command Line]");
+        self.cmdwin = Some(CmdWindow { kind });
+        self.mode = Mode::Normal(NormalState::default());
+        self.cy = lines.len() - 1;
+        self.cx = typed.chars().count();
+        self.layout();
+    }
+
+    /// Closes the command-line window, going back to the window it was opened from.
+    /// Returns the kind of the window and the line the cursor was on.
+    pub(crate) fn close_cmdwin(&mut self) -> Option<(char, String)> {
+        let window = self.cmdwin.take()?;
+        let line: String = self.buffer.line_chars(self.cy).into_iter().collect();
+        self.close_window(self.windows.current_id());
+        self.mode = Mode::Normal(NormalState::default());
+        Some((window.kind, line))
+    }
+
+    /// Handles the keys that leave the command-line window: Enter runs the line under
+    /// the cursor, Ctrl-c takes it back to the command line. Returns None for keys
+    /// that edit the history as in any buffer.
+    pub(crate) fn process_cmdwin_keypress(&mut self, event: KeyEvent) -> Option<Result<bool>> {
+        self.cmdwin.as_ref()?;
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        let leaves = match &self.mode {
+            Mode::Normal(state) => state.pending.is_none(),
+            Mode::Insert(_) => true,
+            _ => false,
+        };
+        if !leaves {
+            return None;
+        }
+        match event.code {
+            KeyCode::Enter => {
+                let (kind, line) = self.close_cmdwin()?;
+                let command = format!("{}{}", kind, line);
+                self.history.add(&command);
+                Some(if kind == ':' {
+                    self.execute_command(&command)
+                } else {
+                    self.search(&command).map(|()| true)
+                })
+            }
+            KeyCode::Char('c') if control => {
+                let (kind, line) = self.close_cmdwin()?;
+                self.mode = Mode::Command(CommandState::new(format!("{}{}", kind, line)));
+                Some(Ok(true))
+            }
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,658 @@
+use crate::render::{Frame, Style};
+use crate::{Buffer, Editor, Mode, display, lsp};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::Color;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Rows of the completion menu at most; longer lists scroll.
+const MENU_HEIGHT: usize = 10;
+
+/// Columns the preview of a match needs at least to be shown beside the menu.
+const PREVIEW_MIN_WIDTH: usize = 12;
+
+/// What is being completed.
+#[derive(Clone, Copy)]
+pub enum Kind {
+    /// Ctrl-n and Ctrl-p.
+    Keyword,
+    /// Ctrl-x Ctrl-f.
+    FileName,
+    /// Ctrl-x Ctrl-l.
+    WholeLine,
+    /// Ctrl-x Ctrl-o, from the language server.
+    Omni,
+}
+
+impl Kind {
+    /// The name in the status bar, with the keys that go through the matches.
+    fn title(self) -> &'static str {
+        match self {
+            Kind::Keyword => "Keyword completion (^N^P)",
+            Kind::FileName => "File name completion (^F^N^P)",
+            Kind::WholeLine => "Whole line completion (^L^N^P)",
+            Kind::Omni => "Omni completion (^O^N^P)",
+        }
+    }
+
+    /// The key that picks the next match besides Ctrl-n and Ctrl-p, as in Vim, and
+    /// whether it goes forward.
+    fn repeat_key(self) -> Option<(char, bool)> {
+        match self {
+            Kind::Keyword => None,
+            Kind::FileName => Some(('f', true)),
+            Kind::WholeLine => Some(('l', false)),
+            Kind::Omni => Some(('o', true)),
+        }
+    }
+}
+
+/// A word that could complete the text.
+pub struct Candidate {
+    pub word: String,
+    /// Where the word was found, shown with the `preview` flag of `completeopt`.
+    pub info: String,
+    /// The language server's item, finished when its match is kept.
+    pub item: Option<lsp::CompletionItem>,
+}
+
+/// Completion in progress in Insert mode: the candidates for the text before the
+/// cursor and which one is in the buffer.
+pub struct Completion {
+    /// The line being completed.
+    row: usize,
+    /// Where the completed text starts in that line.
+    start: usize,
+    /// The text typed before completion started, put back by Ctrl-e.
+    original: String,
+    candidates: Vec<Candidate>,
+    /// The candidate in the text; None while it still holds the original.
+    selected: Option<usize>,
+    kind: Kind,
+    /// Whether the menu is shown.
+    menu: bool,
+    /// The first candidate shown when the menu scrolls.
+    top: usize,
+}
+
+impl Completion {
+    /// Picks the next candidate, or the previous one; past either end of the list
+    /// comes the original text again.
+    fn select(&mut self, forward: bool) {
+        let last = self.candidates.len() - 1;
+        self.selected = match (self.selected, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(last),
+            (Some(i), true) if i < last => Some(i + 1),
+            (Some(i), false) if i > 0 => Some(i - 1),
+            (Some(_), _) => None,
+        };
+        if let Some(selected) = self.selected {
+            self.top = self
+                .top
+                .min(selected)
+                .max((selected + 1).saturating_sub(MENU_HEIGHT));
+        }
+    }
+}
+
+/// Whether a character can be part of a keyword.
+pub fn is_keyword(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Finds the keywords of a line, with the char column each starts at.
+fn keywords(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut start = None;
+    let mut words = Vec::new();
+    for (col, (i, c)) in line.char_indices().enumerate() {
+        match (is_keyword(c), start) {
+            (true, None) => start = Some((col, i)),
+            (false, Some((start_col, start_byte))) => {
+                words.push((start_col, &line[start_byte..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((start_col, start_byte)) = start {
+        words.push((start_col, &line[start_byte..]));
+    }
+    words.into_iter()
+}
+
+/// Whether a character can be part of a file name to complete. Quotes, brackets and
+/// the separators of settings such as `path=a:b` end one.
+fn is_file_name_char(c: char) -> bool {
+    !c.is_whitespace() && !"\"'`<>()[]{}|;,=:".contains(c)
+}
+
+/// Replaces a `~` at the start of a path with the home directory.
+pub fn expand_home(path: &str) -> Cow<'_, str> {
+    match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            Cow::Owned(format!("{}{}", home, rest))
+        }
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// Adds the entries of `dir` whose names start with `name`, ignoring case, as
+/// completions of `typed_dir` (the directory as typed, `~` and all). Hidden entries
+/// are only offered once a `.` is typed.
+fn add_dir_entries(
+    dir: &Path,
+    typed_dir: &str,
+    name: &str,
+    seen: &mut HashSet<String>,
+    candidates: &mut Vec<Candidate>,
+) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let name = name.to_lowercase();
+    let mut found: Vec<(String, bool)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let shown = !file_name.starts_with('.') || name.starts_with('.');
+            (shown && file_name.to_lowercase().starts_with(&name))
+                .then(|| (file_name, entry.path().is_dir()))
+        })
+        .collect();
+    found.sort_by_key(|(file_name, _)| file_name.to_lowercase());
+    for (file_name, is_dir) in found {
+        let slash = if is_dir { "/" } else { "" };
+        let word = format!("{}{}{}", typed_dir, file_name, slash);
+        if seen.insert(word.clone()) {
+            let path = dir.join(&file_name);
+            candidates.push(Candidate {
+                word,
+                info: format!(
+                    "{}{}",
+                    path.strip_prefix(".").unwrap_or(&path).display(),
+                    slash
+                ),
+                item: None,
+            });
+        }
+    }
+}
+
+/// The paths that complete `typed`, relative to the working directory, as the command
+/// line's Tab completion offers them.
+pub fn file_names(typed: &str) -> Vec<String> {
+    let (typed_dir, name) = typed.split_at(typed.rfind('/').map_or(0, |i| i + 1));
+    let dir = expand_home(typed_dir);
+    let dir = Path::new(".").join(dir.as_ref());
+    let mut candidates = Vec::new();
+    add_dir_entries(&dir, typed_dir, name, &mut HashSet::new(), &mut candidates);
+    candidates.into_iter().map(|c| c.word).collect()
+}
+
+/// The name a buffer goes by in completion: its file's, if it has one.
+fn buffer_name(buffer: &Buffer) -> &str {
+    buffer.filename.as_deref().unwrap_or("[No Name]")
+}
+
+/// Adds the words of a dictionary file that complete `prefix` (`k` in `complete`).
+fn add_file_words(
+    path: &str,
+    prefix: &str,
+    seen: &mut HashSet<String>,
+    candidates: &mut Vec<Candidate>,
+) {
+    let Ok(bytes) = fs::read(path) else {
+        return;
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    let lines = text.lines().map(Cow::Borrowed);
+    add_words(path, lines, prefix, true, seen, candidates);
+}
+
+/// Adds the words of the lines of `path` that complete `prefix`, for the buffers of
+/// other windows (`b` in `complete`) and the dictionaries (`k`).
+fn add_words<'a>(
+    path: &str,
+    lines: impl Iterator<Item = Cow<'a, str>>,
+    prefix: &str,
+    is_dictionary: bool,
+    seen: &mut HashSet<String>,
+    candidates: &mut Vec<Candidate>,
+) {
+    let name = Path::new(path)
+        .file_name()
+        .map_or(Cow::Borrowed(path), |name| name.to_string_lossy());
+    for (row, line) in lines.enumerate() {
+        for (_, word) in keywords(&line) {
+            if word.len() > prefix.len()
+                && word.starts_with(prefix)
+                && seen.insert(word.to_string())
+            {
+                let info = if is_dictionary {
+                    format!("[{}]", name)
+                } else {
+                    format!("{}:{}: {}", name, row + 1, line.trim())
+                };
+                candidates.push(Candidate {
+                    word: word.to_string(),
+                    info,
+                    item: None,
+                });
+            }
+        }
+    }
+}
+
+impl Editor {
+    /// Handles the keys of completion in Insert mode: Ctrl-n and Ctrl-p start it and go
+    /// through the matches, Ctrl-x then Ctrl-f completes a file name, Ctrl-x then
+    /// Ctrl-l a whole line and Ctrl-x then Ctrl-o asks the language server. Ctrl-y
+    /// keeps the match and Ctrl-e puts back what was typed.
+    /// Any other key ends it, keeping the match, and is then handled as usual; returns
+    /// whether the key was used up here.
+    pub(crate) fn process_completion_keypress(&mut self, event: KeyEvent) -> bool {
+        let Mode::Insert(state) = &mut self.mode else {
+            return false;
+        };
+        // The cursor left the completed text, e.g. by a mouse click
+        if state
+            .completion
+            .as_ref()
+            .is_some_and(|c| c.row != self.cy || self.cx < c.start)
+        {
+            state.completion = None;
+        }
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        // The key after Ctrl-x says what to complete; any other ends the Ctrl-x mode
+        if std::mem::take(&mut state.ctrl_x) && control {
+            let start: Option<fn(&mut Editor)> = match event.code {
+                KeyCode::Char('f') => Some(Editor::complete_file_name),
+                KeyCode::Char('l') => Some(Editor::complete_line),
+                KeyCode::Char('o') => Some(Editor::complete_omni),
+                KeyCode::Char('n') => Some(|editor| editor.complete_keyword(false)),
+                KeyCode::Char('p') => Some(|editor| editor.complete_keyword(true)),
+                _ => None,
+            };
+            if let Some(start) = start {
+                state.completion = None;
+                start(self);
+                return true;
+            }
+        }
+        let repeat = state.completion.as_ref().and_then(|c| c.kind.repeat_key());
+        match (event.code, &mut state.completion) {
+            (KeyCode::Char('x'), completion) if control => {
+                *completion = None;
+                state.ctrl_x = true;
+                self.status_msg = "-- ^X mode (^F^L^N^O^P)".to_string();
+            }
+            (KeyCode::Char(c @ ('n' | 'p')), Some(completion)) if control => {
+                completion.select(c == 'n');
+                self.show_selected();
+            }
+            (KeyCode::Char(c), Some(completion))
+                if control && repeat.is_some_and(|(key, _)| key == c) =>
+            {
+                completion.select(repeat.is_some_and(|(_, forward)| forward));
+                self.show_selected();
+            }
+            (KeyCode::Char(c @ ('n' | 'p')), None) if control => self.complete_keyword(c == 'p'),
+            (KeyCode::Char('y'), completion @ Some(_)) if control => {
+                if let Some(completion) = completion.take() {
+                    self.keep_completion(completion);
+                }
+            }
+            (KeyCode::Char('e'), Some(completion)) if control => {
+                completion.selected = None;
+                self.show_selected();
+                if let Mode::Insert(state) = &mut self.mode {
+                    state.completion = None;
+                }
+            }
+            (_, completion) => {
+                if let Some(completion) = completion.take() {
+                    self.keep_completion(completion);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Ends completion with the selected match in the text; a language server's item
+    /// is finished by the client.
+    fn keep_completion(&mut self, completion: Completion) {
+        let Some(selected) = completion.selected else {
+            return;
+        };
+        let item = completion
+            .candidates
+            .into_iter()
+            .nth(selected)
+            .and_then(|c| c.item);
+        if let Some(item) = item {
+            self.finish_completion_item(completion.start, item);
+        }
+    }
+
+    /// Starts completing the keyword before the cursor with the words found in the
+    /// places `complete` lists. With `backward`, the nearest word above the cursor
+    /// comes first.
+    fn complete_keyword(&mut self, backward: bool) {
+        let chars = self.buffer.line_chars(self.cy);
+        let cx = self.cx.min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|&c| !is_keyword(c))
+            .map_or(0, |i| i + 1);
+        let prefix: String = chars[start..cx].iter().collect();
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        for source in self.options.complete.split(',') {
+            match source {
+                "." => self.add_buffer_words(&prefix, start, backward, &mut seen, &mut candidates),
+                "b" => {
+                    for buffer in self.windows.buffers() {
+                        let lines = buffer.rope.lines().map(Cow::from);
+                        let name = buffer_name(buffer);
+                        add_words(name, lines, &prefix, false, &mut seen, &mut candidates);
+                    }
+                }
+                "k" => {
+                    for dictionary in self.options.dictionary.split(',').filter(|d| !d.is_empty()) {
+                        add_file_words(dictionary, &prefix, &mut seen, &mut candidates);
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Going backward, the list is shown upside down: the first match at the bottom
+        if backward {
+            candidates.reverse();
+        }
+        self.start_completion(start, candidates, Kind::Keyword, backward);
+    }
+
+    /// Starts completing the file name before the cursor (Ctrl-x Ctrl-f). A relative
+    /// name is looked for from the buffer's directory and from the working directory.
+    fn complete_file_name(&mut self) {
+        let chars = self.buffer.line_chars(self.cy);
+        let cx = self.cx.min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|&c| !is_file_name_char(c))
+            .map_or(0, |i| i + 1);
+        let typed: String = chars[start..cx].iter().collect();
+        let (typed_dir, name) = typed.split_at(typed.rfind('/').map_or(0, |i| i + 1));
+        let dir = expand_home(typed_dir);
+        let mut dirs = Vec::new();
+        if Path::new(dir.as_ref()).is_absolute() {
+            dirs.push(PathBuf::from(dir.as_ref()));
+        } else {
+            let buffer_dir = self
+                .buffer
+                .filename
+                .as_deref()
+                .and_then(|f| Path::new(f).parent());
+            if let Some(buffer_dir) = buffer_dir.filter(|d| !d.as_os_str().is_empty()) {
+                dirs.push(buffer_dir.join(dir.as_ref()));
+            }
+            dirs.push(Path::new(".").join(dir.as_ref()));
+        }
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        for dir in dirs {
+            add_dir_entries(&dir, typed_dir, name, &mut seen, &mut candidates);
+        }
+        self.start_completion(start, candidates, Kind::FileName, false);
+    }
+
+    /// Starts completing the line the cursor is in with whole lines that start with
+    /// the same text, leaving its indent alone (Ctrl-x Ctrl-l). Lines come from the
+    /// places `complete` lists, the nearest line above the cursor first.
+    fn complete_line(&mut self) {
+        let chars = self.buffer.line_chars(self.cy);
+        let cx = self.cx.min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .position(|c| !c.is_whitespace())
+            .unwrap_or(cx);
+        let typed = chars[start..cx].iter().collect::<String>().to_lowercase();
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        let mut add = |line: &str, info: String| {
+            let line = line.trim();
+            if !line.is_empty()
+                && line.to_lowercase().starts_with(&typed)
+                && seen.insert(line.to_string())
+            {
+                candidates.push(Candidate {
+                    word: line.to_string(),
+                    info,
+                    item: None,
+                });
+            }
+        };
+        for source in self.options.complete.split(',') {
+            match source {
+                "." => {
+                    let last = self.buffer.len_lines();
+                    let rows = (0..self.cy).rev().chain((self.cy + 1..last).rev());
+                    for row in rows {
+                        let line: Cow<str> = self.buffer.rope.line(row).into();
+                        add(&line, format!("line {}", row + 1));
+                    }
+                }
+                "b" => {
+                    for buffer in self.windows.buffers() {
+                        for (row, line) in buffer.rope.lines().enumerate() {
+                            let line: Cow<str> = line.into();
+                            add(&line, format!("{}:{}", buffer_name(buffer), row + 1));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Shown upside down, as for Ctrl-p: the nearest line at the bottom
+        candidates.reverse();
+        self.start_completion(start, candidates, Kind::WholeLine, true);
+    }
+
+    /// Adds the words of the buffer that complete `prefix`, in the order met going down
+    /// from the cursor (or up, with `backward`) and round from the other end.
+    fn add_buffer_words(
+        &self,
+        prefix: &str,
+        start: usize,
+        backward: bool,
+        seen: &mut HashSet<String>,
+        candidates: &mut Vec<Candidate>,
+    ) {
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        for row in 0..self.buffer.len_lines() {
+            let line: Cow<str> = self.buffer.rope.line(row).into();
+            for (col, word) in keywords(&line) {
+                // The word being completed
+                if (row, col) == (self.cy, start) {
+                    continue;
+                }
+                if word.len() > prefix.len() && word.starts_with(prefix) {
+                    let found = (row, word.to_string(), line.trim().to_string());
+                    if (row, col) < (self.cy, start) {
+                        before.push(found);
+                    } else {
+                        after.push(found);
+                    }
+                }
+            }
+        }
+        let found: Vec<_> = if backward {
+            before
+                .into_iter()
+                .rev()
+                .chain(after.into_iter().rev())
+                .collect()
+        } else {
+            after.into_iter().chain(before).collect()
+        };
+        for (row, word, line) in found {
+            if seen.insert(word.clone()) {
+                candidates.push(Candidate {
+                    word,
+                    info: format!("line {}: {}", row + 1, line),
+                    item: None,
+                });
+            }
+        }
+    }
+
+    /// Starts completion of the text from `start` to the cursor with the given
+    /// candidates, in the order shown. Unless `completeopt` has `noselect`, the first
+    /// one (the last with `backward`) goes in at once.
+    pub(crate) fn start_completion(
+        &mut self,
+        start: usize,
+        candidates: Vec<Candidate>,
+        kind: Kind,
+        backward: bool,
+    ) {
+        if candidates.is_empty() {
+            self.status_msg = format!("-- {} Pattern not found", kind.title());
+            return;
+        }
+        let chars = self.buffer.line_chars(self.cy);
+        let original = chars[start..self.cx.min(chars.len())].iter().collect();
+        let menu = (self.options.completeopt("menu") && candidates.len() > 1)
+            || self.options.completeopt("menuone");
+        let mut completion = Completion {
+            row: self.cy,
+            start,
+            original,
+            candidates,
+            selected: None,
+            kind,
+            menu,
+            top: 0,
+        };
+        if !self.options.completeopt("noselect") {
+            completion.select(!backward);
+        }
+        if let Mode::Insert(state) = &mut self.mode {
+            state.completion = Some(completion);
+        }
+        self.show_selected();
+    }
+
+    /// Puts the selected candidate (or the original text) in the buffer and says which
+    /// one it is.
+    fn show_selected(&mut self) {
+        let Mode::Insert(state) = &self.mode else {
+            return;
+        };
+        let Some(completion) = &state.completion else {
+            return;
+        };
+        let text = match completion.selected {
+            Some(i) => completion.candidates[i].word.clone(),
+            None => completion.original.clone(),
+        };
+        let count = completion.candidates.len();
+        let title = completion.kind.title();
+        self.status_msg = match completion.selected {
+            _ if count == 1 => format!("-- {} The only match", title),
+            Some(i) => format!("-- {} match {} of {}", title, i + 1, count),
+            None => format!("-- {} Back at original", title),
+        };
+        let start = completion.start;
+        self.buffer.delete_range(self.cy, start, self.cx);
+        self.buffer.insert_str(self.cy, start, &text);
+        self.cx = start + text.chars().count();
+    }
+
+    /// Draws the completion menu below the cursor line (above it if there is no room),
+    /// with the selected match's preview beside it.
+    pub(crate) fn draw_completion(&self, frame: &mut Frame) {
+        let Mode::Insert(state) = &self.mode else {
+            return;
+        };
+        let Some(completion) = state.completion.as_ref().filter(|c| c.menu) else {
+            return;
+        };
+        let (cursor_x, cursor_y) = self.cursor_screen_pos();
+        let below = self.screen_rows.saturating_sub(cursor_y + 1);
+        let count = completion.candidates.len();
+        let (y, height) = if below >= count.min(MENU_HEIGHT) || below >= cursor_y {
+            (cursor_y + 1, count.min(MENU_HEIGHT).min(below))
+        } else {
+            let height = count.min(MENU_HEIGHT).min(cursor_y);
+            (cursor_y - height, height)
+        };
+        if height == 0 {
+            return;
+        }
+        let typed: String = self.buffer.line_chars(self.cy)[completion.start..self.cx]
+            .iter()
+            .collect();
+        let widest = completion
+            .candidates
+            .iter()
+            .map(|c| display::str_width(&c.word))
+            .max()
+            .unwrap_or(0);
+        // A space either side of the words
+        let width = (widest + 2).min(self.screen_cols);
+        let x = cursor_x
+            .saturating_sub(display::str_width(&typed) + 1)
+            .min(self.screen_cols - width);
+        let top = completion.top.min(count - height).max(
+            completion
+                .selected
+                .map_or(0, |s| (s + 1).saturating_sub(height)),
+        );
+        let item = Style::DEFAULT.bg(Color::DarkMagenta).fg(Color::White);
+        let selected = Style::DEFAULT.bg(Color::Grey).fg(Color::Black);
+        for row in 0..height {
+            let index = top + row;
+            let style = if completion.selected == Some(index) {
+                selected
+            } else {
+                item
+            };
+            frame.fill(x, y + row, width, style);
+            let word = display::truncate(&completion.candidates[index].word, width - 1);
+            frame.put_str(x + 1, y + row, word, style);
+        }
+        let Some(index) = completion
+            .selected
+            .filter(|_| self.options.completeopt("preview"))
+        else {
+            return;
+        };
+        let info = &completion.candidates[index].info;
+        let info_width = display::str_width(info) + 2;
+        let right = self.screen_cols.saturating_sub(x + width);
+        let (info_x, info_width) = if right >= info_width.min(PREVIEW_MIN_WIDTH) {
+            (x + width, info_width.min(right))
+        } else if x >= info_width.min(PREVIEW_MIN_WIDTH) {
+            let info_width = info_width.min(x);
+            (x - info_width, info_width)
+        } else {
+            return;
+        };
+        let style = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::White);
+        let info_y = y + index - top;
+        frame.fill(info_x, info_y, info_width, style);
+        frame.put_str(
+            info_x + 1,
+            info_y,
+            display::truncate(info, info_width.saturating_sub(2)),
+            style,
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use crate::encoding;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// A grapheme cluster of a line, with its position in chars and on screen.
+#[derive(Clone, Debug)]
+pub struct Grapheme {
+    /// Index of the first char of the cluster.
+    pub start: usize,
+    /// Index one past the last char of the cluster.
+    pub end: usize,
+    /// Display column where the cluster starts.
+    pub col: usize,
+    /// Number of screen cells the cluster takes up.
+    pub width: usize,
+    /// The text to send to the terminal for this cluster.
+    pub text: String,
+    /// Whether this is a tab, drawn as spaces up to the next tab stop.
+    pub tab: bool,
+}
+
+/// Splits a line (without its terminator) into grapheme clusters and lays them out in
+/// display columns: tabs expand to the next multiple of `tabstop`, wide characters take
+/// two cells, control characters are shown as `^X` and invalid bytes as `<xx>`.
+pub fn layout(chars: &[char], tabstop: usize) -> Vec<Grapheme> {
+    let line: String = chars.iter().collect();
+    let tabstop = tabstop.max(1);
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut col = 0;
+    for cluster in line.graphemes(true) {
+        let len = cluster.chars().count();
+        let (width, text) = if cluster == "\t" {
+            let width = tabstop - col % tabstop;
+            (width, " ".repeat(width))
+        } else if let Some(byte) = cluster.chars().next().and_then(encoding::raw_byte) {
+            // A byte that isn't valid in the file's encoding
+            (4, format!("<{:02x}>", byte))
+        } else if let Some(c) = cluster.chars().next().filter(|c| c.is_control()) {
+            // Shown like Vim: ^A for \x01, ^? for DEL
+            let shown = char::from_u32((c as u32) ^ 0x40).unwrap_or('?');
+            (2, format!("^{}", shown))
+        } else {
+            // A lone combining mark still needs a cell to be visible
+            (cluster.width().max(1), cluster.to_string())
+        };
+        result.push(Grapheme {
+            start,
+            end: start + len,
+            col,
+            width,
+            text,
+            tab: cluster == "\t",
+        });
+        start += len;
+        col += width;
+    }
+    result
+}
+
+/// Returns the display width of a whole line.
+pub fn line_width(graphemes: &[Grapheme]) -> usize {
+    graphemes.last().map_or(0, |g| g.col + g.width)
+}
+
+/// Returns the index of the grapheme that holds char `cx`.
+/// A position at (or past) the end of the line maps to `graphemes.len()`.
+pub fn grapheme_at(graphemes: &[Grapheme], cx: usize) -> usize {
+    graphemes
+        .iter()
+        .position(|g| cx < g.end)
+        .unwrap_or(graphemes.len())
+}
+
+/// Returns the display column where char `cx` is drawn.
+pub fn char_to_col(graphemes: &[Grapheme], cx: usize) -> usize {
+    match graphemes.get(grapheme_at(graphemes, cx)) {
+        Some(g) => g.col,
+        None => line_width(graphemes),
+    }
+}
+
+/// Returns the char index of the grapheme covering display column `col`.
+/// Columns past the end of the line map to the line's length.
+pub fn col_to_char(graphemes: &[Grapheme], col: usize) -> usize {
+    graphemes
+        .iter()
+        .find(|g| col < g.col + g.width)
+        .map_or_else(|| graphemes.last().map_or(0, |g| g.end), |g| g.start)
+}
+
+/// Returns the start of the grapheme before the one holding `cx`.
+pub fn prev_boundary(graphemes: &[Grapheme], cx: usize) -> usize {
+    let index = grapheme_at(graphemes, cx);
+    if index == 0 {
+        0
+    } else {
+        graphemes[index - 1].start
+    }
+}
+
+/// Returns the start of the grapheme after the one holding `cx`.
+pub fn next_boundary(graphemes: &[Grapheme], cx: usize) -> usize {
+    match graphemes.get(grapheme_at(graphemes, cx)) {
+        Some(g) => g.end,
+        None => cx,
+    }
+}
+
+/// Returns the display width of a string, as used for the status bar and prompts.
+pub fn str_width(text: &str) -> usize {
+    text.width()
+}
+
+/// Returns the longest prefix of `text` that fits in `width` columns.
+pub fn truncate(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, cluster) in text.grapheme_indices(true) {
+        used += cluster.width();
+        if used > width {
+            return &text[..i];
+        }
+    }
+    text
+}
@@ -0,0 +1,449 @@
+use encoding_rs::{Decoder, DecoderResult, Encoding};
+
+/// Bytes that aren't valid in a file's encoding are kept as the private-use chars
+/// U+10FF00 to U+10FFFF (U+10FF00 plus the byte value). They are shown as `<xx>`
+/// and written back as the original byte, so nothing is lost by opening a file. A
+/// file that holds those chars itself has their bytes kept the same way.
+const RAW_BYTE_BASE: u32 = 0x10FF00;
+
+/// The text of a file after decoding.
+pub struct Decoded {
+    pub text: String,
+    /// Name of the encoding it was read with, as used by `fileencoding`.
+    pub encoding: String,
+    /// Whether the file started with a byte order mark.
+    pub bom: bool,
+    /// Whether some bytes were invalid and are kept as raw bytes.
+    pub lossy: bool,
+}
+
+/// An encoding rim can read and write.
+enum Codec {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO-8859-1: every byte is the char with the same value.
+    Latin1,
+    Other(&'static Encoding),
+}
+
+/// Looks up an encoding by a Vim-style name such as `utf-8`, `latin1`, `utf-16le`
+/// or `cp1252`, or any label known to the Encoding Standard.
+fn lookup(name: &str) -> Option<Codec> {
+    let name = name.to_ascii_lowercase();
+    Some(match name.as_str() {
+        "utf-8" | "utf8" => Codec::Utf8,
+        // Vim's `utf-16` is big-endian
+        "utf-16" | "utf-16be" | "ucs-2" | "ucs-2be" => Codec::Utf16Be,
+        "utf-16le" | "ucs-2le" => Codec::Utf16Le,
+        "latin1" | "latin-1" | "iso-8859-1" | "iso8859-1" => Codec::Latin1,
+        _ => {
+            let label = name
+                .strip_prefix("cp")
+                .map_or(name.clone(), |n| format!("windows-{}", n));
+            let encoding = Encoding::for_label(label.as_bytes())
+                .or_else(|| Encoding::for_label(name.as_bytes()))?;
+            // Encodings that are read-only in the Encoding Standard can't be written back
+            if encoding.output_encoding() != encoding {
+                return None;
+            }
+            Codec::Other(encoding)
+        }
+    })
+}
+
+/// Whether rim can read and write an encoding with this name.
+pub fn is_known(name: &str) -> bool {
+    lookup(name).is_some()
+}
+
+/// Decodes a file. With `forced` (`++enc=`) that encoding is used; otherwise a BOM
+/// decides when `fileencodings` includes `ucs-bom`, and then the first entry of
+/// `fileencodings` that decodes the whole file without errors is taken. If none
+/// does, the file is read as UTF-8 with the invalid bytes kept as raw bytes.
+pub fn decode(bytes: &[u8], fileencodings: &str, forced: Option<&str>) -> Decoded {
+    if let Some(name) = forced
+        && let Some(codec) = lookup(name)
+    {
+        let (text, lossy) = decode_lossless(&codec, bytes);
+        return Decoded {
+            text,
+            encoding: name.to_ascii_lowercase(),
+            bom: false,
+            lossy,
+        };
+    }
+    for name in fileencodings.split(',').map(str::trim) {
+        if name == "ucs-bom" {
+            if let Some((encoding, codec, len)) = sniff_bom(bytes) {
+                let (text, lossy) = decode_lossless(&codec, &bytes[len..]);
+                return Decoded {
+                    text,
+                    encoding: encoding.to_string(),
+                    bom: true,
+                    lossy,
+                };
+            }
+            continue;
+        }
+        if let Some(codec) = lookup(name)
+            && let (text, false) = decode_lossless(&codec, bytes)
+        {
+            return Decoded {
+                text,
+                encoding: name.to_ascii_lowercase(),
+                bom: false,
+                lossy: false,
+            };
+        }
+    }
+    let (text, lossy) = decode_lossless(&Codec::Utf8, bytes);
+    Decoded {
+        text,
+        encoding: "utf-8".to_string(),
+        bom: false,
+        lossy,
+    }
+}
+
+/// Recognises a byte order mark. Returns the encoding name, codec and BOM length.
+fn sniff_bom(bytes: &[u8]) -> Option<(&'static str, Codec, usize)> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some(("utf-8", Codec::Utf8, 3))
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        Some(("utf-16le", Codec::Utf16Le, 2))
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some(("utf-16", Codec::Utf16Be, 2))
+    } else {
+        None
+    }
+}
+
+/// Decodes bytes, keeping invalid ones as raw-byte chars.
+/// Returns the text and whether any bytes were invalid.
+fn decode_lossless(codec: &Codec, bytes: &[u8]) -> (String, bool) {
+    match codec {
+        Codec::Utf8 => {
+            let mut text = String::with_capacity(bytes.len());
+            let mut lossy = false;
+            let mut rest = bytes;
+            loop {
+                match std::str::from_utf8(rest) {
+                    Ok(valid) => {
+                        push_escaped(&mut text, valid, encoding_rs::UTF_8);
+                        return (text, lossy);
+                    }
+                    Err(e) => {
+                        let (valid, after) = rest.split_at(e.valid_up_to());
+                        // `valid_up_to` marks the end of valid UTF-8, so this never fails
+                        let valid = std::str::from_utf8(valid).unwrap_or_default();
+                        push_escaped(&mut text, valid, encoding_rs::UTF_8);
+                        let bad = e.error_len().unwrap_or(after.len());
+                        text.extend(after[..bad].iter().map(|&b| raw_byte_char(b)));
+                        lossy = true;
+                        rest = &after[bad..];
+                    }
+                }
+            }
+        }
+        Codec::Latin1 => (bytes.iter().map(|&b| b as char).collect(), false),
+        Codec::Utf16Le => decode_with(encoding_rs::UTF_16LE, bytes),
+        Codec::Utf16Be => decode_with(encoding_rs::UTF_16BE, bytes),
+        Codec::Other(encoding) => decode_with(encoding, bytes),
+    }
+}
+
+/// Decodes with an Encoding Standard decoder, keeping malformed bytes as raw-byte chars.
+fn decode_with(encoding: &'static Encoding, bytes: &[u8]) -> (String, bool) {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = String::new();
+    let lossy = decode_chunk(&mut decoder, bytes, true, &mut text);
+    (text, lossy)
+}
+
+/// Decodes the next piece of input onto the end of `text`. Returns whether some bytes
+/// were malformed; they are kept as raw-byte chars.
+fn decode_chunk(decoder: &mut Decoder, bytes: &[u8], last: bool, text: &mut String) -> bool {
+    let capacity = decoder
+        .max_utf8_buffer_length_without_replacement(bytes.len())
+        .unwrap_or(bytes.len() * 3);
+    text.reserve(capacity);
+    let mut lossy = false;
+    let mut rest = bytes;
+    loop {
+        let start = text.len();
+        let (result, read) = decoder.decode_to_string_without_replacement(rest, text, last);
+        if text[start..].chars().any(is_raw_byte_char) {
+            let decoded = text.split_off(start);
+            push_escaped(text, &decoded, decoder.encoding());
+        }
+        match result {
+            DecoderResult::InputEmpty => return lossy,
+            DecoderResult::OutputFull => text.reserve(rest.len() * 3 + 16),
+            DecoderResult::Malformed(bad, after) => {
+                let end = read - after as usize;
+                let start = end.saturating_sub(bad as usize);
+                text.extend(rest[start..end].iter().map(|&b| raw_byte_char(b)));
+                lossy = true;
+            }
+        }
+        rest = &rest[read..];
+    }
+}
+
+/// Appends decoded text to `text`. Chars that stand for raw bytes but were really in
+/// the file become raw-byte chars for their bytes in `encoding`, so that they are
+/// written back as they were.
+fn push_escaped(text: &mut String, decoded: &str, encoding: &'static Encoding) {
+    if !decoded.chars().any(is_raw_byte_char) {
+        text.push_str(decoded);
+        return;
+    }
+    for c in decoded.chars() {
+        if !is_raw_byte_char(c) {
+            text.push(c);
+            continue;
+        }
+        let mut units = [0; 2];
+        let units = c.encode_utf16(&mut units).iter();
+        let bytes: Vec<u8> = if encoding == encoding_rs::UTF_16LE {
+            units.flat_map(|u| u.to_le_bytes()).collect()
+        } else if encoding == encoding_rs::UTF_16BE {
+            units.flat_map(|u| u.to_be_bytes()).collect()
+        } else {
+            encoding.encode(c.encode_utf8(&mut [0; 4])).0.into_owned()
+        };
+        text.extend(bytes.into_iter().map(raw_byte_char));
+    }
+}
+
+/// Decodes a file piece by piece, for files too big to decode in one go.
+pub struct StreamDecoder {
+    /// None for Latin-1, which needs no state.
+    decoder: Option<Decoder>,
+}
+
+impl StreamDecoder {
+    /// Picks the encoding the way `decode` does, but judging only by the first chunk of
+    /// the file. Returns the decoder, the encoding name, and the length of a BOM to skip.
+    pub fn detect(
+        first: &[u8],
+        fileencodings: &str,
+        forced: Option<&str>,
+    ) -> (Self, String, usize) {
+        if let Some(name) = forced
+            && let Some(codec) = lookup(name)
+        {
+            return (Self::new(&codec), name.to_ascii_lowercase(), 0);
+        }
+        for name in fileencodings.split(',').map(str::trim) {
+            if name == "ucs-bom" {
+                if let Some((encoding, codec, len)) = sniff_bom(first) {
+                    return (Self::new(&codec), encoding.to_string(), len);
+                }
+                continue;
+            }
+            let Some(codec) = lookup(name) else {
+                continue;
+            };
+            // A char cut off at the end of the chunk doesn't count against UTF-8
+            let fits = match codec {
+                Codec::Utf8 => match std::str::from_utf8(first) {
+                    Ok(_) => true,
+                    Err(e) => e.error_len().is_none(),
+                },
+                _ => !decode_lossless(&codec, first).1,
+            };
+            if fits {
+                return (Self::new(&codec), name.to_ascii_lowercase(), 0);
+            }
+        }
+        (Self::new(&Codec::Utf8), "utf-8".to_string(), 0)
+    }
+
+    fn new(codec: &Codec) -> Self {
+        let encoding = match codec {
+            Codec::Utf8 => encoding_rs::UTF_8,
+            Codec::Utf16Le => encoding_rs::UTF_16LE,
+            Codec::Utf16Be => encoding_rs::UTF_16BE,
+            Codec::Latin1 => return Self { decoder: None },
+            Codec::Other(encoding) => encoding,
+        };
+        Self {
+            decoder: Some(encoding.new_decoder_without_bom_handling()),
+        }
+    }
+
+    /// Decodes the next chunk; `last` flushes a char cut off at the end.
+    /// Returns the text and whether some bytes were invalid.
+    pub fn decode(&mut self, bytes: &[u8], last: bool) -> (String, bool) {
+        match &mut self.decoder {
+            Some(decoder) => {
+                let mut text = String::new();
+                let lossy = decode_chunk(decoder, bytes, last, &mut text);
+                (text, lossy)
+            }
+            None => (bytes.iter().map(|&b| b as char).collect(), false),
+        }
+    }
+}
+
+/// Encodes text for writing, mapping raw-byte chars back to their bytes.
+/// Fails on a char the encoding can't represent.
+pub fn encode(text: &str, name: &str, bom: bool) -> Result<Vec<u8>, String> {
+    let codec = lookup(name).ok_or_else(|| format!("unknown encoding {}", name))?;
+    let mut bytes = Vec::with_capacity(text.len());
+    if bom {
+        match codec {
+            Codec::Utf8 => bytes.extend_from_slice(&[0xEF, 0xBB, 0xBF]),
+            Codec::Utf16Le => bytes.extend_from_slice(&[0xFF, 0xFE]),
+            Codec::Utf16Be => bytes.extend_from_slice(&[0xFE, 0xFF]),
+            _ => {}
+        }
+    }
+    // Runs of ordinary chars are encoded together; raw-byte chars are copied as is
+    for run in text.split_inclusive(is_raw_byte_char) {
+        let (run, raw) = match run.chars().last() {
+            Some(c) if is_raw_byte_char(c) => (&run[..run.len() - c.len_utf8()], Some(c)),
+            _ => (run, None),
+        };
+        encode_run(&codec, run, &mut bytes)?;
+        if let Some(c) = raw {
+            bytes.push((c as u32 - RAW_BYTE_BASE) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Encodes text without raw-byte chars onto the end of `bytes`.
+fn encode_run(codec: &Codec, text: &str, bytes: &mut Vec<u8>) -> Result<(), String> {
+    match codec {
+        Codec::Utf8 => bytes.extend_from_slice(text.as_bytes()),
+        Codec::Utf16Le => bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+        Codec::Utf16Be => bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+        Codec::Latin1 => {
+            for c in text.chars() {
+                let byte = u8::try_from(c as u32).map_err(|_| unmappable(c))?;
+                bytes.push(byte);
+            }
+        }
+        Codec::Other(encoding) => {
+            let mut encoder = encoding.new_encoder();
+            let mut rest = text;
+            loop {
+                let needed = encoder
+                    .max_buffer_length_from_utf8_without_replacement(rest.len())
+                    .unwrap_or(rest.len() * 4);
+                bytes.reserve(needed);
+                let (result, read) =
+                    encoder.encode_from_utf8_to_vec_without_replacement(rest, bytes, true);
+                match result {
+                    encoding_rs::EncoderResult::InputEmpty => break,
+                    encoding_rs::EncoderResult::OutputFull => {}
+                    encoding_rs::EncoderResult::Unmappable(c) => return Err(unmappable(c)),
+                }
+                rest = &rest[read..];
+            }
+        }
+    }
+    Ok(())
+}
+
+fn unmappable(c: char) -> String {
+    format!("cannot convert {:?} (U+{:04X})", c, c as u32)
+}
+
+/// Returns the char that stands for an invalid byte.
+fn raw_byte_char(byte: u8) -> char {
+    char::from_u32(RAW_BYTE_BASE + byte as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+/// Whether a char stands for an invalid byte of the file.
+fn is_raw_byte_char(c: char) -> bool {
+    (RAW_BYTE_BASE..=RAW_BYTE_BASE + 0xFF).contains(&(c as u32))
+}
+
+/// Returns the byte a char stands for, if it is a raw-byte char, for display as `<xx>`.
+pub fn raw_byte(c: char) -> Option<u8> {
+    is_raw_byte_char(c).then(|| (c as u32 - RAW_BYTE_BASE) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn invalid_bytes_are_written_back() {
+        let bytes = b"caf\xE9 \xFF\xFE end\n";
+        let decoded = decode(bytes, "utf-8", None);
+        assert!(decoded.lossy);
+        assert_eq!(decoded.encoding, "utf-8");
+        assert_eq!(raw_byte(decoded.text.chars().nth(3).unwrap()), Some(0xE9));
+        assert_eq!(encode(&decoded.text, "utf-8", false).unwrap(), bytes);
+    }
+
+    #[test]
+    fn raw_byte_chars_in_the_file_are_kept() {
+        // The first and last chars that stand for raw bytes, really in the file
+        let text = "a\u{10FF00}b\u{10FFFF}\n";
+        for (name, bytes) in [
+            ("utf-8", text.as_bytes().to_vec()),
+            ("utf-16le", utf16le(text)),
+            (
+                "utf-16",
+                text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            ),
+        ] {
+            let decoded = decode(&bytes, "", Some(name));
+            assert!(!decoded.lossy, "{}", name);
+            assert_eq!(
+                encode(&decoded.text, name, false).unwrap(),
+                bytes,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn picks_the_encoding_from_the_bom_or_fileencodings() {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(utf16le("héllo\n"));
+        let decoded = decode(&bytes, "ucs-bom,utf-8,latin1", None);
+        assert_eq!(decoded.text, "héllo\n");
+        assert_eq!((decoded.encoding.as_str(), decoded.bom), ("utf-16le", true));
+        assert_eq!(encode(&decoded.text, "utf-16le", true).unwrap(), bytes);
+
+        // Not valid UTF-8, so the next encoding is tried
+        let decoded = decode(b"caf\xE9\n", "ucs-bom,utf-8,latin1", None);
+        assert_eq!(decoded.text, "café\n");
+        assert_eq!(decoded.encoding, "latin1");
+        assert!(!decoded.lossy);
+    }
+
+    #[test]
+    fn stream_decoder_matches_decode_across_chunks() {
+        let text = "a\u{10FFFF}é\u{10FF00}\n";
+        let bytes = utf16le(text);
+        let (mut decoder, name, bom) = StreamDecoder::detect(&bytes, "", Some("utf-16le"));
+        assert_eq!((name.as_str(), bom), ("utf-16le", 0));
+        let mut streamed = String::new();
+        let chunks: Vec<&[u8]> = bytes.chunks(3).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            streamed.push_str(&decoder.decode(chunk, i == chunks.len() - 1).0);
+        }
+        assert_eq!(streamed, decode(&bytes, "", Some("utf-16le")).text);
+        assert_eq!(encode(&streamed, "utf-16le", false).unwrap(), bytes);
+    }
+
+    #[test]
+    fn unmappable_chars_are_refused() {
+        assert!(encode("snow ☃", "latin1", false).is_err());
+        assert!(encode("snow ☃", "cp1252", false).is_err());
+        assert_eq!(encode("€", "cp1252", false).unwrap(), [0x80]);
+    }
+}
@@ -0,0 +1,337 @@
+use regex::Regex;
+
+/// One error or message found in compiler output.
+pub struct Entry {
+    pub filename: Option<String>,
+    /// One-based line, 0 if unknown.
+    pub line: usize,
+    /// One-based column, 0 if unknown.
+    pub col: usize,
+    /// `e` for an error, `w` for a warning, `i` for info, `n` for a note, or ' '.
+    pub kind: char,
+    /// The error number, as in rustc's `error[E0425]`.
+    pub number: Option<usize>,
+    pub text: String,
+}
+
+impl Entry {
+    /// Whether the entry points at a place in a file, so `:cn` can go there.
+    /// Other lines of the output are kept in the list as plain text.
+    pub fn valid(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    /// The kind as shown in the list, e.g. `error 425`.
+    pub fn kind_name(&self) -> String {
+        let name = match self.kind {
+            'e' => "error",
+            'w' => "warning",
+            'i' => "info",
+            'n' => "note",
+            _ => "",
+        };
+        match self.number {
+            Some(number) if name.is_empty() => format!("{}", number),
+            Some(number) => format!("{} {}", name, number),
+            None => name.to_string(),
+        }
+    }
+}
+
+/// A pattern of `errorformat`, turned into a regex over the whole line.
+struct Format {
+    /// `E`, `W`, `I`, `N` or `A` start a multi-line message, `C` continues it, `Z` ends
+    /// it, `G` is a general message; None for a single-line one.
+    prefix: Option<char>,
+    /// `%-` leaves matching lines out of the list.
+    ignore: bool,
+    /// `%+` takes the whole line as the message.
+    whole_line: bool,
+    regex: Regex,
+    /// Which `%` item each capture group holds, in order.
+    items: Vec<char>,
+}
+
+/// Parses compiler output using the patterns of an `errorformat` option, a subset of
+/// Vim's: `%f` file, `%l` line, `%c` column, `%m` message, `%t` kind, `%n` number,
+/// `%s` and `%r` any text, `%.` any character, `%#` repeats the item before it,
+/// `%%` a percent sign; `%E`, `%W`, `%I`, `%N`, `%A`, `%C`, `%Z`, `%G` and the `%-`
+/// and `%+` flags at the start. Patterns are separated by commas (`\,` is a comma).
+pub struct Parser {
+    formats: Vec<Format>,
+}
+
+impl Parser {
+    pub fn new(errorformat: &str) -> Result<Self, String> {
+        let formats = split_formats(errorformat)
+            .iter()
+            .map(|format| compile(format))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { formats })
+    }
+
+    /// Turns output into entries, one for each message and one for each other line.
+    pub fn parse(&self, output: &str) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        // A multi-line message still collecting its continuation lines
+        let mut pending: Option<Entry> = None;
+        for line in output.lines() {
+            let found = self
+                .formats
+                .iter()
+                .find_map(|format| format.regex.captures(line).map(|c| (format, c)));
+            let Some((format, captures)) = found else {
+                entries.extend(pending.take());
+                entries.push(text_entry(line));
+                continue;
+            };
+            let mut entry = text_entry("");
+            for (i, item) in format.items.iter().enumerate() {
+                let value = captures.get(i + 1).map_or("", |m| m.as_str());
+                match item {
+                    'f' => entry.filename = Some(value.to_string()),
+                    'l' => entry.line = value.parse().unwrap_or(0),
+                    'c' => entry.col = value.parse().unwrap_or(0),
+                    'n' => entry.number = value.parse().ok(),
+                    't' => {
+                        entry.kind = value.chars().next().map_or(' ', |c| c.to_ascii_lowercase())
+                    }
+                    'm' => entry.text = value.to_string(),
+                    _ => {}
+                }
+            }
+            if format.whole_line {
+                entry.text = line.to_string();
+            }
+            match format.prefix {
+                Some(prefix @ ('E' | 'W' | 'I' | 'N' | 'A')) => {
+                    entries.extend(pending.take());
+                    if prefix != 'A' {
+                        entry.kind = prefix.to_ascii_lowercase();
+                    }
+                    if !format.ignore {
+                        pending = Some(entry);
+                    }
+                }
+                Some(prefix @ ('C' | 'Z')) => {
+                    let Some(current) = &mut pending else {
+                        if !format.ignore {
+                            entries.push(text_entry(line));
+                        }
+                        continue;
+                    };
+                    if !format.ignore {
+                        current.add(entry);
+                    }
+                    if prefix == 'Z' {
+                        entries.extend(pending.take());
+                    }
+                }
+                _ => {
+                    entries.extend(pending.take());
+                    if !format.ignore {
+                        entries.push(entry);
+                    }
+                }
+            }
+        }
+        entries.extend(pending);
+        entries
+    }
+}
+
+impl Entry {
+    /// Adds what a continuation line found to a multi-line message.
+    fn add(&mut self, more: Entry) {
+        if self.filename.is_none() {
+            self.filename = more.filename;
+        }
+        if self.line == 0 {
+            self.line = more.line;
+        }
+        if self.col == 0 {
+            self.col = more.col;
+        }
+        if self.number.is_none() {
+            self.number = more.number;
+        }
+        if !more.text.is_empty() {
+            if !self.text.is_empty() {
+                self.text.push('\n');
+            }
+            self.text.push_str(&more.text);
+        }
+    }
+}
+
+/// An entry for a line no pattern matched (or a `%+G` line), kept as plain text.
+fn text_entry(line: &str) -> Entry {
+    Entry {
+        filename: None,
+        line: 0,
+        col: 0,
+        kind: ' ',
+        number: None,
+        text: line.to_string(),
+    }
+}
+
+/// Splits `errorformat` at commas that aren't escaped as `\,`.
+fn split_formats(errorformat: &str) -> Vec<String> {
+    let mut formats = vec![String::new()];
+    let mut chars = errorformat.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(',') => formats.last_mut().unwrap().push(','),
+                Some(next) => {
+                    let format = formats.last_mut().unwrap();
+                    format.push('\\');
+                    format.push(next);
+                }
+                None => formats.last_mut().unwrap().push('\\'),
+            },
+            ',' => formats.push(String::new()),
+            c => formats.last_mut().unwrap().push(c),
+        }
+    }
+    formats
+}
+
+/// Turns one pattern into a regex that must match a whole line.
+fn compile(format: &str) -> Result<Format, String> {
+    // The flag and the prefix share one `%`, as in `%-G` and `%+C`
+    let mut rest = format;
+    let ignore = rest.starts_with("%-");
+    let whole_line = rest.starts_with("%+");
+    let mut prefix = None;
+    if let Some(after) = rest.strip_prefix('%') {
+        let after = after.strip_prefix(['-', '+']).unwrap_or(after);
+        if let Some(c) = after.chars().next().filter(|c| "EWINACZG".contains(*c)) {
+            prefix = Some(c);
+            rest = &after[1..];
+        } else if ignore || whole_line {
+            return Err(format!("Invalid errorformat: {}", format));
+        }
+    }
+    let mut pattern = String::from("^");
+    let mut items = Vec::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            // `\` makes the next char literal
+            let next = chars.next().unwrap_or('\\');
+            pattern.push_str(&regex::escape(&next.to_string()));
+            continue;
+        }
+        if c != '%' {
+            pattern.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+        let Some(item) = chars.next() else {
+            return Err(format!("Invalid errorformat: {}", format));
+        };
+        match item {
+            'f' => pattern.push_str(r"(.+?)"),
+            'l' | 'c' | 'n' => pattern.push_str(r"(\d+)"),
+            'm' | 's' | 'r' => pattern.push_str("(.*)"),
+            't' => pattern.push_str("(.)"),
+            '.' => pattern.push('.'),
+            '#' => pattern.push('*'),
+            '%' => pattern.push('%'),
+            _ => return Err(format!("Invalid errorformat item %{}: {}", item, format)),
+        }
+        if "flcnmsrt".contains(item) {
+            items.push(item);
+        }
+    }
+    pattern.push('$');
+    let regex = Regex::new(&pattern).map_err(|e| format!("Invalid errorformat: {}", e))?;
+    Ok(Format {
+        prefix,
+        ignore,
+        whole_line,
+        regex,
+        items,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+
+    #[test]
+    fn parses_single_line_messages() {
+        let parser = Parser::new("%f:%l:%c: %trror: %m,%f:%l: %m").unwrap();
+        let entries = parser.parse("src/a.c:3:7: error: bad\nb.c:9: odd\nmake: done\n");
+        assert_eq!(entries.len(), 3);
+        let first = &entries[0];
+        assert_eq!(first.filename.as_deref(), Some("src/a.c"));
+        assert_eq!((first.line, first.col, first.kind), (3, 7, 'e'));
+        assert_eq!(first.text, "bad");
+        assert_eq!((entries[1].line, entries[1].col), (9, 0));
+        // Lines no pattern matches are kept as text
+        assert!(!entries[2].valid());
+        assert_eq!(entries[2].text, "make: done");
+    }
+
+    #[test]
+    fn parses_rustc_output_with_the_default_format() {
+        let output = "\
+error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:4:13
+  |
+4 |     let y = x;
+  |             ^ not found in this scope
+
+warning: unused variable: `y`
+ --> src/lib.rs:2:9
+  |
+error: aborting due to 1 previous error
+";
+        let parser = Parser::new(&Options::default().errorformat).unwrap();
+        let entries = parser.parse(output);
+        assert_eq!(entries.len(), 2);
+        let error = &entries[0];
+        assert_eq!(error.filename.as_deref(), Some("src/main.rs"));
+        assert_eq!((error.line, error.col), (4, 13));
+        assert_eq!(error.kind_name(), "error 425");
+        assert_eq!(error.text, "cannot find value `x` in this scope");
+        let warning = &entries[1];
+        assert_eq!(warning.filename.as_deref(), Some("src/lib.rs"));
+        assert_eq!((warning.line, warning.col), (2, 9));
+        assert_eq!(warning.kind_name(), "warning");
+    }
+
+    #[test]
+    fn flags_and_escapes() {
+        // `%-` leaves lines out, `%+` keeps the whole line, `\,` is a comma
+        let parser = Parser::new(r"%-Gskip %m,%+Gnote %m,%f\,%l: %m").unwrap();
+        let entries = parser.parse("skip this\nnote that\na.txt,5: here\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].text, "note that");
+        assert!(!entries[0].valid());
+        assert_eq!(entries[1].filename.as_deref(), Some("a.txt"));
+        assert_eq!(entries[1].line, 5);
+    }
+
+    #[test]
+    fn multi_line_messages_collect_their_continuations() {
+        let parser = Parser::new("%Efail: %m,%C  at %f:%l,%C  %m,%Z--").unwrap();
+        let entries = parser.parse("fail: first\n  at t.rs:8\n  more\n--\nafter\n");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].filename.as_deref(), Some("t.rs"));
+        assert_eq!(entries[0].line, 8);
+        assert_eq!(entries[0].kind, 'e');
+        assert_eq!(entries[0].text, "first\nmore");
+        assert_eq!(entries[1].text, "after");
+    }
+
+    #[test]
+    fn refuses_invalid_formats() {
+        assert!(Parser::new("%f:%q").is_err());
+        assert!(Parser::new("%f:%").is_err());
+        assert!(Parser::new("%-x").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+/// Values accepted by the `fileformat` option.
+pub const NAMES: &[&str] = &["unix", "dos", "mac"];
+
+/// Works out the line ending of a file's text, like Vim with `fileformats=unix,dos,mac`:
+/// `dos` when every line ends in CR LF, `mac` when there are CRs but no LFs at all,
+/// `unix` otherwise. In a file with mixed endings the stray CRs stay in the text.
+pub fn detect(text: &str) -> &'static str {
+    let lf = text.matches('\n').count();
+    let crlf = text.matches("\r\n").count();
+    if lf > 0 && crlf == lf {
+        "dos"
+    } else if lf == 0 && text.contains('\r') {
+        "mac"
+    } else {
+        "unix"
+    }
+}
+
+/// Converts text in the given format so its lines end in a bare LF, as the buffer keeps them.
+pub fn to_lf(text: &str, fileformat: &str) -> String {
+    match fileformat {
+        "dos" => text.replace("\r\n", "\n"),
+        "mac" => text.replace('\r', "\n"),
+        _ => text.to_string(),
+    }
+}
+
+/// Returns the line ending written for a format.
+pub fn ending(fileformat: &str) -> &'static str {
+    match fileformat {
+        "dos" => "\r\n",
+        "mac" => "\r",
+        _ => "\n",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_line_ending() {
+        assert_eq!(detect("one\ntwo\n"), "unix");
+        assert_eq!(detect("one\r\ntwo\r\n"), "dos");
+        assert_eq!(detect("one\rtwo\r"), "mac");
+        // No line ending at all, or a single line without one
+        assert_eq!(detect(""), "unix");
+        assert_eq!(detect("one"), "unix");
+        // The last line may lack an ending
+        assert_eq!(detect("one\r\ntwo"), "dos");
+    }
+
+    #[test]
+    fn mixed_endings_are_unix_with_the_crs_kept() {
+        let text = "one\r\ntwo\nthree\r\n";
+        assert_eq!(detect(text), "unix");
+        assert_eq!(to_lf(text, detect(text)), text);
+        // A CR LF file with a stray CR before the line end
+        assert_eq!(detect("one\r\r\ntwo\r\n"), "dos");
+        assert_eq!(to_lf("one\r\r\ntwo\r\n", "dos"), "one\r\ntwo\n");
+    }
+
+    #[test]
+    fn converts_to_lf_and_back() {
+        for (format, text) in [("unix", "a\nb\n"), ("dos", "a\r\nb\r\n"), ("mac", "a\rb\r")] {
+            let lf = to_lf(text, format);
+            assert_eq!(lf, "a\nb\n");
+            assert_eq!(lf.replace('\n', ending(format)), text);
+        }
+    }
+}
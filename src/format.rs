@@ -0,0 +1,321 @@
+use crate::range::Range;
+use crate::{Editor, lsp, shell};
+use crossterm::event::KeyCode;
+use std::io::Result;
+use std::mem;
+use std::ops;
+use std::time::Duration;
+
+/// How long a formatter may take before it is given up on.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Edits past which two texts are taken to differ in everything between the lines
+/// they start and end with alike, keeping the search for fewer edits cheap.
+const MAX_EDITS: isize = 1000;
+
+/// Where each line starts, counted in chars from the first, and where the last ends.
+fn line_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offsets = vec![0];
+    for line in lines {
+        offsets.push(offsets[offsets.len() - 1] + line.chars().count());
+    }
+    offsets
+}
+
+/// The lines that differ between `old` and `new`, as pairs of index ranges into each,
+/// in order.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<(ops::Range<usize>, ops::Range<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    let Some(matches) = matching_lines(old, new) else {
+        return vec![(prefix..prefix + old.len(), prefix..prefix + new.len())];
+    };
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for (x, y) in matches.into_iter().chain([(old.len(), new.len())]) {
+        if x > i || y > j {
+            hunks.push((prefix + i..prefix + x, prefix + j..prefix + y));
+        }
+        (i, j) = (x + 1, y + 1);
+    }
+    hunks
+}
+
+/// The lines `a` and `b` keep in common when one is turned into the other with the
+/// fewest lines added and removed, as pairs of indices, found with Myers' algorithm.
+/// None if that takes more than `MAX_EDITS` edits.
+fn matching_lines(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m).min(MAX_EDITS);
+    // `v` holds the furthest x reached on each diagonal k = x - y
+    let offset = max + 1;
+    let at = |k: isize| (k + offset) as usize;
+    let mut v = vec![0; 2 * offset as usize + 1];
+    // The diagonals each round started from, -d - 1 to d + 1 in round d
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    for d in 0..=max {
+        trace.push(v[at(-d - 1)..=at(d + 1)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let down = k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]);
+            let mut x = if down { v[at(k + 1)] } else { v[at(k - 1)] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                return Some(backtrack(&trace, n, m));
+            }
+        }
+    }
+    None
+}
+
+/// Follows the rounds of `matching_lines` back from the end, collecting the lines
+/// passed over diagonally, which are the ones alike.
+fn backtrack(trace: &[Vec<isize>], n: isize, m: isize) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| (k + d + 1) as usize;
+        let k = x - y;
+        let previous = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let previous_x = v[at(previous)];
+        let previous_y = previous_x - previous;
+        while x > previous_x && y > previous_y {
+            x -= 1;
+            y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        (x, y) = (previous_x, previous_y);
+    }
+    matches.reverse();
+    matches
+}
+
+impl Editor {
+    /// `:[range]Format`: formats the lines of `range`, or the whole buffer, with the
+    /// `formatprograms` command for the filetype, or else its language server.
+    pub(crate) fn format(&mut self, range: Option<Range>) -> Result<()> {
+        let lines = self.buffer.len_lines();
+        let range = range.filter(|range| range.start > 1 || range.end < lines);
+        // `:Format` asks before taking in all of a large file; `gq` can do that too
+        if range.is_none() && self.buffer.large && !self.whole_file_confirmed {
+            return self.execute_command(":Format").map(drop);
+        }
+        match self.formatprogram() {
+            Some(program) => {
+                self.run_formatprogram(&program, range)?;
+            }
+            None => self.lsp_format(range),
+        }
+        Ok(())
+    }
+
+    /// `gq` followed by a motion: `gqq` formats the cursor line, `gqj` and `gqk` it and
+    /// the line below or above, `gqG` the lines to the end.
+    pub(crate) fn format_motion(&mut self, code: KeyCode) -> Result<()> {
+        let Some((first, last)) = self.motion_lines("gq", code) else {
+            return Ok(());
+        };
+        self.format(Some(Range {
+            start: first + 1,
+            end: last + 1,
+        }))
+    }
+
+    /// Formats the whole buffer before a write when `formatonsave` is set, waiting for
+    /// the formatter no longer than `TIMEOUT`. Returns why the buffer was not
+    /// formatted if that failed; the write goes ahead either way.
+    pub(crate) fn format_before_save(&mut self) -> Result<Option<String>> {
+        if !self.options.formatonsave || self.buffer.large {
+            return Ok(None);
+        }
+        let status = mem::take(&mut self.status_msg);
+        let formatted = match self.formatprogram() {
+            Some(program) => self.run_formatprogram(&program, None)?,
+            None => self.lsp_format_now(TIMEOUT),
+        };
+        let failure = mem::replace(&mut self.status_msg, status);
+        Ok((!formatted).then_some(failure))
+    }
+
+    /// The `formatprograms` command for the filetype of the buffer.
+    fn formatprogram(&self) -> Option<String> {
+        let filetype = lsp::filetype(self.buffer.filename.as_deref()?)?;
+        self.options.formatprogram(filetype).map(str::to_string)
+    }
+
+    /// Passes the lines of `range`, or the whole buffer, through `program` and puts
+    /// what comes out in their place. Returns whether that worked; if not the buffer is
+    /// left alone and the status says why.
+    fn run_formatprogram(&mut self, program: &str, range: Option<Range>) -> Result<bool> {
+        let cmd = match shell::expand(
+            program,
+            self.buffer.filename.as_deref(),
+            self.alternate_file.as_deref(),
+        ) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.status_msg = e.to_string();
+                return Ok(false);
+            }
+        };
+        let range = range.unwrap_or(Range {
+            start: 1,
+            end: self.buffer.len_lines(),
+        });
+        let input = self.range_text(range);
+        let empty = input.is_empty();
+        let Some(mut output) = self.capture_within(&cmd, Some(input), Some(TIMEOUT))? else {
+            return Ok(false);
+        };
+        // Formatters that fail often say so on standard output and exit cleanly
+        if output.is_empty() && !empty {
+            self.status_msg = format!("{} gave no output", cmd);
+            return Ok(false);
+        }
+        let (start, end) = self.range_chars(range);
+        // The last line of a file may have no line break; it doesn't get one now
+        let ends_with_break = end > start && self.buffer.rope.char(end - 1) == '\n';
+        if !ends_with_break && output.ends_with('\n') {
+            output.pop();
+        }
+        self.apply_formatted(Some(range), &output);
+        Ok(true)
+    }
+
+    /// Puts `text` in place of the lines of `range`, or the whole buffer, as one undo
+    /// step. Only the lines that differ are replaced, so the cursor stays on the text
+    /// it was on.
+    pub(crate) fn apply_formatted(&mut self, range: Option<Range>, text: &str) {
+        let range = range.unwrap_or(Range {
+            start: 1,
+            end: self.buffer.len_lines(),
+        });
+        let (first, _) = range.lines();
+        let (start, end) = self.range_chars(range);
+        let old_text = self.buffer.rope.slice(start..end).to_string();
+        let old: Vec<&str> = old_text.split_inclusive('\n').collect();
+        let new: Vec<&str> = text.split_inclusive('\n').collect();
+        let hunks = diff_lines(&old, &new);
+        if hunks.is_empty() {
+            self.status_msg = "Already formatted".to_string();
+            return;
+        }
+        // Made first to last, so undo comes back to the first; the text before each
+        // hunk is the new text by then
+        let old_offsets = line_offsets(&old);
+        let new_offsets = line_offsets(&new);
+        self.buffer.seal_undo_step();
+        for (lines, replacement) in &hunks {
+            let at = start + new_offsets[replacement.start];
+            self.buffer.replace_range(
+                at,
+                at + old_offsets[lines.end] - old_offsets[lines.start],
+                &new[replacement.clone()].concat(),
+            );
+        }
+        self.buffer.seal_undo_step();
+        // A cursor in a replaced part stays as many lines into what replaced it
+        if let Some(row) = self.cy.checked_sub(first) {
+            let row = match hunks.iter().rfind(|(lines, _)| lines.start <= row) {
+                Some((lines, replacement)) if row < lines.end => {
+                    replacement.start + (row - lines.start).min(replacement.len().saturating_sub(1))
+                }
+                Some((lines, replacement)) => row - lines.end + replacement.end,
+                None => row,
+            };
+            self.cy = (first + row).min(self.buffer.len_lines().saturating_sub(1));
+        }
+        self.clamp_cursor_to_line();
+        let changed: usize = hunks
+            .iter()
+            .map(|(lines, replacement)| lines.len().max(replacement.len()))
+            .sum();
+        self.status_msg = format!(
+            "{} line{} formatted",
+            changed,
+            if changed == 1 { "" } else { "s" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rebuilds `new` from `old` and the hunks of `diff_lines`.
+    fn apply(old: &[&str], new: &[&str]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut kept = 0;
+        for (from, to) in diff_lines(old, new) {
+            lines.extend(old[kept..from.start].iter().map(|s| s.to_string()));
+            lines.extend(new[to].iter().map(|s| s.to_string()));
+            kept = from.end;
+        }
+        lines.extend(old[kept..].iter().map(|s| s.to_string()));
+        lines
+    }
+
+    #[test]
+    fn diff_finds_the_changed_lines() {
+        let old = ["a", "b", "c", "d"];
+        assert!(diff_lines(&old, &old).is_empty());
+        assert_eq!(diff_lines(&old, &["a", "x", "c", "d"]), [(1..2, 1..2)]);
+        assert_eq!(diff_lines(&old, &["a", "b", "x", "c", "d"]), [(2..2, 2..3)]);
+        assert_eq!(diff_lines(&old, &["b", "c"]), [(0..1, 0..0), (3..4, 2..2)]);
+        assert_eq!(diff_lines(&[], &["a"]), [(0..0, 0..1)]);
+    }
+
+    #[test]
+    fn diff_hunks_turn_old_into_new() {
+        let old = ["fn a() {", "x", "}", "", "fn b() {", "y", "}"];
+        let new = ["fn a() {", "    x", "}", "fn b() {", "    y", "}", ""];
+        assert_eq!(apply(&old, &new), new);
+    }
+
+    #[test]
+    fn matching_lines_keeps_the_most_in_common() {
+        let a = ["a", "b", "c", "a", "b", "b", "a"];
+        let b = ["c", "b", "a", "b", "a", "c"];
+        let matches = matching_lines(&a, &b).unwrap();
+        assert_eq!(matches.len(), 4);
+        assert!(matches.iter().all(|&(x, y)| a[x] == b[y]));
+        assert!(
+            matches
+                .windows(2)
+                .all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1)
+        );
+        assert_eq!(matching_lines(&a, &[]), Some(Vec::new()));
+    }
+
+    #[test]
+    fn too_many_edits_make_one_hunk() {
+        let old: Vec<String> = (0..600).map(|i| format!("old {}", i)).collect();
+        let new: Vec<String> = (0..600).map(|i| format!("new {}", i)).collect();
+        let old: Vec<&str> = ["same"]
+            .into_iter()
+            .chain(old.iter().map(String::as_str))
+            .collect();
+        let new: Vec<&str> = ["same"]
+            .into_iter()
+            .chain(new.iter().map(String::as_str))
+            .collect();
+        assert_eq!(matching_lines(&old[1..], &new[1..]), None);
+        assert_eq!(diff_lines(&old, &new), [(1..601, 1..601)]);
+    }
+}
@@ -0,0 +1,168 @@
+/// Points for each query character found.
+const SCORE_MATCH: i64 = 16;
+/// Extra points for a character right after the previous match.
+const BONUS_CONSECUTIVE: i64 = 8;
+/// Extra points for a character at the start of a word: after `/`, `_`, `-`, `.` or
+/// a space, or an upper-case letter after a lower-case one.
+const BONUS_BOUNDARY: i64 = 10;
+/// Extra points for a character in the file name rather than the directories above it.
+const BONUS_FILE_NAME: i64 = 4;
+/// Points lost for the first character skipped between two matches.
+const PENALTY_GAP_START: i64 = 3;
+/// Points lost for each further character skipped.
+const PENALTY_GAP_EXTENSION: i64 = 1;
+
+/// A query ready to be matched against many candidates.
+pub struct Query {
+    chars: Vec<char>,
+    /// Smart case: a query with an upper-case letter must match case exactly.
+    case_sensitive: bool,
+}
+
+impl Query {
+    pub fn new(query: &str) -> Self {
+        let case_sensitive = query.chars().any(char::is_uppercase);
+        let chars = query
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| if case_sensitive { c } else { fold(c) })
+            .collect();
+        Self {
+            chars,
+            case_sensitive,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chars.is_empty()
+    }
+
+    /// Scores a candidate if the query's characters all appear in it, in order. Higher
+    /// is better. Also returns the char indices of the matched characters.
+    pub fn score(&self, candidate: &str) -> Option<(i64, Vec<usize>)> {
+        if self.chars.is_empty() {
+            return Some((0, Vec::new()));
+        }
+        let original: Vec<char> = candidate.chars().collect();
+        let text: Vec<char> = if self.case_sensitive {
+            original.clone()
+        } else {
+            original.iter().map(|&c| fold(c)).collect()
+        };
+        // The earliest place the whole query fits...
+        let mut wanted = self.chars.iter().peekable();
+        let mut end = 0;
+        for (i, c) in text.iter().enumerate() {
+            if wanted.next_if(|&&w| w == *c).is_some() && wanted.peek().is_none() {
+                end = i;
+                break;
+            }
+        }
+        if wanted.peek().is_some() {
+            return None;
+        }
+        // ...then the shortest stretch ending there, found by going back from its end
+        let mut start = end;
+        let mut wanted = self.chars.iter().rev().peekable();
+        for i in (0..=end).rev() {
+            if wanted.next_if(|&&w| w == text[i]).is_some() && wanted.peek().is_none() {
+                start = i;
+                break;
+            }
+        }
+        let file_name_start = original
+            .iter()
+            .rposition(|&c| c == '/')
+            .map_or(0, |i| i + 1);
+        let mut positions = Vec::with_capacity(self.chars.len());
+        let mut score = 0;
+        let mut wanted = self.chars.iter().peekable();
+        let mut previous: Option<usize> = None;
+        for (i, &c) in text.iter().enumerate().take(end + 1).skip(start) {
+            if wanted.next_if(|&&w| w == c).is_none() {
+                continue;
+            }
+            score += SCORE_MATCH;
+            if is_boundary(&original, i) {
+                score += BONUS_BOUNDARY;
+            }
+            if i >= file_name_start {
+                score += BONUS_FILE_NAME;
+            }
+            match previous {
+                Some(p) if p + 1 == i => score += BONUS_CONSECUTIVE,
+                Some(p) => {
+                    score -= PENALTY_GAP_START + PENALTY_GAP_EXTENSION * (i - p - 2) as i64;
+                }
+                None => {}
+            }
+            previous = Some(i);
+            positions.push(i);
+        }
+        Some((score, positions))
+    }
+}
+
+/// Lower-cases a character for matching without case, keeping it a single char.
+fn fold(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Whether the character at `i` starts a word of a path.
+fn is_boundary(text: &[char], i: usize) -> bool {
+    let Some(&before) = i.checked_sub(1).and_then(|p| text.get(p)) else {
+        return true;
+    };
+    matches!(before, '/' | '_' | '-' | '.' | ' ')
+        || (before.is_lowercase() && text[i].is_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(query: &str, candidate: &str) -> Option<i64> {
+        Query::new(query).score(candidate).map(|(score, _)| score)
+    }
+
+    #[test]
+    fn matches_the_query_in_order() {
+        assert!(score("mrs", "src/main.rs").is_some());
+        assert!(score("srm", "src/main.rs").is_some());
+        assert_eq!(score("rsm", "src/main.rs"), None);
+        assert_eq!(score("x", "src/main.rs"), None);
+        // An empty query matches everything, spaces are ignored
+        assert_eq!(Query::new("").score("abc"), Some((0, Vec::new())));
+        assert!(Query::new(" ").is_empty());
+        assert!(score("ma rs", "src/main.rs").is_some());
+    }
+
+    #[test]
+    fn case_is_smart() {
+        assert!(score("main", "src/Main.rs").is_some());
+        assert!(score("Main", "src/Main.rs").is_some());
+        assert_eq!(score("Main", "src/main.rs"), None);
+    }
+
+    #[test]
+    fn returns_the_shortest_stretch() {
+        // The leading `a` of `alpha` isn't part of the shortest match of `ab`
+        let (_, positions) = Query::new("ab").score("a/xa/b").unwrap();
+        assert_eq!(positions, [3, 5]);
+    }
+
+    #[test]
+    fn prefers_consecutive_boundary_and_file_name_matches() {
+        let consecutive = score("main", "main.rs").unwrap();
+        let spread = score("main", "m_a_i_n.rs").unwrap();
+        assert!(consecutive > spread);
+        let boundary = score("fb", "foo_bar").unwrap();
+        let inside = score("fb", "fooxbar").unwrap();
+        assert!(boundary > inside);
+        let camel = score("fb", "fooBar").unwrap();
+        assert!(camel > inside);
+        let file_name = score("ab", "x/ab").unwrap();
+        let directory = score("ab", "ab/x").unwrap();
+        assert!(file_name > directory);
+    }
+}
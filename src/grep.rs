@@ -0,0 +1,230 @@
+use crate::errorformat::Entry;
+use crate::quickfix::ListKind;
+use crate::search::{INTERRUPT_AFTER, search_interrupted};
+use crate::{Editor, encoding, fileformat, shell};
+use ignore::WalkBuilder;
+use ignore::overrides::OverrideBuilder;
+use regex::Regex;
+use std::fs;
+use std::io::Result;
+use std::path::Path;
+use std::time::Instant;
+
+/// Bytes at the start of a file looked at for a NUL, which marks it as binary.
+const BINARY_CHECK: usize = 8192;
+
+/// The arguments of `:vimgrep` or `:grep`.
+struct Args<'a> {
+    pattern: String,
+    /// Every match in a line instead of just the first (`g` flag of `:vimgrep`).
+    all: bool,
+    /// Stay put instead of going to the first match (`j` flag).
+    no_jump: bool,
+    /// File globs (`!` in front excludes) and directories to search.
+    filters: Vec<&'a str>,
+}
+
+/// Parses `:vimgrep /pattern/[g][j] [files]`. Like Vim, a pattern made of word
+/// characters can go without the slashes.
+fn parse_vimgrep(args: &str) -> Args<'_> {
+    let Some(delimiter) = args
+        .chars()
+        .next()
+        .filter(|c| !c.is_alphanumeric() && !"\\\"|_".contains(*c))
+    else {
+        let (pattern, rest) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+        return Args {
+            pattern: pattern.to_string(),
+            all: false,
+            no_jump: false,
+            filters: rest.split_whitespace().collect(),
+        };
+    };
+    let mut pattern = String::new();
+    let mut rest = "";
+    let mut chars = args[delimiter.len_utf8()..].char_indices();
+    let start = delimiter.len_utf8();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => pattern.push(next),
+                Some((_, next)) => {
+                    pattern.push('\\');
+                    pattern.push(next);
+                }
+                None => pattern.push('\\'),
+            }
+        } else if c == delimiter {
+            rest = &args[start + i + c.len_utf8()..];
+            break;
+        } else {
+            pattern.push(c);
+        }
+    }
+    let flags_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let (flags, files) = rest.split_at(flags_len);
+    Args {
+        pattern,
+        all: flags.contains('g'),
+        no_jump: flags.contains('j'),
+        filters: files.split_whitespace().collect(),
+    }
+}
+
+/// Parses `:grep pattern [files]`, where the pattern may be quoted to hold spaces.
+fn parse_grep(args: &str) -> Args<'_> {
+    let quoted = args
+        .chars()
+        .next()
+        .filter(|c| *c == '"' || *c == '\'')
+        .and_then(|quote| {
+            let end = args[1..].find(quote)?;
+            Some((&args[1..end + 1], &args[end + 2..]))
+        });
+    let (pattern, rest) =
+        quoted.unwrap_or_else(|| args.split_once(char::is_whitespace).unwrap_or((args, "")));
+    Args {
+        pattern: pattern.to_string(),
+        all: false,
+        no_jump: false,
+        filters: rest.split_whitespace().collect(),
+    }
+}
+
+/// Adds an entry for each match of `regex` in `text` (the first in each line, or
+/// all of them with `all`).
+fn find_matches(regex: &Regex, name: &str, text: &str, all: bool, entries: &mut Vec<Entry>) {
+    if !regex.is_match(text) {
+        return;
+    }
+    for (row, line) in text.lines().enumerate() {
+        let matches = regex.find_iter(line).take(if all { usize::MAX } else { 1 });
+        for found in matches {
+            entries.push(Entry {
+                filename: Some(name.to_string()),
+                line: row + 1,
+                col: line[..found.start()].chars().count() + 1,
+                kind: ' ',
+                number: None,
+                text: line.trim_start().to_string(),
+            });
+        }
+    }
+}
+
+impl Editor {
+    /// Searches the files under the working directory for `:vimgrep` and `:grep` (and
+    /// `:lvimgrep` and `:lgrep`), without any outside program. `.gitignore` files and
+    /// hidden files are left out, and so are binary files. The matches fill a list.
+    pub(crate) fn grep(&mut self, kind: ListKind, args: &str, vim_syntax: bool) -> Result<()> {
+        let parsed = if vim_syntax {
+            parse_vimgrep(args)
+        } else {
+            parse_grep(args)
+        };
+        if parsed.pattern.is_empty() {
+            self.status_msg = "Argument required".to_string();
+            return Ok(());
+        }
+        let regex = match Regex::new(&parsed.pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                let e = e.to_string();
+                let reason = e.lines().last().unwrap_or_default();
+                self.status_msg =
+                    format!("Invalid pattern: {}", reason.trim_start_matches("error: "));
+                return Ok(());
+            }
+        };
+        let walker = match self.grep_walker(&parsed.filters) {
+            Ok(walker) => walker,
+            Err(msg) => {
+                self.status_msg = msg;
+                return Ok(());
+            }
+        };
+        // The file being edited is searched as it is in the buffer, unsaved changes and all
+        let current = self
+            .buffer
+            .filename
+            .as_deref()
+            .and_then(|name| fs::canonicalize(name).ok());
+        let started = Instant::now();
+        let mut entries = Vec::new();
+        for found in walker.build() {
+            if started.elapsed() >= INTERRUPT_AFTER && search_interrupted()? {
+                self.status_msg = "Search interrupted".to_string();
+                return Ok(());
+            }
+            let Ok(found) = found else {
+                continue;
+            };
+            if !found.file_type().is_some_and(|t| t.is_file()) {
+                continue;
+            }
+            let path = found.path();
+            let name = path.strip_prefix(".").unwrap_or(path).to_string_lossy();
+            let is_current = current.as_ref().is_some_and(|current| {
+                current.file_name() == path.file_name()
+                    && fs::canonicalize(path).is_ok_and(|path| path == *current)
+            });
+            let text = if is_current {
+                self.buffer.rope.to_string()
+            } else {
+                let Ok(bytes) = fs::read(path) else {
+                    continue;
+                };
+                if bytes.iter().take(BINARY_CHECK).any(|&b| b == 0) {
+                    continue;
+                }
+                let text = encoding::decode(&bytes, &self.options.fileencodings, None).text;
+                fileformat::to_lf(&text, fileformat::detect(&text))
+            };
+            find_matches(&regex, &name, &text, parsed.all, &mut entries);
+        }
+        if entries.is_empty() {
+            self.status_msg = format!("No match: {}", parsed.pattern);
+            return Ok(());
+        }
+        let name = match (kind, vim_syntax) {
+            (ListKind::Quickfix, true) => "vimgrep",
+            (ListKind::Quickfix, false) => "grep",
+            (ListKind::Location, true) => "lvimgrep",
+            (ListKind::Location, false) => "lgrep",
+        };
+        let title = format!(":{} {}", name, args);
+        self.set_quickfix_list(kind, entries, title, !parsed.no_jump);
+        Ok(())
+    }
+
+    /// Builds the walk over the files to search: the directories among `filters`, or
+    /// the working directory, narrowed down by the globs among them.
+    fn grep_walker(&self, filters: &[&str]) -> std::result::Result<WalkBuilder, String> {
+        let mut roots = Vec::new();
+        let mut globs = OverrideBuilder::new(".");
+        for filter in filters {
+            let filter = shell::expand(
+                filter,
+                self.buffer.filename.as_deref(),
+                self.alternate_file.as_deref(),
+            )
+            .map_err(|e| e.to_string())?;
+            if Path::new(&filter).is_dir() {
+                roots.push(filter);
+            } else {
+                globs
+                    .add(&filter)
+                    .map_err(|e| format!("Invalid glob: {}", e))?;
+            }
+        }
+        let globs = globs.build().map_err(|e| format!("Invalid glob: {}", e))?;
+        let mut roots = roots.into_iter();
+        let mut walker = WalkBuilder::new(roots.next().unwrap_or_else(|| ".".to_string()));
+        for root in roots {
+            walker.add(root);
+        }
+        // `.gitignore` counts outside a git repository too
+        walker.require_git(false).overrides(globs);
+        Ok(walker)
+    }
+}
@@ -0,0 +1,43 @@
+/// Returns the leading whitespace of a line as (chars, display width).
+pub fn leading(chars: &[char], tabstop: usize) -> (usize, usize) {
+    let tabstop = tabstop.max(1);
+    let mut count = 0;
+    let mut width = 0;
+    for c in chars {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += tabstop - width % tabstop,
+            _ => break,
+        }
+        count += 1;
+    }
+    (count, width)
+}
+
+/// Builds whitespace that starts at display column `from` and reaches column `to`,
+/// using tabs where they fit unless `expandtab` is set.
+pub fn fill(from: usize, to: usize, tabstop: usize, expandtab: bool) -> String {
+    let tabstop = tabstop.max(1);
+    let mut result = String::new();
+    let mut col = from;
+    while col < to {
+        let next_tab = (col / tabstop + 1) * tabstop;
+        if !expandtab && next_tab <= to {
+            result.push('\t');
+            col = next_tab;
+        } else {
+            result.push(' ');
+            col += 1;
+        }
+    }
+    result
+}
+
+/// Returns the indent width one level up (`right`) or down from `width`.
+pub fn shift(width: usize, shiftwidth: usize, right: bool) -> usize {
+    if right {
+        width + shiftwidth
+    } else {
+        width.saturating_sub(shiftwidth)
+    }
+}
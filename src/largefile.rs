@@ -0,0 +1,273 @@
+use crate::encoding::StreamDecoder;
+use crate::range::Range;
+use crate::shell::{self, Bang};
+use crate::{Buffer, ConfirmAction, ConfirmState, Editor, Mode, checktime, fileformat, substitute};
+use std::fs;
+use std::io::{self, Read, Result};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bytes read from the file at a time.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Chunks read ahead of the editor before the loader waits for it.
+const READ_AHEAD: usize = 16;
+
+/// Time spent appending loaded text per screen refresh, so keys are still handled.
+const APPEND_BUDGET: Duration = Duration::from_millis(50);
+
+/// What the loader thread sends to the editor.
+enum Message {
+    /// The next piece of text, the number of file bytes it came from, and whether
+    /// some of them were invalid. Zero bytes means the end of the file.
+    Text(String, u64, bool),
+    Failed(io::Error),
+}
+
+/// Loads a large file progressively: a background thread reads it a chunk at a time,
+/// so the start of it can be shown and browsed while the rest is still coming in. All
+/// of the file still ends up in the buffer; what it saves is the wait, not memory.
+/// The buffer can't be changed until the whole file is in, so no edit is made to text
+/// that is still growing under it.
+pub struct Loader {
+    receiver: Receiver<Message>,
+    /// Size of the file in bytes.
+    total: u64,
+    /// Bytes read so far.
+    loaded: u64,
+    /// Whether some bytes were invalid in the file's encoding.
+    lossy: bool,
+}
+
+impl Loader {
+    /// How much of the file is loaded, for the status bar.
+    pub fn progress(&self) -> String {
+        let percent = (self.loaded * 100).checked_div(self.total).unwrap_or(100);
+        format!("Loading {}% of {}", percent, format_size(self.total))
+    }
+}
+
+/// Formats a byte count for messages, e.g. `2.1 GB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Reads, decodes and converts the rest of the file to LF line endings, one chunk at a
+/// time. Stops early if the editor has gone on to another file.
+fn load(
+    mut file: fs::File,
+    mut decoder: StreamDecoder,
+    fileformat: &'static str,
+    mut pending_cr: bool,
+    sender: SyncSender<Message>,
+) {
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = match file.read(&mut chunk) {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                sender.send(Message::Failed(e)).ok();
+                return;
+            }
+        };
+        let (text, lossy) = decoder.decode(&chunk[..read], read == 0);
+        let text = convert_endings(text, fileformat, &mut pending_cr, read == 0);
+        if sender
+            .send(Message::Text(text, read as u64, lossy))
+            .is_err()
+            || read == 0
+        {
+            return;
+        }
+    }
+}
+
+/// Converts a chunk to LF line endings. A CR at the end of a `dos` chunk is held
+/// back until the next chunk shows whether an LF follows it.
+fn convert_endings(text: String, fileformat: &str, pending_cr: &mut bool, last: bool) -> String {
+    let mut text = if std::mem::take(pending_cr) {
+        format!("\r{}", text)
+    } else {
+        text
+    };
+    if fileformat == "dos" && !last && text.ends_with('\r') {
+        text.pop();
+        *pending_cr = true;
+    }
+    fileformat::to_lf(&text, fileformat)
+}
+
+impl Editor {
+    /// Opens a file above the `largefile` size: the first chunk is shown straight away
+    /// and the rest is loaded progressively in the background, with the buffer not
+    /// modifiable until it is all in. Undo and the swap file are off for it.
+    pub(crate) fn open_large_file(
+        &mut self,
+        filename: &str,
+        size: u64,
+        encoding: Option<&str>,
+    ) -> Result<()> {
+        let mut file = fs::File::open(filename)?;
+        let mut first = vec![0; CHUNK_SIZE];
+        let mut len = 0;
+        while len < first.len() {
+            match file.read(&mut first[len..])? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        first.truncate(len);
+        let (mut decoder, name, bom) =
+            StreamDecoder::detect(&first, &self.options.fileencodings, encoding);
+        let last = len as u64 == size;
+        let (text, lossy) = decoder.decode(&first[bom..], last);
+        let fileformat = fileformat::detect(&text);
+        let mut pending_cr = false;
+        let text = convert_endings(text, fileformat, &mut pending_cr, last);
+
+        let mut buffer =
+            Buffer::with_text(ropey::Rope::from_str(&text), Some(filename.to_string()));
+        buffer.large = true;
+        buffer.modifiable = last;
+        buffer.disk = Some(checktime::DiskState::from_metadata(&file.metadata()?));
+        // There is no swap file prompt to go back from
+        self.replace_buffer(buffer);
+        self.options.fileformat = fileformat.to_string();
+        self.options.fileencoding = name;
+        self.options.bomb = bom > 0;
+        self.status_msg = format!(
+            "Loaded file: {} ({}, large file: undo and swap file are off)",
+            filename,
+            format_size(size)
+        );
+        if last {
+            return Ok(());
+        }
+        let (sender, receiver) = mpsc::sync_channel(READ_AHEAD);
+        thread::spawn(move || load(file, decoder, fileformat, pending_cr, sender));
+        self.loader = Some(Loader {
+            receiver,
+            total: size,
+            loaded: len as u64,
+            lossy,
+        });
+        Ok(())
+    }
+
+    /// Appends the text loaded since the last call, for a limited time.
+    /// Called on every pass through the main loop while a large file is loading.
+    pub(crate) fn poll_loader(&mut self) {
+        let Some(loader) = &mut self.loader else {
+            return;
+        };
+        let start = Instant::now();
+        while start.elapsed() < APPEND_BUDGET {
+            match loader.receiver.try_recv() {
+                Ok(Message::Text(text, bytes, lossy)) => {
+                    let end = self.buffer.rope.len_chars();
+                    self.buffer.rope.insert(end, &text);
+                    loader.loaded += bytes;
+                    loader.lossy |= lossy;
+                    if bytes == 0 {
+                        if loader.lossy {
+                            self.status_msg = "Invalid bytes are shown as <xx>".to_string();
+                        }
+                        self.finish_loading();
+                        return;
+                    }
+                }
+                Ok(Message::Failed(e)) => {
+                    // Writing back a partly loaded file would cut it short
+                    self.status_msg = format!("Error reading file: {}; opened read-only", e);
+                    self.buffer.readonly = true;
+                    self.finish_loading();
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.finish_loading();
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stops loading and lets the text be changed; what has been loaded is now what the
+    /// buffer holds as read.
+    fn finish_loading(&mut self) {
+        self.loader = None;
+        self.buffer.saved = self.buffer.rope.clone();
+        self.buffer.modifiable = true;
+    }
+
+    /// What a command does if it takes in all of the buffer, for the whole-file prompt:
+    /// filtering, substituting in or formatting every line, or sending them to a
+    /// command. `range` is the range typed before `command`.
+    pub(crate) fn whole_file_use(
+        &self,
+        range: Option<Range>,
+        command: &str,
+    ) -> Option<&'static str> {
+        let whole = |range: Option<Range>| {
+            range.is_some_and(|range| range.start <= 1 && range.end >= self.buffer.len_lines())
+        };
+        match shell::parse(command) {
+            Some(Bang::Run(_)) if whole(range) => return Some("Filtering"),
+            Some(Bang::Write(_)) if range.is_none() || whole(range) => {
+                return Some("Sending");
+            }
+            Some(_) => return None,
+            None => {}
+        }
+        if let Some(Ok(_)) = substitute::parse(command)
+            && whole(range)
+        {
+            return Some("Substituting in");
+        }
+        if command.trim() == "Format" && (range.is_none() || whole(range)) {
+            return Some("Formatting");
+        }
+        None
+    }
+
+    /// Asks before running a command on a large file that needs another copy of all of
+    /// it in memory. `doing` names what the command does, e.g. "Writing".
+    pub(crate) fn confirm_whole_file(&mut self, command: &str, doing: &str) {
+        let size = format_size(self.buffer.rope.len_bytes() as u64);
+        self.mode = Mode::Confirm(ConfirmState {
+            prompt: format!(
+                "{} this file needs another {} of memory. Continue? [Y]es, [N]o:",
+                doing, size
+            ),
+            choices: "yn".to_string(),
+            cancel: 'n',
+            action: ConfirmAction::WholeFile(command.to_string()),
+        });
+    }
+
+    /// Runs the command the whole-file prompt was about, if the answer was yes.
+    /// Returns false if the editor should quit.
+    pub(crate) fn answer_whole_file_prompt(&mut self, command: &str, choice: char) -> Result<bool> {
+        if choice != 'y' {
+            self.status_msg = "Cancelled".to_string();
+            return Ok(true);
+        }
+        self.whole_file_confirmed = true;
+        let result = self.execute_command(command);
+        self.whole_file_confirmed = false;
+        result
+    }
+}
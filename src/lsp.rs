@@ -0,0 +1,2010 @@
+use crate::complete::{Candidate, Kind, is_keyword};
+use crate::errorformat::Entry;
+use crate::quickfix::{DEFAULT_HEIGHT, ListKind, same_file};
+use crate::render::{Frame, Style};
+use crate::window::Pane;
+use crate::{
+    Buffer, CommandState, ConfirmAction, ConfirmState, Editor, Mode, display, range, save,
+};
+use crossterm::style::{Attribute, Color};
+use ropey::Rope;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// File extensions and the filetype they stand for, which is also the language id
+/// servers are told.
+const FILETYPES: &[(&str, &str)] = &[
+    ("rs", "rust"),
+    ("py", "python"),
+    ("c", "c"),
+    ("h", "c"),
+    ("cc", "cpp"),
+    ("cpp", "cpp"),
+    ("hpp", "cpp"),
+    ("go", "go"),
+    ("js", "javascript"),
+    ("ts", "typescript"),
+    ("java", "java"),
+    ("rb", "ruby"),
+    ("lua", "lua"),
+    ("sh", "sh"),
+    ("zig", "zig"),
+    ("hs", "haskell"),
+];
+
+/// Rows the hover window takes at most.
+const HOVER_HEIGHT: usize = 12;
+
+/// How long a server gets to answer a request before it is given up on.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a server gets to shut down and exit before it is killed.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `textDocumentSync` kinds: how the server wants to hear about edits.
+const SYNC_NONE: u64 = 0;
+const SYNC_FULL: u64 = 1;
+
+/// The filetype of a file, from its extension.
+pub fn filetype(filename: &str) -> Option<&'static str> {
+    let extension = Path::new(filename).extension()?.to_str()?;
+    FILETYPES
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, filetype)| *filetype)
+}
+
+/// A place in a document as the protocol counts it: zero-based lines, and columns in
+/// UTF-16 code units.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+impl Position {
+    /// The position of char `index` of `rope`.
+    fn of(rope: &Rope, index: usize) -> Self {
+        let line = rope.char_to_line(index);
+        let line_start = rope.line_to_char(line);
+        Self {
+            line,
+            character: rope.char_to_utf16_cu(index) - rope.char_to_utf16_cu(line_start),
+        }
+    }
+
+    fn from_json(value: &Value) -> Self {
+        let number = |key: &str| value[key].as_u64().unwrap_or(0) as usize;
+        Self {
+            line: number("line"),
+            character: number("character"),
+        }
+    }
+
+    fn to_json(self) -> Value {
+        json!({ "line": self.line, "character": self.character })
+    }
+
+    /// The char index of `rope` this position stands for, kept within its line.
+    fn char_index(self, rope: &Rope) -> usize {
+        if self.line >= rope.len_lines() {
+            return rope.len_chars();
+        }
+        let start = rope.line_to_char(self.line);
+        let line = rope.line(self.line);
+        let mut len = line.len_chars();
+        while len > 0 && matches!(line.char(len - 1), '\n' | '\r') {
+            len -= 1;
+        }
+        let units = rope.char_to_utf16_cu(start) + self.character;
+        let index = rope.utf16_cu_to_char(units.min(rope.len_utf16_cu()));
+        index.clamp(start, start + len)
+    }
+
+    /// The (line, char column) of `rope` this position stands for.
+    fn line_col(self, rope: &Rope) -> (usize, usize) {
+        let index = self.char_index(rope);
+        let line = rope.char_to_line(index);
+        (line, index - rope.line_to_char(line))
+    }
+}
+
+/// An edit of the buffer to tell the server about, its range taken from the text
+/// before the edit. No range stands for the whole text.
+pub struct Change {
+    range: Option<(Position, Position)>,
+    text: String,
+}
+
+impl Change {
+    /// An edit about to replace the chars `start..end` of `rope` with `text`.
+    pub fn new(rope: &Rope, start: usize, end: usize, text: &str) -> Self {
+        Self {
+            range: Some((Position::of(rope, start), Position::of(rope, end))),
+            text: text.to_string(),
+        }
+    }
+
+    /// The whole text of `rope`, after an edit that replaced all of it.
+    pub fn whole(rope: &Rope) -> Self {
+        Self {
+            range: None,
+            text: rope.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self.range {
+            Some((start, end)) => json!({
+                "range": { "start": start.to_json(), "end": end.to_json() },
+                "text": self.text,
+            }),
+            None => json!({ "text": self.text }),
+        }
+    }
+}
+
+/// A problem a server reported in a file.
+pub struct Diagnostic {
+    start: Position,
+    /// As the server sent it, to give back with a code action request.
+    raw: Value,
+    /// 1 error, 2 warning, 3 information, 4 hint.
+    severity: u64,
+    message: String,
+}
+
+impl Diagnostic {
+    /// The sign in the sign column, and the colour of the sign and the message.
+    fn sign(&self) -> (&'static str, Style) {
+        match self.severity {
+            1 => ("E>", Style::DEFAULT.fg(Color::Red)),
+            2 => ("W>", Style::DEFAULT.fg(Color::Yellow)),
+            3 => ("I>", Style::DEFAULT.fg(Color::Blue)),
+            _ => ("H>", Style::DEFAULT.fg(Color::DarkGrey)),
+        }
+    }
+
+    /// The kind of its quickfix entry.
+    fn kind(&self) -> char {
+        match self.severity {
+            1 => 'e',
+            2 => 'w',
+            3 => 'i',
+            _ => 'n',
+        }
+    }
+}
+
+/// What a request to a server was for, so its answer can be acted on.
+enum Request {
+    Initialize,
+    Definition,
+    /// Finding the references to a word.
+    References(String),
+    Hover,
+    Rename,
+    /// Completing the text from char `start` of line `row` to the cursor.
+    Completion {
+        row: usize,
+        start: usize,
+    },
+    /// Filling in a kept completion item, for its extra edits.
+    ResolveCompletion,
+    SignatureHelp,
+    CodeAction,
+    /// Filling in the chosen code action, for its edit.
+    ResolveCodeAction,
+    /// Running the command of a code action on the server.
+    ExecuteCommand,
+    /// Formatting version `version` of a document, all of it or a range of lines.
+    Formatting {
+        uri: String,
+        version: i64,
+        ranged: bool,
+    },
+}
+
+impl Request {
+    fn method(&self) -> &'static str {
+        match self {
+            Request::Initialize => "initialize",
+            Request::Definition => "textDocument/definition",
+            Request::References(_) => "textDocument/references",
+            Request::Hover => "textDocument/hover",
+            Request::Rename => "textDocument/rename",
+            Request::Completion { .. } => "textDocument/completion",
+            Request::ResolveCompletion => "completionItem/resolve",
+            Request::SignatureHelp => "textDocument/signatureHelp",
+            Request::CodeAction => "textDocument/codeAction",
+            Request::ResolveCodeAction => "codeAction/resolve",
+            Request::ExecuteCommand => "workspace/executeCommand",
+            Request::Formatting { ranged: false, .. } => "textDocument/formatting",
+            Request::Formatting { ranged: true, .. } => "textDocument/rangeFormatting",
+        }
+    }
+}
+
+/// A language server running as a child process, spoken to with JSON-RPC over its
+/// standard input and output. Threads write and read its messages, so a server that
+/// hangs never holds up the editor; dropping the client stops the server.
+struct Client {
+    /// The server's program, for messages.
+    name: String,
+    child: Child,
+    writer: Sender<String>,
+    receiver: Receiver<Value>,
+    next_id: i64,
+    /// Requests sent and not answered yet, with when they were sent.
+    pending: HashMap<i64, (Request, Instant)>,
+    /// Messages held back until the server has answered `initialize`.
+    queued: Option<Vec<Value>>,
+    /// What the server said it can do, in its answer to `initialize`.
+    capabilities: Value,
+    /// How the server wants edits sent: not at all, the whole text, or just the changes.
+    sync: u64,
+}
+
+impl Client {
+    /// Starts the server `command` on the project at `root` and asks it to initialize.
+    fn start(command: &str, root: &str) -> io::Result<Self> {
+        let mut words = command.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (writer, messages) = mpsc::channel();
+        thread::spawn(move || write_messages(stdin, messages));
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || read_messages(stdout, sender));
+        let mut client = Self {
+            name: program.to_string(),
+            child,
+            writer,
+            receiver,
+            next_id: 1,
+            pending: HashMap::new(),
+            queued: None,
+            capabilities: Value::Null,
+            sync: SYNC_NONE,
+        };
+        let params = json!({
+            "processId": std::process::id(),
+            "rootUri": root,
+            "capabilities": {
+                "general": { "positionEncodings": ["utf-16"] },
+                "textDocument": {
+                    "synchronization": { "didSave": true },
+                    "publishDiagnostics": {},
+                    "hover": { "contentFormat": ["plaintext", "markdown"] },
+                    "definition": { "linkSupport": true },
+                    "references": {},
+                    "rename": {},
+                    "completion": {
+                        "completionItem": {
+                            "snippetSupport": true,
+                            "resolveSupport": { "properties": ["additionalTextEdits", "detail"] },
+                        },
+                    },
+                    "signatureHelp": {
+                        "signatureInformation": {
+                            "parameterInformation": { "labelOffsetSupport": true },
+                        },
+                    },
+                    "codeAction": {
+                        "codeActionLiteralSupport": {
+                            "codeActionKind": { "valueSet": ["", "quickfix", "refactor", "source"] },
+                        },
+                        "resolveSupport": { "properties": ["edit"] },
+                    },
+                    "formatting": {},
+                    "rangeFormatting": {},
+                },
+                "workspace": {
+                    "applyEdit": true,
+                    "workspaceEdit": { "documentChanges": true },
+                    "configuration": true,
+                },
+            },
+        });
+        client.request(Request::Initialize, params);
+        client.queued = Some(Vec::new());
+        Ok(client)
+    }
+
+    /// Sends a message, or keeps it until the server is initialized. A server that
+    /// went away is noticed by the reader thread, so write errors are left to it.
+    fn send(&mut self, message: Value) {
+        match &mut self.queued {
+            Some(queued) => queued.push(message),
+            None => {
+                let _ = self.writer.send(message.to_string());
+            }
+        }
+    }
+
+    /// Sends a request. Returns its id, which the answer comes with.
+    fn request(&mut self, kind: Request, params: Value) -> i64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let method = kind.method();
+        self.pending.insert(id, (kind, Instant::now()));
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+        id
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    /// Answers a request the server made.
+    fn respond(&mut self, id: Value, result: Result<Value, String>) {
+        let message = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(message) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": message },
+            }),
+        };
+        self.send(message);
+    }
+
+    /// Takes in the answer to `initialize` and sends what was held back.
+    fn initialized(&mut self, result: &Value) {
+        self.capabilities = result["capabilities"].clone();
+        let sync = &self.capabilities["textDocumentSync"];
+        self.sync = sync
+            .as_u64()
+            .or_else(|| sync["change"].as_u64())
+            .unwrap_or(SYNC_NONE);
+        let queued = self.queued.take().unwrap_or_default();
+        self.notify("initialized", json!({}));
+        for message in queued {
+            self.send(message);
+        }
+    }
+
+    /// Whether the server can fill in the details of what it sent, such as the
+    /// `completionProvider` or `codeActionProvider` items.
+    fn resolves(&self, provider: &str) -> bool {
+        self.capabilities[provider]["resolveProvider"].as_bool() == Some(true)
+    }
+
+    /// Takes out the requests that went unanswered for too long.
+    fn expired(&mut self) -> Vec<Request> {
+        let ids: Vec<i64> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, sent))| sent.elapsed() > TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.pending.remove(&id))
+            .map(|(kind, _)| kind)
+            .collect()
+    }
+}
+
+impl Drop for Client {
+    /// Asks the server to shut down and exit, and kills it if it hasn't done so soon.
+    fn drop(&mut self) {
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        if self.queued.is_none() {
+            let id = self.next_id;
+            self.send(json!({ "jsonrpc": "2.0", "id": id, "method": "shutdown" }));
+            while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+                match self.receiver.recv_timeout(left) {
+                    Ok(message) if message["id"] == id && message.get("method").is_none() => break,
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+            self.send(json!({ "jsonrpc": "2.0", "method": "exit" }));
+            while Instant::now() < deadline {
+                if !matches!(self.child.try_wait(), Ok(None)) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Writes messages to a server, each with its `Content-Length` header, until the
+/// client is dropped or the server stops reading.
+fn write_messages(mut stdin: ChildStdin, messages: Receiver<String>) {
+    for body in messages {
+        let written = write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        if written.and_then(|()| stdin.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the messages of a server, each a `Content-Length` header and a JSON body,
+/// until it closes its output.
+fn read_messages(stdout: ChildStdout, sender: Sender<Value>) {
+    let mut reader = BufReader::new(stdout);
+    let mut header = String::new();
+    loop {
+        let mut length = None;
+        loop {
+            header.clear();
+            match reader.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let line = header.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse().ok();
+            }
+        }
+        let Some(length) = length else {
+            continue;
+        };
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        if let Ok(message) = serde_json::from_slice(&body)
+            && sender.send(message).is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// The file the servers were told is open in the editor.
+struct Document {
+    /// The buffer's filename when it was opened, to notice another file without
+    /// looking up the URI again.
+    filename: String,
+    uri: String,
+    server: String,
+    version: i64,
+}
+
+/// The language servers and what they said.
+#[derive(Default)]
+pub struct Lsp {
+    /// Servers by command; None for one that failed to start, so it isn't tried again.
+    clients: HashMap<String, Option<Client>>,
+    document: Option<Document>,
+    /// The latest diagnostics of each file, by URI, in line order.
+    diagnostics: HashMap<String, Vec<Diagnostic>>,
+    /// The text shown by `K`, until the next key.
+    pub hover: Option<Vec<String>>,
+    /// The signature of the call being typed, with the byte range of the parameter
+    /// the cursor is in, shown while in Insert mode.
+    signature: Option<(String, Option<Range<usize>>)>,
+}
+
+/// The `file://` URI of a path.
+fn path_to_uri(path: &str) -> String {
+    let absolute = fs::canonicalize(path).unwrap_or_else(|_| {
+        env::current_dir()
+            .map(|dir| dir.join(path))
+            .unwrap_or_else(|_| PathBuf::from(path))
+    });
+    let mut uri = String::from("file://");
+    for byte in absolute.to_string_lossy().bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+/// The path of a `file://` URI, relative to the working directory when inside it.
+fn uri_to_path(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (encoded[i], escaped) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                i += 1;
+            }
+        }
+    }
+    let path = PathBuf::from(String::from_utf8_lossy(&bytes).into_owned());
+    let relative = env::current_dir()
+        .ok()
+        .and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .unwrap_or(path);
+    Some(relative.to_string_lossy().into_owned())
+}
+
+/// The text edits of a list, with their ranges, last in the file first so each can
+/// be made without moving the ones still to come. Edits starting at the same place
+/// come last in the list first, so text inserted there ends up in list order.
+fn text_edits(edits: &Value) -> Vec<(Position, Position, String)> {
+    let mut edits: Vec<_> = edits
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, edit)| {
+            (
+                Position::from_json(&edit["range"]["start"]),
+                i,
+                Position::from_json(&edit["range"]["end"]),
+                edit["newText"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    edits.sort_by_key(|edit| std::cmp::Reverse((edit.0, edit.1)));
+    edits
+        .into_iter()
+        .map(|(start, _, end, text)| (start, end, text))
+        .collect()
+}
+
+/// Makes text edits, given last in the text first, in a rope.
+fn apply_text_edits(rope: &mut Rope, edits: Vec<(Position, Position, String)>) {
+    for (start, end, text) in edits {
+        let start = start.char_index(rope);
+        let end = end.char_index(rope).max(start);
+        rope.remove(start..end);
+        rope.insert(start, &text);
+    }
+}
+
+/// The lines of a hover answer, with the fences of code blocks left out.
+fn hover_lines(contents: &Value) -> Vec<String> {
+    let text = match contents {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .map(|part| part.as_str().or(part["value"].as_str()).unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        _ => contents["value"].as_str().unwrap_or_default().to_string(),
+    };
+    let mut lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .map(|line| line.replace('\t', "    ").trim_end().to_string())
+        .collect();
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    let first = lines
+        .iter()
+        .position(|l| !l.is_empty())
+        .unwrap_or(lines.len());
+    lines.split_off(first)
+}
+
+/// The places a definition answer points to: a Location, Locations or LocationLinks.
+fn locations(result: &Value) -> Vec<(String, Position)> {
+    let items = match result {
+        Value::Array(items) => items.clone(),
+        Value::Null => Vec::new(),
+        item => vec![item.clone()],
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            let uri = item["uri"].as_str().or(item["targetUri"].as_str())?;
+            let range = if item["targetSelectionRange"].is_object() {
+                &item["targetSelectionRange"]
+            } else {
+                &item["range"]
+            };
+            Some((uri.to_string(), Position::from_json(&range["start"])))
+        })
+        .collect()
+}
+
+/// A completion item from a server, kept with its candidate until the match is kept.
+pub struct CompletionItem {
+    server: String,
+    item: Value,
+}
+
+/// Expands a snippet to plain text: placeholders become their default text, choices
+/// their first option, and tabstops and variables go. Returns the text and the char
+/// offset of the first tabstop, with `$0` last.
+fn expand_snippet(snippet: &str) -> (String, Option<usize>) {
+    let chars: Vec<char> = snippet.chars().collect();
+    let mut text = String::new();
+    let mut stops = Vec::new();
+    expand_snippet_part(&chars, &mut 0, &mut text, &mut stops, false);
+    let first = stops
+        .iter()
+        .filter(|(number, _)| *number > 0)
+        .min_by_key(|(number, _)| *number)
+        .or_else(|| stops.iter().find(|(number, _)| *number == 0))
+        .map(|(_, offset)| *offset);
+    (text, first)
+}
+
+/// Expands snippet text from `chars[*i]`, up to the `}` that closes a placeholder
+/// when `nested`, noting the number and char offset of each tabstop in `stops`.
+fn expand_snippet_part(
+    chars: &[char],
+    i: &mut usize,
+    text: &mut String,
+    stops: &mut Vec<(usize, usize)>,
+    nested: bool,
+) {
+    while let Some(&c) = chars.get(*i) {
+        *i += 1;
+        match c {
+            '\\' if chars.get(*i).is_some_and(|c| "$}\\,|".contains(*c)) => {
+                text.push(chars[*i]);
+                *i += 1;
+            }
+            '}' if nested => return,
+            '$' => {
+                let braced = chars.get(*i) == Some(&'{');
+                let start = *i + usize::from(braced);
+                let mut end = start;
+                while chars
+                    .get(end)
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '_')
+                {
+                    end += 1;
+                }
+                if end == start {
+                    text.push('$');
+                    continue;
+                }
+                *i = end;
+                let name: String = chars[start..end].iter().collect();
+                if let Ok(number) = name.parse() {
+                    stops.push((number, text.chars().count()));
+                }
+                if !braced {
+                    continue;
+                }
+                match chars.get(*i) {
+                    Some(':') => {
+                        *i += 1;
+                        expand_snippet_part(chars, i, text, stops, true);
+                    }
+                    Some('|') => {
+                        *i += 1;
+                        let mut first = true;
+                        while let Some(&c) = chars.get(*i) {
+                            *i += 1;
+                            match c {
+                                '|' => break,
+                                ',' => first = false,
+                                '\\' if chars.get(*i).is_some() => {
+                                    if first {
+                                        text.push(chars[*i]);
+                                    }
+                                    *i += 1;
+                                }
+                                c if first => text.push(c),
+                                _ => {}
+                            }
+                        }
+                        if chars.get(*i) == Some(&'}') {
+                            *i += 1;
+                        }
+                    }
+                    // `}`, or a transform that has no text of its own here
+                    _ => {
+                        while let Some(&c) = chars.get(*i) {
+                            *i += 1;
+                            if c == '}' {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            c => text.push(c),
+        }
+    }
+}
+
+/// The text a completion item puts in, expanded if it is a snippet, and the char
+/// offset in it where the cursor goes.
+fn item_text(item: &Value) -> (String, Option<usize>) {
+    let text = item["textEdit"]["newText"]
+        .as_str()
+        .or(item["insertText"].as_str())
+        .or(item["label"].as_str())
+        .unwrap_or_default();
+    if item["insertTextFormat"].as_u64() == Some(2) {
+        expand_snippet(text)
+    } else {
+        (text.to_string(), None)
+    }
+}
+
+/// The candidates of a completion answer whose filter text starts with `typed`,
+/// ignoring case, in the server's order. A candidate shows the first line of its text.
+fn completion_candidates(server: &str, result: &Value, typed: &str) -> Vec<Candidate> {
+    let typed = typed.to_lowercase();
+    let mut items: Vec<Value> = result
+        .as_array()
+        .or(result["items"].as_array())
+        .into_iter()
+        .flatten()
+        .filter(|item| {
+            let filter = item["filterText"].as_str().or(item["label"].as_str());
+            filter.is_some_and(|filter| filter.to_lowercase().starts_with(&typed))
+        })
+        .cloned()
+        .collect();
+    items.sort_by_cached_key(|item| {
+        let key = item["sortText"].as_str().or(item["label"].as_str());
+        key.unwrap_or_default().to_string()
+    });
+    items
+        .into_iter()
+        .map(|item| {
+            let (text, _) = item_text(&item);
+            Candidate {
+                word: text.lines().next().unwrap_or_default().to_string(),
+                info: item["detail"].as_str().unwrap_or_default().to_string(),
+                item: Some(CompletionItem {
+                    server: server.to_string(),
+                    item,
+                }),
+            }
+        })
+        .collect()
+}
+
+/// The byte offset in `text` of a UTF-16 offset.
+fn utf16_to_byte(text: &str, units: usize) -> usize {
+    let mut count = 0;
+    for (i, c) in text.char_indices() {
+        if count >= units {
+            return i;
+        }
+        count += c.len_utf16();
+    }
+    text.len()
+}
+
+/// The label of the active signature in a signature help answer, with the byte range
+/// of the active parameter in it.
+fn signature(result: &Value) -> Option<(String, Option<Range<usize>>)> {
+    let signatures = result["signatures"].as_array()?;
+    let active = result["activeSignature"].as_u64().unwrap_or(0) as usize;
+    let signature = signatures.get(active).or(signatures.first())?;
+    let label = signature["label"].as_str()?.to_string();
+    let parameter = signature["activeParameter"]
+        .as_u64()
+        .or(result["activeParameter"].as_u64())
+        .unwrap_or(0) as usize;
+    let range = match &signature["parameters"][parameter]["label"] {
+        // The name of a parameter is looked for after the name of the function
+        Value::String(name) => {
+            let from = label.find('(').unwrap_or(0);
+            label[from..]
+                .find(name.as_str())
+                .map(|i| from + i..from + i + name.len())
+        }
+        Value::Array(offsets) => match (offsets.first(), offsets.get(1)) {
+            (Some(start), Some(end)) => {
+                let offset =
+                    |value: &Value| utf16_to_byte(&label, value.as_u64().unwrap_or(0) as usize);
+                Some(offset(start)..offset(end))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    Some((label, range))
+}
+
+/// The parameters of `workspace/executeCommand` for a Command.
+fn command_params(command: &Value) -> Option<Value> {
+    let mut params = json!({ "command": command["command"].as_str()? });
+    if let Some(arguments) = command.get("arguments") {
+        params["arguments"] = arguments.clone();
+    }
+    Some(params)
+}
+
+impl Editor {
+    /// Keeps the server in step with the buffer, and acts on what the servers sent.
+    pub(crate) fn poll_lsp(&mut self) {
+        self.sync_document();
+        let mut messages = Vec::new();
+        let mut exited = Vec::new();
+        for (server, client) in &mut self.lsp.clients {
+            let Some(client) = client else {
+                continue;
+            };
+            loop {
+                match client.receiver.try_recv() {
+                    Ok(message) => messages.push((server.clone(), message)),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        exited.push(server.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        for (server, message) in messages {
+            self.handle_message(&server, message);
+        }
+        for (server, client) in &mut self.lsp.clients {
+            let Some(client) = client else {
+                continue;
+            };
+            for kind in client.expired() {
+                self.status_msg = format!("{} did not answer {}", client.name, kind.method());
+                // A server that never initializes is given up on
+                if let Request::Initialize = kind {
+                    exited.push(server.clone());
+                }
+            }
+        }
+        if !matches!(self.mode, Mode::Insert(_)) {
+            self.lsp.signature = None;
+        }
+        for server in exited {
+            if let Some(Some(client)) = self.lsp.clients.insert(server.clone(), None) {
+                self.status_msg = format!("Language server {} exited", client.name);
+            }
+            if self
+                .lsp
+                .document
+                .as_ref()
+                .is_some_and(|d| d.server == server)
+            {
+                self.lsp.document = None;
+                self.buffer.lsp_changes = None;
+            }
+        }
+    }
+
+    /// Whether answers are awaited, to poll for them more often.
+    pub(crate) fn lsp_waiting(&self) -> bool {
+        self.lsp
+            .clients
+            .values()
+            .flatten()
+            .any(|client| !client.pending.is_empty())
+    }
+
+    /// Tells the server about the edits made since the last call, or about a new file
+    /// in the buffer or a new server set for it: the old one is closed and the new one
+    /// opened.
+    fn sync_document(&mut self) {
+        let filename = self.buffer.filename.as_deref();
+        let server = filename
+            .and_then(filetype)
+            .and_then(|filetype| self.options.lspserver(filetype));
+        if let Some(changes) = &mut self.buffer.lsp_changes
+            && let Some(document) = &mut self.lsp.document
+            && filename == Some(document.filename.as_str())
+            && server == Some(document.server.as_str())
+        {
+            let Some(Some(client)) = self.lsp.clients.get_mut(&document.server) else {
+                return;
+            };
+            // Edits wait until the server says how it wants them
+            if changes.is_empty() || client.queued.is_some() {
+                return;
+            }
+            let changes = std::mem::take(changes);
+            let content_changes: Vec<Value> = match client.sync {
+                SYNC_NONE => return,
+                SYNC_FULL => vec![json!({ "text": self.buffer.rope.to_string() })],
+                _ => changes.iter().map(Change::to_json).collect(),
+            };
+            document.version += 1;
+            client.notify(
+                "textDocument/didChange",
+                json!({
+                    "textDocument": { "uri": document.uri, "version": document.version },
+                    "contentChanges": content_changes,
+                }),
+            );
+            return;
+        }
+        if let Some(document) = self.lsp.document.take()
+            && let Some(Some(client)) = self.lsp.clients.get_mut(&document.server)
+        {
+            client.notify(
+                "textDocument/didClose",
+                json!({ "textDocument": { "uri": document.uri } }),
+            );
+        }
+        self.buffer.lsp_changes = None;
+        self.open_document();
+    }
+
+    /// Starts following the buffer if its filetype has a server, starting the server
+    /// the first time.
+    fn open_document(&mut self) {
+        if self.buffer.large || self.browser.is_some() || self.loader.is_some() {
+            return;
+        }
+        let Some(filename) = self.buffer.filename.clone() else {
+            return;
+        };
+        let Some(filetype) = filetype(&filename) else {
+            return;
+        };
+        let Some(command) = self.options.lspserver(filetype) else {
+            return;
+        };
+        if !self.lsp.clients.contains_key(command) {
+            let client = match Client::start(command, &path_to_uri(".")) {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    self.status_msg = format!("Cannot start language server {}: {}", command, e);
+                    None
+                }
+            };
+            self.lsp.clients.insert(command.to_string(), client);
+        }
+        let Some(Some(client)) = self.lsp.clients.get_mut(command) else {
+            return;
+        };
+        let uri = path_to_uri(&filename);
+        client.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": filetype,
+                    "version": 0,
+                    "text": self.buffer.rope.to_string(),
+                },
+            }),
+        );
+        self.buffer.lsp_changes = Some(Vec::new());
+        self.lsp.document = Some(Document {
+            filename,
+            uri,
+            server: command.to_string(),
+            version: 0,
+        });
+    }
+
+    /// Tells the server the buffer was written.
+    pub(crate) fn lsp_did_save(&mut self) {
+        self.sync_document();
+        let Some(document) = &self.lsp.document else {
+            return;
+        };
+        if let Some(Some(client)) = self.lsp.clients.get_mut(&document.server) {
+            client.notify(
+                "textDocument/didSave",
+                json!({ "textDocument": { "uri": document.uri } }),
+            );
+        }
+    }
+
+    /// The cursor's place as the server counts it.
+    fn cursor_position(&self) -> Position {
+        let rope = &self.buffer.rope;
+        Position::of(
+            rope,
+            (rope.line_to_char(self.cy) + self.cx).min(rope.len_chars()),
+        )
+    }
+
+    /// The server following the buffer and the URI it knows the file by, after the
+    /// latest edits are sent.
+    fn lsp_target(&mut self) -> Option<(&mut Client, &str)> {
+        self.sync_document();
+        let Some(document) = &self.lsp.document else {
+            self.status_msg = "No language server for this file".to_string();
+            return None;
+        };
+        let client = self.lsp.clients.get_mut(&document.server)?.as_mut()?;
+        Some((client, &document.uri))
+    }
+
+    /// Sends a request about the place under the cursor. `extra` is merged into the
+    /// parameters.
+    fn request_at_cursor(&mut self, kind: Request, extra: Value) {
+        let position = self.cursor_position().to_json();
+        let Some((client, uri)) = self.lsp_target() else {
+            return;
+        };
+        let mut params = json!({ "textDocument": { "uri": uri }, "position": position });
+        if let (Some(params), Value::Object(extra)) = (params.as_object_mut(), extra) {
+            params.extend(extra);
+        }
+        client.request(kind, params);
+    }
+
+    /// `gd`: goes to the definition of the symbol under the cursor.
+    pub(crate) fn goto_definition(&mut self) {
+        self.request_at_cursor(Request::Definition, json!({}));
+    }
+
+    /// `gr`: lists the references to the symbol under the cursor in the quickfix list.
+    pub(crate) fn find_references(&mut self) {
+        let word = self.word_under_cursor().unwrap_or_default();
+        self.request_at_cursor(
+            Request::References(word),
+            json!({ "context": { "includeDeclaration": true } }),
+        );
+    }
+
+    /// `K`: shows what the server knows about the symbol under the cursor.
+    pub(crate) fn show_hover(&mut self) {
+        self.request_at_cursor(Request::Hover, json!({}));
+    }
+
+    /// `:LspRename {name}` renames the symbol under the cursor in every file that uses
+    /// it. Without a name, the command line is offered with the current one.
+    pub(crate) fn lsp_rename(&mut self, name: &str) {
+        if name.is_empty() {
+            let word = self.word_under_cursor().unwrap_or_default();
+            self.mode = Mode::Command(CommandState::new(format!(":LspRename {}", word)));
+            return;
+        }
+        self.request_at_cursor(Request::Rename, json!({ "newName": name }));
+    }
+
+    /// `:LspDiagnostics` puts the diagnostics of every file in the quickfix list.
+    pub(crate) fn list_diagnostics(&mut self) {
+        let mut entries = Vec::new();
+        let mut uris: Vec<&String> = self.lsp.diagnostics.keys().collect();
+        uris.sort();
+        for uri in uris {
+            let Some(path) = uri_to_path(uri) else {
+                continue;
+            };
+            let rope = self.file_text(&path);
+            for diagnostic in &self.lsp.diagnostics[uri] {
+                let (line, col) = diagnostic.start.line_col(&rope);
+                entries.push(Entry {
+                    filename: Some(path.clone()),
+                    line: line + 1,
+                    col: col + 1,
+                    kind: diagnostic.kind(),
+                    number: None,
+                    text: diagnostic.message.clone(),
+                });
+            }
+        }
+        self.show_quickfix_list(entries, "Diagnostics".to_string());
+    }
+
+    /// The text of a file: the buffer's if it is the file being edited, else as on disk.
+    fn file_text(&self, path: &str) -> Rope {
+        match &self.buffer.filename {
+            Some(current) if same_file(current, path) => self.buffer.rope.clone(),
+            _ => fs::read_to_string(path)
+                .map(|text| Rope::from_str(&text))
+                .unwrap_or_default(),
+        }
+    }
+
+    fn handle_message(&mut self, server: &str, message: Value) {
+        let method = message["method"].as_str();
+        match (method, message.get("id")) {
+            (Some(method), Some(id)) => {
+                let result = self.answer_server_request(method, &message["params"]);
+                if let Some(Some(client)) = self.lsp.clients.get_mut(server) {
+                    client.respond(id.clone(), result);
+                }
+            }
+            (Some(method), None) => self.handle_notification(server, method, &message["params"]),
+            (None, Some(id)) => {
+                let Some(Some(client)) = self.lsp.clients.get_mut(server) else {
+                    return;
+                };
+                let Some((kind, _)) = id.as_i64().and_then(|id| client.pending.remove(&id)) else {
+                    return;
+                };
+                if let Some(error) = message.get("error") {
+                    let text = error["message"].as_str().unwrap_or("request failed");
+                    self.status_msg = format!("{}: {}", client.name, text);
+                    return;
+                }
+                let result = &message["result"];
+                if let Request::Initialize = kind {
+                    client.initialized(result);
+                    return;
+                }
+                self.handle_response(server, kind, result);
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// The answer to a request a server made of the editor.
+    fn answer_server_request(&mut self, method: &str, params: &Value) -> Result<Value, String> {
+        match method {
+            // Servers get their default settings
+            "workspace/configuration" => {
+                let items = params["items"].as_array().map_or(0, Vec::len);
+                Ok(Value::Array(vec![Value::Null; items]))
+            }
+            "window/workDoneProgress/create"
+            | "client/registerCapability"
+            | "client/unregisterCapability" => Ok(Value::Null),
+            "workspace/applyEdit" => Ok(match self.apply_workspace_edit(&params["edit"]) {
+                Ok(_) => json!({ "applied": true }),
+                Err(reason) => json!({ "applied": false, "failureReason": reason }),
+            }),
+            _ => Err(format!("Unsupported request: {}", method)),
+        }
+    }
+
+    fn handle_notification(&mut self, server: &str, method: &str, params: &Value) {
+        match method {
+            "textDocument/publishDiagnostics" => {
+                let Some(uri) = params["uri"].as_str() else {
+                    return;
+                };
+                let mut diagnostics: Vec<Diagnostic> = params["diagnostics"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|d| Diagnostic {
+                        start: Position::from_json(&d["range"]["start"]),
+                        raw: d.clone(),
+                        severity: d["severity"].as_u64().unwrap_or(1),
+                        message: d["message"].as_str().unwrap_or_default().to_string(),
+                    })
+                    .collect();
+                diagnostics.sort_by_key(|d| (d.start, d.severity));
+                self.lsp.diagnostics.insert(uri.to_string(), diagnostics);
+            }
+            // Errors, warnings and information; log messages are left out
+            "window/showMessage" if params["type"].as_u64().is_some_and(|t| t <= 3) => {
+                let name = match self.lsp.clients.get(server) {
+                    Some(Some(client)) => client.name.as_str(),
+                    _ => server,
+                };
+                let text = params["message"].as_str().unwrap_or_default();
+                self.status_msg = format!("{}: {}", name, text.lines().next().unwrap_or(""));
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_response(&mut self, server: &str, kind: Request, result: &Value) {
+        match kind {
+            Request::Initialize => {}
+            Request::Definition => {
+                let found = locations(result);
+                match &found[..] {
+                    [] => self.status_msg = "No definition found".to_string(),
+                    [(uri, position)] => self.jump_to_location(uri, *position),
+                    _ => {
+                        let entries = self.location_entries(&found);
+                        self.show_quickfix_list(entries, "Definitions".to_string());
+                    }
+                }
+            }
+            Request::References(word) => {
+                let found = locations(result);
+                if found.is_empty() {
+                    self.status_msg = "No references found".to_string();
+                    return;
+                }
+                let entries = self.location_entries(&found);
+                self.show_quickfix_list(entries, format!("References to {}", word));
+            }
+            Request::Hover => {
+                let lines = hover_lines(&result["contents"]);
+                if lines.is_empty() {
+                    self.status_msg = "No information available".to_string();
+                } else {
+                    self.lsp.hover = Some(lines);
+                }
+            }
+            Request::Rename => {
+                if result.is_null() {
+                    self.status_msg = "Nothing to rename here".to_string();
+                    return;
+                }
+                self.status_msg = match self.apply_workspace_edit(result) {
+                    Ok(1) => "Renamed in 1 file".to_string(),
+                    Ok(files) => format!("Renamed in {} files", files),
+                    Err(reason) => reason,
+                };
+            }
+            Request::Completion { row, start } => {
+                self.show_lsp_completion(server, row, start, result)
+            }
+            Request::ResolveCompletion => {
+                self.apply_buffer_edits(text_edits(&result["additionalTextEdits"]))
+            }
+            Request::SignatureHelp => self.lsp.signature = signature(result),
+            Request::CodeAction => {
+                let actions: Vec<Value> = result.as_array().cloned().unwrap_or_default();
+                if actions.is_empty() {
+                    self.status_msg = "No code actions available".to_string();
+                    return;
+                }
+                let count = actions.len().min(9);
+                self.mode = Mode::Confirm(ConfirmState {
+                    prompt: format!("Code action (1-{}, Esc cancels): ", count),
+                    choices: (1..=count).map(|n| n.to_string()).collect(),
+                    cancel: '0',
+                    action: ConfirmAction::CodeAction(server.to_string(), actions),
+                });
+            }
+            Request::ResolveCodeAction => self.run_code_action(server, result.clone(), true),
+            Request::ExecuteCommand => {}
+            Request::Formatting { uri, version, .. } => {
+                // Edits made since the request was sent would be undone
+                let current = self.lsp.document.as_ref().is_some_and(|d| {
+                    d.uri == uri
+                        && d.version == version
+                        && self.buffer.filename.as_deref() == Some(d.filename.as_str())
+                });
+                if !current {
+                    self.status_msg = "The buffer changed while it was formatted".to_string();
+                    return;
+                }
+                let mut rope = self.buffer.rope.clone();
+                apply_text_edits(&mut rope, text_edits(result));
+                self.apply_formatted(None, &rope.to_string());
+            }
+        }
+    }
+
+    /// Moves the cursor to a place a server pointed to, opening its file if needed.
+    fn jump_to_location(&mut self, uri: &str, position: Position) {
+        let Some(path) = uri_to_path(uri) else {
+            return;
+        };
+        let current = self.buffer.filename.as_deref();
+        if !current.is_some_and(|current| same_file(current, &path)) {
+            self.edit(false, &[&path]);
+            // `edit` has said why the file could not be opened
+            if self.buffer.filename.as_deref() != Some(path.as_str()) {
+                return;
+            }
+        }
+        (self.cy, self.cx) = position.line_col(&self.buffer.rope);
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+
+    /// Quickfix entries for places in files, each with the text of its line.
+    fn location_entries(&self, found: &[(String, Position)]) -> Vec<Entry> {
+        let mut texts: HashMap<String, Rope> = HashMap::new();
+        found
+            .iter()
+            .filter_map(|(uri, position)| {
+                let path = uri_to_path(uri)?;
+                let rope = texts
+                    .entry(path.clone())
+                    .or_insert_with(|| self.file_text(&path));
+                let (line, col) = position.line_col(rope);
+                let text = rope
+                    .get_line(line)
+                    .map(|l| l.to_string().trim().to_string())
+                    .unwrap_or_default();
+                Some(Entry {
+                    filename: Some(path),
+                    line: line + 1,
+                    col: col + 1,
+                    kind: ' ',
+                    number: None,
+                    text,
+                })
+            })
+            .collect()
+    }
+
+    /// Makes the edits of a WorkspaceEdit: in the buffers of the files the windows show,
+    /// as one undo step in each, and on disk for other files. Every other file is read
+    /// and its new contents encoded before any is written, so a file that can't be read
+    /// leaves all of them as they were. Returns how many files changed.
+    fn apply_workspace_edit(&mut self, edit: &Value) -> Result<usize, String> {
+        let mut files: Vec<(&str, &Value)> = Vec::new();
+        if let Some(changes) = edit["changes"].as_object() {
+            files.extend(changes.iter().map(|(uri, edits)| (uri.as_str(), edits)));
+        }
+        for change in edit["documentChanges"].as_array().into_iter().flatten() {
+            if change.get("kind").is_some() {
+                return Err("Creating, renaming and deleting files is not supported".to_string());
+            }
+            if let Some(uri) = change["textDocument"]["uri"].as_str() {
+                files.push((uri, &change["edits"]));
+            }
+        }
+        let mut current_edits = Vec::new();
+        let mut other_edits = Vec::new();
+        let mut writes = Vec::new();
+        for (uri, edits) in &files {
+            let path = uri_to_path(uri).ok_or_else(|| format!("Not a file: {}", uri))?;
+            let edits = text_edits(edits);
+            let current = self.buffer.filename.as_deref();
+            if current.is_some_and(|current| same_file(current, &path)) {
+                current_edits.extend(edits);
+                continue;
+            }
+            if self.windows.file_buffer(&path).is_some() {
+                other_edits.push((path, edits));
+                continue;
+            }
+            let (mut buffer, info) = Buffer::from_file(&path, &self.options.fileencodings, None)
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            if buffer.disk.is_none() {
+                return Err(format!("Cannot read {}: file not found", path));
+            }
+            apply_text_edits(&mut buffer.rope, edits);
+            let bytes = buffer
+                .to_bytes(info.fileformat, &info.encoding, info.bom)
+                .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+            writes.push((path, buffer.rope.to_string(), bytes));
+        }
+        if !current_edits.is_empty() {
+            self.buffer.seal_undo_step();
+            self.apply_buffer_edits(current_edits);
+            self.buffer.seal_undo_step();
+        }
+        for (path, edits) in other_edits {
+            let Some(buffer) = self.windows.file_buffer(&path) else {
+                continue;
+            };
+            buffer.seal_undo_step();
+            for (start, end, text) in edits {
+                let start = start.char_index(&buffer.rope);
+                let end = end.char_index(&buffer.rope).max(start);
+                buffer.replace_range(start, end, &text);
+            }
+            buffer.seal_undo_step();
+        }
+        for (path, text, bytes) in writes {
+            save::write_file(&path, &bytes, &self.options)
+                .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+            self.lsp_file_changed(&path, text);
+        }
+        Ok(files.len())
+    }
+
+    /// Tells the server of a file that was changed on disk what it now holds: it is
+    /// opened with its new text and closed again, so the server reads it from disk
+    /// from then on.
+    fn lsp_file_changed(&mut self, path: &str, text: String) {
+        let Some(filetype) = filetype(path) else {
+            return;
+        };
+        let Some(command) = self.options.lspserver(filetype) else {
+            return;
+        };
+        let Some(Some(client)) = self.lsp.clients.get_mut(command) else {
+            return;
+        };
+        let uri = path_to_uri(path);
+        client.notify(
+            "textDocument/didOpen",
+            json!({
+                "textDocument": {
+                    "uri": uri,
+                    "languageId": filetype,
+                    "version": 0,
+                    "text": text,
+                },
+            }),
+        );
+        client.notify(
+            "textDocument/didClose",
+            json!({ "textDocument": { "uri": uri } }),
+        );
+    }
+
+    /// Ctrl-x Ctrl-o: asks the server for completions of the keyword before the cursor.
+    /// They are offered when the answer comes, if the cursor is still in that text.
+    pub(crate) fn complete_omni(&mut self) {
+        let chars = self.buffer.line_chars(self.cy);
+        let cx = self.cx.min(chars.len());
+        let start = chars[..cx]
+            .iter()
+            .rposition(|&c| !is_keyword(c))
+            .map_or(0, |i| i + 1);
+        self.status_msg = "-- Omni completion (^O^N^P) Searching...".to_string();
+        self.request_at_cursor(
+            Request::Completion {
+                row: self.cy,
+                start,
+            },
+            json!({}),
+        );
+    }
+
+    /// Starts completion with the items a server found, unless completion has started
+    /// meanwhile or the cursor has left the text they complete.
+    fn show_lsp_completion(&mut self, server: &str, row: usize, start: usize, result: &Value) {
+        let Mode::Insert(state) = &self.mode else {
+            return;
+        };
+        if state.completion.is_some() || self.cy != row || self.cx < start {
+            return;
+        }
+        let chars = self.buffer.line_chars(row);
+        let typed: String = chars
+            .get(start..self.cx)
+            .unwrap_or_default()
+            .iter()
+            .collect();
+        let candidates = completion_candidates(server, result, &typed);
+        self.start_completion(start, candidates, Kind::Omni, false);
+    }
+
+    /// Finishes a server's completion item once its match is kept, with its text from
+    /// char `start` to the cursor: a snippet goes in whole with the cursor at its first
+    /// tabstop, and the item's extra edits, such as an import, are made, asking the
+    /// server for them if need be.
+    pub(crate) fn finish_completion_item(&mut self, start: usize, item: CompletionItem) {
+        let (text, stop) = item_text(&item.item);
+        if text.contains('\n') || stop.is_some() {
+            let line_start = self.buffer.rope.line_to_char(self.cy);
+            let start = line_start + start;
+            self.buffer
+                .replace_range(start, line_start + self.cx, &text);
+            let cursor = start + stop.unwrap_or(text.chars().count());
+            self.cy = self.buffer.rope.char_to_line(cursor);
+            self.cx = cursor - self.buffer.rope.line_to_char(self.cy);
+        }
+        if item.item.get("additionalTextEdits").is_some() {
+            self.apply_buffer_edits(text_edits(&item.item["additionalTextEdits"]));
+        } else if let Some(Some(client)) = self.lsp.clients.get_mut(&item.server)
+            && client.resolves("completionProvider")
+        {
+            client.request(Request::ResolveCompletion, item.item);
+        }
+    }
+
+    /// Asks for the signature of the call being typed after a character the server
+    /// gave for it, such as `(`, or `,` while it is shown; `)` closes it.
+    pub(crate) fn lsp_char_typed(&mut self, c: char) {
+        if c == ')' {
+            self.lsp.signature = None;
+            return;
+        }
+        let Some(document) = &self.lsp.document else {
+            return;
+        };
+        let Some(Some(client)) = self.lsp.clients.get(&document.server) else {
+            return;
+        };
+        let provider = &client.capabilities["signatureHelpProvider"];
+        let c = c.to_string();
+        let listed = |key: &str| {
+            provider[key]
+                .as_array()
+                .is_some_and(|chars| chars.iter().any(|t| t.as_str() == Some(c.as_str())))
+        };
+        let triggered = (provider.is_object() && c == "(")
+            || listed("triggerCharacters")
+            || (self.lsp.signature.is_some() && listed("retriggerCharacters"));
+        if triggered {
+            self.request_at_cursor(Request::SignatureHelp, json!({}));
+        }
+    }
+
+    /// `:CodeAction` asks for the code actions at the cursor, for the diagnostics of its
+    /// line among others, to choose one by its number.
+    pub(crate) fn code_action(&mut self) {
+        let diagnostics: Vec<Value> = self
+            .diagnostics_of(&self.buffer)
+            .iter()
+            .filter(|d| d.start.line == self.cy)
+            .map(|d| d.raw.clone())
+            .collect();
+        let position = self.cursor_position().to_json();
+        let Some((client, uri)) = self.lsp_target() else {
+            return;
+        };
+        let params = json!({
+            "textDocument": { "uri": uri },
+            "range": { "start": position, "end": position },
+            "context": { "diagnostics": diagnostics },
+        });
+        client.request(Request::CodeAction, params);
+    }
+
+    /// Carries out the code action chosen by its number.
+    pub(crate) fn answer_code_action(&mut self, server: &str, actions: Vec<Value>, choice: char) {
+        let chosen = choice
+            .to_digit(10)
+            .and_then(|n| (n as usize).checked_sub(1))
+            .and_then(|i| actions.into_iter().nth(i));
+        if let Some(action) = chosen {
+            self.run_code_action(server, action, false);
+        }
+    }
+
+    /// Carries out a code action: makes its edit across files, as one undo step in the
+    /// buffer, and has the server run its command. An action that comes without its
+    /// edit is first resolved by a server that can.
+    fn run_code_action(&mut self, server: &str, action: Value, resolved: bool) {
+        let Some(Some(client)) = self.lsp.clients.get_mut(server) else {
+            return;
+        };
+        // A bare Command rather than a CodeAction
+        if action["command"].is_string() {
+            if let Some(params) = command_params(&action) {
+                client.request(Request::ExecuteCommand, params);
+            }
+            return;
+        }
+        if action.get("edit").is_none() && !resolved && client.resolves("codeActionProvider") {
+            client.request(Request::ResolveCodeAction, action);
+            return;
+        }
+        if let Some(edit) = action.get("edit") {
+            let title = action["title"].as_str().unwrap_or_default();
+            self.status_msg = match self.apply_workspace_edit(edit) {
+                Ok(_) => title.to_string(),
+                Err(reason) => reason,
+            };
+        }
+        if let Some(params) = command_params(&action["command"])
+            && let Some(Some(client)) = self.lsp.clients.get_mut(server)
+        {
+            client.request(Request::ExecuteCommand, params);
+        }
+    }
+
+    /// `:Format` and `gq` for a filetype without a `formatprograms` command: asks the
+    /// server to format the lines of `range`, or the whole buffer. The edits are made
+    /// when the answer comes.
+    pub(crate) fn lsp_format(&mut self, range: Option<range::Range>) {
+        self.request_formatting(range);
+    }
+
+    /// Has the server format the whole buffer and waits for the answer, as a write
+    /// does, for no longer than `timeout`. Messages that come meanwhile are handled as
+    /// usual. Returns whether the buffer was formatted, or needs no server to be.
+    pub(crate) fn lsp_format_now(&mut self, timeout: Duration) -> bool {
+        let filetype = self.buffer.filename.as_deref().and_then(filetype);
+        if filetype.and_then(|f| self.options.lspserver(f)).is_none() {
+            return true;
+        }
+        let Some((server, id)) = self.request_formatting(None) else {
+            return false;
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let Some(Some(client)) = self.lsp.clients.get_mut(&server) else {
+                return false;
+            };
+            if !client.pending.contains_key(&id) {
+                return true;
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match client.receiver.recv_timeout(left) {
+                Ok(message) => self.handle_message(&server, message),
+                Err(RecvTimeoutError::Timeout) => {
+                    // An answer that comes later is dropped
+                    client.pending.remove(&id);
+                    self.status_msg = format!("{} did not format in time", client.name);
+                    return false;
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.status_msg = format!("Language server {} exited", client.name);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Sends a formatting request for the lines of `range`, or the whole buffer, if the
+    /// server can do that. Returns the server and the request's id.
+    fn request_formatting(&mut self, range: Option<range::Range>) -> Option<(String, i64)> {
+        let options = json!({
+            "tabSize": self.options.shiftwidth(),
+            "insertSpaces": self.options.expandtab,
+        });
+        let range = range.map(|range| {
+            let (start, end) = self.range_chars(range);
+            let rope = &self.buffer.rope;
+            json!({
+                "start": Position::of(rope, start).to_json(),
+                "end": Position::of(rope, end).to_json(),
+            })
+        });
+        // The server has to have the latest edits to format them
+        self.lsp_target()?;
+        let document = self.lsp.document.as_ref()?;
+        let (server, uri, version) = (
+            document.server.clone(),
+            document.uri.clone(),
+            document.version,
+        );
+        let client = self.lsp.clients.get_mut(&server)?.as_mut()?;
+        let provider = match range {
+            Some(_) => "documentRangeFormattingProvider",
+            None => "documentFormattingProvider",
+        };
+        // Until it has initialized, the server is given the benefit of the doubt
+        let capable = &client.capabilities[provider];
+        if client.queued.is_none() && !matches!(capable, Value::Bool(true) | Value::Object(_)) {
+            self.status_msg = match range {
+                Some(_) => format!("{} cannot format a range of lines", client.name),
+                None => format!("{} cannot format", client.name),
+            };
+            return None;
+        }
+        let mut params = json!({ "textDocument": { "uri": uri }, "options": options });
+        let ranged = range.is_some();
+        if let Some(range) = range {
+            params["range"] = range;
+        }
+        let id = client.request(
+            Request::Formatting {
+                uri,
+                version,
+                ranged,
+            },
+            params,
+        );
+        Some((server, id))
+    }
+
+    /// Makes text edits in the buffer, given last in the file first, keeping the cursor
+    /// on the text it was on.
+    fn apply_buffer_edits(&mut self, edits: Vec<(Position, Position, String)>) {
+        let rope = &self.buffer.rope;
+        let mut cursor = (rope.line_to_char(self.cy) + self.cx).min(rope.len_chars());
+        for (start, end, text) in edits {
+            let start = start.char_index(&self.buffer.rope);
+            let end = end.char_index(&self.buffer.rope).max(start);
+            self.buffer.replace_range(start, end, &text);
+            if end <= cursor {
+                cursor = cursor - (end - start) + text.chars().count();
+            } else if start < cursor {
+                cursor = start;
+            }
+        }
+        self.cy = self.buffer.rope.char_to_line(cursor);
+        self.cx = cursor - self.buffer.rope.line_to_char(self.cy);
+        self.clamp_cursor_to_line();
+    }
+
+    /// The diagnostics of the file in a buffer, if it is the one the server follows.
+    pub(crate) fn diagnostics_of(&self, buffer: &Buffer) -> &[Diagnostic] {
+        match &self.lsp.document {
+            Some(document) if buffer.filename.as_deref() == Some(document.filename.as_str()) => {
+                self.lsp
+                    .diagnostics
+                    .get(&document.uri)
+                    .map_or(&[], Vec::as_slice)
+            }
+            _ => &[],
+        }
+    }
+
+    /// Draws the windows floating by the cursor: the hover text, the signature of the
+    /// call being typed and the code actions to choose from.
+    pub(crate) fn draw_lsp_windows(&self, frame: &mut Frame) {
+        if let Some(lines) = &self.lsp.hover {
+            self.draw_float(frame, lines, None);
+        }
+        if let (Some((label, active)), Mode::Insert(_)) = (&self.lsp.signature, &self.mode) {
+            self.draw_float(frame, std::slice::from_ref(label), active.clone());
+        }
+        if let Mode::Confirm(ConfirmState {
+            action: ConfirmAction::CodeAction(_, actions),
+            ..
+        }) = &self.mode
+        {
+            let lines: Vec<String> = actions
+                .iter()
+                .take(9)
+                .enumerate()
+                .map(|(i, action)| format!("{}. {}", i + 1, action["title"].as_str().unwrap_or("")))
+                .collect();
+            self.draw_float(frame, &lines, None);
+        }
+    }
+
+    /// Draws lines in a box next to the cursor: above it if there is room, else below.
+    /// `highlight` is a byte range of the first line to set apart.
+    fn draw_float(&self, frame: &mut Frame, lines: &[String], highlight: Option<Range<usize>>) {
+        let (cursor_x, cursor_y) = self.cursor_screen_pos();
+        let height = lines.len().min(HOVER_HEIGHT);
+        let width = lines
+            .iter()
+            .map(|line| display::str_width(line))
+            .max()
+            .unwrap_or(0)
+            .min(self.screen_cols.saturating_sub(2))
+            + 2;
+        let top = if cursor_y >= height {
+            cursor_y - height
+        } else if cursor_y + 1 + height <= self.screen_rows {
+            cursor_y + 1
+        } else {
+            0
+        };
+        let left = cursor_x.min(self.screen_cols.saturating_sub(width));
+        let style = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::White);
+        for (row, line) in lines.iter().take(height).enumerate() {
+            let y = top + row;
+            if y >= self.screen_rows {
+                break;
+            }
+            frame.fill(left, y, width, style);
+            let line = display::truncate(line, width - 2);
+            match highlight
+                .clone()
+                .filter(|range| row == 0 && range.end <= line.len())
+            {
+                Some(range) => {
+                    let x = frame.put_str(left + 1, y, &line[..range.start], style);
+                    let active = style.fg(Color::Yellow).attr(Attribute::Bold);
+                    let x = frame.put_str(x, y, &line[range.clone()], active);
+                    frame.put_str(x, y, &line[range.end..], style);
+                }
+                None => {
+                    frame.put_str(left + 1, y, line, style);
+                }
+            }
+        }
+    }
+
+    /// Fills the quickfix list with places a server found and opens its window.
+    fn show_quickfix_list(&mut self, entries: Vec<Entry>, title: String) {
+        if entries.is_empty() {
+            self.status_msg = format!("{}: none", title);
+            return;
+        }
+        self.set_quickfix_list(ListKind::Quickfix, entries, title, false);
+        self.open_quickfix_window(ListKind::Quickfix, DEFAULT_HEIGHT);
+    }
+}
+
+impl Pane<'_> {
+    /// The most severe diagnostic on a line of the buffer.
+    fn line_diagnostic(&self, line: usize) -> Option<&Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.start.line == line)
+            .min_by_key(|d| d.severity)
+    }
+
+    /// Columns of the sign column, shown while the file has diagnostics.
+    pub fn sign_width(&self) -> usize {
+        if self.diagnostics.is_empty() { 0 } else { 2 }
+    }
+
+    /// Draws the sign of a line's diagnostics at the left edge of row `y`.
+    pub fn draw_sign(&self, frame: &mut Frame, line: usize, y: usize) {
+        if let Some(diagnostic) = self.line_diagnostic(line) {
+            let (sign, style) = diagnostic.sign();
+            frame.put_str(0, y, sign, style);
+        }
+    }
+
+    /// Draws the message of a line's diagnostics after its text, which ends at
+    /// column `x` of row `y`.
+    pub fn draw_inline_diagnostic(&self, frame: &mut Frame, line: usize, y: usize, x: usize) {
+        if let Some(diagnostic) = self.line_diagnostic(line) {
+            let (_, style) = diagnostic.sign();
+            let message = diagnostic.message.lines().next().unwrap_or_default();
+            frame.put_str(x + 2, y, message, style);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "fn helper() {}\nfn main() {\n    helper();\n}\n";
+
+    /// Polls the server until `done` holds, failing after five seconds.
+    fn wait_for(editor: &mut Editor, what: &str, done: impl Fn(&Editor) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(editor) {
+            assert!(Instant::now() < deadline, "no {} from the server", what);
+            editor.poll_lsp();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The rows of the text and the quickfix window as drawn.
+    fn screen(editor: &Editor) -> Vec<String> {
+        let mut frame = Frame::new(editor.terminal_cols, editor.terminal_rows);
+        editor.draw_windows(&mut frame);
+        editor.draw_quickfix(&mut frame);
+        (0..frame.height)
+            .map(|y| {
+                (0..frame.width)
+                    .map(|x| frame.cell(x, y).symbol.as_str())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn edits_at_one_place_keep_their_order() {
+        let edit = |line: usize, start: usize, end: usize, text: &str| {
+            json!({
+                "range": {
+                    "start": {"line": line, "character": start},
+                    "end": {"line": line, "character": end},
+                },
+                "newText": text,
+            })
+        };
+        // Two inserts at one place, among edits before and after it
+        let edits = json!([
+            edit(0, 3, 3, "a"),
+            edit(1, 0, 2, "Z"),
+            edit(0, 3, 3, "b"),
+            edit(0, 0, 1, "X"),
+        ]);
+        let mut rope = Rope::from_str("one\ntwo\n");
+        apply_text_edits(&mut rope, text_edits(&edits));
+        assert_eq!(rope.to_string(), "Xneab\nZo\n");
+    }
+
+    #[test]
+    fn workspace_edits_keep_other_files_as_stored() {
+        let dir = env::temp_dir().join(format!("rim-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let latin1 = dir.join("latin1.txt");
+        fs::write(&latin1, b"caf\xe9\r\nhelper\r\n").unwrap();
+        let missing = dir.join("missing.txt");
+        let uri = |path: &Path| path_to_uri(path.to_str().unwrap());
+        let edits = json!([{
+            "range": {
+                "start": {"line": 1, "character": 0},
+                "end": {"line": 1, "character": 6},
+            },
+            "newText": "assist",
+        }]);
+        let mut editor = Editor::with_size(80, 24);
+
+        // A file that can't be read leaves the others alone
+        let edit = json!({ "changes": { uri(&latin1): edits, uri(&missing): edits } });
+        assert!(editor.apply_workspace_edit(&edit).is_err());
+        assert_eq!(fs::read(&latin1).unwrap(), b"caf\xe9\r\nhelper\r\n");
+
+        // The file keeps its encoding and line endings
+        let edit = json!({ "changes": { uri(&latin1): edits } });
+        assert_eq!(editor.apply_workspace_edit(&edit), Ok(1));
+        assert_eq!(fs::read(&latin1).unwrap(), b"caf\xe9\r\nassist\r\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Runs the client against `tests/mock_lsp.sh`, a server with canned answers that
+    /// logs the messages it gets.
+    #[test]
+    fn scripted_server() {
+        let dir = env::temp_dir().join(format!("rim-lsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.rs");
+        fs::write(&file, TEXT).unwrap();
+        let log = dir.join("server.log");
+        let script = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/mock_lsp.sh");
+        let mut editor = Editor::with_size(80, 24);
+        editor.options.updatecount = 0;
+        editor.options.lspservers = format!("rust:sh {} {}", script.display(), log.display());
+        editor
+            .open_file(file.to_str().unwrap(), None, false)
+            .unwrap();
+
+        // The file is opened once the server has initialized, and its diagnostics
+        // come back as a sign, after the line and in the list
+        wait_for(&mut editor, "diagnostics", |e| {
+            e.pane().line_diagnostic(2).is_some()
+        });
+        let rows = screen(&editor);
+        assert!(rows[2].starts_with("E>"), "{:?}", rows[2]);
+        assert!(rows[2].contains("helper();  mock error"), "{:?}", rows[2]);
+        editor.list_diagnostics();
+        assert_eq!(editor.status_msg, "1 entry in the quickfix list");
+        let listed = screen(&editor)
+            .iter()
+            .skip(4)
+            .filter(|row| row.contains("main.rs|3 col 5 error| mock error"))
+            .count();
+        assert_eq!(listed, 1);
+        let sent = fs::read_to_string(&log).unwrap();
+        assert!(
+            sent.lines()
+                .next()
+                .unwrap()
+                .contains(r#""method":"initialize""#)
+        );
+        assert!(sent.contains(r#""method":"initialized""#));
+        assert!(sent.contains(r#""method":"textDocument/didOpen""#));
+
+        // Edits go out as just the changed range
+        let end = editor.buffer.rope.len_chars();
+        editor.buffer.replace_range(end, end, "// end\n");
+        wait_for(&mut editor, "new diagnostics", |e| {
+            e.pane().line_diagnostic(1).is_some_and(|d| d.severity == 2)
+        });
+        let sent = fs::read_to_string(&log).unwrap();
+        let change = sent
+            .lines()
+            .find(|line| line.contains("didChange"))
+            .unwrap();
+        let change: Value = serde_json::from_str(change).unwrap();
+        assert_eq!(
+            change["params"]["contentChanges"],
+            json!([{
+                "range": {
+                    "start": { "line": 4, "character": 0 },
+                    "end": { "line": 4, "character": 0 },
+                },
+                "text": "// end\n",
+            }])
+        );
+        assert_eq!(change["params"]["textDocument"]["version"], 1);
+
+        // `gd` jumps to the definition
+        (editor.cy, editor.cx) = (2, 6);
+        editor.goto_definition();
+        wait_for(&mut editor, "definition", |e| (e.cy, e.cx) == (0, 3));
+
+        // `gr` lists the references
+        editor.find_references();
+        wait_for(&mut editor, "references", |e| {
+            e.status_msg == "2 entries in the quickfix list"
+        });
+        let rows = screen(&editor);
+        assert!(rows.iter().any(|row| row.contains("References to helper")));
+        assert!(rows.iter().any(|row| row.contains("main.rs|1 col 4|")));
+        assert!(rows.iter().any(|row| row.contains("main.rs|3 col 5|")));
+
+        // A rename is made in the buffer, as one undo step
+        editor.lsp_rename("assist");
+        wait_for(&mut editor, "rename", |e| {
+            e.status_msg == "Renamed in 1 file"
+        });
+        let renamed = "fn assist() {}\nfn main() {\n    assist();\n}\n// end\n";
+        assert_eq!(editor.buffer.rope.to_string(), renamed);
+        editor.buffer.undo();
+        assert_eq!(
+            editor.buffer.rope.to_string(),
+            "fn helper() {}\nfn main() {\n    helper();\n}\n// end\n"
+        );
+
+        // Dropping the editor shuts the server down
+        drop(editor);
+        let sent = fs::read_to_string(&log).unwrap();
+        assert!(sent.contains(r#""method":"shutdown""#));
+        assert!(sent.contains(r#""method":"exit""#));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,60 +1,178 @@
+mod browser;
+mod checktime;
+mod cmdline;
+mod cmdwin;
+mod complete;
+mod display;
+mod encoding;
+mod errorformat;
+mod fileformat;
+mod format;
+mod fuzzy;
+mod grep;
+mod indent;
+mod largefile;
+mod lsp;
+mod mouse;
+mod options;
+mod picker;
+mod quickfix;
+mod range;
+mod render;
+mod save;
+mod search;
+mod shell;
+mod substitute;
+mod swap;
+mod window;
+mod wrap;
+
 use crossterm::{
     cursor::{self, SetCursorStyle},
     event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
-    execute, queue, style,
+    execute, style,
     terminal::{self, ClearType},
 };
+use display::Grapheme;
+use options::Options;
+use render::{Frame, Renderer, Style};
 use ropey::Rope;
 use std::env;
 use std::fs;
-use std::io::{Result, Write, stdout};
-use std::time::Duration;
+use std::io::{Read, Result, stdout};
+use std::time::{Duration, Instant};
 
+/// The text as it was before a change, for undo and redo.
+struct Revision {
+    rope: Rope,
+    /// Where the change started, as (line, col); the cursor goes back there.
+    cursor: (usize, usize),
+}
 struct Buffer {
     rope: Rope,
     filename: Option<String>,
     dirty: bool,
+    /// The text as last loaded or saved, to tell whether undo made the buffer clean again.
+    saved: Rope,
+    undo_stack: Vec<Revision>,
+    redo_stack: Vec<Revision>,
+    /// Whether edits still join the undo step started by the previous one.
+    /// Sealed once a command or an Insert session is complete.
+    step_open: bool,
+    /// Counts every change to the text, so the swap file knows when it is out of date.
+    edits: u64,
+    /// Refuse to write the file (opened read-only from the swap file prompt).
+    readonly: bool,
+    /// Whether the text may be changed at all; a directory listing may not, as its
+    /// lines stand for the entries the browser acts on.
+    modifiable: bool,
+    /// An edit was refused since this was last cleared, to tell the user.
+    refused: bool,
+    /// Opened in large-file mode: no undo history is kept.
+    large: bool,
+    /// The file as last read or written, to notice when another program changes it.
+    disk: Option<checktime::DiskState>,
+    /// Edits not yet sent to the language server, while one follows the buffer.
+    lsp_changes: Option<Vec<lsp::Change>>,
 }
 
 impl Buffer {
     /// Creates a new, empty buffer.
     fn new() -> Self {
+        Self::with_text(Rope::new(), None)
+    }
+    /// Creates a buffer holding the given text, with no undo history.
+    fn with_text(rope: Rope, filename: Option<String>) -> Self {
         Self {
-            rope: Rope::new(),
-            filename: None,
+            saved: rope.clone(),
+            rope,
+            filename,
             dirty: false,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            step_open: false,
+            edits: 0,
+            readonly: false,
+            modifiable: true,
+            refused: false,
+            large: false,
+            disk: None,
+            lsp_changes: None,
         }
     }
-    /// Creates a buffer by loading a file.
-    fn from_file(filename: &str) -> Result<Self> {
+    /// Creates a buffer by loading a file, decoded with `encoding` or else the first of
+    /// `fileencodings` that fits. Lines are kept with a bare `\n`; how the file was
+    /// stored is returned alongside.
+    fn from_file(
+        filename: &str,
+        fileencodings: &str,
+        encoding: Option<&str>,
+    ) -> Result<(Self, FileInfo)> {
         match fs::File::open(filename) {
-            Ok(file) => {
-                let rope = Rope::from_reader(std::io::BufReader::new(file))?;
-                Ok(Self {
-                    rope,
-                    filename: Some(filename.to_string()),
-                    dirty: false,
-                })
+            Ok(mut file) => {
+                let disk = checktime::DiskState::from_metadata(&file.metadata()?);
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                let decoded = encoding::decode(&bytes, fileencodings, encoding);
+                let fileformat = fileformat::detect(&decoded.text);
+                let rope = Rope::from_str(&fileformat::to_lf(&decoded.text, fileformat));
+                let info = FileInfo {
+                    fileformat,
+                    encoding: decoded.encoding,
+                    bom: decoded.bom,
+                    lossy: decoded.lossy,
+                };
+                let mut buffer = Self::with_text(rope, Some(filename.to_string()));
+                buffer.disk = Some(disk);
+                Ok((buffer, info))
             }
             Err(e) => {
                 // If file doesn't exist, create an empty buffer with that name
                 if e.kind() == std::io::ErrorKind::NotFound {
-                    Ok(Self {
-                        rope: Rope::new(),
-                        filename: Some(filename.to_string()),
-                        dirty: false,
-                    })
+                    let info = FileInfo {
+                        fileformat: "unix",
+                        encoding: "utf-8".to_string(),
+                        bom: false,
+                        lossy: false,
+                    };
+                    Ok((
+                        Self::with_text(Rope::new(), Some(filename.to_string())),
+                        info,
+                    ))
                 } else {
                     Err(e)
                 }
             }
         }
     }
-    /// Saves the buffer to its filename.
-    fn save(&mut self) -> Result<bool> {
+    /// Converts the text to the bytes stored on disk, with the line ending of
+    /// `fileformat`, in `encoding`, and with a BOM if `bom` is set.
+    fn to_bytes(&self, fileformat: &str, encoding: &str, bom: bool) -> Result<Vec<u8>> {
+        let ending = fileformat::ending(fileformat);
+        let mut bytes = Vec::with_capacity(self.rope.len_bytes());
+        for (i, line) in self.rope.lines().enumerate() {
+            let mut text = line.to_string();
+            if ending != "\n" && text.ends_with('\n') {
+                text.pop();
+                text.push_str(ending);
+            }
+            let encoded = encoding::encode(&text, encoding, bom && i == 0).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("conversion error in line {}: {}", i + 1, e),
+                )
+            })?;
+            bytes.extend(encoded);
+        }
+        Ok(bytes)
+    }
+    /// Saves the buffer to its filename in the given encoding. Returns false if it has none.
+    fn save(&mut self, options: &Options, encoding: &str) -> Result<bool> {
         if let Some(filename) = &self.filename {
-            let file = fs::File::create(filename)?;
-            self.rope.write_to(std::io::BufWriter::new(file))?;
+            let bytes = self.to_bytes(&options.fileformat, encoding, options.bomb)?;
+            save::write_file(filename, &bytes, options)?;
+            self.disk = checktime::DiskState::of(filename);
+            self.saved = self.rope.clone();
             self.dirty = false;
             Ok(true)
         } else {
@@ -65,30 +183,126 @@ impl Buffer {
     fn len_lines(&self) -> usize {
         self.rope.len_lines()
     }
-    /// Returns a slice of a specific line.
-    fn line(&self, index: usize) -> ropey::RopeSlice<'_> {
-        self.rope.line(index)
+    /// Returns the chars of a line, without its line terminator.
+    fn line_chars(&self, index: usize) -> Vec<char> {
+        let mut chars: Vec<char> = self.rope.line(index).chars().collect();
+        if chars.last() == Some(&'\n') {
+            chars.pop();
+        }
+        chars
+    }
+    /// Returns the number of chars in a line, without its line terminator.
+    fn line_len(&self, index: usize) -> usize {
+        if index >= self.len_lines() {
+            return 0;
+        }
+        let line = self.rope.line(index);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len - 1
+        } else {
+            len
+        }
+    }
+    /// Records the text before an edit at (line, col), unless the edit joins
+    /// the undo step that is already open or the buffer keeps no undo history.
+    /// Returns false, and makes no record, if the buffer may not be changed.
+    fn begin_change(&mut self, line: usize, col: usize) -> bool {
+        if !self.modifiable {
+            self.refused = true;
+            return false;
+        }
+        if !self.step_open && !self.large {
+            self.undo_stack.push(Revision {
+                rope: self.rope.clone(),
+                cursor: (line, col),
+            });
+            self.redo_stack.clear();
+            self.step_open = true;
+        }
+        self.dirty = true;
+        self.edits += 1;
+        true
+    }
+    /// Closes the open undo step, so the next edit starts a new one.
+    fn seal_undo_step(&mut self) {
+        self.step_open = false;
+    }
+    /// Reverts the last undo step. Returns where the cursor goes, or None at the oldest change.
+    fn undo(&mut self) -> Option<(usize, usize)> {
+        self.seal_undo_step();
+        let revision = self.undo_stack.pop()?;
+        let cursor = revision.cursor;
+        let current = std::mem::replace(&mut self.rope, revision.rope);
+        self.redo_stack.push(Revision {
+            rope: current,
+            cursor,
+        });
+        self.record_whole_text();
+        self.dirty = self.rope != self.saved;
+        self.edits += 1;
+        Some(cursor)
+    }
+    /// Reapplies the last undone step. Returns where the cursor goes, or None at the newest change.
+    fn redo(&mut self) -> Option<(usize, usize)> {
+        self.seal_undo_step();
+        let revision = self.redo_stack.pop()?;
+        let cursor = revision.cursor;
+        let current = std::mem::replace(&mut self.rope, revision.rope);
+        self.undo_stack.push(Revision {
+            rope: current,
+            cursor,
+        });
+        self.record_whole_text();
+        self.dirty = self.rope != self.saved;
+        self.edits += 1;
+        Some(cursor)
+    }
+    /// Replaces the whole text, as one undo step.
+    fn replace_text(&mut self, rope: Rope) {
+        self.seal_undo_step();
+        if !self.begin_change(0, 0) {
+            return;
+        }
+        self.rope = rope;
+        self.record_whole_text();
+        self.seal_undo_step();
+        self.dirty = self.rope != self.saved;
     }
     /// Inserts a character at (line, col).
     fn insert_char(&mut self, line: usize, col: usize, c: char) {
-        let line_char_idx = self.rope.line_to_char(line);
-        self.rope.insert_char(line_char_idx + col, c);
-        self.dirty = true;
+        if !self.begin_change(line, col) {
+            return;
+        }
+        let char_idx = self.rope.line_to_char(line) + col;
+        self.record_change(char_idx, char_idx, c.encode_utf8(&mut [0; 4]));
+        self.rope.insert_char(char_idx, c);
+    }
+    /// Inserts a string at (line, col).
+    fn insert_str(&mut self, line: usize, col: usize, text: &str) {
+        if !text.is_empty() && self.begin_change(line, col) {
+            let char_idx = self.rope.line_to_char(line) + col;
+            self.record_change(char_idx, char_idx, text);
+            self.rope.insert(char_idx, text);
+        }
     }
-    /// Deletes a character at (line, col) [for Backspace].
-    fn delete_char(&mut self, line: usize, col: usize) {
-        if col > 0 {
+    /// Deletes the characters `start..end` of a line [for Backspace].
+    fn delete_range(&mut self, line: usize, start: usize, end: usize) {
+        if start < end && self.begin_change(line, start) {
             let line_char_idx = self.rope.line_to_char(line);
+            self.record_change(line_char_idx + start, line_char_idx + end, "");
             self.rope
-                .remove((line_char_idx + col - 1)..(line_char_idx + col));
-            self.dirty = true;
+                .remove((line_char_idx + start)..(line_char_idx + end));
         }
     }
     /// Inserts a newline at (line, col).
     fn insert_new_line(&mut self, line: usize, col: usize) {
+        if !self.begin_change(line, col) {
+            return;
+        }
         let char_idx = self.rope.line_to_char(line) + col;
+        self.record_change(char_idx, char_idx, "\n");
         self.rope.insert(char_idx, "\n"); // ‼️ Just insert newline text
-        self.dirty = true;
     }
     /// Joins the given line with the previous one [for Backspace at col 0].
     /// Returns the new `cx` (length of the previous line).
@@ -97,24 +311,107 @@ impl Buffer {
             return 0;
         }
         // Get length of previous line *before* joining
-        let prev_line_len = self.rope.line(line - 1).len_chars();
+        let prev_line_len = self.line_len(line - 1);
+        if !self.begin_change(line - 1, prev_line_len) {
+            return prev_line_len;
+        }
         // Find the char index of the newline to remove
         let prev_line_end_char = self.rope.line_to_char(line);
+        self.record_change(prev_line_end_char - 1, prev_line_end_char, "");
         self.rope
             .remove((prev_line_end_char - 1)..prev_line_end_char);
-        self.dirty = true;
         prev_line_len
     }
+    /// Replaces the chars `start..end` of the text, for edits from a language server.
+    fn replace_range(&mut self, start: usize, end: usize, text: &str) {
+        let line = self.rope.char_to_line(start);
+        if !self.begin_change(line, start - self.rope.line_to_char(line)) {
+            return;
+        }
+        self.record_change(start, end, text);
+        self.rope.remove(start..end);
+        self.rope.insert(start, text);
+    }
+    /// Notes an edit replacing the chars `start..end` with `text`, made next, for the
+    /// language server following the buffer.
+    fn record_change(&mut self, start: usize, end: usize, text: &str) {
+        if let Some(changes) = &mut self.lsp_changes {
+            changes.push(lsp::Change::new(&self.rope, start, end, text));
+        }
+    }
+    /// Notes that the whole text was replaced, as undo and redo do.
+    fn record_whole_text(&mut self) {
+        if let Some(changes) = &mut self.lsp_changes {
+            changes.push(lsp::Change::whole(&self.rope));
+        }
+    }
+}
+/// How a file is stored on disk, as found when it was read.
+struct FileInfo {
+    fileformat: &'static str,
+    encoding: String,
+    /// Whether it starts with a byte order mark.
+    bom: bool,
+    /// Whether some bytes were invalid in `encoding` (kept and shown as `<xx>`).
+    lossy: bool,
+}
+#[derive(Default)]
+struct NormalState {
+    /// The first key of a two-key command (e.g. the `g` of `gj`, or `\u{17}` for Ctrl-w),
+    /// or `Q` for a `gq` waiting for its motion.
+    pending: Option<char>,
+}
+#[derive(Default)]
+struct InsertState {
+    /// The line whose indent was added by autoindent and nothing typed after it yet.
+    /// Vim removes such an indent again when the line is left blank.
+    auto_indented: Option<usize>,
+    /// Completion in progress, started with Ctrl-n, Ctrl-p or Ctrl-x.
+    completion: Option<complete::Completion>,
+    /// Ctrl-x was typed; the next key says what to complete.
+    ctrl_x: bool,
 }
-struct NormalState {}
-struct InsertState {}
 struct VisualState {
     // Needs to store the origin point of the selection
     selection_start: (usize, usize),
+    /// `g` was typed; `gq` formats the selected lines.
+    g_pending: bool,
 }
 struct CommandState {
     // Needs to store the text buffer for the command line
     command_buffer: String,
+    /// Byte index of the cursor in `command_buffer`, never before its first character.
+    cursor: usize,
+    /// Where Up and Down have got to in the history, once used.
+    history: Option<cmdline::Browse>,
+    /// Tab completion in progress.
+    wildmenu: Option<cmdline::Wildmenu>,
+    /// Ctrl-r was typed; the next key says what to insert.
+    ctrl_r: bool,
+}
+/// What a Confirm prompt was asked for, and the data needed to carry out the answer.
+enum ConfirmAction {
+    /// An existing swap file, and what to go back to if the user quits instead.
+    SwapExists(swap::Found, Option<Box<swap::Previous>>),
+    /// A command that needs the whole of a large file in memory once more.
+    WholeFile(String),
+    /// Another program changed the file being edited.
+    FileChanged,
+    /// A write that would overwrite such a change.
+    WriteChanged(String),
+    /// Files and directories to delete from a directory listing.
+    Delete(Vec<std::path::PathBuf>),
+    /// Code actions a language server offered, to choose one of by number.
+    CodeAction(String, Vec<serde_json::Value>),
+}
+struct ConfirmState {
+    /// The question, shown in the status bar.
+    prompt: String,
+    /// The keys accepted as answers.
+    choices: String,
+    /// The answer taken when Esc is pressed.
+    cancel: char,
+    action: ConfirmAction,
 }
 // 2. Define the main Mode enum
 enum Mode {
@@ -122,83 +419,265 @@ enum Mode {
     Insert(InsertState),
     Visual(VisualState),
     Command(CommandState),
+    /// Waiting for a one-key answer to a question in the status bar.
+    Confirm(ConfirmState),
 }
 // End new states
 /// Represents the editor's state.
 struct Editor {
     /// The user's horizontal cursor position.
     cx: usize,
-    /// The user's vertical cursor position.
+    /// The user's vertical cursor position (a line of the file).
     cy: usize,
-    /// The number of rows in the terminal.
+    /// The number of text rows in the current window.
     screen_rows: usize,
-    /// The number of columns in the terminal.
+    /// The number of rows in the terminal: the windows, the quickfix window and the
+    /// status bar.
+    terminal_rows: usize,
+    /// The number of columns in the current window.
     screen_cols: usize,
+    /// The number of columns in the terminal.
+    terminal_cols: usize,
     /// The file buffer
     buffer: Buffer,
     /// The row index of the file that is at the top of the screen (for scrolling).
     row_offset: usize,
+    /// The display column at the left edge of the screen (for scrolling without wrap).
+    col_offset: usize,
     /// A message to display in the status bar.
     status_msg: String,
     mode: Mode,
+    /// Settings changed with `:set`.
+    options: Options,
+    /// Sends each new frame to the terminal.
+    renderer: Renderer,
+    /// Click and drag state for mouse support.
+    mouse: mouse::MouseState,
+    /// Where unsaved changes are kept in case the editor dies.
+    swap: Option<swap::SwapFile>,
+    /// The file edited before the current one, for `:e #`.
+    alternate_file: Option<String>,
+    /// Reads the rest of a large file in the background.
+    loader: Option<largefile::Loader>,
+    /// Set while running a whole-file command the user agreed to.
+    whole_file_confirmed: bool,
+    /// The last search, repeated with `n` and `N`.
+    last_search: Option<search::Search>,
+    /// When the file was last checked for changes by other programs.
+    last_check: Instant,
+    /// Errors from `:make` or `:cfile`, matches from `:vimgrep`, and the window that
+    /// lists them.
+    quickfix: quickfix::Quickfix,
+    /// The `:Files` picker, while it is open.
+    picker: Option<picker::Picker>,
+    /// What the buffer lists when a directory was opened.
+    browser: Option<browser::Browser>,
+    /// The command lines and searches entered, for Up and Down on the command line.
+    history: cmdline::History,
+    /// The command-line window, while it is open.
+    cmdwin: Option<cmdwin::CmdWindow>,
+    /// The windows and the buffers they show; the current one's is `buffer`.
+    windows: window::Windows,
+    /// The language servers and their diagnostics.
+    lsp: lsp::Lsp,
+    /// Whether `new` set up the terminal, which is reset on drop.
+    terminal: bool,
 }
 impl Editor {
-    /// Creates a new Editor instance, loading a file from the command line arguments.
-    fn new() -> Result<Self> {
+    /// Creates a new Editor instance, loading a file given on the command line.
+    /// With `recover`, the file's text is taken from its swap file.
+    fn new(filename: Option<String>, recover: bool) -> Result<Self> {
         let (cols, rows) = terminal::size()?;
         terminal::enable_raw_mode()?;
+        execute!(
+            stdout(),
+            event::EnableBracketedPaste,
+            event::EnableFocusChange
+        )?;
+        let mut editor = Self::with_size(cols, rows);
+        editor.terminal = true;
         // ‼️ Load buffer based on args
-        let buffer = if let Some(filename) = env::args().nth(1) {
-            Buffer::from_file(&filename)?
-        } else {
-            Buffer::new()
-        };
+        if let Some(filename) = &filename {
+            editor.open_file(filename, None, recover)?;
+            // There is nothing to go back to yet; quitting at the swap file prompt quits
+            if let Mode::Confirm(ConfirmState {
+                action: ConfirmAction::SwapExists(_, previous),
+                ..
+            }) = &mut editor.mode
+            {
+                *previous = None;
+            }
+        }
+        Ok(editor)
+    }
+    /// Creates an Editor on an empty buffer for a screen of `cols` by `rows`, leaving
+    /// the terminal alone.
+    fn with_size(cols: u16, rows: u16) -> Self {
         let mut editor = Self {
             cx: 0,
             cy: 0,
-            screen_rows: rows as usize - 1,
-            screen_cols: cols as usize,
-            buffer,
+            screen_rows: 0,
+            terminal_rows: 0,
+            screen_cols: 0,
+            terminal_cols: 0,
+            buffer: Buffer::new(),
             row_offset: 0,
+            col_offset: 0,
             status_msg: "HELP: :q = quit".to_string(),
-            mode: Mode::Normal(NormalState {}),
+            mode: Mode::Normal(NormalState::default()),
+            options: Options::default(),
+            renderer: Renderer::new(),
+            mouse: mouse::MouseState::default(),
+            swap: None,
+            alternate_file: None,
+            loader: None,
+            whole_file_confirmed: false,
+            last_search: None,
+            last_check: Instant::now(),
+            quickfix: quickfix::Quickfix::default(),
+            picker: None,
+            browser: None,
+            history: cmdline::History::default(),
+            cmdwin: None,
+            windows: window::Windows::default(),
+            lsp: lsp::Lsp::default(),
+            terminal: false,
         };
-        // Set status message from buffer loading
-        if editor.buffer.filename.is_some() {
-            editor.status_msg = format!(
-                "Loaded file: {}",
-                editor.buffer.filename.as_deref().unwrap()
-            );
-        } else if editor.buffer.rope.len_chars() > 0 {
-            // Loaded from stdin or other source, but no filename
-            editor.status_msg = "[No Name]".to_string();
+        editor.set_screen_size(cols, rows);
+        editor
+    }
+    /// Replaces the buffer with a file read from disk (decoded with `encoding` if given)
+    /// and starts keeping its swap file. Files of `largefile` MB or more are loaded
+    /// in the background instead, and a file another window shows is not read again.
+    fn open_file(&mut self, filename: &str, encoding: Option<&str>, recover: bool) -> Result<()> {
+        // Dropping the loader stops the thread still reading the previous file
+        self.loader = None;
+        // A file another window shows is edited in the same buffer
+        if self.show_file(filename) {
+            self.status_msg = format!("Loaded file: {}", filename);
+            return Ok(());
         }
-        Ok(editor)
+        if fs::metadata(filename).is_ok_and(|m| m.is_dir()) {
+            return self.open_directory(filename);
+        }
+        let size = fs::metadata(filename).map_or(0, |m| m.len());
+        if self.options.largefile > 0 && size >= self.options.largefile as u64 * 1024 * 1024 {
+            return self.open_large_file(filename, size, encoding);
+        }
+        let (buffer, info) = Buffer::from_file(filename, &self.options.fileencodings, encoding)?;
+        let previous = self.replace_buffer(buffer);
+        self.options.fileformat = info.fileformat.to_string();
+        self.options.fileencoding = info.encoding.clone();
+        self.options.bomb = info.bom;
+        self.status_msg = format!("Loaded file: {}", filename);
+        if info.encoding != "utf-8" {
+            self.status_msg.push_str(&format!(" [{}]", info.encoding));
+        }
+        if info.lossy {
+            self.status_msg.push_str(" [invalid bytes shown as <xx>]");
+        }
+        self.open_swap(recover, Some(previous));
+        Ok(())
     }
     /// The main event loop, waiting for input and processing it.
     fn run(&mut self) -> Result<()> {
         self.refresh_screen()?;
         loop {
-            if event::poll(Duration::from_millis(100))? {
-                if let Event::Key(key_event) = event::read()? {
+            let mut typed = false;
+            // Wake up often while a large file, picker results or server answers are coming in
+            let timeout = if self.loader.is_some() || self.picker_waiting() || self.lsp_waiting() {
+                10
+            } else {
+                100
+            };
+            if event::poll(Duration::from_millis(timeout))? {
+                let keep_running = match event::read()? {
                     // ‼️ process_keypress now routes to other functions
-                    if self.process_keypress(key_event)? == false {
-                        return Ok(());
+                    Event::Key(key_event) => {
+                        typed = true;
+                        self.process_keypress(key_event)?
                     }
+                    Event::Paste(text) => {
+                        self.paste(&text);
+                        true
+                    }
+                    Event::Mouse(mouse_event) => {
+                        self.process_mouse_event(mouse_event);
+                        true
+                    }
+                    Event::Resize(cols, rows) => {
+                        self.resize(cols, rows);
+                        true
+                    }
+                    // Coming back to the editor is when a changed file is most likely
+                    Event::FocusGained => {
+                        self.check_time(false);
+                        true
+                    }
+                    _ => true,
+                };
+                if !keep_running {
+                    return Ok(());
+                }
+                if std::mem::take(&mut self.buffer.refused) {
+                    self.status_msg = if self.loader.is_some() {
+                        "Cannot make changes until the file is loaded".to_string()
+                    } else {
+                        "Cannot make changes to a directory listing".to_string()
+                    };
                 }
             }
+            self.poll_loader();
+            self.poll_picker();
+            self.poll_lsp();
+            self.poll_check_time();
+            self.update_swap(typed);
             self.refresh_screen()?;
         }
     }
+    /// Recomputes the layout for a new terminal size and redraws everything.
+    fn resize(&mut self, cols: u16, rows: u16) {
+        self.set_screen_size(cols, rows);
+        self.renderer.invalidate();
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+    /// Sets the text area from the terminal size; the last row holds the status bar,
+    /// with the quickfix window above it when open. A one-row terminal shows only the
+    /// status bar.
+    fn set_screen_size(&mut self, cols: u16, rows: u16) {
+        self.terminal_rows = (rows as usize).max(1);
+        self.terminal_cols = (cols as usize).max(1);
+        self.quickfix_layout();
+    }
+    /// The terminal row of the status bar, where commands are typed.
+    fn status_row(&self) -> usize {
+        self.terminal_rows - 1
+    }
     // --- Main Keypress Router ---
     /// Routes key events to the correct handler based on the current mode.
     fn process_keypress(&mut self, event: KeyEvent) -> Result<bool> {
-        match self.mode {
+        // The picker takes all keys while it is open
+        if self.picker.is_some() {
+            return self.process_picker_keypress(event);
+        }
+        // The hover window stays until the next key
+        self.lsp.hover = None;
+        if let Some(keep_running) = self.process_cmdwin_keypress(event) {
+            return keep_running;
+        }
+        let keep_running = match self.mode {
             Mode::Normal(_) => self.process_normal_keypress(event), // ‼️
             Mode::Insert(_) => self.process_insert_keypress(event), // ‼️
             Mode::Visual(_) => self.process_visual_keypress(event), // ‼️
             Mode::Command(_) => self.process_command_keypress(event), // ‼️
+            Mode::Confirm(_) => self.process_confirm_keypress(event),
+        };
+        // Everything typed in one Insert session is undone together
+        if !matches!(self.mode, Mode::Insert(_)) {
+            self.buffer.seal_undo_step();
         }
+        keep_running
     }
     // --- Normal Mode Logic ---
     /// Handles key events in Normal mode.
@@ -207,81 +686,121 @@ impl Editor {
         if !matches!(event.code, KeyCode::Char(':')) {
             self.status_msg.clear();
         }
-        match event.code {
-            // --- MOVEMENT ---
-            KeyCode::Char('h') | KeyCode::Left => {
-                if self.cx > 0 {
-                    self.cx -= 1;
+        // Finish a two-key command if its first key was already typed
+        if let Mode::Normal(state) = &mut self.mode
+            && let Some(first) = state.pending.take()
+        {
+            match (first, event.code) {
+                ('g', _) => self.process_g_keypress(event),
+                // `>>` and `<<` shift the current line by one indent level
+                ('>', KeyCode::Char('>')) | ('<', KeyCode::Char('<')) => {
+                    self.shift_lines(self.cy, self.cy, first == '>');
+                    self.cx = self.first_non_blank(self.cy);
                 }
-            }
-            KeyCode::Char('l') | KeyCode::Right => {
-                // Get current line length from buffer
-                let file_row = self.cy + self.row_offset;
-                let current_line_len = if file_row < self.buffer.len_lines() {
-                    self.buffer.line(file_row).len_chars() // ‼️ Get char length
-                } else {
-                    0
-                };
-                // In normal mode, cursor can't go past the last char (if line not empty)
-                let max_cx = if current_line_len > 0 {
-                    current_line_len - 1
-                } else {
-                    0
-                };
-                if self.cx < max_cx {
-                    self.cx += 1;
+                // `!{motion}` starts a filter command for the lines the motion covers
+                ('!', code) => {
+                    if let Some((first, last)) = self.motion_lines("!", code) {
+                        let end = self.buffer.len_lines().saturating_sub(1);
+                        let line = |line: usize| match line {
+                            _ if line == self.cy => ".".to_string(),
+                            _ if line == end => "$".to_string(),
+                            _ if line < self.cy => format!(".-{}", self.cy - line),
+                            _ => format!(".+{}", line - self.cy),
+                        };
+                        let range = if first == last {
+                            line(first)
+                        } else {
+                            format!("{},{}", line(first), line(last))
+                        };
+                        self.mode = Mode::Command(CommandState::new(format!(":{}!", range)));
+                    }
                 }
+                // Ctrl-w splits, closes and moves between windows
+                ('\u{17}', code) if !self.window_command(code) => return Ok(false),
+                // `\` is the leader key; `\f` finds a file
+                ('\\', KeyCode::Char('f')) => self.open_picker(),
+                // `q:` and `q/` open the command-line window
+                ('q', KeyCode::Char(kind @ (':' | '/' | '?'))) => self.open_cmdwin(kind, ""),
+                // `gq{motion}` formats the lines the motion covers
+                ('Q', code) => self.format_motion(code)?,
+                _ => {}
             }
-            KeyCode::Char('k') | KeyCode::Up => {
-                if self.cy > 0 {
-                    self.cy -= 1;
-                } else if self.row_offset > 0 {
-                    self.row_offset -= 1;
-                }
+            self.clamp_cursor_to_line();
+            self.scroll_check();
+            return Ok(true);
+        }
+        if self.quickfix.focused() {
+            return self.process_quickfix_keypress(event);
+        }
+        if self.process_browser_keypress(event) {
+            self.clamp_cursor_to_line();
+            self.scroll_check();
+            return Ok(true);
+        }
+        match event.code {
+            // --- MOVEMENT ---
+            KeyCode::Char('h') | KeyCode::Left => self.move_left(),
+            KeyCode::Char('l') | KeyCode::Right => self.move_right(),
+            KeyCode::Char('k') | KeyCode::Up if self.cy > 0 => {
+                self.move_to_row(self.cy - 1);
             }
             KeyCode::Char('j') | KeyCode::Down => {
                 // Get file length from buffer
                 let file_last_row = self.buffer.len_lines().saturating_sub(1);
-                if self.cy + self.row_offset < file_last_row {
-                    if self.cy < self.screen_rows - 1 {
-                        self.cy += 1;
-                    } else {
-                        self.row_offset += 1;
-                    }
+                if self.cy < file_last_row {
+                    self.move_to_row(self.cy + 1);
+                }
+            }
+            KeyCode::Char(c @ ('g' | '>' | '<' | '!' | '\\' | 'q')) => {
+                if let Mode::Normal(state) = &mut self.mode {
+                    state.pending = Some(c);
                 }
             }
+            KeyCode::Char('w') if event.modifiers == KeyModifiers::CONTROL => {
+                if let Mode::Normal(state) = &mut self.mode {
+                    state.pending = Some('\u{17}');
+                }
+            }
+            KeyCode::Char('G') => {
+                self.cy = self.buffer.len_lines().saturating_sub(1);
+                self.cx = self.first_non_blank(self.cy);
+            }
             // --- SCROLLING (half-page) ---
             KeyCode::Char('d') if event.modifiers == KeyModifiers::CONTROL => {
-                let new_offset = (self.row_offset + self.screen_rows / 2)
+                self.row_offset = (self.row_offset + self.screen_rows / 2)
                     .min(self.buffer.len_lines().saturating_sub(1)); // ‼️ Use buffer.len_lines()
-                let dy = new_offset - self.row_offset;
-                self.row_offset = new_offset;
-                self.cy = self.cy.saturating_sub(dy);
+                self.cy = self.cy.max(self.row_offset);
                 self.scroll_check();
             }
             KeyCode::Char('u') if event.modifiers == KeyModifiers::CONTROL => {
-                let new_offset = self.row_offset.saturating_sub(self.screen_rows / 2);
-                let dy = self.row_offset - new_offset;
-                self.row_offset = new_offset;
-                self.cy = (self.cy + dy).min(self.screen_rows - 1);
+                self.row_offset = self.row_offset.saturating_sub(self.screen_rows / 2);
+                self.cy = self.cy.min(self.last_visible_row());
                 self.scroll_check();
             }
+            // --- UNDO ---
+            KeyCode::Char('u') => self.undo(false),
+            KeyCode::Char('r') if event.modifiers == KeyModifiers::CONTROL => self.undo(true),
+            // --- LANGUAGE SERVER ---
+            KeyCode::Char('K') => self.show_hover(),
             // --- MODE SWITCHING ---
+            KeyCode::Char('i') if !self.buffer.modifiable => self.buffer.refused = true,
             KeyCode::Char('i') => {
-                self.mode = Mode::Insert(InsertState {});
+                self.mode = Mode::Insert(InsertState::default());
                 self.status_msg = "-- INSERT --".to_string();
             }
             KeyCode::Char('v') => {
                 self.mode = Mode::Visual(VisualState {
-                    selection_start: (self.cx, self.cy + self.row_offset),
+                    selection_start: (self.cx, self.cy),
+                    g_pending: false,
                 });
                 self.status_msg = "-- VISUAL --".to_string();
             }
+            // --- SEARCH ---
+            KeyCode::Char('n') => self.search_next(false)?,
+            KeyCode::Char('N') => self.search_next(true)?,
             // --- COMMANDS ---
-            KeyCode::Char(':') => {
-                self.mode = Mode::Command(CommandState {
-                    command_buffer: ":".to_string(),
-                });
+            KeyCode::Char(c @ (':' | '/' | '?')) => {
+                self.mode = Mode::Command(CommandState::new(c.to_string()));
                 self.status_msg.clear();
             }
             _ => {}
@@ -294,79 +813,122 @@ impl Editor {
     /// Handles key events in Insert mode.
     fn process_insert_keypress(&mut self, event: KeyEvent) -> Result<bool> {
         self.status_msg.clear(); // Clear status message on any insert mode keypress
+        if self.process_completion_keypress(event) {
+            self.scroll_check();
+            return Ok(true);
+        }
+        let Mode::Insert(state) = &mut self.mode else {
+            return Ok(true);
+        };
+        let auto_indented = state.auto_indented.take();
+        let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
         match event.code {
             // --- MODE SWITCHING ---
             KeyCode::Esc => {
-                self.mode = Mode::Normal(NormalState {});
+                self.remove_unused_indent(auto_indented, self.cy);
+                self.mode = Mode::Normal(NormalState::default());
                 self.status_msg.clear();
                 self.clamp_cursor_to_line();
             }
+            // --- INDENTING ---
+            KeyCode::Char('t' | 'd') if ctrl => {
+                self.shift_lines(self.cy, self.cy, event.code == KeyCode::Char('t'));
+                self.keep_auto_indent(auto_indented);
+            }
+            KeyCode::Tab => {
+                self.insert_tab();
+                self.keep_auto_indent(auto_indented);
+            }
             // --- TYPING ---
             KeyCode::Char(c) => {
                 self.insert_char(c);
+                if c == '}' && self.options.smartindent {
+                    self.dedent_closing_brace();
+                }
+                self.lsp_char_typed(c);
             }
             // --- ENTER ---
             KeyCode::Enter => {
-                self.insert_new_line();
+                let indented = self.insert_new_line();
+                // The indent stays on the new line; the split-off part is left empty
+                self.remove_unused_indent(auto_indented, self.cy - 1);
+                if let (true, Mode::Insert(state)) = (indented, &mut self.mode) {
+                    state.auto_indented = Some(self.cy);
+                }
             }
             // --- BACKSPACE ---
             KeyCode::Backspace => {
-                self.delete_char();
+                if !self.delete_soft_tab() {
+                    self.delete_char();
+                }
+                self.keep_auto_indent(auto_indented);
             }
             _ => {}
         }
+        self.scroll_check();
         Ok(true)
     }
     // --- Visual Mode Logic ---
     /// Handles key events in Visual mode.
     fn process_visual_keypress(&mut self, event: KeyEvent) -> Result<bool> {
         self.status_msg.clear();
+        let g_pending = match &mut self.mode {
+            Mode::Visual(state) => std::mem::take(&mut state.g_pending),
+            _ => false,
+        };
         match event.code {
             // --- MODE SWITCHING ---
             KeyCode::Esc => {
-                self.mode = Mode::Normal(NormalState {});
+                self.mode = Mode::Normal(NormalState::default());
                 self.status_msg.clear();
             }
             // TODO: Add visual mode movement and commands (y, d, etc.)
             // For now, just movement like normal mode
-            KeyCode::Char('h') | KeyCode::Left => {
-                if self.cx > 0 {
-                    self.cx -= 1;
+            KeyCode::Char('h') | KeyCode::Left => self.move_left(),
+            KeyCode::Char('l') | KeyCode::Right => self.move_right(),
+            KeyCode::Char('k') | KeyCode::Up if self.cy > 0 => {
+                self.move_to_row(self.cy - 1);
+            }
+            KeyCode::Char('j') | KeyCode::Down => {
+                // ‼️ Get file length from buffer
+                let file_last_row = self.buffer.len_lines().saturating_sub(1);
+                if self.cy < file_last_row {
+                    self.move_to_row(self.cy + 1);
                 }
             }
-            KeyCode::Char('l') | KeyCode::Right => {
-                // Get current line length from buffer
-                let file_row = self.cy + self.row_offset;
-                let current_line_len = if file_row < self.buffer.len_lines() {
-                    self.buffer.line(file_row).len_chars()
-                } else {
-                    0
-                };
-                let max_cx = if current_line_len > 0 {
-                    current_line_len - 1
-                } else {
-                    0
-                };
-                if self.cx < max_cx {
-                    self.cx += 1;
+            // --- INDENTING ---
+            KeyCode::Char(c @ ('>' | '<')) => {
+                if let Some(((_, start_y), (_, end_y))) = self.get_selection_range() {
+                    self.shift_lines(start_y, end_y, c == '>');
+                    self.cy = start_y;
+                    self.cx = self.first_non_blank(start_y);
                 }
+                self.mode = Mode::Normal(NormalState::default());
             }
-            KeyCode::Char('k') | KeyCode::Up => {
-                if self.cy > 0 {
-                    self.cy -= 1;
-                } else if self.row_offset > 0 {
-                    self.row_offset -= 1;
+            // --- FORMATTING ---
+            KeyCode::Char('g') => {
+                if let Mode::Visual(state) = &mut self.mode {
+                    state.g_pending = true;
                 }
             }
-            KeyCode::Char('j') | KeyCode::Down => {
-                // ‼️ Get file length from buffer
-                let file_last_row = self.buffer.len_lines().saturating_sub(1);
-                if self.cy + self.row_offset < file_last_row {
-                    if self.cy < self.screen_rows - 1 {
-                        self.cy += 1;
-                    } else {
-                        self.row_offset += 1;
-                    }
+            KeyCode::Char('q') if g_pending => {
+                if let Some(((_, start_y), (_, end_y))) = self.get_selection_range() {
+                    self.mode = Mode::Normal(NormalState::default());
+                    self.format(Some(range::Range {
+                        start: start_y + 1,
+                        end: end_y + 1,
+                    }))?;
+                }
+            }
+            // --- FILTERING ---
+            KeyCode::Char('!') => {
+                if let Some(((_, start_y), (_, end_y))) = self.get_selection_range() {
+                    self.mode = Mode::Command(CommandState::new(format!(
+                        ":{},{}!",
+                        start_y + 1,
+                        end_y + 1
+                    )));
+                    return Ok(true);
                 }
             }
             _ => {}
@@ -382,32 +944,66 @@ impl Editor {
             return Ok(true); // Should not happen
         };
         match event.code {
-            KeyCode::Enter => {
+            KeyCode::Enter if !state.ctrl_r => {
                 let command_to_execute = state.command_buffer.clone();
+                self.history.add(&command_to_execute);
                 // Switch back to Normal mode *before* executing
-                self.mode = Mode::Normal(NormalState {});
+                self.mode = Mode::Normal(NormalState::default());
+                if command_to_execute.starts_with(['/', '?']) {
+                    self.search(&command_to_execute)?;
+                    return Ok(true);
+                }
                 self.execute_command(&command_to_execute)
             }
             KeyCode::Esc => {
-                self.mode = Mode::Normal(NormalState {}); // Switch to Normal
+                self.mode = Mode::Normal(NormalState::default()); // Switch to Normal
                 self.status_msg.clear();
                 Ok(true)
             }
-            KeyCode::Char(c) => {
-                state.command_buffer.push(c);
+            _ => {
+                self.process_cmdline_keypress(event);
                 Ok(true)
             }
-            KeyCode::Backspace => {
-                if state.command_buffer.len() > 1 {
-                    state.command_buffer.pop();
-                } else {
-                    // ‼️ Popped the ':', abort to Normal mode
-                    self.mode = Mode::Normal(NormalState {});
-                    self.status_msg.clear();
-                }
+        }
+    }
+    // --- Confirm Mode Logic ---
+    /// Handles the answer to a Confirm prompt. Returns false if the editor should quit.
+    fn process_confirm_keypress(&mut self, event: KeyEvent) -> Result<bool> {
+        let Mode::Confirm(state) = &self.mode else {
+            return Ok(true);
+        };
+        let choice = match event.code {
+            KeyCode::Esc => state.cancel,
+            KeyCode::Char(c) if state.choices.contains(c.to_ascii_lowercase()) => {
+                c.to_ascii_lowercase()
+            }
+            // Any other key leaves the question up
+            _ => return Ok(true),
+        };
+        let Mode::Confirm(state) =
+            std::mem::replace(&mut self.mode, Mode::Normal(NormalState::default()))
+        else {
+            return Ok(true);
+        };
+        self.status_msg.clear();
+        match state.action {
+            ConfirmAction::SwapExists(found, previous) => {
+                Ok(self.answer_swap_prompt(found, previous, choice))
+            }
+            ConfirmAction::WholeFile(command) => self.answer_whole_file_prompt(&command, choice),
+            ConfirmAction::FileChanged => {
+                self.answer_file_changed_prompt(choice);
+                Ok(true)
+            }
+            ConfirmAction::WriteChanged(command) => self.answer_overwrite_prompt(&command, choice),
+            ConfirmAction::Delete(paths) => {
+                self.answer_delete_prompt(paths, choice);
+                Ok(true)
+            }
+            ConfirmAction::CodeAction(server, actions) => {
+                self.answer_code_action(&server, actions, choice);
                 Ok(true)
             }
-            _ => Ok(true),
         }
     }
     /// Executes a command string.
@@ -416,69 +1012,423 @@ impl Editor {
         if parts.is_empty() {
             return Ok(true); // Should not happen
         }
-        match parts[0] {
-            ":q" => {
-                if self.buffer.dirty {
-                    self.status_msg =
-                        "No write since last change (use :q! to override)".to_string();
-                    Ok(true) // Don't quit
-                } else {
-                    Ok(false) // Quit
+        // A line range, as in `:1,5!sort`, goes before the command name
+        let last = self.buffer.len_lines().saturating_sub(1);
+        let (range, rest) =
+            match range::parse(command.strip_prefix(':').unwrap_or(command), self.cy, last) {
+                Ok(parsed) => parsed,
+                Err(msg) => {
+                    self.status_msg = msg;
+                    return Ok(true);
+                }
+            };
+        // Like `:w`, these take in all of a large file, which has to be there first
+        if self.buffer.large
+            && !self.whole_file_confirmed
+            && let Some(doing) = self.whole_file_use(range, rest)
+        {
+            if self.loader.is_some() {
+                self.status_msg =
+                    "The file is still loading; try again when it is done".to_string();
+            } else {
+                self.confirm_whole_file(command, doing);
+            }
+            return Ok(true);
+        }
+        if let Some(bang) = shell::parse(rest) {
+            self.shell_command(range, bang)?;
+            self.clamp_cursor_to_line();
+            self.scroll_check();
+            return Ok(true);
+        }
+        match substitute::parse(rest) {
+            Some(Ok(substitute)) => {
+                self.substitute(range, substitute);
+                self.clamp_cursor_to_line();
+                self.scroll_check();
+                return Ok(true);
+            }
+            Some(Err(msg)) => {
+                self.status_msg = msg;
+                return Ok(true);
+            }
+            None => {}
+        }
+        // `:Format` takes a range too, and formats the whole buffer without one
+        if rest.trim() == "Format" {
+            self.format(range)?;
+            self.clamp_cursor_to_line();
+            self.scroll_check();
+            return Ok(true);
+        }
+        if let Some(range) = range {
+            if rest.trim().is_empty() {
+                // `:N` goes to line N
+                self.cy = range.lines().1;
+                self.cx = self.first_non_blank(self.cy);
+                self.scroll_check();
+            } else {
+                self.status_msg = "No range allowed".to_string();
+            }
+            return Ok(true);
+        }
+        // Only commands that work on the history itself are allowed in the
+        // command-line window, and `:q` closes it
+        if self.cmdwin.is_some() {
+            match parts[0] {
+                ":set" | ":se" => {}
+                ":q" | ":q!" => {
+                    self.close_cmdwin();
+                    return Ok(true);
+                }
+                _ => {
+                    self.status_msg = cmdwin::INVALID.to_string();
+                    return Ok(true);
                 }
             }
-            ":q!" => {
-                Ok(false) // Force quit
+        }
+        if let Some(quickfix_command) = quickfix::command_name(parts[0]) {
+            let args = &command[parts[0].len()..];
+            let keep_running = self.quickfix_command(quickfix_command, args)?;
+            self.clamp_cursor_to_line();
+            self.scroll_check();
+            return Ok(keep_running);
+        }
+        match parts[0] {
+            ":Files" => {
+                self.open_picker();
+                Ok(true)
+            }
+            ":Ex" | ":Explore" | ":Ex!" | ":Explore!" => {
+                self.explore(parts[0].ends_with('!'), parts.get(1).copied());
+                Ok(true)
+            }
+            ":Mkdir" => {
+                self.make_directory(command[parts[0].len()..].trim());
+                Ok(true)
+            }
+            ":Rename" => {
+                self.rename_entry(command[parts[0].len()..].trim());
+                Ok(true)
+            }
+            ":LspRename" => {
+                self.lsp_rename(command[parts[0].len()..].trim());
+                Ok(true)
+            }
+            ":LspDiagnostics" => {
+                self.list_diagnostics();
+                Ok(true)
+            }
+            ":CodeAction" => {
+                self.code_action();
+                Ok(true)
+            }
+            // The last window quits
+            ":q" | ":q!" => Ok(self.quit_window(parts[0].ends_with('!'))),
+            ":qa" | ":qall" | ":qa!" | ":qall!" => Ok(self.quit_all(parts[0].ends_with('!'))),
+            ":sp" | ":split" | ":vs" | ":vsplit" => {
+                self.split_window(parts[0].starts_with(":v"), &parts[1..]);
+                Ok(true)
+            }
+            ":clo" | ":close" | ":clo!" | ":close!" => {
+                self.close_current_window(parts[0].ends_with('!'));
+                Ok(true)
+            }
+            ":on" | ":only" | ":on!" | ":only!" => {
+                self.only_window(parts[0].ends_with('!'));
+                Ok(true)
+            }
+            ":tabe" | ":tabedit" | ":tabnew" => {
+                self.new_tab(&parts[1..]);
+                Ok(true)
+            }
+            ":tabn" | ":tabnext" | ":tabp" | ":tabprevious" | ":tabN" | ":tabNext" => {
+                self.next_tab(parts[0].starts_with(":tabn"));
+                Ok(true)
+            }
+            ":tabc" | ":tabclose" | ":tabc!" | ":tabclose!" => {
+                self.close_tab(parts[0].ends_with('!'));
+                Ok(true)
+            }
+            ":w" | ":wq" if self.buffer.readonly => {
+                self.status_msg = "File is read-only".to_string();
+                Ok(true)
+            }
+            ":w" | ":wq" if self.loader.is_some() => {
+                self.status_msg = "The file is still loading; write it when it is done".to_string();
+                Ok(true)
+            }
+            ":w" | ":wq" if self.write_overwrites_change(&parts[1..]) => {
+                self.confirm_overwrite(command);
+                Ok(true)
+            }
+            // The text is converted to bytes in one piece before it is written
+            ":w" | ":wq" if self.buffer.large && !self.whole_file_confirmed => {
+                self.confirm_whole_file(command, "Writing");
+                Ok(true)
             }
             ":w" => {
-                if parts.len() > 1 {
+                let (encoding, args) = split_enc_arg(&parts[1..]);
+                if let Some(filename) = args.first() {
                     // Update buffer's filename
-                    self.buffer.filename = Some(parts[1].to_string());
+                    self.buffer.filename = Some(filename.to_string());
                 }
-                // Tell buffer to save
-                if self.buffer.save()? {
-                    self.status_msg =
-                        format!("Saved file: {}", self.buffer.filename.as_deref().unwrap());
-                } else {
-                    self.status_msg = "No filename specified. Use :w <filename>".to_string();
+                let unformatted = self.format_before_save()?;
+                // Tell buffer to save, in another encoding for `++enc=`
+                let encoding = encoding.unwrap_or(&self.options.fileencoding).to_string();
+                match self.buffer.save(&self.options, &encoding) {
+                    Ok(true) => {
+                        self.status_msg =
+                            format!("Saved file: {}", self.buffer.filename.as_deref().unwrap());
+                        if let Some(reason) = unformatted {
+                            self.status_msg += &format!(" (not formatted: {})", reason);
+                        }
+                        self.lsp_did_save();
+                    }
+                    Ok(false) => {
+                        self.status_msg = "No filename specified. Use :w <filename>".to_string();
+                    }
+                    Err(e) => self.status_msg = format!("Error writing file: {}", e),
                 }
                 Ok(true) // Continue
             }
             ":wq" => {
-                // Tell buffer to save
-                let save_success = if parts.len() > 1 {
-                    self.buffer.filename = Some(parts[1].to_string());
-                    self.buffer.save()?
-                } else {
-                    self.buffer.save()?
+                let (encoding, args) = split_enc_arg(&parts[1..]);
+                if let Some(filename) = args.first() {
+                    self.buffer.filename = Some(filename.to_string());
+                }
+                // A formatter that hangs is given up on, so the write still happens
+                self.format_before_save()?;
+                // Tell buffer to save; a failed write keeps the editor open
+                let encoding = encoding.unwrap_or(&self.options.fileencoding).to_string();
+                let save_success = match self.buffer.save(&self.options, &encoding) {
+                    Ok(saved) => saved,
+                    Err(e) => {
+                        self.status_msg = format!("Error writing file: {}", e);
+                        return Ok(true);
+                    }
                 };
                 // Only quit if save was successful or file wasn't dirty
                 if save_success {
                     self.status_msg =
                         format!("Saved file: {}", self.buffer.filename.as_deref().unwrap());
-                    Ok(false) // Quit
+                    Ok(self.quit_window(false))
                 } else if !self.buffer.dirty {
-                    Ok(false) // Quit (wasn't dirty)
+                    Ok(self.quit_window(false)) // Wasn't dirty
                 } else {
                     // Save failed (e.g., no filename)
                     self.status_msg = "No filename specified. Use :w <filename>".to_string();
                     Ok(true) // Don't quit
                 }
             }
+            // Writes only if there are changes, as `:cdo s/a/b/ | update` needs
+            ":up" | ":update" => {
+                if self.buffer.dirty {
+                    self.execute_command(&format!(":w{}", &command[parts[0].len()..]))
+                } else {
+                    Ok(true)
+                }
+            }
+            ":checktime" | ":checkt" => {
+                self.check_time(true);
+                Ok(true)
+            }
+            ":e" | ":e!" | ":edit" | ":edit!" => {
+                self.edit(parts[0].ends_with('!'), &parts[1..]);
+                Ok(true)
+            }
+            ":b" | ":b!" | ":buffer" | ":buffer!" => {
+                self.switch_buffer(parts[0].ends_with('!'), parts.get(1).copied());
+                Ok(true)
+            }
+            ":set" | ":se" => {
+                let args = command[parts[0].len()..].trim_start();
+                let stored = (
+                    self.options.fileformat.clone(),
+                    self.options.fileencoding.clone(),
+                    self.options.bomb,
+                );
+                self.status_msg = match self.options.apply(args) {
+                    Ok(msg) => msg,
+                    Err(msg) => msg,
+                };
+                // A new line ending or encoding changes the file as written
+                if (
+                    &self.options.fileformat,
+                    &self.options.fileencoding,
+                    self.options.bomb,
+                ) != (&stored.0, &stored.1, stored.2)
+                {
+                    self.buffer.dirty = true;
+                }
+                self.sync_mouse_capture()?;
+                // Wrapping changes how many rows each line needs
+                self.clamp_cursor_to_line();
+                self.scroll_check();
+                Ok(true)
+            }
             _ => {
                 self.status_msg = format!("Unknown command: {}", command);
                 Ok(true) // Continue
             }
         }
     }
-    /// Inserts a character at the cursor position.
-    fn insert_char(&mut self, c: char) {
-        let file_row = self.cy + self.row_offset;
-        // Get line length from buffer
-        let line_len = if file_row < self.buffer.len_lines() {
-            self.buffer.line(file_row).len_chars()
+    /// The names of the buffers: the file being edited, those the other windows show
+    /// and the alternate file.
+    fn buffer_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let others = self.windows.buffers().filter_map(|b| b.filename.as_ref());
+        for name in self
+            .buffer
+            .filename
+            .iter()
+            .chain(others)
+            .chain(&self.alternate_file)
+        {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names
+    }
+    /// Edits the buffer named by `:b {name}`. Like Vim, part of a name will do if only
+    /// one buffer has it, and `#` stands for the alternate file.
+    fn switch_buffer(&mut self, force: bool, name: Option<&str>) {
+        let Some(name) = name else {
+            return;
+        };
+        if name == "#" {
+            return self.edit(force, &["#"]);
+        }
+        let names = self.buffer_names();
+        let found: Vec<&String> = match names.iter().find(|n| *n == name) {
+            Some(exact) => vec![exact],
+            None => names.iter().filter(|n| n.contains(name)).collect(),
+        };
+        match found[..] {
+            [] => self.status_msg = format!("No matching buffer for {}", name),
+            [found] if self.buffer.filename.as_ref() == Some(found) => {}
+            [found] => self.edit(force, &[found.as_str()]),
+            _ => self.status_msg = format!("More than one match for {}", name),
+        }
+    }
+    /// Opens a file for `:e [++enc=name] [file]`. Without a file name the current file
+    /// is read again, keeping the cursor; `#` stands for the alternate file.
+    fn edit(&mut self, force: bool, args: &[&str]) {
+        if self.window_locked() {
+            return;
+        }
+        let (encoding, args) = split_enc_arg(args);
+        if let Some(name) = encoding
+            && !encoding::is_known(name)
+        {
+            self.status_msg = format!("Unknown encoding: {}", name);
+            return;
+        }
+        let filename = match args.first() {
+            Some(&"#") => match &self.alternate_file {
+                Some(name) => name.clone(),
+                None => {
+                    self.status_msg = "No alternate file".to_string();
+                    return;
+                }
+            },
+            Some(name) => name.to_string(),
+            None => match &self.buffer.filename {
+                Some(name) => name.clone(),
+                None => {
+                    self.status_msg = "No file name".to_string();
+                    return;
+                }
+            },
+        };
+        // Changes another window shows stay there, unless the file is read again
+        let current = self.buffer.filename.as_deref();
+        let reload = current.is_some_and(|current| quickfix::same_file(current, &filename));
+        let kept = self.windows.shared() && !reload;
+        if self.buffer.dirty && !force && !kept {
+            self.status_msg = "No write since last change (add ! to override)".to_string();
+            return;
+        }
+        let previous = self.buffer.filename.clone();
+        if let Err(e) = self.open_file(&filename, encoding, false) {
+            self.status_msg = format!("Cannot open {}: {}", filename, e);
+            return;
+        }
+        if previous.as_deref() != Some(filename.as_str()) {
+            self.alternate_file = previous;
+            self.cx = 0;
+            // A directory listing starts on its first entry, below the header
+            self.cy = self.browser.as_ref().map_or(0, |b| b.first_row());
+            self.row_offset = 0;
+            self.col_offset = 0;
+        }
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+    /// Undoes the last change, or redoes the last undone one, and puts the cursor where it was.
+    fn undo(&mut self, redo: bool) {
+        if self.buffer.large {
+            self.status_msg = "Undo is off for large files".to_string();
+            return;
+        }
+        let cursor = if redo {
+            self.buffer.redo()
         } else {
-            0
+            self.buffer.undo()
         };
+        match cursor {
+            Some((line, col)) => {
+                self.cy = line;
+                self.cx = col;
+            }
+            None if redo => self.status_msg = "Already at newest change".to_string(),
+            None => self.status_msg = "Already at oldest change".to_string(),
+        }
+    }
+    /// Inserts pasted text at the cursor as a single edit and a single undo step.
+    /// Nothing in it is taken as a command, and no indent is added to its lines.
+    fn paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        match &mut self.mode {
+            // The command line holds one line; like Vim, the paste stops at a line break
+            Mode::Confirm(_) => return,
+            Mode::Command(state) => {
+                state.insert(text.split('\n').next().unwrap_or(""));
+                return;
+            }
+            Mode::Insert(state) => state.auto_indented = None,
+            // A directory listing can't be edited
+            Mode::Normal(_) | Mode::Visual(_) if self.browser.is_some() => return,
+            Mode::Normal(_) | Mode::Visual(_) => {
+                self.mode = Mode::Normal(NormalState::default());
+                self.status_msg.clear();
+            }
+        }
+        let line_len = self.line_layout(self.cy).last().map_or(0, |g| g.end);
+        self.cx = self.cx.min(line_len);
+        self.buffer.seal_undo_step();
+        self.buffer.insert_str(self.cy, self.cx, &text);
+        self.buffer.seal_undo_step();
+        // Leave the cursor after the text in Insert mode, on its last char otherwise
+        let lines: Vec<&str> = text.split('\n').collect();
+        let last = lines[lines.len() - 1].chars().count();
+        if lines.len() > 1 {
+            self.cy += lines.len() - 1;
+            self.cx = last;
+        } else {
+            self.cx += last;
+        }
+        if !matches!(self.mode, Mode::Insert(_)) {
+            self.cx = self.cx.saturating_sub(1);
+        }
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+    /// Inserts a character at the cursor position.
+    fn insert_char(&mut self, c: char) {
+        let file_row = self.cy;
+        let line_len = self.buffer.line_len(file_row);
         if self.cx > line_len {
             self.cx = line_len;
         }
@@ -487,58 +1437,183 @@ impl Editor {
         self.cx += 1;
     }
     /// Inserts a new line at the cursor position.
-    fn insert_new_line(&mut self) {
-        let file_row = self.cy + self.row_offset;
-        // Get line length from buffer
-        let line_len = if file_row < self.buffer.len_lines() {
-            self.buffer.line(file_row).len_chars()
-        } else {
-            0
-        };
+    /// Returns whether autoindent/smartindent gave the new line an indent.
+    fn insert_new_line(&mut self) -> bool {
+        let file_row = self.cy;
+        let line_len = self.buffer.line_len(file_row);
         if self.cx > line_len {
             self.cx = line_len;
         }
+        // Work out the new line's indent from the line being split
+        let chars = self.buffer.line_chars(file_row);
+        let (_, mut width) = indent::leading(&chars, self.options.tabstop);
+        let before_cursor = &chars[..self.cx.min(chars.len())];
+        if self.options.smartindent
+            && before_cursor.iter().rev().find(|c| !c.is_whitespace()) == Some(&'{')
+        {
+            width = indent::shift(width, self.options.shiftwidth(), true);
+        }
         // Call buffer's new line method
         self.buffer.insert_new_line(file_row, self.cx);
         // Move cursor
         self.cx = 0;
-        if self.cy < self.screen_rows - 1 {
-            self.cy += 1;
-        } else {
-            self.row_offset += 1;
+        self.cy += 1;
+        if !self.options.autoindent && !self.options.smartindent {
+            return false;
+        }
+        // Like Vim, whitespace after the cursor is replaced by the new indent
+        let (moved, _) = indent::leading(&self.buffer.line_chars(self.cy), self.options.tabstop);
+        self.buffer.delete_range(self.cy, 0, moved);
+        let new_indent = indent::fill(0, width, self.options.tabstop, self.options.expandtab);
+        self.buffer.insert_str(self.cy, 0, &new_indent);
+        self.cx = new_indent.chars().count();
+        self.cx > 0
+    }
+    /// Inserts whitespace for the Tab key, honouring `softtabstop` and `expandtab`.
+    fn insert_tab(&mut self) {
+        let col = display::char_to_col(&self.line_layout(self.cy), self.cx);
+        let step = match self.options.softtabstop {
+            0 if !self.options.expandtab => {
+                self.insert_char('\t');
+                return;
+            }
+            0 => self.options.tabstop,
+            sts => sts,
+        };
+        let target = (col / step + 1) * step;
+        let text = indent::fill(col, target, self.options.tabstop, self.options.expandtab);
+        self.buffer.insert_str(self.cy, self.cx, &text);
+        self.cx += text.chars().count();
+    }
+    /// Deletes the spaces back to the previous `softtabstop` column, if the cursor
+    /// follows only whitespace since then. Returns whether anything was deleted.
+    fn delete_soft_tab(&mut self) -> bool {
+        let sts = self.options.softtabstop;
+        let graphemes = self.line_layout(self.cy);
+        let col = display::char_to_col(&graphemes, self.cx);
+        if sts == 0 || col == 0 {
+            return false;
+        }
+        let stop = (col - 1) / sts * sts;
+        let mut start = self.cx;
+        for g in graphemes.iter().rev().filter(|g| g.end <= self.cx) {
+            if g.col < stop || g.text.chars().any(|c| c != ' ') {
+                break;
+            }
+            start = g.start;
+        }
+        // A single space is an ordinary Backspace
+        if self.cx - start < 2 {
+            return false;
+        }
+        self.buffer.delete_range(self.cy, start, self.cx);
+        self.cx = start;
+        true
+    }
+    /// Carries the "indent not used yet" marker over keys that only change the indent.
+    fn keep_auto_indent(&mut self, auto_indented: Option<usize>) {
+        if let Mode::Insert(state) = &mut self.mode {
+            state.auto_indented = auto_indented.filter(|row| *row == self.cy);
+        }
+    }
+    /// Removes an autoindent that was never typed after, if `row` is still blank.
+    fn remove_unused_indent(&mut self, auto_indented: Option<usize>, row: usize) {
+        if auto_indented != Some(row) {
+            return;
+        }
+        let chars = self.buffer.line_chars(row);
+        if chars.iter().all(|c| c.is_whitespace()) {
+            self.buffer.delete_range(row, 0, chars.len());
+            if row == self.cy {
+                self.cx = 0;
+            }
+        }
+    }
+    /// Gives a line that starts with the `}` just typed the indent of the matching `{`.
+    fn dedent_closing_brace(&mut self) {
+        let chars = self.buffer.line_chars(self.cy);
+        let (count, _) = indent::leading(&chars, self.options.tabstop);
+        if chars.get(count) != Some(&'}') || self.cx != count + 1 {
+            return;
+        }
+        if let Some(row) = self.matching_open_brace(self.cy, count) {
+            let (_, width) = indent::leading(&self.buffer.line_chars(row), self.options.tabstop);
+            self.set_indent(self.cy, width);
+        }
+    }
+    /// Finds the line of the `{` matching the `}` at (row, col).
+    fn matching_open_brace(&self, row: usize, col: usize) -> Option<usize> {
+        let char_idx = self.buffer.rope.line_to_char(row) + col;
+        let mut depth = 0;
+        for (i, c) in self.buffer.rope.chars_at(char_idx).reversed().enumerate() {
+            match c {
+                '}' => depth += 1,
+                '{' if depth == 0 => return Some(self.buffer.rope.char_to_line(char_idx - i - 1)),
+                '{' => depth -= 1,
+                _ => {}
+            }
+        }
+        None
+    }
+    /// Shifts the lines `first..=last` one `shiftwidth` right or left (`>>`, `<<`).
+    /// Empty lines are not indented, as in Vim.
+    fn shift_lines(&mut self, first: usize, last: usize, right: bool) {
+        for row in first..=last.min(self.buffer.len_lines().saturating_sub(1)) {
+            let chars = self.buffer.line_chars(row);
+            if right && chars.is_empty() {
+                continue;
+            }
+            let (_, width) = indent::leading(&chars, self.options.tabstop);
+            self.set_indent(row, indent::shift(width, self.options.shiftwidth(), right));
+        }
+    }
+    /// Replaces the indent of a line with whitespace `width` columns wide.
+    /// A cursor on that line stays on the same text.
+    fn set_indent(&mut self, row: usize, width: usize) {
+        let chars = self.buffer.line_chars(row);
+        let (old_len, _) = indent::leading(&chars, self.options.tabstop);
+        let new_indent = indent::fill(0, width, self.options.tabstop, self.options.expandtab);
+        if chars[..old_len].iter().copied().eq(new_indent.chars()) {
+            return;
+        }
+        self.buffer.delete_range(row, 0, old_len);
+        self.buffer.insert_str(row, 0, &new_indent);
+        let new_len = new_indent.chars().count();
+        if row == self.cy {
+            self.cx = if self.cx >= old_len {
+                self.cx - old_len + new_len
+            } else {
+                new_len
+            };
         }
     }
+    /// Returns the char index of the first non-blank on a line.
+    fn first_non_blank(&self, row: usize) -> usize {
+        indent::leading(&self.buffer.line_chars(row), self.options.tabstop).0
+    }
     /// Deletes a character at the cursor position (Backspace).
     fn delete_char(&mut self) {
-        let file_row = self.cy + self.row_offset;
+        let file_row = self.cy;
         if self.cx == 0 {
             // At the start of a line, join with the previous line
             if file_row > 0 {
                 // Tell buffer to join lines
                 let prev_line_len = self.buffer.join_with_previous_line(file_row);
                 // Move cursor
-                if self.cy > 0 {
-                    self.cy -= 1;
-                } else {
-                    self.row_offset -= 1;
-                }
+                self.cy -= 1;
                 self.cx = prev_line_len;
             }
         } else {
             // In the middle of a line, remove the character to the left
-            // Get line length from buffer
-            let line_len = if file_row < self.buffer.len_lines() {
-                self.buffer.line(file_row).len_chars()
-            } else {
-                0
-            };
+            let line_len = self.buffer.line_len(file_row);
             if self.cx > line_len {
                 self.cx = line_len;
             }
             if self.cx > 0 {
-                //  Tell buffer to delete
-                self.buffer.delete_char(file_row, self.cx);
-                self.cx -= 1;
+                // Tell buffer to delete the whole grapheme left of the cursor
+                let start = display::prev_boundary(&self.line_layout(file_row), self.cx);
+                self.buffer.delete_range(file_row, start, self.cx);
+                self.cx = start;
             }
         }
     }
@@ -549,7 +1624,7 @@ impl Editor {
     fn get_selection_range(&self) -> Option<((usize, usize), (usize, usize))> {
         if let Mode::Visual(state) = &self.mode {
             let start_pos = state.selection_start; // (x, y_file)
-            let end_pos = (self.cx, self.cy + self.row_offset); // (x, y_file)
+            let end_pos = (self.cx, self.cy); // (x, y_file)
 
             if end_pos.1 < start_pos.1 || (end_pos.1 == start_pos.1 && end_pos.0 < start_pos.0) {
                 Some((end_pos, start_pos)) // Swap if end is before start
@@ -561,231 +1636,362 @@ impl Editor {
         }
     }
 
-    /// Clears the screen and redraws all content.
+    /// Draws all content into a new frame; only the cells that changed reach the terminal.
     fn refresh_screen(&mut self) -> Result<()> {
-        let mut stdout = stdout();
+        let mut frame = Frame::new(self.terminal_cols, self.terminal_rows);
         // ‼️ Set cursor style based on mode
-        match self.mode {
-            Mode::Normal(_) => queue!(stdout, SetCursorStyle::SteadyBlock)?,
-            Mode::Insert(_) => queue!(stdout, SetCursorStyle::SteadyBar)?,
-            Mode::Visual(_) => queue!(stdout, SetCursorStyle::SteadyBlock)?,
-            Mode::Command(_) => queue!(stdout, SetCursorStyle::SteadyBar)?,
-        }
-        queue!(
-            stdout,
-            cursor::Hide,
-            terminal::Clear(ClearType::All),
-            cursor::MoveTo(0, 0),
-        )?;
-        self.draw_rows()?;
-        self.draw_status_bar()?;
+        frame.cursor_style = match self.mode {
+            Mode::Normal(_) => SetCursorStyle::SteadyBlock,
+            Mode::Insert(_) => SetCursorStyle::SteadyBar,
+            Mode::Visual(_) => SetCursorStyle::SteadyBlock,
+            Mode::Command(_) | Mode::Confirm(_) => SetCursorStyle::SteadyBar,
+        };
+        self.draw_windows(&mut frame);
+        self.draw_quickfix(&mut frame);
+        self.draw_status_bar(&mut frame);
+        self.draw_wildmenu(&mut frame);
+        self.draw_picker(&mut frame);
         // ‼️ Move cursor to correct position based on mode
-        let (cx, cy) = if let Mode::Command(state) = &self.mode {
+        frame.cursor = Some(if let Some(pos) = self.picker_cursor_pos() {
+            // The query is typed on the status line
+            frame.cursor_style = SetCursorStyle::SteadyBar;
+            pos
+        } else if let Mode::Command(state) = &self.mode {
             // ‼️ In command mode, cursor is on status line
-            let cx = state.command_buffer.len().min(self.screen_cols - 1);
-            let cy = self.screen_rows;
-            (cx, cy)
+            let (_, cx) = state.view(self.terminal_cols.saturating_sub(1));
+            (cx, self.status_row())
+        } else if let Mode::Confirm(state) = &self.mode {
+            // The answer is typed right after the question
+            let cx = display::str_width(&state.prompt) + 1;
+            (
+                cx.min(self.terminal_cols.saturating_sub(1)),
+                self.status_row(),
+            )
+        } else if let Some(pos) = self.quickfix_cursor_pos() {
+            pos
         } else {
-            // In other modes, cursor is in the text area
-            (self.cx, self.cy)
-        };
-        queue!(stdout, cursor::MoveTo(cx as u16, cy as u16), cursor::Show)?;
-        stdout.flush()
+            // In other modes, cursor is in the current window
+            let (x, y) = self.cursor_screen_pos();
+            let rect = self.window_rect();
+            (rect.x + x, rect.y + y)
+        });
+        self.renderer.draw(&mut stdout(), frame)
     }
     /// Ensures the cursor is within the visible screen area, adjusting scroll if needed.
+    /// Scrolling is counted in screen rows, so a wrapped line takes up several.
     fn scroll_check(&mut self) {
-        if self.cy + self.row_offset >= self.buffer.len_lines() {
-            if self.buffer.len_lines() > 0 {
-                self.cy = self
-                    .buffer
-                    .len_lines()
-                    .saturating_sub(1)
-                    .saturating_sub(self.row_offset);
-            } else {
+        let last_row = self.buffer.len_lines().saturating_sub(1);
+        self.cy = self.cy.min(last_row);
+        self.row_offset = self.row_offset.min(last_row);
+        if self.cy < self.row_offset {
+            self.row_offset = self.cy;
+        }
+        // Find the lowest top line that still shows the cursor's screen row
+        let (graphemes, segments) = self.line_rows(self.cy);
+        let mut rows = wrap::segment_index(&segments, self.cx) + 1;
+        let mut top = self.cy;
+        while top > self.row_offset {
+            let height = self.line_rows(top - 1).1.len();
+            if rows + height > self.screen_rows {
+                break;
+            }
+            rows += height;
+            top -= 1;
+        }
+        self.row_offset = top;
+        // Without wrap, scroll sideways to keep the cursor's cells in view
+        if self.options.wrap {
+            self.col_offset = 0;
+        } else {
+            let col = display::char_to_col(&graphemes, self.cx);
+            let width = graphemes
+                .get(display::grapheme_at(&graphemes, self.cx))
+                .map_or(1, |g| g.width);
+            if col < self.col_offset {
+                self.col_offset = col;
+            } else if col + width > self.col_offset + self.text_cols() {
+                self.col_offset = (col + width).saturating_sub(self.text_cols());
+            }
+        }
+    }
+    /// Returns the width of the sign and line number columns of the current window.
+    fn gutter_width(&self) -> usize {
+        self.pane().gutter_width()
+    }
+    /// Returns the number of columns available for text in the current window.
+    fn text_cols(&self) -> usize {
+        self.pane().text_cols()
+    }
+    /// Returns the last file line that is fully shown in the current window.
+    fn last_visible_row(&self) -> usize {
+        self.pane().last_visible_row()
+    }
+    /// Lays out a file line as grapheme clusters in display columns.
+    fn line_layout(&self, row: usize) -> Vec<Grapheme> {
+        self.pane().line_layout(row)
+    }
+    /// Lays out a file line and splits it into the rows it occupies in the current window.
+    fn line_rows(&self, row: usize) -> (Vec<Grapheme>, Vec<wrap::Segment>) {
+        self.pane().line_rows(row)
+    }
+    /// Returns the (column, row) of the cursor in the current window.
+    fn cursor_screen_pos(&self) -> (usize, usize) {
+        self.pane().cursor_screen_pos()
+    }
+    /// Moves the cursor one grapheme to the left.
+    fn move_left(&mut self) {
+        self.cx = display::prev_boundary(&self.line_layout(self.cy), self.cx);
+    }
+    /// Moves the cursor one grapheme to the right, staying on the last one.
+    fn move_right(&mut self) {
+        let graphemes = self.line_layout(self.cy);
+        let next = display::next_boundary(&graphemes, self.cx);
+        if graphemes.last().is_some_and(|g| next < g.end) {
+            self.cx = next;
+        }
+    }
+    /// Moves the cursor to another line, keeping its display column.
+    fn move_to_row(&mut self, row: usize) {
+        let col = display::char_to_col(&self.line_layout(self.cy), self.cx);
+        self.cy = row;
+        self.cx = display::col_to_char(&self.line_layout(row), col);
+    }
+    /// Handles the second key of a `g` command.
+    fn process_g_keypress(&mut self, event: KeyEvent) {
+        let (graphemes, segments) = self.line_rows(self.cy);
+        let segment = segments[wrap::segment_index(&segments, self.cx)];
+        // The columns of the line shown on the cursor's screen row
+        let (first_col, last_col) = if self.options.wrap {
+            (segment.col, display::char_to_col(&graphemes, segment.end))
+        } else {
+            (self.col_offset, self.col_offset + self.text_cols())
+        };
+        match event.code {
+            KeyCode::Char('g') => {
                 self.cy = 0;
+                self.cx = self.first_non_blank(0);
+            }
+            KeyCode::Char('d') => self.goto_definition(),
+            // `gt` and `gT` go to the next and the previous tab page
+            KeyCode::Char('t') => self.next_tab(true),
+            KeyCode::Char('T') => self.next_tab(false),
+            KeyCode::Char('h') if self.browser.is_some() => self.toggle_hidden(),
+            KeyCode::Char('r') => self.find_references(),
+            KeyCode::Char('q') => {
+                if let Mode::Normal(state) = &mut self.mode {
+                    state.pending = Some('Q');
+                }
+            }
+            KeyCode::Char('j') | KeyCode::Down => self.move_screen_line(true),
+            KeyCode::Char('k') | KeyCode::Up => self.move_screen_line(false),
+            KeyCode::Char('0') | KeyCode::Home => {
+                if let Some(g) = graphemes.iter().find(|g| g.col >= first_col) {
+                    self.cx = g.start;
+                }
+            }
+            KeyCode::Char('$') | KeyCode::End => {
+                if let Some(g) = graphemes.iter().rev().find(|g| g.col + g.width <= last_col) {
+                    self.cx = g.start;
+                }
             }
+            _ => {}
         }
-        self.row_offset = self
-            .row_offset
-            .min(self.buffer.len_lines().saturating_sub(1));
     }
-    /// Ensures the horizontal cursor (cx) isn't past the end of the current line.
-    fn clamp_cursor_to_line(&mut self) {
-        let file_row = self.cy + self.row_offset;
-        // Get line length from buffer
-        let current_line_len = if file_row < self.buffer.len_lines() {
-            self.buffer.line(file_row).len_chars()
+    /// The first and last line covered by the motion typed after `operator` (`!` or
+    /// `gq`): the operator's last key again for the cursor line, `j` and `k` for it and
+    /// the line below or above, `G` for the lines to the end. None when the motion goes
+    /// nowhere or is not one of these, which the status says.
+    fn motion_lines(&mut self, operator: &str, code: KeyCode) -> Option<(usize, usize)> {
+        let last = self.buffer.len_lines().saturating_sub(1);
+        let repeat = operator.chars().last().unwrap_or_default();
+        match code {
+            KeyCode::Char(c) if c == repeat => Some((self.cy, self.cy)),
+            KeyCode::Char('j') | KeyCode::Down => {
+                (self.cy < last).then_some((self.cy, self.cy + 1))
+            }
+            KeyCode::Char('k') | KeyCode::Up => (self.cy > 0).then(|| (self.cy - 1, self.cy)),
+            KeyCode::Char('G') => Some((self.cy, last)),
+            KeyCode::Esc => None,
+            _ => {
+                self.status_msg = format!(
+                    "Unsupported motion; {0}{1}, {0}j, {0}k and {0}G work",
+                    operator, repeat
+                );
+                None
+            }
+        }
+    }
+    /// Moves the cursor one screen row down or up (`gj`/`gk`), keeping its screen column.
+    fn move_screen_line(&mut self, down: bool) {
+        let (graphemes, segments) = self.line_rows(self.cy);
+        let index = wrap::segment_index(&segments, self.cx);
+        let column = segments[index].prefix + display::char_to_col(&graphemes, self.cx)
+            - segments[index].col;
+        let (row, target) = if down {
+            if index + 1 < segments.len() {
+                (self.cy, segments[index + 1])
+            } else if self.cy + 1 < self.buffer.len_lines() {
+                (self.cy + 1, self.line_rows(self.cy + 1).1[0])
+            } else {
+                return;
+            }
+        } else if index > 0 {
+            (self.cy, segments[index - 1])
+        } else if self.cy > 0 {
+            let above = self.line_rows(self.cy - 1).1;
+            (self.cy - 1, above[above.len() - 1])
         } else {
-            0
+            return;
         };
+        self.cy = row;
+        let graphemes = self.line_layout(row);
+        let col = target.col + column.saturating_sub(target.prefix);
+        // Stay on this screen row: the char at `end` already belongs to the next one
+        let last = display::prev_boundary(&graphemes, target.end).max(target.start);
+        self.cx = display::col_to_char(&graphemes, col).clamp(target.start, last);
+    }
+    /// Ensures the horizontal cursor (cx) isn't past the end of the current line
+    /// and sits on the start of a grapheme cluster.
+    fn clamp_cursor_to_line(&mut self) {
+        let graphemes = self.line_layout(self.cy);
         match self.mode {
             // Clamp for Normal and Visual
             Mode::Normal(_) | Mode::Visual(_) => {
-                // In Normal mode, cursor stays *on* the last char
-                let max_cx = if current_line_len > 0 {
-                    current_line_len - 1
-                } else {
-                    0
-                };
+                // In Normal mode, cursor stays *on* the last grapheme
+                let max_cx = graphemes.last().map_or(0, |g| g.start);
                 if self.cx > max_cx {
                     self.cx = max_cx;
                 }
             }
             // Clamp for Insert
             Mode::Insert(_) => {
-                // In Insert mode, cursor can go one *past* the last char
-                if self.cx > current_line_len {
-                    self.cx = current_line_len;
+                // In Insert mode, cursor can go one *past* the last grapheme
+                let line_len = graphemes.last().map_or(0, |g| g.end);
+                if self.cx > line_len {
+                    self.cx = line_len;
                 }
             }
             // Do nothing for command mode
-            Mode::Command(_) => {}
+            Mode::Command(_) | Mode::Confirm(_) => return,
         }
-        if current_line_len == 0 && self.cx > 0 {
-            self.cx = 0;
+        if let Some(g) = graphemes.get(display::grapheme_at(&graphemes, self.cx)) {
+            self.cx = g.start;
         }
     }
-    /// Draws the text buffer to the screen.
-    fn draw_rows(&self) -> Result<()> {
-        let mut stdout = stdout();
-
-        // Get the selection range *once* before the loop
-        let selection = self.get_selection_range();
-
-        for y in 0..self.screen_rows {
-            let file_row_index = y + self.row_offset;
-            queue!(stdout, cursor::MoveTo(0, y as u16))?; // Move cursor at start of loop
-
-            if file_row_index >= self.buffer.len_lines() {
-                // Welcome message logic
-                if self.buffer.len_lines() == 1
-                    && self.buffer.line(0).len_chars() == 0
-                    && y == self.screen_rows / 3
-                {
-                    let welcome = "Vim-like Editor - v0.0.1";
-                    let padding = (self.screen_cols.saturating_sub(welcome.len())) / 2;
-                    let padding_str = " ".repeat(padding);
-                    queue!(
-                        stdout,
-                        // No need for MoveTo, already at (0, y)
-                        style::Print(format!("~{}{}", padding_str, welcome))
-                    )?;
-                } else {
-                    queue!(stdout, style::Print("~"))?;
-                }
-            } else {
-                // Get line from buffer
-                let line = self.buffer.line(file_row_index);
-                let start_char = 0; // ‼️ TODO: Add horizontal scrolling
-                let end_char = (start_char + self.screen_cols).min(line.len_chars());
-
-                // --- Highlighting Logic ---
-                let mut is_highlighted = false;
-
-                // Iterate over the chars we are actually drawing
-                for (cx, char) in line.chars().enumerate().skip(start_char).take(end_char) {
-                    let mut should_highlight = false;
-
-                    // Check if this char (cx, file_row_index) is in selection
-                    if let Some(((start_x, start_y), (end_x, end_y))) = selection {
-                        if file_row_index > start_y && file_row_index < end_y {
-                            should_highlight = true;
-                        } else if file_row_index == start_y && file_row_index == end_y {
-                            should_highlight = cx >= start_x && cx <= end_x;
-                        } else if file_row_index == start_y {
-                            should_highlight = cx >= start_x;
-                        } else if file_row_index == end_y {
-                            should_highlight = cx <= end_x;
-                        }
-                    }
-
-                    // Apply/remove highlighting if state changes
-                    if should_highlight && !is_highlighted {
-                        queue!(stdout, style::SetAttribute(style::Attribute::Reverse))?;
-                        is_highlighted = true;
-                    } else if !should_highlight && is_highlighted {
-                        queue!(stdout, style::ResetColor)?;
-                        is_highlighted = false;
-                    }
-
-                    queue!(stdout, style::Print(char))?;
-                }
-
-                if is_highlighted {
-                    queue!(stdout, style::ResetColor)?;
-                }
-                // --- End Highlighting Logic ---
-            }
-            // Clear rest of the line (in case new line is shorter than old)
-            queue!(stdout, terminal::Clear(ClearType::UntilNewLine))?;
-        }
-        Ok(())
-    }
     /// Draws the status bar at the bottom of the screen.
-    fn draw_status_bar(&self) -> Result<()> {
-        let mut stdout = stdout();
-        queue!(
-            stdout,
-            cursor::MoveTo(0, self.screen_rows as u16),
-            style::SetBackgroundColor(style::Color::DarkGrey),
-            style::SetForegroundColor(style::Color::Black)
-        )?;
+    fn draw_status_bar(&self, frame: &mut Frame) {
+        let bar = Style::DEFAULT
+            .bg(style::Color::DarkGrey)
+            .fg(style::Color::Black);
         // Build status text based on mode
         let (mode_str, status_to_show) = match &self.mode {
             Mode::Normal(_) => ("-- NORMAL --", self.status_msg.clone()),
             Mode::Insert(_) => ("-- INSERT --", self.status_msg.clone()),
             Mode::Visual(_) => ("-- VISUAL --", self.status_msg.clone()), // ‼️
-            Mode::Command(state) => ("", state.command_buffer.clone()),   // ‼️
+            Mode::Command(state) => (
+                "",
+                state
+                    .view(self.terminal_cols.saturating_sub(1))
+                    .0
+                    .to_string(),
+            ),
+            Mode::Confirm(state) => ("", state.prompt.clone()),
         };
-        let file_row = self.cy + self.row_offset + 1;
+        let file_row = self.cy + 1;
         // Get total rows from buffer
         let total_rows = self.buffer.len_lines();
-        let right_status = format!(
-            "{}:{} -- {}/{}",
-            self.cx + 1,
-            file_row,
-            file_row,
-            total_rows
-        );
+        // Like Vim, show "char-screen column" when the two differ (tabs, wide chars)
+        let vcol = display::char_to_col(&self.line_layout(self.cy), self.cx) + 1;
+        let col_str = if vcol == self.cx + 1 {
+            (self.cx + 1).to_string()
+        } else {
+            format!("{}-{}", self.cx + 1, vcol)
+        };
+        // A question or a command line gets the whole line
+        let right_status = if let Mode::Confirm(_) | Mode::Command(_) = self.mode {
+            String::new()
+        } else if let Some(loader) = &self.loader {
+            format!(
+                "{}  {}:{} -- {}/{}",
+                loader.progress(),
+                col_str,
+                file_row,
+                file_row,
+                total_rows
+            )
+        } else {
+            format!("{}:{} -- {}/{}", col_str, file_row, file_row, total_rows)
+        };
         // Build left status string
         let left_status = if !status_to_show.is_empty() {
             status_to_show
         } else {
             // Show mode, filename, and dirty status
-            let filename_str = self.buffer.filename.as_deref().unwrap_or("[No Name]");
+            let filename_str = self
+                .windows
+                .current()
+                .title
+                .or(self.buffer.filename.as_deref())
+                .unwrap_or("[No Name]");
             let dirty_str = if self.buffer.dirty { " [+]" } else { "" };
-            format!("{} \"{}\"{}", mode_str, filename_str, dirty_str)
-        };
-        let right_len = right_status.len();
-        let left_len = left_status
-            .len()
-            .min(self.screen_cols.saturating_sub(right_len + 1));
-        // Ensure left_status is not truncated mid-char
-        let (left_status_truncated, left_len) = if left_status.len() > left_len {
-            let mut new_len = left_len;
-            while !left_status.is_char_boundary(new_len) {
-                new_len -= 1;
-            }
-            (&left_status[..new_len], new_len)
-        } else {
-            (left_status.as_str(), left_len)
+            let readonly_str = if self.buffer.readonly { " [RO]" } else { "" };
+            // Like Vim, only a line ending other than LF is pointed out
+            let format_str = match self.options.fileformat.as_str() {
+                "unix" => String::new(),
+                other => format!(" [{}]", other),
+            };
+            format!(
+                "{} \"{}\"{}{}{}",
+                mode_str, filename_str, dirty_str, readonly_str, format_str
+            )
         };
-        let padding = " ".repeat(self.screen_cols.saturating_sub(left_len + right_len));
-        // Use style::Print for status bar content
-        queue!(
-            stdout,
-            style::Print(left_status_truncated),
-            style::Print(padding),
-            style::Print(right_status),
-            style::ResetColor
-        )?;
-        Ok(())
+        let y = self.status_row();
+        let right_len = display::str_width(&right_status);
+        frame.fill(0, y, self.terminal_cols, bar);
+        // The left part is cut short rather than overlapping the right part
+        let left_width = self.terminal_cols.saturating_sub(right_len + 1);
+        frame.put_str(0, y, display::truncate(&left_status, left_width), bar);
+        frame.put_str(
+            self.terminal_cols.saturating_sub(right_len),
+            y,
+            &right_status,
+            bar,
+        );
     }
 }
+/// Splits a `++enc=name` argument off the arguments of `:e` and `:w`.
+fn split_enc_arg<'a>(args: &[&'a str]) -> (Option<&'a str>, Vec<&'a str>) {
+    let mut encoding = None;
+    let mut rest = Vec::new();
+    for arg in args {
+        match arg.strip_prefix("++enc=") {
+            Some(name) => encoding = Some(name),
+            None => rest.push(*arg),
+        }
+    }
+    (encoding, rest)
+}
 /// Disables raw mode when the Editor is dropped (e.g., on panic or exit).
 impl Drop for Editor {
     fn drop(&mut self) {
+        // After a panic the swap file is all that is left of unsaved changes
+        if !std::thread::panicking() {
+            self.remove_swap();
+            self.windows.remove_swaps();
+        }
+        if !self.terminal {
+            return;
+        }
         terminal::disable_raw_mode().ok();
+        execute!(
+            stdout(),
+            event::DisableBracketedPaste,
+            event::DisableFocusChange
+        )
+        .ok();
+        if self.mouse.captured {
+            execute!(stdout(), event::DisableMouseCapture).ok();
+        }
         execute!(
             stdout(),
             cursor::Show,
@@ -798,8 +2004,18 @@ impl Drop for Editor {
 }
 /// Main function: setup and error handling.
 fn main() -> Result<()> {
+    // `rim -r` lists swap files; `rim -r file` recovers the file from its swap file
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let recover = args.first().is_some_and(|arg| arg == "-r");
+    if recover {
+        args.remove(0);
+    }
+    let filename = args.into_iter().next();
+    if recover && filename.is_none() {
+        return swap::print_list(&Options::default().directory);
+    }
     let run_result = {
-        let mut editor = Editor::new()?;
+        let mut editor = Editor::new(filename, recover)?;
         editor.run()
     };
     if let Err(e) = run_result {
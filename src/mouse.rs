@@ -0,0 +1,258 @@
+use crate::{Editor, Mode, NormalState, VisualState, display};
+use crossterm::event::{
+    DisableMouseCapture, EnableMouseCapture, MouseButton, MouseEvent, MouseEventKind,
+};
+use crossterm::execute;
+use std::io::{Result, stdout};
+use std::time::{Duration, Instant};
+
+/// Clicks on the same cell closer together than this count as a double or triple click
+/// (Vim's default `mousetime`).
+const MULTI_CLICK_TIME: Duration = Duration::from_millis(500);
+
+/// What the editor remembers between mouse events.
+#[derive(Default)]
+pub struct MouseState {
+    /// Whether the terminal is currently reporting mouse events.
+    pub captured: bool,
+    /// Time and screen cell of the last left click.
+    last_click: Option<(Instant, u16, u16)>,
+    /// 1, 2 or 3 for a single, double or triple click.
+    clicks: u8,
+    /// The (char, line) position of the last click, where a drag selection starts.
+    anchor: (usize, usize),
+}
+
+impl Editor {
+    /// Turns terminal mouse reporting on or off to match the `mouse` option.
+    pub(crate) fn sync_mouse_capture(&mut self) -> Result<()> {
+        let wanted = !self.options.mouse.is_empty();
+        if wanted != self.mouse.captured {
+            if wanted {
+                execute!(stdout(), EnableMouseCapture)?;
+            } else {
+                execute!(stdout(), DisableMouseCapture)?;
+            }
+            self.mouse.captured = wanted;
+        }
+        Ok(())
+    }
+
+    /// Handles a mouse event: clicks place the cursor or focus a window, drags select,
+    /// the wheel scrolls.
+    pub(crate) fn process_mouse_event(&mut self, event: MouseEvent) {
+        let flag = match self.mode {
+            Mode::Normal(_) => 'n',
+            Mode::Insert(_) => 'i',
+            Mode::Visual(_) => 'v',
+            Mode::Command(_) | Mode::Confirm(_) => 'c',
+        };
+        // The command line and the picker have nothing to click on yet
+        if !self.options.mouse_enabled(flag) || flag == 'c' || self.picker.is_some() {
+            return;
+        }
+        let (column, row) = (event.column, event.row);
+        if let Mode::Normal(state) = &mut self.mode {
+            state.pending = None;
+        }
+        // Moving the cursor in Insert mode starts a new undo step, as in Vim
+        self.buffer.seal_undo_step();
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => self.mouse_click(column, row),
+            MouseEventKind::Drag(MouseButton::Left) => self.mouse_drag(column, row),
+            MouseEventKind::ScrollDown => self.scroll_lines(true),
+            MouseEventKind::ScrollUp => self.scroll_lines(false),
+            _ => {}
+        }
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+
+    /// Places the cursor, or selects a word or line on a double or triple click. A click
+    /// in another window or on its status line focuses it first. One in the quickfix
+    /// window or on its title line focuses that, one on the status bar gives the focus
+    /// back to the current window, and one on the tab line shows that tab page.
+    fn mouse_click(&mut self, column: u16, row: u16) {
+        let now = Instant::now();
+        self.mouse.clicks = match self.mouse.last_click {
+            Some((time, c, r)) if (c, r) == (column, row) && now - time < MULTI_CLICK_TIME => {
+                self.mouse.clicks % 3 + 1
+            }
+            _ => 1,
+        };
+        self.mouse.last_click = Some((now, column, row));
+        let (column, row) = (column as usize, row as usize);
+        let area = self.text_area();
+        // Above the windows is the tab line, when there are several tab pages
+        if row < area.y {
+            if let Some(tab) = self.tab_at(column) {
+                self.show_tab(tab);
+            }
+            return;
+        }
+        // Below the windows is the quickfix window, if open, then the status bar
+        if row >= area.y + area.rows {
+            if row >= self.status_row() {
+                self.quickfix.set_focus(false);
+            } else if !self.window_locked() {
+                self.quickfix_click(row - area.y - area.rows, self.mouse.clicks == 2);
+            }
+            return;
+        }
+        // The lines between windows side by side belong to none of them
+        let Some((id, rect)) = self.window_at(column, row) else {
+            return;
+        };
+        if !self.focus_window(id) {
+            return;
+        }
+        self.quickfix.set_focus(false);
+        if row >= rect.y + rect.rows {
+            return;
+        }
+        let (cx, cy) = self.position_at(column - rect.x, row - rect.y);
+        self.mouse.anchor = (cx, cy);
+        match self.mouse.clicks {
+            1 => {
+                self.leave_line(cy);
+                if let Mode::Visual(_) = self.mode {
+                    self.mode = Mode::Normal(NormalState::default());
+                    self.status_msg.clear();
+                }
+                self.cx = cx;
+                self.cy = cy;
+            }
+            2 => {
+                let (start, last) = self.word_at(cy, cx);
+                self.select(start, last, cy);
+            }
+            _ => {
+                let last = self.line_layout(cy).last().map_or(0, |g| g.start);
+                self.select(0, last, cy);
+            }
+        }
+    }
+
+    /// Extends a selection from the last click to the cell under the mouse, in the
+    /// current window. Dragging onto its status line selects into the line below it.
+    fn mouse_drag(&mut self, column: u16, row: u16) {
+        // Entries of the quickfix window aren't selected as text
+        if self.quickfix.focused() {
+            return;
+        }
+        let rect = self.window_rect();
+        let x = (column as usize).saturating_sub(rect.x);
+        let y = (row as usize).saturating_sub(rect.y);
+        let (cx, cy) = self.position_at(x, y);
+        if !matches!(self.mode, Mode::Visual(_)) {
+            self.leave_line(cy);
+            self.mode = Mode::Visual(VisualState {
+                selection_start: self.mouse.anchor,
+                g_pending: false,
+            });
+            self.status_msg = "-- VISUAL --".to_string();
+        }
+        self.cx = cx;
+        self.cy = cy;
+    }
+
+    /// Scrolls the view `scroll` lines with the wheel, moving the cursor only
+    /// as far as needed to keep it on screen.
+    fn scroll_lines(&mut self, down: bool) {
+        let last_row = self.buffer.len_lines().saturating_sub(1);
+        let lines = self.options.scroll.max(1);
+        if down {
+            self.row_offset = (self.row_offset + lines).min(last_row);
+            if self.cy < self.row_offset {
+                self.move_to_row(self.row_offset);
+            }
+        } else {
+            self.row_offset = self.row_offset.saturating_sub(lines);
+            let last_visible = self.last_visible_row();
+            if self.cy > last_visible {
+                self.move_to_row(last_visible);
+            }
+        }
+    }
+
+    /// Selects chars `start..=last` of a line in Visual mode, with the cursor at the end.
+    fn select(&mut self, start: usize, last: usize, row: usize) {
+        self.leave_line(row);
+        self.mode = Mode::Visual(VisualState {
+            selection_start: (start, row),
+            g_pending: false,
+        });
+        self.status_msg = "-- VISUAL --".to_string();
+        self.cx = last;
+        self.cy = row;
+    }
+
+    /// Drops an unused autoindent before the mouse takes the cursor to another line.
+    fn leave_line(&mut self, row: usize) {
+        if row != self.cy
+            && let Mode::Insert(state) = &mut self.mode
+        {
+            let auto_indented = state.auto_indented.take();
+            self.remove_unused_indent(auto_indented, self.cy);
+        }
+    }
+
+    /// Returns the first and last grapheme start of the word, run of punctuation or
+    /// run of blanks under char `cx`, as Vim selects on a double click.
+    fn word_at(&self, row: usize, cx: usize) -> (usize, usize) {
+        let graphemes = self.line_layout(row);
+        let index = display::grapheme_at(&graphemes, cx);
+        let Some(g) = graphemes.get(index) else {
+            return (cx, cx);
+        };
+        let class = |g: &display::Grapheme| match g.text.chars().next() {
+            Some(c) if c.is_whitespace() => 0,
+            Some(c) if c.is_alphanumeric() || c == '_' => 1,
+            _ => 2,
+        };
+        let target = class(g);
+        let first = graphemes[..index]
+            .iter()
+            .rposition(|g| class(g) != target)
+            .map_or(0, |i| i + 1);
+        let last = graphemes[index..]
+            .iter()
+            .position(|g| class(g) != target)
+            .map_or(graphemes.len(), |i| index + i)
+            - 1;
+        (graphemes[first].start, graphemes[last].start)
+    }
+
+    /// Maps a cell of the current window to the (char, line) position drawn there. Cells left of the
+    /// text go to the row's first char; cells past its end go to its last char (or
+    /// just past it in Insert mode); rows below the file go to its last line.
+    fn position_at(&self, x: usize, y: usize) -> (usize, usize) {
+        let last_row = self.buffer.len_lines().saturating_sub(1);
+        let mut top = 0;
+        let mut row = self.row_offset;
+        loop {
+            let (graphemes, segments) = self.line_rows(row);
+            if y < top + segments.len() || row >= last_row {
+                let index = (y - top.min(y)).min(segments.len() - 1);
+                let segment = segments[index];
+                let x = x.saturating_sub(self.gutter_width() + segment.prefix);
+                let col = if self.options.wrap {
+                    segment.col + x
+                } else {
+                    self.col_offset + x
+                };
+                // The char at `end` belongs to the next screen row, unless this is the
+                // last one and Insert mode may put the cursor after the line
+                let last = if index + 1 == segments.len() && matches!(self.mode, Mode::Insert(_)) {
+                    segment.end
+                } else {
+                    display::prev_boundary(&graphemes, segment.end).max(segment.start)
+                };
+                let cx = display::col_to_char(&graphemes, col).clamp(segment.start, last);
+                return (cx, row);
+            }
+            top += segments.len();
+            row += 1;
+        }
+    }
+}
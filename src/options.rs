@@ -0,0 +1,483 @@
+use crate::{encoding, errorformat, fileformat};
+
+/// A value held by an option, used when reading or printing it.
+enum Value {
+    Bool(bool),
+    Number(usize),
+    String(String),
+}
+
+/// Full names of every option known to `:set`.
+pub const NAMES: &[&str] = &[
+    "wrap",
+    "linebreak",
+    "showbreak",
+    "breakindent",
+    "tabstop",
+    "shiftwidth",
+    "softtabstop",
+    "expandtab",
+    "autoindent",
+    "smartindent",
+    "number",
+    "mouse",
+    "scroll",
+    "directory",
+    "updatetime",
+    "updatecount",
+    "backup",
+    "backupdir",
+    "fileformat",
+    "fileencoding",
+    "fileencodings",
+    "bomb",
+    "largefile",
+    "autoread",
+    "shell",
+    "makeprg",
+    "errorformat",
+    "errorfile",
+    "complete",
+    "dictionary",
+    "completeopt",
+    "lspservers",
+    "formatprograms",
+    "formatonsave",
+];
+
+/// Short names accepted by `:set`, mapped to the full option name.
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("lbr", "linebreak"),
+    ("sbr", "showbreak"),
+    ("bri", "breakindent"),
+    ("ts", "tabstop"),
+    ("sw", "shiftwidth"),
+    ("sts", "softtabstop"),
+    ("et", "expandtab"),
+    ("ai", "autoindent"),
+    ("si", "smartindent"),
+    ("nu", "number"),
+    ("scr", "scroll"),
+    ("dir", "directory"),
+    ("ut", "updatetime"),
+    ("uc", "updatecount"),
+    ("bk", "backup"),
+    ("bdir", "backupdir"),
+    ("ff", "fileformat"),
+    ("fenc", "fileencoding"),
+    ("fencs", "fileencodings"),
+    ("ar", "autoread"),
+    ("sh", "shell"),
+    ("mp", "makeprg"),
+    ("efm", "errorformat"),
+    ("ef", "errorfile"),
+    ("cpt", "complete"),
+    ("dict", "dictionary"),
+    ("cot", "completeopt"),
+    ("lsp", "lspservers"),
+    ("fps", "formatprograms"),
+    ("fos", "formatonsave"),
+];
+
+/// Values `completeopt` can hold, separated by commas.
+const COMPLETEOPT_VALUES: &[&str] = &["menu", "menuone", "noselect", "preview"];
+
+/// The `errorformat` for rustc and cargo: errors and warnings with the `-->` line
+/// below them that says where they are, leaving out the source snippets and summary
+/// lines, plus the `file:line:col: message` form of many other tools.
+const DEFAULT_ERRORFORMAT: &str = "%-G,%-G %#|%.%#,%-G%l %#|%.%#,\
+%-Gerror: aborting %.%#,%-Gerror: could not compile %.%#,%-Gwarning: %.%#generated %.%#,\
+%Eerror: %m,%Eerror[E%n]: %m,%Wwarning: %m,%Inote: %m,%C %#--> %f:%l:%c,\
+%f:%l:%c: %m,%f:%l: %m";
+
+/// Editor settings changed with `:set`.
+pub struct Options {
+    /// Continue long lines onto the following screen rows.
+    pub wrap: bool,
+    /// Break wrapped lines after a `breakat` character instead of at the last cell.
+    pub linebreak: bool,
+    /// Text shown at the start of every continuation row.
+    pub showbreak: String,
+    /// Indent continuation rows to the same column as the start of the line.
+    pub breakindent: bool,
+    /// Number of columns a tab character advances to.
+    pub tabstop: usize,
+    /// Columns per indent level for `>>`, `<<`, Ctrl-t and Ctrl-d (0 means `tabstop`).
+    pub shiftwidth: usize,
+    /// Columns the Tab and Backspace keys move in Insert mode (0 disables it).
+    pub softtabstop: usize,
+    /// Insert spaces instead of tab characters.
+    pub expandtab: bool,
+    /// Start a new line with the indent of the line above.
+    pub autoindent: bool,
+    /// Indent after `{` and dedent a line starting with `}`.
+    pub smartindent: bool,
+    /// Show line numbers in a gutter left of the text.
+    pub number: bool,
+    /// Modes where the mouse works: `n`, `v`, `i`, `c`, or `a` for all (empty disables it).
+    pub mouse: String,
+    /// Lines scrolled by one step of the mouse wheel.
+    pub scroll: usize,
+    /// Where swap files go: the first of these comma-separated directories where one
+    /// can be written, with `.` (or an empty entry) for next to the edited file.
+    /// Existing swap files are looked for in all of them.
+    pub directory: String,
+    /// Milliseconds without typing after which the swap file is written.
+    pub updatetime: usize,
+    /// Keys typed after which the swap file is written (0 turns swap files off).
+    pub updatecount: usize,
+    /// Keep a copy of the file as it was before each write, named `file~`.
+    pub backup: bool,
+    /// Where backup copies go; empty puts them next to the file.
+    pub backupdir: String,
+    /// Line ending of the file: `unix` (LF), `dos` (CR LF) or `mac` (CR).
+    /// Set from the file when it is loaded; changing it converts the file on the next write.
+    pub fileformat: String,
+    /// Encoding of the file. Set from the file when it is loaded; changing it
+    /// converts the file on the next write.
+    pub fileencoding: String,
+    /// Encodings tried in turn when reading a file; `ucs-bom` stands for "whatever
+    /// a byte order mark says".
+    pub fileencodings: String,
+    /// Write a byte order mark at the start of a Unicode file.
+    pub bomb: bool,
+    /// Size in MB from which a file is loaded in the background, with undo and the
+    /// swap file turned off (0 never does this).
+    pub largefile: usize,
+    /// Reload the file without asking when another program changed it and the buffer
+    /// has no changes of its own.
+    pub autoread: bool,
+    /// Shell that runs the commands of `:!`, `:r !`, `:w !` and filters, with `-c`.
+    pub shell: String,
+    /// Command run by `:make`; `$*` stands for its arguments, which otherwise go at the end.
+    pub makeprg: String,
+    /// Patterns that find file names, lines and messages in the output of `:make`.
+    pub errorformat: String,
+    /// File read by `:cfile` when it is given no name.
+    pub errorfile: String,
+    /// Where Ctrl-n and Ctrl-p look for words, separated by commas: `.` the buffer,
+    /// `b` the buffers of the other windows, `k` the files of `dictionary`.
+    pub complete: String,
+    /// Word list files for completion, separated by commas.
+    pub dictionary: String,
+    /// How completion behaves: `menu` shows a menu of two or more matches, `menuone`
+    /// also of one, `noselect` inserts nothing until a match is picked, `preview`
+    /// shows where the picked match was found.
+    pub completeopt: String,
+    /// The language server started for each filetype, as `filetype:command` entries
+    /// separated by commas, e.g. `rust:rust-analyzer,python:pylsp`. None by default.
+    pub lspservers: String,
+    /// The command `:Format` and `gq` pipe the text through for each filetype, as
+    /// `filetype:command` entries separated by commas, e.g. `rust:rustfmt --emit stdout`;
+    /// `%` stands for the file name. Filetypes without one are formatted by their
+    /// language server.
+    pub formatprograms: String,
+    /// Format the buffer before each write.
+    pub formatonsave: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            wrap: false,
+            linebreak: false,
+            showbreak: String::new(),
+            breakindent: false,
+            tabstop: 8,
+            shiftwidth: 8,
+            softtabstop: 0,
+            expandtab: false,
+            autoindent: false,
+            smartindent: false,
+            number: false,
+            mouse: String::new(),
+            scroll: 3,
+            directory: ".,~/tmp,/var/tmp,/tmp".to_string(),
+            updatetime: 4000,
+            updatecount: 200,
+            backup: false,
+            backupdir: String::new(),
+            fileformat: "unix".to_string(),
+            fileencoding: "utf-8".to_string(),
+            fileencodings: "ucs-bom,utf-8,latin1".to_string(),
+            bomb: false,
+            largefile: 100,
+            autoread: false,
+            shell: std::env::var("SHELL").unwrap_or_else(|_| "sh".to_string()),
+            makeprg: "cargo build".to_string(),
+            errorformat: DEFAULT_ERRORFORMAT.to_string(),
+            errorfile: "errors.err".to_string(),
+            complete: ".,b,k".to_string(),
+            dictionary: String::new(),
+            completeopt: "menu,preview".to_string(),
+            lspservers: String::new(),
+            formatprograms: String::new(),
+            formatonsave: false,
+        }
+    }
+}
+
+impl Options {
+    /// Returns the effective indent width, falling back to `tabstop` like Vim.
+    pub fn shiftwidth(&self) -> usize {
+        if self.shiftwidth == 0 {
+            self.tabstop
+        } else {
+            self.shiftwidth
+        }
+    }
+
+    /// Whether the mouse is enabled in the mode with the given `mouse` flag.
+    pub fn mouse_enabled(&self, flag: char) -> bool {
+        self.mouse.contains('a') || self.mouse.contains(flag)
+    }
+
+    /// The command of the language server for `filetype`, if one is set.
+    pub fn lspserver(&self, filetype: &str) -> Option<&str> {
+        filetype_entry(&self.lspservers, filetype)
+    }
+
+    /// The command that formats `filetype`, if one is set.
+    pub fn formatprogram(&self, filetype: &str) -> Option<&str> {
+        filetype_entry(&self.formatprograms, filetype)
+    }
+
+    /// Whether `completeopt` holds the given value.
+    pub fn completeopt(&self, value: &str) -> bool {
+        self.completeopt.split(',').any(|v| v == value)
+    }
+
+    /// Applies the arguments of a `:set` command.
+    /// Returns the text to show in the status bar, or an error message.
+    pub fn apply(&mut self, args: &str) -> Result<String, String> {
+        let mut shown = Vec::new();
+        for arg in split_args(args) {
+            if let Some(msg) = self.apply_one(&arg)? {
+                shown.push(msg);
+            }
+        }
+        Ok(shown.join("  "))
+    }
+
+    /// Applies a single `:set` argument such as `wrap`, `nowrap`, `wrap!` or `sbr=>`.
+    fn apply_one(&mut self, arg: &str) -> Result<Option<String>, String> {
+        if let Some((name, value)) = arg.split_once('=') {
+            let name = resolve(name).ok_or_else(|| format!("Unknown option: {}", name))?;
+            match self.get(name) {
+                Some(Value::String(_)) => self.set(name, Value::String(value.to_string()))?,
+                Some(Value::Number(_)) => {
+                    let number = value
+                        .parse()
+                        .map_err(|_| format!("Number required after =: {}", arg))?;
+                    self.set(name, Value::Number(number))?;
+                }
+                Some(Value::Bool(_)) => return Err(format!("Invalid argument: {}", arg)),
+                None => return Err(format!("Unknown option: {}", name)),
+            }
+            return Ok(None);
+        }
+        if let Some(name) = arg.strip_suffix('?') {
+            let name = resolve(name).ok_or_else(|| format!("Unknown option: {}", name))?;
+            return Ok(self.get(name).map(|value| show(name, &value)));
+        }
+        // Boolean forms: `name`, `noname`, `invname` and `name!`
+        let (name, toggle, on) = if let Some(name) = arg.strip_suffix('!') {
+            (name, true, true)
+        } else if let Some(name) = arg.strip_prefix("inv") {
+            (name, true, true)
+        } else if let Some(name) = arg.strip_prefix("no").filter(|n| resolve(n).is_some()) {
+            (name, false, false)
+        } else {
+            (arg, false, true)
+        };
+        let name = resolve(name).ok_or_else(|| format!("Unknown option: {}", name))?;
+        match self.get(name) {
+            Some(Value::Bool(current)) => {
+                let value = if toggle { !current } else { on };
+                self.set(name, Value::Bool(value))?;
+                Ok(None)
+            }
+            // `:set tabstop` without a value shows it, like Vim
+            Some(value) => Ok(Some(show(name, &value))),
+            None => Err(format!("Unknown option: {}", name)),
+        }
+    }
+
+    /// Returns the current value of a (full-named) option.
+    fn get(&self, name: &str) -> Option<Value> {
+        Some(match name {
+            "wrap" => Value::Bool(self.wrap),
+            "linebreak" => Value::Bool(self.linebreak),
+            "showbreak" => Value::String(self.showbreak.clone()),
+            "breakindent" => Value::Bool(self.breakindent),
+            "tabstop" => Value::Number(self.tabstop),
+            "shiftwidth" => Value::Number(self.shiftwidth),
+            "softtabstop" => Value::Number(self.softtabstop),
+            "expandtab" => Value::Bool(self.expandtab),
+            "autoindent" => Value::Bool(self.autoindent),
+            "smartindent" => Value::Bool(self.smartindent),
+            "number" => Value::Bool(self.number),
+            "mouse" => Value::String(self.mouse.clone()),
+            "scroll" => Value::Number(self.scroll),
+            "directory" => Value::String(self.directory.clone()),
+            "updatetime" => Value::Number(self.updatetime),
+            "updatecount" => Value::Number(self.updatecount),
+            "backup" => Value::Bool(self.backup),
+            "backupdir" => Value::String(self.backupdir.clone()),
+            "fileformat" => Value::String(self.fileformat.clone()),
+            "fileencoding" => Value::String(self.fileencoding.clone()),
+            "fileencodings" => Value::String(self.fileencodings.clone()),
+            "bomb" => Value::Bool(self.bomb),
+            "largefile" => Value::Number(self.largefile),
+            "autoread" => Value::Bool(self.autoread),
+            "shell" => Value::String(self.shell.clone()),
+            "makeprg" => Value::String(self.makeprg.clone()),
+            "errorformat" => Value::String(self.errorformat.clone()),
+            "errorfile" => Value::String(self.errorfile.clone()),
+            "complete" => Value::String(self.complete.clone()),
+            "dictionary" => Value::String(self.dictionary.clone()),
+            "completeopt" => Value::String(self.completeopt.clone()),
+            "lspservers" => Value::String(self.lspservers.clone()),
+            "formatprograms" => Value::String(self.formatprograms.clone()),
+            "formatonsave" => Value::Bool(self.formatonsave),
+            _ => return None,
+        })
+    }
+
+    /// Stores a new value for a (full-named) option.
+    fn set(&mut self, name: &str, value: Value) -> Result<(), String> {
+        match (name, value) {
+            ("wrap", Value::Bool(b)) => self.wrap = b,
+            ("linebreak", Value::Bool(b)) => self.linebreak = b,
+            ("showbreak", Value::String(s)) => self.showbreak = s,
+            ("breakindent", Value::Bool(b)) => self.breakindent = b,
+            ("tabstop", Value::Number(0)) => return Err("Argument must be positive".to_string()),
+            ("tabstop", Value::Number(n)) => self.tabstop = n,
+            ("shiftwidth", Value::Number(n)) => self.shiftwidth = n,
+            ("softtabstop", Value::Number(n)) => self.softtabstop = n,
+            ("expandtab", Value::Bool(b)) => self.expandtab = b,
+            ("autoindent", Value::Bool(b)) => self.autoindent = b,
+            ("smartindent", Value::Bool(b)) => self.smartindent = b,
+            ("number", Value::Bool(b)) => self.number = b,
+            ("mouse", Value::String(s)) if !s.chars().all(|c| "anvic".contains(c)) => {
+                return Err(format!("Invalid argument: mouse={}", s));
+            }
+            ("mouse", Value::String(s)) => self.mouse = s,
+            ("scroll", Value::Number(n)) => self.scroll = n,
+            ("directory", Value::String(s)) => self.directory = s,
+            ("updatetime", Value::Number(n)) => self.updatetime = n,
+            ("updatecount", Value::Number(n)) => self.updatecount = n,
+            ("backup", Value::Bool(b)) => self.backup = b,
+            ("backupdir", Value::String(s)) => self.backupdir = s,
+            ("fileformat", Value::String(s)) if !fileformat::NAMES.contains(&s.as_str()) => {
+                return Err(format!("Invalid argument: fileformat={}", s));
+            }
+            ("fileformat", Value::String(s)) => self.fileformat = s,
+            ("fileencoding", Value::String(s)) if !encoding::is_known(&s) => {
+                return Err(format!("Invalid argument: fileencoding={}", s));
+            }
+            ("fileencoding", Value::String(s)) => self.fileencoding = s.to_ascii_lowercase(),
+            ("fileencodings", Value::String(s))
+                if !s
+                    .split(',')
+                    .all(|name| name == "ucs-bom" || encoding::is_known(name)) =>
+            {
+                return Err(format!("Invalid argument: fileencodings={}", s));
+            }
+            ("fileencodings", Value::String(s)) => self.fileencodings = s,
+            ("bomb", Value::Bool(b)) => self.bomb = b,
+            ("largefile", Value::Number(n)) => self.largefile = n,
+            ("autoread", Value::Bool(b)) => self.autoread = b,
+            ("shell", Value::String(s)) => self.shell = s,
+            ("makeprg", Value::String(s)) => self.makeprg = s,
+            ("errorformat", Value::String(s)) => {
+                errorformat::Parser::new(&s)?;
+                self.errorformat = s;
+            }
+            ("errorfile", Value::String(s)) => self.errorfile = s,
+            ("complete", Value::String(s))
+                if !s.split(',').all(|v| matches!(v, "" | "." | "b" | "k")) =>
+            {
+                return Err(format!("Invalid argument: complete={}", s));
+            }
+            ("complete", Value::String(s)) => self.complete = s,
+            ("dictionary", Value::String(s)) => self.dictionary = s,
+            ("completeopt", Value::String(s))
+                if !s
+                    .split(',')
+                    .all(|v| v.is_empty() || COMPLETEOPT_VALUES.contains(&v)) =>
+            {
+                return Err(format!("Invalid argument: completeopt={}", s));
+            }
+            ("completeopt", Value::String(s)) => self.completeopt = s,
+            ("lspservers", Value::String(s))
+                if !s.split(',').all(|v| v.is_empty() || v.contains(':')) =>
+            {
+                return Err(format!("Invalid argument: lspservers={}", s));
+            }
+            ("lspservers", Value::String(s)) => self.lspservers = s,
+            ("formatprograms", Value::String(s))
+                if !s.split(',').all(|v| v.is_empty() || v.contains(':')) =>
+            {
+                return Err(format!("Invalid argument: formatprograms={}", s));
+            }
+            ("formatprograms", Value::String(s)) => self.formatprograms = s,
+            ("formatonsave", Value::Bool(b)) => self.formatonsave = b,
+            _ => return Err(format!("Invalid argument: {}", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Finds the command of a `filetype:command` entry in a list separated by commas.
+fn filetype_entry<'a>(list: &'a str, filetype: &str) -> Option<&'a str> {
+    list.split(',')
+        .filter_map(|entry| entry.split_once(':'))
+        .find(|(name, _)| *name == filetype)
+        .map(|(_, command)| command)
+        .filter(|command| !command.trim().is_empty())
+}
+
+/// Resolves an option name or abbreviation to its full name.
+fn resolve(name: &str) -> Option<&'static str> {
+    if let Some((_, full)) = ABBREVIATIONS.iter().find(|(short, _)| *short == name) {
+        return Some(full);
+    }
+    NAMES.iter().copied().find(|full| *full == name)
+}
+
+/// Formats an option for display, e.g. `nowrap` or `showbreak=>`.
+fn show(name: &str, value: &Value) -> String {
+    match value {
+        Value::Bool(true) => name.to_string(),
+        Value::Bool(false) => format!("no{}", name),
+        Value::Number(n) => format!("{}={}", name, n),
+        Value::String(s) => format!("{}={}", name, s),
+    }
+}
+
+/// Splits `:set` arguments on whitespace, honouring `\ ` as an escaped space.
+fn split_args(args: &str) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current = String::new();
+    let mut chars = args.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+            }
+            c if c.is_whitespace() => {
+                if !current.is_empty() {
+                    result.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
@@ -0,0 +1,357 @@
+use crate::fuzzy::Query;
+use crate::render::{Frame, Style};
+use crate::{Editor, display};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::{Attribute, Color};
+use ignore::WalkBuilder;
+use std::io::Result;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rows the picker covers at most, its header included.
+const HEIGHT: usize = 16;
+
+/// Best matches sent back to the editor; the rest are only counted.
+const RESULTS: usize = 200;
+
+/// Paths the walker collects before handing them to the matcher...
+const BATCH_SIZE: usize = 1000;
+
+/// ...or sooner, so the first results show up quickly.
+const BATCH_TIME: Duration = Duration::from_millis(50);
+
+/// What the matcher thread is told, by the walker and by the editor.
+enum Input {
+    /// More files found by the walker.
+    Paths(Vec<String>),
+    /// The walk is over.
+    Done,
+    /// The user changed the query.
+    Query(String),
+}
+
+/// One path that matches the query.
+struct Match {
+    path: String,
+    /// Char indices of the path's characters that matched, to highlight.
+    positions: Vec<usize>,
+}
+
+/// What the matcher sends back after each change.
+#[derive(Default)]
+struct Results {
+    /// The query these results are for.
+    query: String,
+    /// The best matches, best first.
+    matches: Vec<Match>,
+    /// How many paths match.
+    matched: usize,
+    /// How many paths were found so far.
+    total: usize,
+    /// Whether the walker is still looking for files.
+    scanning: bool,
+}
+
+/// The `:Files` picker. The walk over the working directory and the scoring of paths
+/// run in two threads of their own, so large trees don't hold up typing; dropping
+/// the picker makes both stop.
+pub struct Picker {
+    query: String,
+    sender: Sender<Input>,
+    receiver: Receiver<Results>,
+    /// The latest results received.
+    results: Results,
+    /// Index in `results.matches` of the path Enter opens.
+    selected: usize,
+}
+
+impl Picker {
+    fn new() -> Self {
+        let (sender, inputs) = mpsc::channel();
+        let (results, receiver) = mpsc::channel();
+        let paths = sender.clone();
+        thread::spawn(move || walk(paths));
+        thread::spawn(move || match_paths(inputs, results));
+        Self {
+            query: String::new(),
+            sender,
+            receiver,
+            results: Results {
+                scanning: true,
+                ..Results::default()
+            },
+            selected: 0,
+        }
+    }
+
+    /// Whether results are still to come, so the editor should check back soon.
+    fn waiting(&self) -> bool {
+        self.results.scanning || self.results.query != self.query
+    }
+
+    /// Moves the selection one match further from the best.
+    fn select_worse(&mut self) {
+        if self.selected + 1 < self.results.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Tells the matcher about a change to the query.
+    fn query_changed(&mut self) {
+        self.selected = 0;
+        self.sender.send(Input::Query(self.query.clone())).ok();
+    }
+}
+
+/// Walks the working directory, leaving out what `.gitignore` files and hidden
+/// names leave out, and passes the files found on in batches.
+fn walk(sender: Sender<Input>) {
+    let mut batch = Vec::new();
+    let mut sent = Instant::now();
+    for found in WalkBuilder::new(".").require_git(false).build() {
+        let Ok(found) = found else {
+            continue;
+        };
+        if !found.file_type().is_some_and(|t| t.is_file()) {
+            continue;
+        }
+        let path = found.path();
+        batch.push(
+            path.strip_prefix(".")
+                .unwrap_or(path)
+                .to_string_lossy()
+                .into_owned(),
+        );
+        if batch.len() >= BATCH_SIZE || sent.elapsed() >= BATCH_TIME {
+            // The picker was closed
+            if sender
+                .send(Input::Paths(std::mem::take(&mut batch)))
+                .is_err()
+            {
+                return;
+            }
+            sent = Instant::now();
+        }
+    }
+    sender.send(Input::Paths(batch)).ok();
+    sender.send(Input::Done).ok();
+}
+
+/// Scores the paths against the query and sends back the best of them. New paths are
+/// scored as they come in; a new query scores them all again. Whatever piled up while
+/// busy is handled in one go, so a fast typist doesn't leave a backlog of queries.
+fn match_paths(inputs: Receiver<Input>, results: Sender<Results>) {
+    let mut paths: Vec<String> = Vec::new();
+    let mut query_text = String::new();
+    let mut query = Query::new("");
+    let mut scanning = true;
+    // Score and index of each matching path
+    let mut scored: Vec<(i64, usize)> = Vec::new();
+    while let Ok(first) = inputs.recv() {
+        let mut next = Some(first);
+        let scored_up_to = paths.len();
+        let mut new_query = false;
+        while let Some(input) = next {
+            match input {
+                Input::Paths(batch) => paths.extend(batch),
+                Input::Done => scanning = false,
+                Input::Query(text) => {
+                    query_text = text;
+                    new_query = true;
+                }
+            }
+            next = match inputs.try_recv() {
+                Ok(input) => Some(input),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            };
+        }
+        let start = if new_query {
+            query = Query::new(&query_text);
+            scored.clear();
+            0
+        } else {
+            scored_up_to
+        };
+        for (index, path) in paths.iter().enumerate().skip(start) {
+            if query.is_empty() {
+                scored.push((0, index));
+            } else if let Some((score, _)) = query.score(path) {
+                scored.push((score, index));
+            }
+        }
+        // Best score first, then the shorter path, then the order they were found in
+        let order = |a: &(i64, usize), b: &(i64, usize)| {
+            b.0.cmp(&a.0)
+                .then(paths[a.1].len().cmp(&paths[b.1].len()))
+                .then(a.1.cmp(&b.1))
+        };
+        if scored.len() > RESULTS {
+            scored.select_nth_unstable_by(RESULTS, order);
+        }
+        let best = scored.len().min(RESULTS);
+        scored[..best].sort_unstable_by(order);
+        let matches = scored[..best]
+            .iter()
+            .map(|&(_, index)| Match {
+                path: paths[index].clone(),
+                // Only the paths shown need their matched characters
+                positions: query
+                    .score(&paths[index])
+                    .map(|(_, positions)| positions)
+                    .unwrap_or_default(),
+            })
+            .collect();
+        let sent = results.send(Results {
+            query: query_text.clone(),
+            matches,
+            matched: scored.len(),
+            total: paths.len(),
+            scanning,
+        });
+        if sent.is_err() {
+            return;
+        }
+    }
+}
+
+impl Editor {
+    /// Opens the file picker for `:Files` and `\f`.
+    pub(crate) fn open_picker(&mut self) {
+        self.picker = Some(Picker::new());
+    }
+
+    /// Takes in the latest results of the picker's threads.
+    pub(crate) fn poll_picker(&mut self) {
+        let Some(picker) = &mut self.picker else {
+            return;
+        };
+        // Only the most recent results matter
+        while let Ok(results) = picker.receiver.try_recv() {
+            picker.results = results;
+        }
+        picker.selected = picker
+            .selected
+            .min(picker.results.matches.len().saturating_sub(1));
+    }
+
+    /// Whether the picker is waiting on its threads, to poll for results more often.
+    pub(crate) fn picker_waiting(&self) -> bool {
+        self.picker.as_ref().is_some_and(Picker::waiting)
+    }
+
+    /// Handles a key while the picker is open: typing edits the query, the arrows pick
+    /// a path, and Enter opens it.
+    pub(crate) fn process_picker_keypress(&mut self, event: KeyEvent) -> Result<bool> {
+        let Some(picker) = &mut self.picker else {
+            return Ok(true);
+        };
+        let control = event.modifiers.contains(KeyModifiers::CONTROL);
+        match event.code {
+            KeyCode::Esc => self.picker = None,
+            KeyCode::Char('c') if control => self.picker = None,
+            // The list grows upwards from the prompt, so up is the next worse match
+            KeyCode::Char('p' | 'k') if control => picker.select_worse(),
+            KeyCode::Up => picker.select_worse(),
+            KeyCode::Char('n' | 'j') if control => {
+                picker.selected = picker.selected.saturating_sub(1)
+            }
+            KeyCode::Down => picker.selected = picker.selected.saturating_sub(1),
+            KeyCode::Enter => self.open_picked(None),
+            // Ctrl-s or Ctrl-x opens it in a split, Ctrl-v in a vertical one, Ctrl-t in a tab
+            KeyCode::Char(key @ ('s' | 'x' | 'v' | 't')) if control => self.open_picked(Some(key)),
+            KeyCode::Char('u') if control => {
+                picker.query.clear();
+                picker.query_changed();
+            }
+            KeyCode::Backspace if picker.query.pop().is_some() => picker.query_changed(),
+            KeyCode::Char(c) if !control => {
+                picker.query.push(c);
+                picker.query_changed();
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    /// Closes the picker and edits the selected path: in the current window, or in a
+    /// new one for the Ctrl `key` typed.
+    fn open_picked(&mut self, key: Option<char>) {
+        let Some(picker) = self.picker.take() else {
+            return;
+        };
+        let Some(picked) = picker.results.matches.get(picker.selected) else {
+            return;
+        };
+        let path = picked.path.as_str();
+        match key {
+            Some('t') => self.new_tab(&[path]),
+            Some(key) => self.split_window(key == 'v', &[path]),
+            None => self.edit(false, &[path]),
+        }
+    }
+
+    /// Draws the picker over the bottom of the text area: a header, the best matches
+    /// with the best one at the bottom, and the query in place of the status bar.
+    pub(crate) fn draw_picker(&self, frame: &mut Frame) {
+        let Some(picker) = &self.picker else {
+            return;
+        };
+        let area = self.text_area();
+        let bottom = area.y + area.rows;
+        let height = HEIGHT.min(area.rows);
+        let bar = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        if height > 0 {
+            let top = bottom - height;
+            let results = &picker.results;
+            let header = format!(
+                "Files {}/{}{}",
+                results.matched,
+                results.total,
+                if results.scanning { " (scanning)" } else { "" }
+            );
+            frame.fill(0, top, self.terminal_cols, bar);
+            frame.put_str(0, top, &header, bar.attr(Attribute::Bold));
+            let rows = height - 1;
+            // Scrolled up far enough to show the selected path
+            let first = picker.selected.saturating_sub(rows.saturating_sub(1));
+            for row in 0..rows {
+                let y = bottom - 1 - row;
+                let selected = first + row == picker.selected;
+                let style = if selected {
+                    Style::DEFAULT.bg(Color::DarkGrey)
+                } else {
+                    Style::DEFAULT
+                };
+                frame.fill(0, y, self.terminal_cols, style);
+                let Some(found) = results.matches.get(first + row) else {
+                    continue;
+                };
+                let highlight = style.fg(Color::Yellow).attr(Attribute::Bold);
+                let mut x = frame.put_str(0, y, if selected { "> " } else { "  " }, style);
+                for (i, c) in found.path.chars().enumerate() {
+                    let style = if found.positions.contains(&i) {
+                        highlight
+                    } else {
+                        style
+                    };
+                    x = frame.put_str(x, y, c.encode_utf8(&mut [0; 4]), style);
+                }
+            }
+        }
+        let y = self.status_row();
+        frame.fill(0, y, self.terminal_cols, Style::DEFAULT);
+        frame.put_str(0, y, &format!("Files> {}", picker.query), Style::DEFAULT);
+    }
+
+    /// Where the cursor goes while the picker is open: after the query.
+    pub(crate) fn picker_cursor_pos(&self) -> Option<(usize, usize)> {
+        let picker = self.picker.as_ref()?;
+        let x = display::str_width("Files> ") + display::str_width(&picker.query);
+        Some((
+            x.min(self.terminal_cols.saturating_sub(1)),
+            self.status_row(),
+        ))
+    }
+}
@@ -0,0 +1,666 @@
+use crate::errorformat::{Entry, Parser};
+use crate::render::{Frame, Style};
+use crate::{CommandState, Editor, Mode, encoding, fileformat, shell};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::{Attribute, Color};
+use std::fs;
+use std::io::{Read, Result, Write, stdout};
+use std::process::{ExitStatus, Stdio};
+
+/// Entry rows of the quickfix window when `:copen` is given no height.
+pub const DEFAULT_HEIGHT: usize = 10;
+
+/// The quickfix commands: full name, shortest abbreviation, and what it does. The `l`
+/// commands do the same as their `c` counterparts, on the location list.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    ("make", "mak", "make"),
+    ("cnext", "cn", "next"),
+    ("cNext", "cN", "previous"),
+    ("cprevious", "cp", "previous"),
+    ("cc", "cc", "cc"),
+    ("cfirst", "cfir", "first"),
+    ("crewind", "cr", "first"),
+    ("clast", "cla", "last"),
+    ("copen", "cope", "open"),
+    ("cclose", "ccl", "close"),
+    ("cfile", "cf", "file"),
+    ("cdo", "cdo", "do"),
+    ("cfdo", "cfdo", "fdo"),
+    ("vimgrep", "vim", "vimgrep"),
+    ("grep", "gr", "grep"),
+    ("lnext", "lne", "next"),
+    ("lNext", "lN", "previous"),
+    ("lprevious", "lp", "previous"),
+    ("ll", "ll", "cc"),
+    ("lfirst", "lfir", "first"),
+    ("lrewind", "lr", "first"),
+    ("llast", "lla", "last"),
+    ("lopen", "lope", "open"),
+    ("lclose", "lcl", "close"),
+    ("ldo", "ldo", "do"),
+    ("lfdo", "lfdo", "fdo"),
+    ("lvimgrep", "lv", "vimgrep"),
+    ("lgrep", "lgr", "grep"),
+];
+
+/// Which of the two lists a command works on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ListKind {
+    /// The list filled by `:make`, `:cfile`, `:vimgrep` and `:grep`.
+    Quickfix,
+    /// A second list, filled by `:lvimgrep` and `:lgrep`, so a search doesn't replace the
+    /// errors of the last build. Vim keeps one per window; rim keeps one for all.
+    Location,
+}
+
+/// A quickfix command recognised by `command_name`.
+pub struct Command {
+    /// What the command does, the same for `:cnext` and `:lnext`: `next`, `open`, ...
+    pub action: &'static str,
+    pub kind: ListKind,
+    /// Whether the name ended in `!`.
+    pub bang: bool,
+}
+
+/// The full names of the quickfix commands, for completing command names.
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|(full, _, _)| *full)
+}
+
+/// Recognises a quickfix command name such as `:cn`, `:cc!` or `:lvimgrep`.
+pub fn command_name(word: &str) -> Option<Command> {
+    let word = word.strip_prefix(':').unwrap_or(word);
+    let (word, bang) = match word.strip_suffix('!') {
+        Some(word) => (word, true),
+        None => (word, false),
+    };
+    COMMANDS
+        .iter()
+        .find(|(full, short, _)| full.starts_with(word) && word.starts_with(short))
+        .map(|(full, _, action)| Command {
+            action,
+            kind: if full.starts_with('l') {
+                ListKind::Location
+            } else {
+                ListKind::Quickfix
+            },
+            bang,
+        })
+}
+
+/// A list of places in files, with other output lines kept as text.
+#[derive(Default)]
+pub struct List {
+    entries: Vec<Entry>,
+    /// The entry last jumped to.
+    current: usize,
+    /// The command that filled the list, shown above the window.
+    title: String,
+}
+
+impl List {
+    /// The first entry with a place in a file among `indices`.
+    fn find_valid(&self, mut indices: impl Iterator<Item = usize>) -> Option<usize> {
+        indices.find(|&i| self.entries[i].valid())
+    }
+}
+
+/// The quickfix and location lists, and the window that shows one of them.
+#[derive(Default)]
+pub struct Quickfix {
+    quickfix: List,
+    location: List,
+    window: Option<Window>,
+}
+
+/// The window opened with `:copen` or `:lopen`, between the text and the status bar.
+struct Window {
+    /// The list shown.
+    kind: ListKind,
+    /// Entry rows, not counting the title line.
+    height: usize,
+    /// Whether keys go to the window instead of the text.
+    focused: bool,
+    /// The first entry shown.
+    top: usize,
+    /// The entry under the window's cursor.
+    selected: usize,
+}
+
+impl Quickfix {
+    fn list(&self, kind: ListKind) -> &List {
+        match kind {
+            ListKind::Quickfix => &self.quickfix,
+            ListKind::Location => &self.location,
+        }
+    }
+
+    fn list_mut(&mut self, kind: ListKind) -> &mut List {
+        match kind {
+            ListKind::Quickfix => &mut self.quickfix,
+            ListKind::Location => &mut self.location,
+        }
+    }
+
+    /// The window, if it shows the list of `kind`.
+    fn window_of(&mut self, kind: ListKind) -> Option<&mut Window> {
+        self.window.as_mut().filter(|window| window.kind == kind)
+    }
+
+    /// Rows the window takes below the text, its title line included. The text keeps at
+    /// least one row, and a terminal with too few rows leaves the window out.
+    pub fn window_rows(&self, terminal_rows: usize) -> usize {
+        self.window.as_ref().map_or(0, |window| {
+            let rows = (window.height + 1).min(terminal_rows.saturating_sub(2));
+            if rows < 2 { 0 } else { rows }
+        })
+    }
+
+    /// Whether keys go to the quickfix window.
+    pub fn focused(&self) -> bool {
+        self.window.as_ref().is_some_and(|window| window.focused)
+    }
+
+    /// Gives the keys back to the text, or to the window if it is open.
+    pub fn set_focus(&mut self, focused: bool) {
+        if let Some(window) = &mut self.window {
+            window.focused = focused;
+        }
+    }
+
+    /// Scrolls the window so the selected entry is one of the `visible` rows.
+    pub fn scroll_to_selected(&mut self, visible: usize) {
+        if let Some(window) = &mut self.window {
+            window.top = window.top.min(window.selected);
+            if window.selected >= window.top + visible {
+                window.top = window.selected + 1 - visible.max(1);
+            }
+        }
+    }
+}
+
+/// Whether two names refer to the same file, e.g. `src/main.rs` and `./src/main.rs`.
+pub fn same_file(a: &str, b: &str) -> bool {
+    a == b
+        || matches!(
+            (fs::canonicalize(a), fs::canonicalize(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+/// How an entry is listed in the window, like Vim: `file|line col N error 425| text`.
+fn entry_line(entry: &Entry) -> String {
+    let text = entry.text.replace(['\n', '\t'], " ");
+    let Some(filename) = &entry.filename else {
+        return format!("|| {}", text);
+    };
+    let mut location = String::new();
+    if entry.line > 0 {
+        location.push_str(&entry.line.to_string());
+        if entry.col > 0 {
+            location.push_str(&format!(" col {}", entry.col));
+        }
+    }
+    let kind = entry.kind_name();
+    if !kind.is_empty() {
+        if !location.is_empty() {
+            location.push(' ');
+        }
+        location.push_str(&kind);
+    }
+    format!("{}|{}| {}", filename, location, text)
+}
+
+/// Splits the command given to `:cdo` at each `|`, as Vim does; `\|` is a `|` that
+/// belongs to the command. Each part gets the `:` that `execute_command` expects.
+fn split_bar(command: &str) -> Vec<String> {
+    let mut commands = vec![String::new()];
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'|') => {
+                chars.next();
+                commands.last_mut().unwrap().push('|');
+            }
+            '|' => commands.push(String::new()),
+            c => commands.last_mut().unwrap().push(c),
+        }
+    }
+    commands
+        .iter()
+        .map(|command| command.trim())
+        .filter(|command| !command.is_empty())
+        .map(|command| format!(":{}", command.trim_start_matches(':')))
+        .collect()
+}
+
+impl Editor {
+    /// Runs a quickfix command; `args` is the text after its name.
+    /// Returns false if the editor should quit, which `:cdo q` can do.
+    pub(crate) fn quickfix_command(&mut self, command: Command, args: &str) -> Result<bool> {
+        let Command { action, kind, bang } = command;
+        let args = args.trim();
+        match action {
+            "make" => self.make(args, !bang)?,
+            "file" => self.load_error_file(args),
+            "vimgrep" | "grep" => self.grep(kind, args, action == "vimgrep")?,
+            "do" | "fdo" => return self.run_on_entries(kind, action == "fdo", args),
+            "open" => match args {
+                "" => self.open_quickfix_window(kind, DEFAULT_HEIGHT),
+                _ => match args.parse() {
+                    Ok(height) if height > 0 => self.open_quickfix_window(kind, height),
+                    _ => self.status_msg = format!("Invalid argument: {}", args),
+                },
+            },
+            "close" => self.close_quickfix_window(kind),
+            _ => self.quickfix_jump_command(kind, action, bang, args),
+        }
+        Ok(true)
+    }
+
+    /// Carries out `:cn`, `:cp`, `:cc [N]`, `:cfirst` and `:clast` and their `l` forms.
+    /// Entries that are just text from the output are skipped.
+    fn quickfix_jump_command(&mut self, kind: ListKind, action: &str, force: bool, args: &str) {
+        let list = self.quickfix.list(kind);
+        let len = list.entries.len();
+        if list.find_valid(0..len).is_none() {
+            self.status_msg = "No Errors".to_string();
+            return;
+        }
+        let index = match action {
+            "next" => list.find_valid(list.current + 1..len),
+            "previous" => list.find_valid((0..list.current).rev()),
+            "first" => list.find_valid(0..len),
+            "last" => list.find_valid((0..len).rev()),
+            _ => {
+                let wanted = match args {
+                    "" => list.current + 1,
+                    _ => match args.parse::<usize>() {
+                        Ok(number) if number > 0 => number,
+                        _ => {
+                            self.status_msg = format!("Invalid argument: {}", args);
+                            return;
+                        }
+                    },
+                };
+                // Past the end means the last entry; a text line means the next error
+                let wanted = wanted.min(len) - 1;
+                list.find_valid(wanted..len)
+                    .or_else(|| list.find_valid((0..wanted).rev()))
+            }
+        };
+        match index {
+            Some(index) => {
+                self.jump_to_entry(kind, index, force);
+            }
+            None => self.status_msg = "No more items".to_string(),
+        }
+    }
+
+    /// Goes to the file, line and column of an entry, opening the file if needed.
+    /// `force` drops changes to the current file, as with `:e!`. Returns false if the
+    /// file could not be opened.
+    fn jump_to_entry(&mut self, kind: ListKind, index: usize, force: bool) -> bool {
+        let list = self.quickfix.list(kind);
+        let entry = &list.entries[index];
+        let Some(filename) = entry.filename.clone() else {
+            return false;
+        };
+        let (line, col) = (entry.line, entry.col);
+        let mut message = format!("({} of {})", index + 1, list.entries.len());
+        let kind_name = entry.kind_name();
+        if !kind_name.is_empty() {
+            message.push(' ');
+            message.push_str(&kind_name);
+        }
+        message.push_str(": ");
+        message.push_str(entry.text.lines().next().unwrap_or_default());
+        let current_file = self.buffer.filename.as_deref();
+        if !current_file.is_some_and(|current| same_file(current, &filename)) {
+            self.edit(force, &[&filename]);
+            // `edit` has said why the file could not be opened
+            let opened = self.buffer.filename.as_deref();
+            if !opened.is_some_and(|opened| same_file(opened, &filename)) {
+                return false;
+            }
+        }
+        self.cy = line
+            .saturating_sub(1)
+            .min(self.buffer.len_lines().saturating_sub(1));
+        self.cx = col.saturating_sub(1);
+        self.quickfix.list_mut(kind).current = index;
+        if let Some(window) = self.quickfix.window_of(kind) {
+            window.selected = index;
+        }
+        self.quickfix.set_focus(false);
+        self.quickfix_layout();
+        self.status_msg = message;
+        true
+    }
+
+    /// Runs `command` at each entry of a list (`:cdo`), or once in each file the list
+    /// points to (`:cfdo`). Changes to a file stay one undo step. A file must be written,
+    /// e.g. with `:cdo s/a/b/ | update`, before the next one can be opened.
+    fn run_on_entries(&mut self, kind: ListKind, per_file: bool, command: &str) -> Result<bool> {
+        let commands = split_bar(command);
+        if commands.is_empty() {
+            self.status_msg = "Argument required".to_string();
+            return Ok(true);
+        }
+        let list = self.quickfix.list(kind);
+        let mut targets = Vec::new();
+        for (index, entry) in list.entries.iter().enumerate() {
+            let Some(filename) = &entry.filename else {
+                continue;
+            };
+            let seen = targets
+                .iter()
+                .any(|&i: &usize| list.entries[i].filename.as_ref() == Some(filename));
+            if !per_file || !seen {
+                targets.push(index);
+            }
+        }
+        if targets.is_empty() {
+            self.status_msg = "No Errors".to_string();
+            return Ok(true);
+        }
+        for index in targets {
+            if !self.jump_to_entry(kind, index, false) {
+                break;
+            }
+            for command in &commands {
+                if !self.execute_command(command)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs `makeprg` with `args`, showing its output as it comes, and fills the quickfix
+    /// list from it. `jump` goes to the first error, as `:make` does and `:make!` doesn't.
+    fn make(&mut self, args: &str, jump: bool) -> Result<()> {
+        let program = &self.options.makeprg;
+        // `$*` in `makeprg` says where the arguments go; otherwise they go at the end
+        let program = if program.contains("$*") {
+            program.replace("$*", args)
+        } else {
+            format!("{} {}", program, args)
+        };
+        let cmd = match shell::expand(
+            program.trim(),
+            self.buffer.filename.as_deref(),
+            self.alternate_file.as_deref(),
+        ) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.status_msg = e.to_string();
+                return Ok(());
+            }
+        };
+        let parser = match Parser::new(&self.options.errorformat) {
+            Ok(parser) => parser,
+            Err(msg) => {
+                self.status_msg = msg;
+                return Ok(());
+            }
+        };
+        self.suspend_terminal()?;
+        let mut out = stdout();
+        writeln!(out, "\n:!{}", cmd)?;
+        out.flush()?;
+        let (output, message) = match self.run_make(&cmd) {
+            Ok((output, status)) => (output, shell::failure(status)),
+            Err(e) => (Vec::new(), Some(format!("Cannot run shell: {}", e))),
+        };
+        self.return_to_editor(message.as_deref())?;
+        let text = encoding::decode(&output, "utf-8", Some("utf-8")).text;
+        let text = fileformat::to_lf(&text, fileformat::detect(&text));
+        let entries = parser.parse(&text);
+        let found = entries.iter().any(Entry::valid);
+        self.set_quickfix_list(ListKind::Quickfix, entries, format!(":{}", cmd), jump);
+        if let Some(message) = message
+            && !found
+        {
+            self.status_msg = message;
+        }
+        // The build may have written files, such as generated code
+        self.check_time(false);
+        Ok(())
+    }
+
+    /// Runs a build command with its error output joined to its standard output.
+    /// Everything is shown on the terminal as well as returned.
+    fn run_make(&self, cmd: &str) -> Result<(Vec<u8>, ExitStatus)> {
+        let mut child = self
+            .shell(&format!("({}) 2>&1", cmd), false)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut output = Vec::new();
+        if let Some(mut pipe) = child.stdout.take() {
+            let mut out = stdout();
+            let mut chunk = [0; 4096];
+            loop {
+                let n = pipe.read(&mut chunk)?;
+                if n == 0 {
+                    break;
+                }
+                out.write_all(&chunk[..n])?;
+                out.flush()?;
+                output.extend_from_slice(&chunk[..n]);
+            }
+        }
+        Ok((output, child.wait()?))
+    }
+
+    /// Fills the quickfix list from a file of errors for `:cfile` (`errorfile` by default).
+    fn load_error_file(&mut self, name: &str) {
+        let name = if name.is_empty() {
+            self.options.errorfile.clone()
+        } else {
+            name.to_string()
+        };
+        let parser = match Parser::new(&self.options.errorformat) {
+            Ok(parser) => parser,
+            Err(msg) => {
+                self.status_msg = msg;
+                return;
+            }
+        };
+        let bytes = match fs::read(&name) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.status_msg = format!("Cannot open errorfile {}: {}", name, e);
+                return;
+            }
+        };
+        let text = encoding::decode(&bytes, "utf-8", Some("utf-8")).text;
+        let text = fileformat::to_lf(&text, fileformat::detect(&text));
+        let title = format!(":cfile {}", name);
+        self.set_quickfix_list(ListKind::Quickfix, parser.parse(&text), title, true);
+    }
+
+    /// Replaces a list, and goes to its first entry if `jump` is set.
+    pub(crate) fn set_quickfix_list(
+        &mut self,
+        kind: ListKind,
+        entries: Vec<Entry>,
+        title: String,
+        jump: bool,
+    ) {
+        let list = self.quickfix.list_mut(kind);
+        list.entries = entries;
+        list.title = title;
+        list.current = list.find_valid(0..list.entries.len()).unwrap_or(0);
+        let (current, len) = (list.current, list.entries.len());
+        if let Some(window) = self.quickfix.window_of(kind) {
+            window.top = 0;
+            window.selected = current;
+        }
+        self.quickfix_layout();
+        if !self.quickfix.list(kind).entries.iter().any(Entry::valid) {
+            self.status_msg = "No Errors".to_string();
+        } else if jump {
+            self.jump_to_entry(kind, current, false);
+        } else {
+            let name = match kind {
+                ListKind::Quickfix => "quickfix",
+                ListKind::Location => "location",
+            };
+            self.status_msg = format!(
+                "{} {} in the {} list",
+                len,
+                if len == 1 { "entry" } else { "entries" },
+                name
+            );
+        }
+    }
+
+    /// Opens the window on a list with `height` entry rows and moves the keys into it.
+    /// A window showing the other list switches to this one.
+    pub(crate) fn open_quickfix_window(&mut self, kind: ListKind, height: usize) {
+        let current = self.quickfix.list(kind).current;
+        let window = self.quickfix.window.get_or_insert(Window {
+            kind,
+            height,
+            focused: true,
+            top: 0,
+            selected: current,
+        });
+        if window.kind != kind {
+            window.kind = kind;
+            window.top = 0;
+            window.selected = current;
+        }
+        window.height = height;
+        window.focused = true;
+        self.quickfix_layout();
+    }
+
+    /// Closes the window if it shows the list of `kind`.
+    fn close_quickfix_window(&mut self, kind: ListKind) {
+        if self.quickfix.window_of(kind).is_some() {
+            self.quickfix.window = None;
+            self.quickfix_layout();
+        }
+    }
+
+    /// Shares the terminal between the windows and the quickfix window after it opened,
+    /// closed, or moved its selection.
+    pub(crate) fn quickfix_layout(&mut self) {
+        let window_rows = self.quickfix.window_rows(self.terminal_rows);
+        self.quickfix
+            .scroll_to_selected(window_rows.saturating_sub(1));
+        self.layout();
+    }
+
+    /// Handles a key while the quickfix window has the focus: `j` and `k` move through
+    /// the list, Enter goes to the entry, `q` closes the window.
+    pub(crate) fn process_quickfix_keypress(&mut self, event: KeyEvent) -> Result<bool> {
+        let Some(window) = &mut self.quickfix.window else {
+            return Ok(true);
+        };
+        let kind = window.kind;
+        let len = match kind {
+            ListKind::Quickfix => self.quickfix.quickfix.entries.len(),
+            ListKind::Location => self.quickfix.location.entries.len(),
+        };
+        match event.code {
+            KeyCode::Char('j') | KeyCode::Down if window.selected + 1 < len => {
+                window.selected += 1;
+            }
+            KeyCode::Char('k') | KeyCode::Up => window.selected = window.selected.saturating_sub(1),
+            KeyCode::Char('G') => window.selected = len.saturating_sub(1),
+            KeyCode::Enter if window.selected < len => {
+                let selected = window.selected;
+                if self.quickfix.list(kind).entries[selected].valid() {
+                    self.jump_to_entry(kind, selected, false);
+                }
+            }
+            KeyCode::Char('q') => self.close_quickfix_window(kind),
+            KeyCode::Char('w') if event.modifiers == KeyModifiers::CONTROL => {
+                if let Mode::Normal(state) = &mut self.mode {
+                    state.pending = Some('\u{17}');
+                }
+            }
+            KeyCode::Char(':') => {
+                self.mode = Mode::Command(CommandState::new(":".to_string()));
+            }
+            _ => {}
+        }
+        self.quickfix_layout();
+        Ok(true)
+    }
+
+    /// Handles a click on the quickfix window: `row` 0 is its title. A click selects an
+    /// entry and a double click goes to it.
+    pub(crate) fn quickfix_click(&mut self, row: usize, double: bool) {
+        let Some(window) = &mut self.quickfix.window else {
+            return;
+        };
+        window.focused = true;
+        let kind = window.kind;
+        let index = window.top + row.saturating_sub(1);
+        let len = match kind {
+            ListKind::Quickfix => self.quickfix.quickfix.entries.len(),
+            ListKind::Location => self.quickfix.location.entries.len(),
+        };
+        if row == 0 || index >= len {
+            return;
+        }
+        window.selected = index;
+        if double && self.quickfix.list(kind).entries[index].valid() {
+            self.jump_to_entry(kind, index, false);
+        }
+    }
+
+    /// Draws the quickfix window below the text: a title line, then one row per entry.
+    /// The entry last jumped to is highlighted.
+    pub(crate) fn draw_quickfix(&self, frame: &mut Frame) {
+        let rows = self.quickfix.window_rows(self.terminal_rows);
+        let Some(window) = &self.quickfix.window else {
+            return;
+        };
+        if rows == 0 {
+            return;
+        }
+        let list = self.quickfix.list(window.kind);
+        let mut bar = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        if window.focused {
+            bar = bar.attr(Attribute::Bold);
+        }
+        let area = self.text_area();
+        let y = area.y + area.rows;
+        frame.fill(0, y, self.terminal_cols, bar);
+        let name = match window.kind {
+            ListKind::Quickfix => "Quickfix List",
+            ListKind::Location => "Location List",
+        };
+        frame.put_str(0, y, &format!("[{}] {}", name, list.title), bar);
+        let current = Style::DEFAULT.bg(Color::DarkYellow).fg(Color::Black);
+        for row in 1..rows {
+            let index = window.top + row - 1;
+            let Some(entry) = list.entries.get(index) else {
+                frame.put_str(0, y + row, "~", Style::DEFAULT.fg(Color::Blue));
+                continue;
+            };
+            let style = if index == list.current && entry.valid() {
+                frame.fill(0, y + row, self.terminal_cols, current);
+                current
+            } else {
+                Style::DEFAULT
+            };
+            frame.put_str(0, y + row, &entry_line(entry), style);
+        }
+    }
+
+    /// Where the cursor goes while the quickfix window has the focus.
+    pub(crate) fn quickfix_cursor_pos(&self) -> Option<(usize, usize)> {
+        let window = self.quickfix.window.as_ref().filter(|w| w.focused)?;
+        let area = self.text_area();
+        Some((
+            0,
+            area.y + area.rows + 1 + window.selected.saturating_sub(window.top),
+        ))
+    }
+}
@@ -0,0 +1,124 @@
+/// A range of lines given before an Ex command, one-based and inclusive as typed.
+/// Line 0 is allowed for commands that insert below a line, such as `:0r`.
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Range {
+    /// The zero-based lines of the range; line 0 counts as the first line.
+    pub fn lines(&self) -> (usize, usize) {
+        (self.start.saturating_sub(1), self.end.saturating_sub(1))
+    }
+}
+
+/// Parses the line range at the start of an Ex command (without its `:`), such as
+/// `%`, `5`, `.,$`, `.,.+2` or `-1,+1`. `current` and `last` are zero-based lines.
+/// Returns the range, if there is one, and the rest of the command.
+pub fn parse(command: &str, current: usize, last: usize) -> Result<(Option<Range>, &str), String> {
+    let (current, last) = (current as i64 + 1, last as i64 + 1);
+    let text = command.trim_start();
+    if let Some(rest) = text.strip_prefix('%') {
+        let range = Range {
+            start: 1,
+            end: last as usize,
+        };
+        return Ok((Some(range), rest));
+    }
+    let Some((start, rest)) = address(text, current, last) else {
+        return Ok((None, command));
+    };
+    let (end, rest) = match rest.strip_prefix([',', ';']) {
+        // A missing second address means the current line, like Vim
+        Some(rest) => address(rest, current, last).unwrap_or((current, rest)),
+        None => (start, rest),
+    };
+    if start < 0 || end < 0 || start > last || end > last {
+        return Err("Invalid range".to_string());
+    }
+    // A backwards range is turned around, as Vim does after asking
+    let (start, end) = (start.min(end) as usize, start.max(end) as usize);
+    Ok((Some(Range { start, end }), rest))
+}
+
+/// Parses one address: a line number, `.` or `$`, followed by any `+N` or `-N`
+/// offsets. An offset alone counts from the current line.
+fn address(text: &str, current: i64, last: i64) -> Option<(i64, &str)> {
+    let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (mut line, mut rest) = if digits > 0 {
+        (text[..digits].parse().ok()?, &text[digits..])
+    } else if let Some(rest) = text.strip_prefix('.') {
+        (current, rest)
+    } else if let Some(rest) = text.strip_prefix('$') {
+        (last, rest)
+    } else if text.starts_with(['+', '-']) {
+        (current, text)
+    } else {
+        return None;
+    };
+    while let Some(sign) = rest.chars().next().filter(|c| *c == '+' || *c == '-') {
+        let after = &rest[1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let offset: i64 = if digits > 0 {
+            after[..digits].parse().ok()?
+        } else {
+            1
+        };
+        line += if sign == '+' { offset } else { -offset };
+        rest = &after[digits..];
+    }
+    Some((line, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses with the cursor on line 5 of 10, as typed (one-based).
+    fn lines(command: &str) -> (Option<(usize, usize)>, &str) {
+        let (range, rest) = parse(command, 4, 9).unwrap();
+        (range.map(|r| (r.start, r.end)), rest)
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(lines("%d"), (Some((1, 10)), "d"));
+        assert_eq!(lines("3w"), (Some((3, 3)), "w"));
+        assert_eq!(lines(".,$!sort"), (Some((5, 10)), "!sort"));
+        assert_eq!(lines(".,.+2"), (Some((5, 7)), ""));
+        assert_eq!(lines("-1,+1"), (Some((4, 6)), ""));
+        assert_eq!(lines("$-2;$"), (Some((8, 10)), ""));
+        // Offsets without a number count one each
+        assert_eq!(lines("++"), (Some((7, 7)), ""));
+        // A missing second address is the current line
+        assert_eq!(lines("2,"), (Some((2, 5)), ""));
+        assert_eq!(lines("0r file"), (Some((0, 0)), "r file"));
+    }
+
+    #[test]
+    fn commands_without_a_range_are_left_alone() {
+        assert_eq!(lines("w"), (None, "w"));
+        assert_eq!(lines("s/a/b/"), (None, "s/a/b/"));
+    }
+
+    #[test]
+    fn backwards_ranges_are_turned_around() {
+        assert_eq!(lines("7,2"), (Some((2, 7)), ""));
+    }
+
+    #[test]
+    fn ranges_past_the_buffer_are_refused() {
+        assert!(parse("11", 4, 9).is_err());
+        assert!(parse("1,$+1", 4, 9).is_err());
+        assert!(parse(".-6", 4, 9).is_err());
+        // Line 0, before the first line, is allowed
+        assert!(parse(".-5", 4, 9).is_ok());
+    }
+
+    #[test]
+    fn lines_are_zero_based() {
+        let (range, _) = parse("0,3", 0, 9).unwrap();
+        assert_eq!(range.unwrap().lines(), (0, 2));
+    }
+}
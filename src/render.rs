@@ -0,0 +1,295 @@
+use crossterm::{
+    cursor::{self, SetCursorStyle},
+    queue,
+    style::{self, Attribute, Attributes, Color},
+    terminal::{self, ClearType},
+};
+use std::io::{Result, Write};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Colors and attributes of a cell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    pub fg: Color,
+    pub bg: Color,
+    pub attrs: Attributes,
+}
+
+impl Style {
+    /// The terminal's default colors with no attributes.
+    pub const DEFAULT: Style = Style {
+        fg: Color::Reset,
+        bg: Color::Reset,
+        attrs: Attributes::none(),
+    };
+
+    /// Returns this style with another foreground color.
+    pub const fn fg(self, fg: Color) -> Style {
+        Style { fg, ..self }
+    }
+
+    /// Returns this style with another background color.
+    pub const fn bg(self, bg: Color) -> Style {
+        Style { bg, ..self }
+    }
+
+    /// Returns this style with an attribute added.
+    pub const fn attr(self, attr: Attribute) -> Style {
+        Style {
+            attrs: self.attrs.with(attr),
+            ..self
+        }
+    }
+}
+
+/// One screen cell: a grapheme cluster and its style.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cell {
+    /// The cluster drawn here; empty for the second half of a wide character.
+    pub symbol: String,
+    pub style: Style,
+}
+
+impl Cell {
+    fn blank() -> Self {
+        Self {
+            symbol: " ".to_string(),
+            style: Style::DEFAULT,
+        }
+    }
+}
+
+/// A complete picture of the screen, built fresh for every refresh.
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    cells: Vec<Cell>,
+    /// Where to show the cursor, if anywhere.
+    pub cursor: Option<(usize, usize)>,
+    pub cursor_style: SetCursorStyle,
+}
+
+impl Frame {
+    /// Creates a blank frame.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::blank(); width * height],
+            cursor: None,
+            cursor_style: SetCursorStyle::DefaultUserShape,
+        }
+    }
+
+    /// Writes one grapheme cluster of the given display width at (x, y).
+    /// Returns the column after it. Clusters that don't fit are left out.
+    pub fn put_grapheme(
+        &mut self,
+        x: usize,
+        y: usize,
+        symbol: &str,
+        width: usize,
+        style: Style,
+    ) -> usize {
+        if y >= self.height || x + width > self.width {
+            return x + width;
+        }
+        // Overwriting half of a wide character blanks the other half
+        self.clear_wide(x, y);
+        if width > 1 {
+            self.clear_wide(x + width - 1, y);
+        }
+        self.cells[y * self.width + x] = Cell {
+            symbol: symbol.to_string(),
+            style,
+        };
+        for i in 1..width {
+            self.cells[y * self.width + x + i] = Cell {
+                symbol: String::new(),
+                style,
+            };
+        }
+        x + width
+    }
+
+    /// Writes text at (x, y), clipped at the right edge. Returns the column after it.
+    pub fn put_str(&mut self, x: usize, y: usize, text: &str, style: Style) -> usize {
+        let mut x = x;
+        for cluster in text.graphemes(true) {
+            let width = cluster.width();
+            if width == 0 {
+                continue;
+            }
+            if x + width > self.width {
+                break;
+            }
+            x = self.put_grapheme(x, y, cluster, width, style);
+        }
+        x
+    }
+
+    /// Fills `width` cells from (x, y) with spaces in the given style.
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, style: Style) {
+        for col in x..(x + width).min(self.width) {
+            self.put_grapheme(col, y, " ", 1, style);
+        }
+    }
+
+    /// Copies `other`, drawn on its own, onto this frame with its top left cell at (x, y).
+    pub fn blit(&mut self, other: &Frame, x: usize, y: usize) {
+        let width = other.width.min(self.width.saturating_sub(x));
+        for row in 0..other.height.min(self.height.saturating_sub(y)) {
+            let from = row * other.width;
+            let to = (y + row) * self.width + x;
+            self.cells[to..to + width].clone_from_slice(&other.cells[from..from + width]);
+        }
+    }
+
+    /// Returns the cell at (x, y).
+    pub fn cell(&self, x: usize, y: usize) -> &Cell {
+        &self.cells[y * self.width + x]
+    }
+
+    /// If (x, y) is part of a wide character, blanks the whole character.
+    fn clear_wide(&mut self, x: usize, y: usize) {
+        let row = y * self.width;
+        let mut lead = x;
+        while lead > 0 && self.cells[row + lead].symbol.is_empty() {
+            lead -= 1;
+        }
+        let mut end = lead + 1;
+        while end < self.width && self.cells[row + end].symbol.is_empty() {
+            end += 1;
+        }
+        if end - lead > 1 {
+            for col in lead..end {
+                self.cells[row + col] = Cell::blank();
+            }
+        }
+    }
+}
+
+/// Draws frames to the terminal, sending only the cells that changed since the last one.
+pub struct Renderer {
+    previous: Option<Frame>,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self { previous: None }
+    }
+
+    /// Forgets the last frame, so the next one is drawn in full
+    /// (after a resize, when the terminal contents can no longer be trusted).
+    pub fn invalidate(&mut self) {
+        self.previous = None;
+    }
+
+    /// Draws a frame. Does nothing at all if it matches the previous one.
+    pub fn draw(&mut self, out: &mut impl Write, frame: Frame) -> Result<()> {
+        if self.previous.as_ref() == Some(&frame) {
+            return Ok(());
+        }
+        let previous = self
+            .previous
+            .take()
+            .filter(|p| p.width == frame.width && p.height == frame.height);
+        queue!(out, cursor::Hide)?;
+        if previous.is_none() {
+            queue!(out, style::ResetColor, terminal::Clear(ClearType::All))?;
+        }
+        let mut pen = Pen::default();
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let cell = frame.cell(x, y);
+                let changed = match &previous {
+                    Some(p) => p.cell(x, y) != cell || continuation_changed(p, &frame, x, y),
+                    None => cell.symbol != " " || cell.style != Style::DEFAULT,
+                };
+                if !changed || cell.symbol.is_empty() {
+                    continue;
+                }
+                pen.print(out, x, y, cell)?;
+            }
+        }
+        pen.flush(out)?;
+        queue!(out, style::ResetColor)?;
+        if previous
+            .as_ref()
+            .is_none_or(|p| p.cursor_style != frame.cursor_style)
+        {
+            queue!(out, frame.cursor_style)?;
+        }
+        if let Some((x, y)) = frame.cursor {
+            queue!(out, cursor::MoveTo(x as u16, y as u16), cursor::Show)?;
+        }
+        out.flush()?;
+        self.previous = Some(frame);
+        Ok(())
+    }
+}
+
+/// Whether the trailing half of a wide character at (x, y) changed while its lead didn't.
+fn continuation_changed(previous: &Frame, frame: &Frame, x: usize, y: usize) -> bool {
+    let width = frame.cell(x, y).symbol.width();
+    (1..width).any(|i| x + i < frame.width && previous.cell(x + i, y) != frame.cell(x + i, y))
+}
+
+/// Tracks the terminal's cursor position and style while a frame is written,
+/// batching adjacent cells of the same style into one print.
+#[derive(Default)]
+struct Pen {
+    /// Where the terminal cursor is after the pending text.
+    at: Option<(usize, usize)>,
+    style: Option<Style>,
+    pending: String,
+}
+
+impl Pen {
+    fn print(&mut self, out: &mut impl Write, x: usize, y: usize, cell: &Cell) -> Result<()> {
+        if self.at != Some((x, y)) {
+            self.flush(out)?;
+            queue!(out, cursor::MoveTo(x as u16, y as u16))?;
+        }
+        if self.style != Some(cell.style) {
+            self.flush(out)?;
+            set_style(out, self.style, cell.style)?;
+            self.style = Some(cell.style);
+        }
+        self.pending.push_str(&cell.symbol);
+        self.at = Some((x + cell.symbol.width().max(1), y));
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut impl Write) -> Result<()> {
+        if !self.pending.is_empty() {
+            queue!(out, style::Print(&self.pending))?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Switches the terminal from one style to another with as few sequences as possible.
+fn set_style(out: &mut impl Write, from: Option<Style>, to: Style) -> Result<()> {
+    let from = match from {
+        // Attributes can only be switched off all at once
+        Some(from) if from.attrs == to.attrs => from,
+        _ => {
+            queue!(out, style::SetAttribute(Attribute::Reset))?;
+            if !to.attrs.is_empty() {
+                queue!(out, style::SetAttributes(to.attrs))?;
+            }
+            Style::DEFAULT
+        }
+    };
+    if from.fg != to.fg {
+        queue!(out, style::SetForegroundColor(to.fg))?;
+    }
+    if from.bg != to.bg {
+        queue!(out, style::SetBackgroundColor(to.bg))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use crate::options::Options;
+use std::fs;
+use std::io::{self, Result, Write};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// How many symlinks in a row are followed before giving up, as on Linux.
+const MAX_LINKS: usize = 40;
+
+/// Writes the bytes of a file without ever leaving it half-written: they go to a
+/// temporary file in the same directory, which is synced and then renamed over the
+/// original. A symlink is written through to its target, even one that doesn't exist
+/// yet, and the original's mode bits and owner are kept. If anything fails, the
+/// original file is left as it was. A file with hard links is the exception: it is
+/// overwritten in place, after the backup, so its other names see the new contents.
+pub fn write_file(filename: &str, bytes: &[u8], options: &Options) -> Result<()> {
+    // Replace the file a symlink points to, not the link itself
+    let target = resolve_links(Path::new(filename))?;
+    let original = fs::metadata(&target).ok();
+    if options.backup && original.is_some() {
+        let backup = backup_path(&target, &options.backupdir);
+        fs::copy(&target, &backup).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot make backup {}: {}", backup.display(), e),
+            )
+        })?;
+    }
+    if original.as_ref().is_some_and(has_other_names) {
+        return write_in_place(&target, bytes);
+    }
+    let temp = temp_path(&target);
+    let result = write_temp(&temp, bytes, original.as_ref()).and_then(|()| {
+        fs::rename(&temp, &target)?;
+        sync_dir(&target);
+        Ok(())
+    });
+    if result.is_err() {
+        fs::remove_file(&temp).ok();
+    }
+    result
+}
+
+/// Writes and syncs the temporary file, giving it the original's permissions and owner.
+fn write_temp(temp: &Path, bytes: &[u8], original: Option<&fs::Metadata>) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(temp)?;
+    file.write_all(bytes)?;
+    if let Some(meta) = original {
+        file.set_permissions(meta.permissions())?;
+        #[cfg(unix)]
+        {
+            let current = file.metadata()?;
+            if (current.uid(), current.gid()) != (meta.uid(), meta.gid()) {
+                // Only root can give a file away; keeping the group may still work
+                if std::os::unix::fs::fchown(&file, Some(meta.uid()), Some(meta.gid())).is_err() {
+                    std::os::unix::fs::fchown(&file, None, Some(meta.gid())).ok();
+                }
+            }
+        }
+    }
+    file.sync_all()
+}
+
+/// Overwrites a file's contents, keeping the file itself and so all its names.
+fn write_in_place(target: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(target)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+/// Follows a symlink, and any link it points to, to the path at the end of the chain,
+/// which need not exist.
+fn resolve_links(path: &Path) -> Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_LINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                // A relative link is relative to the directory holding it
+                let link = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(dir) => dir.join(link),
+                    None => link,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(io::Error::other(format!(
+        "too many levels of symbolic links: {}",
+        path.display()
+    )))
+}
+
+/// Whether the file has hard links, which a rename over it would leave on the old
+/// contents.
+#[cfg(unix)]
+fn has_other_names(meta: &fs::Metadata) -> bool {
+    meta.nlink() > 1
+}
+
+#[cfg(not(unix))]
+fn has_other_names(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Returns a temporary file name next to the target, unique to this process.
+fn temp_path(target: &Path) -> PathBuf {
+    let name = target
+        .file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+    target.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Returns where the backup copy goes: `file~`, or `backupdir/file~`.
+fn backup_path(target: &Path, backupdir: &str) -> PathBuf {
+    let name = target
+        .file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+    let name = format!("{}~", name);
+    if backupdir.is_empty() {
+        target.with_file_name(name)
+    } else {
+        Path::new(backupdir).join(name)
+    }
+}
+
+/// Syncs the directory holding a file, so the rename itself survives a crash.
+/// Some filesystems don't support this, so failures are ignored.
+fn sync_dir(target: &Path) {
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = fs::File::open(dir) {
+        dir.sync_all().ok();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rim-save-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn writes_through_a_dangling_symlink() {
+        let dir = temp_dir("symlink");
+        let link = dir.join("link");
+        symlink("target", &link).unwrap();
+        write_file(link.to_str().unwrap(), b"text\n", &Options::default()).unwrap();
+        assert!(
+            fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink()
+        );
+        assert_eq!(fs::read(dir.join("target")).unwrap(), b"text\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn keeps_hard_links() {
+        let dir = temp_dir("hardlink");
+        let file = dir.join("file");
+        fs::write(&file, "old\n").unwrap();
+        fs::hard_link(&file, dir.join("other")).unwrap();
+        write_file(file.to_str().unwrap(), b"new\n", &Options::default()).unwrap();
+        assert_eq!(fs::read(dir.join("other")).unwrap(), b"new\n");
+        fs::remove_dir_all(&dir).ok();
+    }
+}
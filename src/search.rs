@@ -0,0 +1,195 @@
+use crate::Editor;
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use regex::{Regex, RegexBuilder};
+use std::borrow::Cow;
+use std::io::{self, Result};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// Lines searched in one go; Esc and Ctrl-c are checked between blocks.
+const BLOCK_LINES: usize = 4096;
+
+/// How long a search runs before Esc and Ctrl-c are checked for. Keys typed during
+/// a search that takes longer are dropped, as Vim does.
+pub const INTERRUPT_AFTER: Duration = Duration::from_millis(300);
+
+/// A search pattern typed after `/` or `?`.
+pub struct Search {
+    /// The pattern as typed, for messages.
+    pattern: String,
+    regex: Regex,
+    /// Typed after `?`: `n` goes up the file.
+    backward: bool,
+}
+
+impl Search {
+    /// The pattern as typed, for Ctrl-r / on the command line.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+}
+
+impl Editor {
+    /// The pattern of the last search, which `:s//` uses again.
+    pub(crate) fn last_search_regex(&self) -> Option<&Regex> {
+        self.last_search.as_ref().map(|search| &search.regex)
+    }
+
+    /// Runs a search typed on the command line: `/pattern` searches down the file,
+    /// `?pattern` up. An empty pattern uses the last one again.
+    pub(crate) fn search(&mut self, command: &str) -> Result<()> {
+        let backward = command.starts_with('?');
+        let pattern = &command[1..];
+        if pattern.is_empty() {
+            match &mut self.last_search {
+                Some(search) => search.backward = backward,
+                None => {
+                    self.status_msg = "No previous regular expression".to_string();
+                    return Ok(());
+                }
+            }
+        } else {
+            // `^` and `$` match at the start and end of every line
+            match RegexBuilder::new(pattern).multi_line(true).build() {
+                Ok(regex) => {
+                    self.last_search = Some(Search {
+                        pattern: pattern.to_string(),
+                        regex,
+                        backward,
+                    });
+                }
+                Err(e) => {
+                    // Syntax errors come with the pattern and a caret on lines above
+                    let e = e.to_string();
+                    let reason = e.lines().last().unwrap_or_default();
+                    self.status_msg =
+                        format!("Invalid pattern: {}", reason.trim_start_matches("error: "));
+                    return Ok(());
+                }
+            }
+        }
+        self.search_next(false)
+    }
+
+    /// Moves to the next match of the last search (`n`), or the one the other
+    /// way (`N`), wrapping around the end of the file.
+    pub(crate) fn search_next(&mut self, reverse: bool) -> Result<()> {
+        let Some(search) = &self.last_search else {
+            self.status_msg = "No previous regular expression".to_string();
+            return Ok(());
+        };
+        let backward = search.backward != reverse;
+        let prefix = if backward { '?' } else { '/' };
+        self.status_msg = match self.find_match(&search.regex, backward) {
+            Ok(Some((row, col, wrapped))) => {
+                self.cy = row;
+                self.cx = col;
+                match (wrapped, backward) {
+                    (false, _) => format!("{}{}", prefix, search.pattern),
+                    (true, false) => "search hit BOTTOM, continuing at TOP".to_string(),
+                    (true, true) => "search hit TOP, continuing at BOTTOM".to_string(),
+                }
+            }
+            Ok(None) => format!("Pattern not found: {}", search.pattern),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => "Search interrupted".to_string(),
+            Err(e) => return Err(e),
+        };
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+        Ok(())
+    }
+
+    /// Finds the match nearest to the cursor. Returns its (line, col) and whether the
+    /// search wrapped around the end of the file.
+    fn find_match(&self, regex: &Regex, backward: bool) -> Result<Option<(usize, usize, bool)>> {
+        let (cy, cx) = (self.cy, self.cx);
+        let lines = self.buffer.len_lines();
+        let rest_of_line = |col: usize| if backward { col < cx } else { col > cx };
+        if let Some(col) = self.find_in_line(regex, cy, rest_of_line, backward) {
+            return Ok(Some((cy, col, false)));
+        }
+        let (ahead, behind) = if backward {
+            (0..cy, cy + 1..lines)
+        } else {
+            (cy + 1..lines, 0..cy)
+        };
+        let started = Instant::now();
+        for (range, wrapped) in [(ahead, false), (behind, true)] {
+            if let Some((row, col)) = self.find_in_lines(regex, range, backward, started)? {
+                return Ok(Some((row, col, wrapped)));
+            }
+        }
+        // Back at the cursor line: the part skipped at first, and the cursor itself
+        let start_of_line = |col: usize| if backward { col >= cx } else { col <= cx };
+        let col = self.find_in_line(regex, cy, start_of_line, backward);
+        Ok(col.map(|col| (cy, col, true)))
+    }
+
+    /// Finds the first (or last) match in a line that starts at a column `wanted` accepts.
+    fn find_in_line(
+        &self,
+        regex: &Regex,
+        row: usize,
+        wanted: impl Fn(usize) -> bool,
+        backward: bool,
+    ) -> Option<usize> {
+        let text: Cow<str> = self.buffer.rope.line(row).into();
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        let mut found = regex
+            .find_iter(text)
+            .map(|m| text[..m.start()].chars().count())
+            .filter(|&col| wanted(col));
+        if backward { found.last() } else { found.next() }
+    }
+
+    /// Finds the first (or last) match in a range of lines. The lines are searched a
+    /// block at a time, which is much faster than one by one in a large file.
+    /// Fails with `ErrorKind::Interrupted` if the user pressed Esc or Ctrl-c.
+    fn find_in_lines(
+        &self,
+        regex: &Regex,
+        range: Range<usize>,
+        backward: bool,
+        started: Instant,
+    ) -> Result<Option<(usize, usize)>> {
+        let rope = &self.buffer.rope;
+        let mut blocks: Vec<usize> = range.clone().step_by(BLOCK_LINES).collect();
+        if backward {
+            blocks.reverse();
+        }
+        for first in blocks {
+            if started.elapsed() >= INTERRUPT_AFTER && search_interrupted()? {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let last = (first + BLOCK_LINES).min(range.end);
+            let slice = rope.slice(rope.line_to_char(first)..rope.line_to_char(last));
+            let text: Cow<str> = slice.into();
+            let found = if backward {
+                regex.find_iter(&text).last()
+            } else {
+                regex.find(&text)
+            };
+            if let Some(found) = found {
+                let before = &text[..found.start()];
+                let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+                let row = first + before.bytes().filter(|&b| b == b'\n').count();
+                return Ok(Some((row, before[line_start..].chars().count())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Whether Esc or Ctrl-c was pressed.
+pub fn search_interrupted() -> Result<bool> {
+    while event::poll(Duration::ZERO)? {
+        if let Event::Key(key) = event::read()?
+            && (key.code == KeyCode::Esc
+                || (key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL)))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
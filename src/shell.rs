@@ -0,0 +1,411 @@
+use crate::range::Range;
+use crate::{Editor, encoding, fileformat};
+use crossterm::{cursor, event, execute, style, terminal};
+use std::io::{self, BufRead, Read, Result, Write, stdout};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A shell command typed on the command line.
+pub enum Bang<'a> {
+    /// `:!cmd`, or `:{range}!cmd` to filter lines through it.
+    Run(&'a str),
+    /// `:r !cmd`: insert the command's output below a line.
+    Read(&'a str),
+    /// `:w !cmd`: send lines to the command's input.
+    Write(&'a str),
+}
+
+/// Recognises the shell commands in an Ex command (after its range, if any).
+/// `:w!name` is a forced write, not a shell command, so `:w` needs a space first.
+pub fn parse(command: &str) -> Option<Bang<'_>> {
+    let command = command.trim_start();
+    if let Some(cmd) = command.strip_prefix('!') {
+        return Some(Bang::Run(cmd));
+    }
+    let name_len = command
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(command.len());
+    let (name, args) = command.split_at(name_len);
+    let cmd = args.trim_start().strip_prefix('!');
+    match name {
+        "r" | "read" => cmd.map(Bang::Read),
+        "w" | "write" if args.starts_with(char::is_whitespace) => cmd.map(Bang::Write),
+        _ => None,
+    }
+}
+
+/// Replaces `%` with the current file name and `#` with the alternate one, like Vim.
+/// `\%` and `\#` stand for the characters themselves.
+pub fn expand(cmd: &str, current: Option<&str>, alternate: Option<&str>) -> Result<String> {
+    let mut expanded = String::new();
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        let name = match c {
+            '\\' => match chars.next() {
+                Some(next @ ('%' | '#')) => {
+                    expanded.push(next);
+                    continue;
+                }
+                Some(next) => {
+                    expanded.push('\\');
+                    expanded.push(next);
+                    continue;
+                }
+                None => {
+                    expanded.push('\\');
+                    continue;
+                }
+            },
+            '%' => current,
+            '#' => alternate,
+            c => {
+                expanded.push(c);
+                continue;
+            }
+        };
+        let name = name.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No file name to substitute for '{}'", c),
+            )
+        })?;
+        expanded.push_str(name);
+    }
+    Ok(expanded)
+}
+
+/// Describes how a command ended, or None if it succeeded.
+pub fn failure(status: ExitStatus) -> Option<String> {
+    match status.code() {
+        Some(0) => None,
+        Some(code) => Some(format!("shell returned {}", code)),
+        None => Some("shell command was killed by a signal".to_string()),
+    }
+}
+
+/// Waits for a command to finish and collects its output, like
+/// `Child::wait_with_output`. A command still running after `timeout` is killed with
+/// its process group, and None is returned.
+fn wait_within(mut child: Child, timeout: Duration) -> Result<Option<Output>> {
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            kill_group(&mut child);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    Ok(Some(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    }))
+}
+
+/// Kills a command started in a group of its own, with the group, so whatever the
+/// shell started goes too and lets go of the pipes.
+#[cfg(unix)]
+fn kill_group(child: &mut Child) {
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) {
+    child.kill().ok();
+}
+
+/// Reads a pipe to its end on another thread.
+fn read_all(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut bytes).ok();
+        }
+        bytes
+    })
+}
+
+impl Editor {
+    /// Runs a shell command from the command line. `range` is the line range typed
+    /// before it, if any.
+    pub(crate) fn shell_command(&mut self, range: Option<Range>, bang: Bang) -> Result<()> {
+        let cmd = match bang {
+            Bang::Run(cmd) | Bang::Read(cmd) | Bang::Write(cmd) => cmd.trim(),
+        };
+        if cmd.is_empty() {
+            self.status_msg = "Argument required".to_string();
+            return Ok(());
+        }
+        let cmd = match expand(
+            cmd,
+            self.buffer.filename.as_deref(),
+            self.alternate_file.as_deref(),
+        ) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.status_msg = e.to_string();
+                return Ok(());
+            }
+        };
+        let last = self.buffer.len_lines() - 1;
+        let current = Range {
+            start: self.cy + 1,
+            end: self.cy + 1,
+        };
+        let all = Range {
+            start: 1,
+            end: last + 1,
+        };
+        match (bang, range) {
+            (Bang::Run(_), None) => self.run_in_terminal(&cmd, None)?,
+            (Bang::Run(_), Some(range)) => self.filter_lines(&cmd, range)?,
+            (Bang::Read(_), range) => self.read_command(&cmd, range.unwrap_or(current))?,
+            (Bang::Write(_), range) => {
+                let input = self.range_text(range.unwrap_or(all));
+                self.run_in_terminal(&cmd, Some(input))?;
+            }
+        }
+        // The command may well have changed the file being edited
+        self.check_time(false);
+        Ok(())
+    }
+
+    /// Runs a command on the terminal, out of raw mode, and waits for Enter before
+    /// going back to the editor. `input` is sent to the command's standard input.
+    fn run_in_terminal(&mut self, cmd: &str, input: Option<Vec<u8>>) -> Result<()> {
+        self.suspend_terminal()?;
+        let mut out = stdout();
+        writeln!(out)?;
+        out.flush()?;
+        let status = self
+            .shell(cmd, input.is_some())
+            .spawn()
+            .and_then(|mut child| {
+                if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+                    // A command that doesn't read all of its input is no error
+                    stdin.write_all(&input).ok();
+                }
+                child.wait()
+            });
+        let message = match &status {
+            Ok(status) => failure(*status),
+            Err(e) => Some(format!("Cannot run shell: {}", e)),
+        };
+        self.return_to_editor(message.as_deref())?;
+        self.status_msg = message.unwrap_or_default();
+        Ok(())
+    }
+
+    /// Shows how a command run on the terminal failed, if it did, and waits for Enter
+    /// before taking the terminal back.
+    pub(crate) fn return_to_editor(&mut self, message: Option<&str>) -> Result<()> {
+        let mut out = stdout();
+        if let Some(message) = message {
+            writeln!(out, "\n{}", message)?;
+        }
+        write!(out, "\nPress ENTER to continue")?;
+        out.flush()?;
+        io::stdin().lock().read_line(&mut String::new())?;
+        self.resume_terminal()
+    }
+
+    /// Replaces the lines of `range` with what they become after passing through a
+    /// command, as one undo step. If the command fails the lines are left alone.
+    fn filter_lines(&mut self, cmd: &str, range: Range) -> Result<()> {
+        let (first, last) = range.lines();
+        let input = self.range_text(range);
+        let Some(mut output) = self.capture(cmd, Some(input))? else {
+            return Ok(());
+        };
+        let (start, end) = self.range_chars(range);
+        // The last line of a file may have no line break; it doesn't get one now
+        let ends_with_break = end > start && self.buffer.rope.char(end - 1) == '\n';
+        if !ends_with_break && output.ends_with('\n') {
+            output.pop();
+        }
+        let lines = last - first + 1;
+        self.buffer.seal_undo_step();
+        self.buffer.delete_range(first, 0, end - start);
+        self.buffer.insert_str(first, 0, &output);
+        self.buffer.seal_undo_step();
+        self.cy = first;
+        self.cx = self.first_non_blank(first);
+        self.status_msg = format!(
+            "{} line{} filtered",
+            lines,
+            if lines == 1 { "" } else { "s" }
+        );
+        Ok(())
+    }
+
+    /// Inserts the output of a command below line `range.end` (0 puts it at the top).
+    fn read_command(&mut self, cmd: &str, range: Range) -> Result<()> {
+        let Some(mut output) = self.capture(cmd, None)? else {
+            return Ok(());
+        };
+        if output.is_empty() {
+            return Ok(());
+        }
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+        let (line, col) = if range.end < self.buffer.len_lines() {
+            (range.end, 0)
+        } else {
+            // Below the last line, which has no line break to insert after
+            output.pop();
+            output.insert(0, '\n');
+            (range.end - 1, self.buffer.line_len(range.end - 1))
+        };
+        self.buffer.seal_undo_step();
+        self.buffer.insert_str(line, col, &output);
+        self.buffer.seal_undo_step();
+        self.cy = range.end;
+        self.cx = self.first_non_blank(self.cy);
+        Ok(())
+    }
+
+    /// Runs a command with its output captured, decoded like a UTF-8 file. Returns None
+    /// and reports the error if it fails.
+    fn capture(&mut self, cmd: &str, input: Option<Vec<u8>>) -> Result<Option<String>> {
+        self.capture_within(cmd, input, None)
+    }
+
+    /// Like `capture`, but gives up on a command that runs longer than `timeout`.
+    pub(crate) fn capture_within(
+        &mut self,
+        cmd: &str,
+        input: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<Option<String>> {
+        let mut command = self.shell(cmd, input.is_some());
+        // A group of its own, to be killed as a whole if it hangs
+        #[cfg(unix)]
+        if timeout.is_some() {
+            command.process_group(0);
+        }
+        let child = command
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                self.status_msg = format!("Cannot run shell: {}", e);
+                return Ok(None);
+            }
+        };
+        // Written from another thread, so a command that writes a lot before it has
+        // read everything can't fill its output pipe and block both sides
+        let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+            thread::spawn(move || {
+                stdin.write_all(&input).ok();
+            })
+        });
+        let output = match timeout {
+            None => child.wait_with_output()?,
+            Some(timeout) => match wait_within(child, timeout)? {
+                Some(output) => output,
+                None => {
+                    self.status_msg =
+                        format!("Command timed out after {} seconds", timeout.as_secs());
+                    return Ok(None);
+                }
+            },
+        };
+        if let Some(writer) = writer {
+            writer.join().ok();
+        }
+        if let Some(message) = failure(output.status) {
+            // The first line of the error output usually says what went wrong
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            self.status_msg = match stderr.lines().find(|line| !line.trim().is_empty()) {
+                Some(line) => format!("{}: {}", message, line.trim()),
+                None => message,
+            };
+            return Ok(None);
+        }
+        let text = encoding::decode(&output.stdout, "utf-8", Some("utf-8")).text;
+        Ok(Some(fileformat::to_lf(&text, fileformat::detect(&text))))
+    }
+
+    /// Builds the command that runs `cmd` through the `shell` option.
+    pub(crate) fn shell(&self, cmd: &str, piped_input: bool) -> Command {
+        let mut command = Command::new(&self.options.shell);
+        command.arg("-c").arg(cmd);
+        command.stdin(if piped_input {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        });
+        command
+    }
+
+    /// Returns the char indices where the lines of a range start and end, including
+    /// the line break of the last one.
+    pub(crate) fn range_chars(&self, range: Range) -> (usize, usize) {
+        let (first, last) = range.lines();
+        let rope = &self.buffer.rope;
+        let end = if last + 1 < self.buffer.len_lines() {
+            rope.line_to_char(last + 1)
+        } else {
+            rope.len_chars()
+        };
+        (rope.line_to_char(first), end)
+    }
+
+    /// Returns the lines of a range as bytes to send to a command.
+    pub(crate) fn range_text(&self, range: Range) -> Vec<u8> {
+        let (start, end) = self.range_chars(range);
+        let mut text = self.buffer.rope.slice(start..end).to_string();
+        // Commands expect every line, the last one too, to end in a line break
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        // Raw bytes go back out as they came in; UTF-8 can encode everything else
+        encoding::encode(&text, "utf-8", false).unwrap_or_else(|_| text.into_bytes())
+    }
+
+    /// Hands the terminal back to the shell: out of raw mode, cursor shown, and the
+    /// editor's terminal features switched off.
+    pub(crate) fn suspend_terminal(&self) -> Result<()> {
+        terminal::disable_raw_mode()?;
+        execute!(
+            stdout(),
+            event::DisableBracketedPaste,
+            event::DisableFocusChange,
+            style::ResetColor,
+            cursor::SetCursorStyle::DefaultUserShape,
+            cursor::MoveTo(0, self.status_row() as u16),
+            cursor::Show
+        )?;
+        if self.mouse.captured {
+            execute!(stdout(), event::DisableMouseCapture)?;
+        }
+        Ok(())
+    }
+
+    /// Takes the terminal back after `suspend_terminal`, redrawing everything.
+    pub(crate) fn resume_terminal(&mut self) -> Result<()> {
+        terminal::enable_raw_mode()?;
+        execute!(
+            stdout(),
+            event::EnableBracketedPaste,
+            event::EnableFocusChange
+        )?;
+        if self.mouse.captured {
+            execute!(stdout(), event::EnableMouseCapture)?;
+        }
+        self.renderer.invalidate();
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+use crate::Editor;
+use crate::range::Range;
+use regex::RegexBuilder;
+use std::borrow::Cow;
+
+/// A `:s/pattern/replacement/flags` command.
+pub struct Substitute {
+    /// Empty means the last search pattern.
+    pattern: String,
+    /// In the regex crate's syntax: `${1}` for a group, `$$` for a dollar sign.
+    replacement: String,
+    /// `g`: every match in a line, not just the first.
+    global: bool,
+    /// `i`: ignore case.
+    ignore_case: bool,
+    /// `e`: finding nothing is no error (useful with `:cdo`).
+    quiet: bool,
+}
+
+/// Recognises `:s` (or `:substitute`) in an Ex command, after its range. Any character
+/// that isn't a letter, a digit, a space, `\`, `"` or `|` can stand in for the `/`.
+pub fn parse(command: &str) -> Option<Result<Substitute, String>> {
+    let command = command.trim_start();
+    let rest = command
+        .strip_prefix("substitute")
+        .or_else(|| command.strip_prefix('s'))?;
+    let delimiter = rest
+        .chars()
+        .next()
+        .filter(|c| !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|".contains(*c))?;
+    let mut parts = split_delimited(&rest[delimiter.len_utf8()..], delimiter);
+    let pattern = parts.next().unwrap_or_default();
+    let replacement = parts.next().unwrap_or_default();
+    let flags = parts.next().unwrap_or_default();
+    let mut substitute = Substitute {
+        pattern,
+        replacement: replacement_to_regex(&replacement),
+        global: false,
+        ignore_case: false,
+        quiet: false,
+    };
+    for flag in flags.trim_end().chars() {
+        match flag {
+            'g' => substitute.global = true,
+            'i' => substitute.ignore_case = true,
+            'I' => substitute.ignore_case = false,
+            'e' => substitute.quiet = true,
+            _ => return Some(Err(format!("Trailing characters: {}", flags))),
+        }
+    }
+    Some(Ok(substitute))
+}
+
+/// Splits `pattern/replacement/flags` at each unescaped delimiter. `\` before the
+/// delimiter makes it part of the text; other escapes are kept for the next step.
+fn split_delimited(text: &str, delimiter: char) -> impl Iterator<Item = String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        // The flags are the rest of the command
+        if parts.len() == 3 {
+            parts.last_mut().unwrap().push(c);
+            continue;
+        }
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => parts.last_mut().unwrap().push(next),
+                Some(next) => {
+                    let part = parts.last_mut().unwrap();
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => parts.last_mut().unwrap().push('\\'),
+            },
+            c if c == delimiter => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts.into_iter()
+}
+
+/// Turns a replacement written as in Vim (`&` or `\0` for the match, `\1` to `\9` for
+/// groups, `\n` for a line break, `\&` and `\\` for the characters) into the regex
+/// crate's syntax.
+fn replacement_to_regex(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => converted.push_str(&format!("${{{}}}", digit)),
+                Some('n' | 'r') => converted.push('\n'),
+                Some('t') => converted.push('\t'),
+                Some(next) => converted.push(next),
+                None => converted.push('\\'),
+            },
+            c => converted.push(c),
+        }
+    }
+    converted
+}
+
+impl Editor {
+    /// Replaces matches of a pattern in the lines of `range` (the cursor line if None).
+    /// All the changes of one command form a single undo step, and so do those of every
+    /// `:s` that `:cdo` runs in the same file.
+    pub(crate) fn substitute(&mut self, range: Option<Range>, substitute: Substitute) {
+        let regex = if substitute.pattern.is_empty() {
+            match self.last_search_regex() {
+                Some(regex) => regex.clone(),
+                None => {
+                    self.status_msg = "No previous regular expression".to_string();
+                    return;
+                }
+            }
+        } else {
+            let built = RegexBuilder::new(&substitute.pattern)
+                .case_insensitive(substitute.ignore_case)
+                .build();
+            match built {
+                Ok(regex) => regex,
+                Err(e) => {
+                    let e = e.to_string();
+                    let reason = e.lines().last().unwrap_or_default();
+                    self.status_msg =
+                        format!("Invalid pattern: {}", reason.trim_start_matches("error: "));
+                    return;
+                }
+            }
+        };
+        let (first, last) = match range {
+            Some(range) => range.lines(),
+            None => (self.cy, self.cy),
+        };
+        let limit = if substitute.global { 0 } else { 1 };
+        let mut substitutions = 0;
+        let mut lines = 0;
+        // Lines are changed from the bottom up, so a `\n` in the replacement doesn't move
+        // the lines still to do
+        let mut added = 0;
+        let mut cursor = None;
+        for row in (first..=last).rev() {
+            let text: Cow<str> = self.buffer.rope.line(row).into();
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            let count = if substitute.global {
+                regex.find_iter(text).count()
+            } else {
+                usize::from(regex.is_match(text))
+            };
+            if count == 0 {
+                continue;
+            }
+            substitutions += count;
+            lines += 1;
+            let replaced = regex.replacen(text, limit, substitute.replacement.as_str());
+            if replaced != text {
+                let replaced = replaced.into_owned();
+                let len = text.chars().count();
+                self.buffer.delete_range(row, 0, len);
+                self.buffer.insert_str(row, 0, &replaced);
+                added += replaced.matches('\n').count();
+            }
+            cursor.get_or_insert((row, added));
+        }
+        let Some((row, added_below)) = cursor else {
+            if !substitute.quiet {
+                let pattern = match &substitute.pattern {
+                    pattern if pattern.is_empty() => regex.as_str().to_string(),
+                    pattern => pattern.clone(),
+                };
+                self.status_msg = format!("Pattern not found: {}", pattern);
+            }
+            return;
+        };
+        // The cursor goes to the last line changed, moved down by the lines added above it
+        self.cy = row + (added - added_below);
+        self.cx = self.first_non_blank(self.cy);
+        self.status_msg = format!(
+            "{} substitution{} on {} line{}",
+            substitutions,
+            if substitutions == 1 { "" } else { "s" },
+            lines,
+            if lines == 1 { "" } else { "s" }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(command: &str) -> Substitute {
+        parse(command).unwrap().unwrap()
+    }
+
+    #[test]
+    fn recognises_the_command_and_its_delimiter() {
+        let substitute = parsed("s/a/b/");
+        assert_eq!(
+            (substitute.pattern.as_str(), substitute.replacement.as_str()),
+            ("a", "b")
+        );
+        let substitute = parsed(" substitute#a/b#c");
+        assert_eq!(substitute.pattern, "a/b");
+        assert_eq!(substitute.replacement, "c");
+        // Escaped delimiters belong to the text, the trailing delimiter is optional
+        let substitute = parsed(r"s/a\/b/c");
+        assert_eq!(substitute.pattern, "a/b");
+        assert_eq!(substitute.replacement, "c");
+        assert!(parse("set").is_none());
+        assert!(parse("s a b").is_none());
+        assert!(parse("s|a|b|").is_none());
+    }
+
+    #[test]
+    fn parses_flags() {
+        let substitute = parsed("s/a/b/");
+        assert!(!substitute.global && !substitute.ignore_case && !substitute.quiet);
+        let substitute = parsed("s/a/b/gie ");
+        assert!(substitute.global && substitute.ignore_case && substitute.quiet);
+        // The last of `i` and `I` wins
+        assert!(!parsed("s/a/b/iI").ignore_case);
+        assert!(parsed("s/a/b/Ii").ignore_case);
+        // Flags after the delimiter are flags even when they hold it
+        assert_eq!(
+            parse("s/a/b/g/").unwrap().err(),
+            Some("Trailing characters: g/".to_string())
+        );
+        assert_eq!(
+            parse("s/a/b/x").unwrap().err(),
+            Some("Trailing characters: x".to_string())
+        );
+    }
+
+    #[test]
+    fn converts_the_replacement() {
+        assert_eq!(replacement_to_regex(r"<&>"), "<${0}>");
+        assert_eq!(replacement_to_regex(r"\2-\1\0"), "${2}-${1}${0}");
+        assert_eq!(replacement_to_regex(r"a\nb\tc"), "a\nb\tc");
+        assert_eq!(replacement_to_regex(r"\&\\$"), r"&\$$");
+    }
+}
@@ -0,0 +1,478 @@
+use crate::browser::Browser;
+use crate::complete;
+use crate::quickfix::same_file;
+use crate::{Buffer, ConfirmAction, ConfirmState, Editor, Mode, NormalState, window};
+use ropey::Rope;
+use std::fs;
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// First line of every swap file, so unrelated `.swp` files are left alone.
+const MAGIC: &str = "rim swap file";
+
+/// Last letters of the swap file extensions tried in turn, as in Vim:
+/// `.swp`, then `.swo`, `.swn` and so on when the previous ones are taken.
+const SUFFIXES: &str = "ponmlkjihgfedcba";
+
+/// A swap file found on disk.
+pub struct Found {
+    pub path: PathBuf,
+    /// Process that wrote it.
+    pub pid: u32,
+    /// The absolute path of the file being edited.
+    pub file: String,
+    /// The buffer text at the time it was last written.
+    pub text: String,
+    pub modified: Option<SystemTime>,
+}
+
+impl Found {
+    /// Whether the process that wrote the swap file is still running.
+    pub fn owner_running(&self) -> bool {
+        pid_running(self.pid)
+    }
+}
+
+/// What was being edited before another file was opened, to go back to when the user
+/// quits at the prompt about that file's swap file.
+pub struct Previous {
+    buffer: Buffer,
+    browser: Option<Browser>,
+    fileformat: String,
+    fileencoding: String,
+    bomb: bool,
+    /// The cursor and scroll position: `cx`, `cy`, `row_offset` and `col_offset`.
+    position: (usize, usize, usize, usize),
+    alternate_file: Option<String>,
+    /// The id of the buffer when it was put aside for another window that shows it,
+    /// which holds it instead of `buffer`.
+    kept: Option<window::Id>,
+}
+
+/// The swap file of the buffer being edited.
+pub struct SwapFile {
+    pub path: PathBuf,
+    /// The buffer's edit count when the swap file was last written.
+    written: u64,
+    /// Keys typed since then.
+    keys: usize,
+    last_key: Instant,
+}
+
+impl SwapFile {
+    /// Deletes the file, as its buffer is done with.
+    pub fn remove(self) {
+        fs::remove_file(self.path).ok();
+    }
+}
+
+/// The entries of the `directory` option, in order; `.` stands for the directory of
+/// the edited file, as does an empty entry.
+fn directories(directory: &str) -> impl Iterator<Item = &str> {
+    directory
+        .split(',')
+        .map(|dir| if dir.is_empty() { "." } else { dir })
+}
+
+/// Returns the swap file names for a file in one entry of `directory`, in the order
+/// they are tried. They go next to the file for `.`, or into the directory with the
+/// full path in the name.
+fn names(filename: &str, dir: &str) -> Vec<PathBuf> {
+    let path = std::path::absolute(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let base = if dir == "." {
+        let name = path.file_name().map_or_else(
+            || filename.to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        path.with_file_name(format!(".{}", name))
+    } else {
+        Path::new(&*complete::expand_home(dir)).join(path.to_string_lossy().replace('/', "%"))
+    };
+    SUFFIXES
+        .chars()
+        .map(|c| {
+            let mut name = base.clone().into_os_string();
+            name.push(format!(".sw{}", c));
+            PathBuf::from(name)
+        })
+        .collect()
+}
+
+/// Returns the names a new swap file for a file can take, in the order they are
+/// tried: in each entry of `directory` that is an existing directory, so one where
+/// it can't be written is passed over.
+pub fn candidates(filename: &str, directory: &str) -> Vec<PathBuf> {
+    directories(directory)
+        .filter(|dir| *dir == "." || Path::new(&*complete::expand_home(dir)).is_dir())
+        .flat_map(|dir| names(filename, dir))
+        .collect()
+}
+
+/// Returns the first existing swap file for a file, in any entry of `directory`.
+pub fn find(filename: &str, directory: &str) -> Option<PathBuf> {
+    directories(directory)
+        .flat_map(|dir| names(filename, dir))
+        .find(|path| path.exists())
+}
+
+/// Reads a swap file written by rim.
+pub fn read(path: &Path) -> Result<Found> {
+    let content = fs::read_to_string(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a rim swap file");
+    let (header, text) = content.split_once("\n\n").ok_or_else(invalid)?;
+    let mut lines = header.lines();
+    if lines.next() != Some(MAGIC) {
+        return Err(invalid());
+    }
+    let mut pid = 0;
+    let mut file = String::new();
+    for line in lines {
+        match line.split_once(": ") {
+            Some(("pid", value)) => pid = value.parse().map_err(|_| invalid())?,
+            Some(("file", value)) => file = value.to_string(),
+            _ => {}
+        }
+    }
+    Ok(Found {
+        path: path.to_path_buf(),
+        pid,
+        file,
+        text: text.to_string(),
+        modified: fs::metadata(path).and_then(|m| m.modified()).ok(),
+    })
+}
+
+/// Writes the buffer text to a swap file. The text goes to a temporary file first,
+/// so a crash while writing never leaves a half-written swap file behind.
+pub fn write(path: &Path, filename: &str, rope: &Rope) -> Result<()> {
+    let absolute = std::path::absolute(filename).unwrap_or_else(|_| PathBuf::from(filename));
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    // Anyone who may not read the file may not read its unsaved changes either
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let mode = fs::metadata(filename).map_or(0o600, |m| m.permissions().mode() & 0o666);
+        options.mode(mode);
+    }
+    // A temporary file left by a crash would keep its own permissions
+    fs::remove_file(&temp).ok();
+    let mut out = io::BufWriter::new(options.open(&temp)?);
+    write!(
+        out,
+        "{}\npid: {}\nfile: {}\n\n",
+        MAGIC,
+        std::process::id(),
+        absolute.display()
+    )?;
+    rope.write_to(&mut out)?;
+    out.into_inner()?.sync_all()?;
+    fs::rename(&temp, path)
+}
+
+/// Whether a process with this id exists.
+#[cfg(unix)]
+fn pid_running(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    // Signal 0 only checks that the process exists; EPERM means it does but isn't ours
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Whether a process with this id exists. Without a way to ask, a swap file is taken
+/// to be in use unless rim itself wrote it, so it is never deleted from under its owner.
+#[cfg(not(unix))]
+fn pid_running(pid: u32) -> bool {
+    pid != 0 && pid != std::process::id()
+}
+
+/// Prints the swap files in the current directory and in the other entries of
+/// `directory`, for `rim -r`.
+pub fn print_list(directory: &str) -> Result<()> {
+    let mut dirs: Vec<&str> = vec!["."];
+    for dir in directories(directory) {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    for dir in dirs {
+        // The current directory is always listed; the others only if they exist
+        let entries = match fs::read_dir(&*complete::expand_home(dir)) {
+            Ok(entries) => entries,
+            Err(_) if dir != "." => continue,
+            Err(e) => return Err(e),
+        };
+        let mut found = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let is_swap = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .is_some_and(|e| e.len() == 3 && e.starts_with("sw"));
+            if let Some(swap) = is_swap.then(|| read(&path).ok()).flatten() {
+                found.push(swap);
+            }
+        }
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        if dir == "." {
+            println!("Swap files found in the current directory:");
+        } else {
+            println!("Swap files found in {}:", dir);
+        }
+        if found.is_empty() {
+            println!("   -- none --");
+        }
+        for (i, swap) in found.iter().enumerate() {
+            let state = if swap.owner_running() {
+                " (STILL RUNNING)"
+            } else {
+                ""
+            };
+            println!("{}.    {}", i + 1, display_name(&swap.path));
+            println!("          owned by: pid {}{}", swap.pid, state);
+            println!("         file name: {}", swap.file);
+        }
+    }
+    println!("Use \"rim -r <file>\" to recover a file.");
+    Ok(())
+}
+
+impl Editor {
+    /// Puts `buffer` in place of the one being edited and returns what was being
+    /// edited. Its swap file is deleted, unless another window shows it too and it is
+    /// put aside for that window; the same file read again replaces it there as well.
+    pub(crate) fn replace_buffer(&mut self, buffer: Buffer) -> Previous {
+        let position = (self.cx, self.cy, self.row_offset, self.col_offset);
+        let alternate_file = self.alternate_file.clone();
+        let reload = match (&buffer.filename, &self.buffer.filename) {
+            (Some(new), Some(old)) => same_file(new, old),
+            _ => false,
+        };
+        let kept = if reload {
+            self.remove_swap();
+            None
+        } else {
+            self.release_buffer()
+        };
+        Previous {
+            buffer: std::mem::replace(&mut self.buffer, buffer),
+            browser: self.browser.take(),
+            fileformat: self.options.fileformat.clone(),
+            fileencoding: self.options.fileencoding.clone(),
+            bomb: self.options.bomb,
+            position,
+            alternate_file,
+            kept,
+        }
+    }
+
+    /// Looks for an existing swap file when a file is opened. With `recover` (`rim -r`)
+    /// its text is loaded straight away; otherwise the user is asked what to do, and
+    /// quitting goes back to `previous`, or leaves the editor if there is none.
+    pub(crate) fn open_swap(&mut self, recover: bool, previous: Option<Previous>) {
+        let Some(filename) = self.buffer.filename.clone() else {
+            return;
+        };
+        let Some(path) = find(&filename, &self.options.directory) else {
+            if recover {
+                self.status_msg = format!("No swap file found for {}", filename);
+            }
+            self.claim_swap(None);
+            return;
+        };
+        let found = match read(&path) {
+            Ok(found) => found,
+            Err(e) => {
+                self.status_msg = format!("Cannot read swap file {}: {}", path.display(), e);
+                self.claim_swap(None);
+                return;
+            }
+        };
+        if recover {
+            self.recover(found);
+            return;
+        }
+        let running = found.owner_running();
+        let file_time = fs::metadata(&filename).and_then(|m| m.modified()).ok();
+        let age = match (found.modified, file_time) {
+            (Some(swap), Some(file)) if swap < file => ", older than the file",
+            _ => "",
+        };
+        let owner = if running {
+            format!("in use by running process {}", found.pid)
+        } else {
+            format!("left by process {}{}", found.pid, age)
+        };
+        let mut choices = "roeq".to_string();
+        let mut prompt = format!(
+            "Swap file \"{}\" exists ({})! [R]ecover, [O]pen read-only, [E]dit anyway, [Q]uit",
+            display_name(&found.path),
+            owner
+        );
+        // A running process still needs its swap file
+        if !running {
+            choices.push('d');
+            prompt.push_str(", [D]elete it");
+        }
+        prompt.push(':');
+        self.mode = Mode::Confirm(ConfirmState {
+            prompt,
+            choices,
+            cancel: 'q',
+            action: ConfirmAction::SwapExists(found, previous.map(Box::new)),
+        });
+    }
+
+    /// Carries out the answer to the "swap file exists" prompt.
+    /// Returns false if the editor should quit.
+    pub(crate) fn answer_swap_prompt(
+        &mut self,
+        found: Found,
+        previous: Option<Box<Previous>>,
+        choice: char,
+    ) -> bool {
+        self.mode = Mode::Normal(NormalState::default());
+        match choice {
+            'r' => self.recover(found),
+            'o' => {
+                self.buffer.readonly = true;
+                self.status_msg = "Opened read-only".to_string();
+            }
+            'e' => self.claim_swap(None),
+            'd' => {
+                match fs::remove_file(&found.path) {
+                    Ok(()) => self.status_msg = format!("Deleted {}", display_name(&found.path)),
+                    Err(e) => self.status_msg = format!("Cannot delete swap file: {}", e),
+                }
+                self.claim_swap(None);
+            }
+            _ => match previous {
+                Some(previous) => self.restore(*previous),
+                None => return false,
+            },
+        }
+        true
+    }
+
+    /// Goes back to what was being edited before opening a file whose swap file the
+    /// user left alone.
+    fn restore(&mut self, previous: Previous) {
+        let name = self.buffer.filename.take().unwrap_or_default();
+        (self.cx, self.cy, self.row_offset, self.col_offset) = previous.position;
+        self.alternate_file = previous.alternate_file;
+        if let Some(id) = previous.kept {
+            self.show_buffer(id);
+            self.status_msg = format!("Did not open {}", name);
+            return;
+        }
+        self.buffer = previous.buffer;
+        self.browser = previous.browser;
+        self.options.fileformat = previous.fileformat;
+        self.options.fileencoding = previous.fileencoding;
+        self.options.bomb = previous.bomb;
+        // Its swap file went when the other file was opened; listings and large files
+        // never have one
+        if self.browser.is_none() && !self.buffer.large {
+            self.claim_swap(None);
+        }
+        self.status_msg = format!("Did not open {}", name);
+    }
+
+    /// Replaces the buffer text with the text of a swap file.
+    fn recover(&mut self, found: Found) {
+        self.buffer.replace_text(Rope::from_str(&found.text));
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+        self.status_msg = format!(
+            "Recovered from {}; check the text, then :w to keep it",
+            display_name(&found.path)
+        );
+        // The old swap file is taken over unless its process is still running
+        let reuse = (!found.owner_running()).then_some(found.path);
+        self.claim_swap(reuse);
+    }
+
+    /// Starts keeping a swap file for the buffer, at `path` or the first free name.
+    fn claim_swap(&mut self, path: Option<PathBuf>) {
+        let Some(filename) = self.buffer.filename.clone() else {
+            return;
+        };
+        if self.options.updatecount == 0 {
+            return;
+        }
+        let paths = match path {
+            Some(path) => vec![path],
+            None => candidates(&filename, &self.options.directory)
+                .into_iter()
+                .filter(|path| !path.exists())
+                .collect(),
+        };
+        let mut failure = None;
+        for path in paths {
+            if let Err(e) = write(&path, &filename, &self.buffer.rope) {
+                failure = Some(format!(
+                    "Unable to write swap file {}: {}",
+                    path.display(),
+                    e
+                ));
+                continue;
+            }
+            self.swap = Some(SwapFile {
+                path,
+                written: self.buffer.edits,
+                keys: 0,
+                last_key: Instant::now(),
+            });
+            return;
+        }
+        self.status_msg =
+            failure.unwrap_or_else(|| "Too many swap files; editing without one".to_string());
+    }
+
+    /// Writes the swap file once `updatecount` keys were typed or the user has been
+    /// idle for `updatetime` milliseconds, if the buffer changed since the last write.
+    pub(crate) fn update_swap(&mut self, typed: bool) {
+        let Some(swap) = &mut self.swap else {
+            return;
+        };
+        if typed {
+            swap.keys += 1;
+            swap.last_key = Instant::now();
+        }
+        if self.buffer.edits == swap.written || self.options.updatecount == 0 {
+            return;
+        }
+        let idle = swap.last_key.elapsed() >= Duration::from_millis(self.options.updatetime as u64);
+        if swap.keys < self.options.updatecount && !idle {
+            return;
+        }
+        let filename = self.buffer.filename.as_deref().unwrap_or("");
+        if let Err(e) = write(&swap.path, filename, &self.buffer.rope) {
+            self.status_msg = format!("Unable to write swap file: {}", e);
+        }
+        // Not retried on every tick after a failure; the next change tries again
+        swap.written = self.buffer.edits;
+        swap.keys = 0;
+    }
+
+    /// Deletes the swap file when the editor exits normally.
+    pub(crate) fn remove_swap(&mut self) {
+        if let Some(swap) = self.swap.take() {
+            swap.remove();
+        }
+    }
+}
+
+/// The swap file name as shown in prompts.
+fn display_name(path: &Path) -> String {
+    path.file_name().map_or_else(
+        || path.display().to_string(),
+        |n| n.to_string_lossy().into_owned(),
+    )
+}
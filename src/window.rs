@@ -0,0 +1,1326 @@
+use crate::browser::Browser;
+use crate::display::{self, Grapheme};
+use crate::lsp::Diagnostic;
+use crate::options::Options;
+use crate::quickfix::same_file;
+use crate::render::{Frame, Style};
+use crate::swap::SwapFile;
+use crate::{Buffer, Editor, Mode, NormalState, cmdwin, wrap};
+use crossterm::event::KeyCode;
+use crossterm::style::{self, Attribute, Color};
+use std::collections::HashMap;
+use std::mem;
+use std::path::Path;
+
+/// Identifies a window, or a buffer shown in windows.
+pub type Id = usize;
+
+/// Where the cursor of a window is, and which part of its buffer it shows.
+#[derive(Clone, Copy, Default)]
+pub struct View {
+    pub cx: usize,
+    pub cy: usize,
+    pub row_offset: usize,
+    pub col_offset: usize,
+}
+
+/// A window on a buffer. The current window's cursor and alternate file are kept in
+/// the editor's own fields while it is current, and only stored here when it is left.
+pub struct Window {
+    /// The buffer shown.
+    buffer: Id,
+    view: View,
+    alternate_file: Option<String>,
+    /// Text rows the window keeps when the room is shared out, as the command-line
+    /// window does; the others share what is left.
+    height: Option<usize>,
+    /// Shown in place of the file name, by a window on a buffer that is no file.
+    pub title: Option<&'static str>,
+}
+
+/// A buffer not shown in the current window, with what the editor keeps along with
+/// the buffer it edits.
+pub struct Stored {
+    buffer: Buffer,
+    swap: Option<SwapFile>,
+    browser: Option<Browser>,
+    fileformat: String,
+    fileencoding: String,
+    bomb: bool,
+}
+
+impl Stored {
+    /// A buffer of its own for a new window, with no file behind it.
+    fn new(buffer: Buffer) -> Self {
+        let options = Options::default();
+        Self {
+            buffer,
+            swap: None,
+            browser: None,
+            fileformat: options.fileformat,
+            fileencoding: options.fileencoding,
+            bomb: false,
+        }
+    }
+}
+
+/// How the windows share the text area: one window, or several side by side or one
+/// above the other, each of which may be split again.
+enum Layout {
+    Window(Id),
+    Split {
+        /// Side by side, with a line between them; else one above the other.
+        vertical: bool,
+        children: Vec<Layout>,
+    },
+}
+
+impl Layout {
+    /// The windows in order from the top left.
+    fn ids(&self) -> Vec<Id> {
+        match self {
+            Layout::Window(id) => vec![*id],
+            Layout::Split { children, .. } => children.iter().flat_map(Layout::ids).collect(),
+        }
+    }
+
+    /// Puts window `new` above or left of window `id`, splitting its place in two. A
+    /// split the same way already gets one part more.
+    fn insert(&mut self, id: Id, new: Id, vertical: bool) -> bool {
+        match self {
+            Layout::Window(w) if *w == id => {
+                *self = Layout::Split {
+                    vertical,
+                    children: vec![Layout::Window(new), Layout::Window(id)],
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split {
+                vertical: v,
+                children,
+            } => {
+                if *v == vertical
+                    && let Some(i) = children
+                        .iter()
+                        .position(|child| matches!(child, Layout::Window(w) if *w == id))
+                {
+                    children.insert(i, Layout::Window(new));
+                    return true;
+                }
+                children
+                    .iter_mut()
+                    .any(|child| child.insert(id, new, vertical))
+            }
+        }
+    }
+
+    /// Takes a window out. A split left with a single part gives its place to that part.
+    fn remove(&mut self, id: Id) -> bool {
+        let Layout::Split { children, .. } = self else {
+            return false;
+        };
+        if let Some(i) = children
+            .iter()
+            .position(|child| matches!(child, Layout::Window(w) if *w == id))
+        {
+            children.remove(i);
+        } else if !children.iter_mut().any(|child| child.remove(id)) {
+            return false;
+        }
+        if children.len() == 1 {
+            *self = children.pop().unwrap();
+        }
+        true
+    }
+}
+
+/// A tab page: windows sharing the text area, of which one is shown at a time.
+struct Tab {
+    layout: Layout,
+    /// The window being edited, or the one that was when another tab page was shown.
+    current: Id,
+    /// The window that was current before it.
+    previous: Option<Id>,
+}
+
+impl Tab {
+    fn new(window: Id) -> Self {
+        Self {
+            layout: Layout::Window(window),
+            current: window,
+            previous: None,
+        }
+    }
+}
+
+/// A part of the screen: its top left cell and its size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub cols: usize,
+    /// Text rows, not counting the status line below them.
+    pub rows: usize,
+}
+
+impl Rect {
+    /// Whether the cell (x, y) is in the rectangle or on the status line below it.
+    fn contains(&self, x: usize, y: usize, status: bool) -> bool {
+        (self.x..self.x + self.cols).contains(&x)
+            && (self.y..self.y + self.rows + status as usize).contains(&y)
+    }
+}
+
+/// The windows on the screen, in tab pages, and the buffers they show.
+pub struct Windows {
+    tabs: Vec<Tab>,
+    /// The tab page shown.
+    tab: usize,
+    windows: HashMap<Id, Window>,
+    /// The buffers of windows other than the current one, unless the current window
+    /// shows them too.
+    buffers: HashMap<Id, Stored>,
+    /// The buffer of the current window, which is the editor's own `buffer`.
+    buffer: Id,
+    next_id: Id,
+}
+
+impl Default for Windows {
+    fn default() -> Self {
+        let window = Window {
+            buffer: 1,
+            view: View::default(),
+            alternate_file: None,
+            height: None,
+            title: None,
+        };
+        Self {
+            tabs: vec![Tab::new(0)],
+            tab: 0,
+            windows: HashMap::from([(0, window)]),
+            buffers: HashMap::new(),
+            buffer: 1,
+            next_id: 1,
+        }
+    }
+}
+
+impl Windows {
+    /// The id of the window being edited.
+    pub fn current_id(&self) -> Id {
+        self.tabs[self.tab].current
+    }
+
+    /// The window being edited.
+    pub fn current(&self) -> &Window {
+        &self.windows[&self.current_id()]
+    }
+
+    /// A new id for a window or a buffer.
+    fn new_id(&mut self) -> Id {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// The buffer a window shows, when it is not the current window's.
+    fn stored(&self, window: &Window) -> Option<&Buffer> {
+        (window.buffer != self.buffer).then(|| &self.buffers[&window.buffer].buffer)
+    }
+
+    /// The buffers the windows show other than the one being edited, oldest first.
+    pub fn buffers(&self) -> impl Iterator<Item = &Buffer> {
+        let mut ids: Vec<&Id> = self.buffers.keys().collect();
+        ids.sort();
+        ids.into_iter().map(|id| &self.buffers[id].buffer)
+    }
+
+    /// The buffer put aside for another window that holds a file, if there is one.
+    fn buffer_of(&self, filename: &str) -> Option<Id> {
+        self.buffers.iter().find_map(|(&id, stored)| {
+            let name = stored.buffer.filename.as_deref()?;
+            same_file(name, filename).then_some(id)
+        })
+    }
+
+    /// The buffer put aside for another window that holds a file, to change it there.
+    pub fn file_buffer(&mut self, filename: &str) -> Option<&mut Buffer> {
+        let id = self.buffer_of(filename)?;
+        self.buffers.get_mut(&id).map(|stored| &mut stored.buffer)
+    }
+
+    /// The tab page a window is in.
+    fn tab_of(&self, id: Id) -> usize {
+        self.tabs
+            .iter()
+            .position(|tab| tab.layout.ids().contains(&id))
+            .unwrap_or(self.tab)
+    }
+
+    /// Whether each window has a status line of its own, as it does when the tab page
+    /// has several; a single window uses the status bar.
+    fn status_lines(&self) -> bool {
+        matches!(self.tabs[self.tab].layout, Layout::Split { .. })
+    }
+
+    /// Whether the top row lists the tab pages, as it does when there are several.
+    fn tab_line(&self) -> bool {
+        self.tabs.len() > 1
+    }
+
+    /// Where each window of the tab page shown goes in `area`, in order from the top
+    /// left.
+    fn rects(&self, area: Rect) -> Vec<(Id, Rect)> {
+        let mut rects = Vec::new();
+        self.place(&self.tabs[self.tab].layout, area, &mut rects);
+        rects
+    }
+
+    fn place(&self, layout: &Layout, area: Rect, rects: &mut Vec<(Id, Rect)>) {
+        let status = self.status_lines() as usize;
+        match layout {
+            Layout::Window(id) => rects.push((
+                *id,
+                Rect {
+                    rows: area.rows.saturating_sub(status),
+                    ..area
+                },
+            )),
+            Layout::Split {
+                vertical: true,
+                children,
+            } => {
+                // One column between windows for the line that separates them
+                let cols = area.cols.saturating_sub(children.len() - 1);
+                let mut x = area.x;
+                for (child, cols) in children
+                    .iter()
+                    .zip(share(cols, &vec![None; children.len()]))
+                {
+                    self.place(child, Rect { x, cols, ..area }, rects);
+                    x += cols + 1;
+                }
+            }
+            Layout::Split {
+                vertical: false,
+                children,
+            } => {
+                let wanted: Vec<Option<usize>> = children
+                    .iter()
+                    .map(|child| match child {
+                        Layout::Window(id) => self.windows[id].height.map(|rows| rows + status),
+                        Layout::Split { .. } => None,
+                    })
+                    .collect();
+                let mut y = area.y;
+                for (child, rows) in children.iter().zip(share(area.rows, &wanted)) {
+                    self.place(child, Rect { y, rows, ..area }, rects);
+                    y += rows;
+                }
+            }
+        }
+    }
+
+    /// Adds a window with a buffer of its own below all the others of the tab page shown,
+    /// keeping `height`
+    /// text rows when there is room for them.
+    fn add_bottom(&mut self, buffer: Buffer, height: usize, title: &'static str) -> Id {
+        let id = self.new_id();
+        let buffer_id = self.new_id();
+        let window = Window {
+            buffer: buffer_id,
+            view: View::default(),
+            alternate_file: None,
+            height: Some(height),
+            title: Some(title),
+        };
+        self.windows.insert(id, window);
+        self.buffers.insert(buffer_id, Stored::new(buffer));
+        let tab = &mut self.tabs[self.tab];
+        let layout = mem::replace(&mut tab.layout, Layout::Window(id));
+        tab.layout = match layout {
+            Layout::Split {
+                vertical: false,
+                mut children,
+            } => {
+                children.push(Layout::Window(id));
+                Layout::Split {
+                    vertical: false,
+                    children,
+                }
+            }
+            layout => Layout::Split {
+                vertical: false,
+                children: vec![layout, Layout::Window(id)],
+            },
+        };
+        id
+    }
+
+    /// Adds a window on the buffer being edited above or left of the current one, which
+    /// it starts out as a copy of.
+    fn split(&mut self, vertical: bool, view: View, alternate_file: Option<String>) -> Id {
+        let id = self.new_id();
+        let window = Window {
+            buffer: self.buffer,
+            view,
+            alternate_file,
+            height: None,
+            title: None,
+        };
+        self.windows.insert(id, window);
+        let current = self.current_id();
+        self.tabs[self.tab].layout.insert(current, id, vertical);
+        id
+    }
+
+    /// Adds a tab page after the one shown, with a window on an empty buffer.
+    fn add_tab(&mut self) -> Id {
+        let id = self.new_id();
+        let buffer_id = self.new_id();
+        let window = Window {
+            buffer: buffer_id,
+            view: View::default(),
+            alternate_file: None,
+            height: None,
+            title: None,
+        };
+        self.windows.insert(id, window);
+        self.buffers.insert(buffer_id, Stored::new(Buffer::new()));
+        self.tabs.insert(self.tab + 1, Tab::new(id));
+        id
+    }
+
+    /// Whether a window other than `except` shows the buffer.
+    fn shown(&self, buffer: Id, except: Id) -> bool {
+        self.windows
+            .iter()
+            .any(|(&id, window)| id != except && window.buffer == buffer)
+    }
+
+    /// Whether a window other than the current one shows the buffer being edited.
+    pub fn shared(&self) -> bool {
+        self.shown(self.buffer, self.current_id())
+    }
+
+    /// Deletes the swap files of the buffers put aside, when the editor exits normally.
+    pub fn remove_swaps(&mut self) {
+        for stored in self.buffers.values_mut() {
+            if let Some(swap) = stored.swap.take() {
+                swap.remove();
+            }
+        }
+    }
+}
+
+/// Shares `total` rows or columns out: parts that want a size get it, up to half of
+/// the total, and the others get equal shares of the rest.
+fn share(total: usize, wanted: &[Option<usize>]) -> Vec<usize> {
+    let mut sizes: Vec<usize> = wanted
+        .iter()
+        .map(|want| want.map_or(0, |want| want.min(total / 2)))
+        .collect();
+    let rest = total.saturating_sub(sizes.iter().sum());
+    let equal = wanted.iter().filter(|want| want.is_none()).count();
+    if equal == 0 {
+        if let Some(last) = sizes.last_mut() {
+            *last += rest;
+        }
+        return sizes;
+    }
+    let mut given = 0;
+    for (size, want) in sizes.iter_mut().zip(wanted) {
+        if want.is_none() {
+            *size = rest / equal + (given < rest % equal) as usize;
+            given += 1;
+        }
+    }
+    sizes
+}
+
+/// What it takes to lay out and draw the text of a window.
+pub struct Pane<'a> {
+    pub buffer: &'a Buffer,
+    pub options: &'a Options,
+    pub view: View,
+    /// Text rows of the window.
+    pub rows: usize,
+    pub cols: usize,
+    /// The Visual selection, normalized so the start comes first.
+    pub selection: Option<((usize, usize), (usize, usize))>,
+    /// The diagnostics of the file shown, for the sign column.
+    pub diagnostics: &'a [Diagnostic],
+    /// Whether the cursor is a block, which sits on the last cell of a tab.
+    pub block_cursor: bool,
+}
+
+impl Pane<'_> {
+    /// Returns the width of the sign and line number columns, including the space
+    /// after the numbers.
+    pub fn gutter_width(&self) -> usize {
+        if self.options.number {
+            // Room for at least three digits, like Vim's default `numberwidth`
+            self.sign_width() + self.buffer.len_lines().to_string().len().max(3) + 1
+        } else {
+            self.sign_width()
+        }
+    }
+
+    /// Returns the number of columns available for text, right of the gutter.
+    pub fn text_cols(&self) -> usize {
+        self.cols.saturating_sub(self.gutter_width()).max(1)
+    }
+
+    /// Returns the last file line that is fully shown on screen.
+    pub fn last_visible_row(&self) -> usize {
+        let last_row = self.buffer.len_lines().saturating_sub(1);
+        let mut rows = self.line_rows(self.view.row_offset).1.len();
+        let mut row = self.view.row_offset;
+        while row < last_row {
+            let height = self.line_rows(row + 1).1.len();
+            if rows + height > self.rows {
+                break;
+            }
+            rows += height;
+            row += 1;
+        }
+        row
+    }
+
+    /// Lays out a file line as grapheme clusters in display columns.
+    pub fn line_layout(&self, row: usize) -> Vec<Grapheme> {
+        if row >= self.buffer.len_lines() {
+            return Vec::new();
+        }
+        display::layout(&self.buffer.line_chars(row), self.options.tabstop)
+    }
+
+    /// Lays out a file line and splits it into the screen rows it occupies.
+    pub fn line_rows(&self, row: usize) -> (Vec<Grapheme>, Vec<wrap::Segment>) {
+        let graphemes = self.line_layout(row);
+        let segments = wrap::segments(&graphemes, self.text_cols(), self.options);
+        (graphemes, segments)
+    }
+
+    /// Returns the (column, row) of the cursor in the window.
+    pub fn cursor_screen_pos(&self) -> (usize, usize) {
+        let View {
+            cx,
+            cy,
+            row_offset,
+            col_offset,
+        } = self.view;
+        let gutter = self.gutter_width();
+        let mut y = 0;
+        for row in row_offset..cy {
+            y += self.line_rows(row).1.len();
+        }
+        let (graphemes, segments) = self.line_rows(cy);
+        let index = wrap::segment_index(&segments, cx);
+        let segment = segments[index];
+        let mut col = display::char_to_col(&graphemes, cx);
+        // Like Vim, the block cursor sits on the last cell of a tab
+        if let (true, Some(g)) = (
+            self.block_cursor,
+            graphemes.get(display::grapheme_at(&graphemes, cx)),
+        ) && g.tab
+        {
+            col += g.width - 1;
+        }
+        let x = gutter + segment.prefix + col - segment.col - col_offset;
+        (
+            x.min(self.cols.saturating_sub(1)),
+            (y + index).min(self.rows.saturating_sub(1)),
+        )
+    }
+
+    /// Draws the text of the buffer into a frame the size of the window.
+    pub fn draw(&self, frame: &mut Frame) {
+        let marker = Style::DEFAULT.fg(style::Color::Blue);
+        let line_number = Style::DEFAULT.fg(style::Color::DarkYellow);
+        let gutter = self.gutter_width();
+        let text_cols = self.text_cols();
+
+        let mut y = 0;
+        let mut file_row_index = self.view.row_offset;
+        while y < self.rows {
+            if file_row_index >= self.buffer.len_lines() {
+                // Welcome message logic
+                if self.buffer.len_lines() == 1
+                    && self.buffer.line_len(0) == 0
+                    && y == self.rows / 3
+                {
+                    let welcome = "Vim-like Editor - v0.0.1";
+                    let padding = (self.cols.saturating_sub(welcome.len())) / 2;
+                    let padding_str = " ".repeat(padding);
+                    frame.put_str(
+                        0,
+                        y,
+                        &format!("~{}{}", padding_str, welcome),
+                        Style::DEFAULT,
+                    );
+                } else {
+                    frame.put_str(0, y, "~", Style::DEFAULT);
+                }
+                y += 1;
+                continue;
+            }
+
+            // Get line from buffer
+            let (graphemes, segments) = self.line_rows(file_row_index);
+
+            // A wrapped line that doesn't fit at the bottom is shown as `@` rows instead
+            if y + segments.len() > self.rows && file_row_index != self.view.row_offset {
+                while y < self.rows {
+                    frame.put_str(0, y, "@", marker);
+                    y += 1;
+                }
+                break;
+            }
+
+            // Where the text of the line ends on screen, for its diagnostic
+            let mut end = None;
+            for segment in segments {
+                if y >= self.rows {
+                    break;
+                }
+                // The sign and number go on the first row of the line only
+                if gutter > 0 && segment.start == 0 {
+                    let signs = self.sign_width();
+                    self.draw_sign(frame, file_row_index, y);
+                    if self.options.number {
+                        let number = format!("{:>1$} ", file_row_index + 1, gutter - signs - 1);
+                        frame.put_str(signs, y, &number, line_number);
+                    }
+                }
+                let mut end_x = gutter + segment.prefix;
+                if segment.prefix > 0 {
+                    frame.put_str(
+                        gutter,
+                        y,
+                        &wrap::prefix_text(segment.prefix, self.options),
+                        marker,
+                    );
+                }
+                // The display columns of the line that fit on this screen row
+                let (view_start, view_end) = if self.options.wrap {
+                    (segment.col, segment.col + text_cols - segment.prefix)
+                } else {
+                    (self.view.col_offset, self.view.col_offset + text_cols)
+                };
+
+                // Iterate over the graphemes we are actually drawing
+                for g in &graphemes {
+                    if g.start < segment.start || g.col + g.width <= view_start {
+                        continue;
+                    }
+                    if g.start >= segment.end || g.col >= view_end {
+                        break;
+                    }
+                    let cx = g.start;
+                    let mut should_highlight = false;
+
+                    // Check if this char (cx, file_row_index) is in selection
+                    if let Some(((start_x, start_y), (end_x, end_y))) = self.selection {
+                        if file_row_index > start_y && file_row_index < end_y {
+                            should_highlight = true;
+                        } else if file_row_index == start_y && file_row_index == end_y {
+                            should_highlight = cx >= start_x && cx <= end_x;
+                        } else if file_row_index == start_y {
+                            should_highlight = cx >= start_x;
+                        } else if file_row_index == end_y {
+                            should_highlight = cx <= end_x;
+                        }
+                    }
+                    let style = if should_highlight {
+                        Style::DEFAULT.attr(style::Attribute::Reverse)
+                    } else {
+                        Style::DEFAULT
+                    };
+
+                    // Each cluster is one cell; one cut by a screen edge is blanked
+                    let x = gutter + segment.prefix + g.col.max(view_start) - view_start;
+                    if g.col < view_start || g.col + g.width > view_end {
+                        let visible = (g.col + g.width).min(view_end) - g.col.max(view_start);
+                        frame.fill(x, y, visible, style);
+                        end_x = x + visible;
+                    } else {
+                        frame.put_grapheme(x, y, &g.text, g.width, style);
+                        end_x = x + g.width;
+                    }
+                }
+                end = Some((y, end_x));
+                y += 1;
+            }
+            if let Some((y, x)) = end {
+                self.draw_inline_diagnostic(frame, file_row_index, y, x);
+            }
+            file_row_index += 1;
+        }
+    }
+}
+
+impl Editor {
+    /// The current window, with its cursor and selection, to lay out and draw.
+    pub(crate) fn pane(&self) -> Pane<'_> {
+        Pane {
+            buffer: &self.buffer,
+            options: &self.options,
+            view: self.view(),
+            rows: self.screen_rows,
+            cols: self.screen_cols,
+            selection: self.get_selection_range(),
+            diagnostics: self.diagnostics_of(&self.buffer),
+            block_cursor: matches!(self.mode, Mode::Normal(_) | Mode::Visual(_)),
+        }
+    }
+
+    /// Another window, as it was left, to draw in `rect`.
+    fn window_pane(&self, id: Id, rect: Rect) -> Pane<'_> {
+        let window = &self.windows.windows[&id];
+        let buffer = self.window_buffer(window);
+        Pane {
+            buffer,
+            options: &self.options,
+            view: window.view,
+            rows: rect.rows,
+            cols: rect.cols,
+            selection: None,
+            diagnostics: self.diagnostics_of(buffer),
+            block_cursor: true,
+        }
+    }
+
+    /// The rows the windows share: all but the tab line, the quickfix window and the
+    /// status bar.
+    pub(crate) fn text_area(&self) -> Rect {
+        let quickfix = self.quickfix.window_rows(self.terminal_rows);
+        let top = self.windows.tab_line() as usize;
+        Rect {
+            x: 0,
+            y: top,
+            cols: self.terminal_cols,
+            rows: (self.terminal_rows - 1 - quickfix).saturating_sub(top),
+        }
+    }
+
+    /// Where the current window is on the screen.
+    pub(crate) fn window_rect(&self) -> Rect {
+        self.windows
+            .rects(self.text_area())
+            .into_iter()
+            .find(|&(id, _)| id == self.windows.current_id())
+            .map_or(self.text_area(), |(_, rect)| rect)
+    }
+
+    /// The window drawn at the cell (x, y), its status line included, and where it is.
+    pub(crate) fn window_at(&self, x: usize, y: usize) -> Option<(Id, Rect)> {
+        let status = self.windows.status_lines();
+        self.windows
+            .rects(self.text_area())
+            .into_iter()
+            .find(|(_, rect)| rect.contains(x, y, status))
+    }
+
+    /// The window next to the current one in the direction of an `h`, `j`, `k` or `l`
+    /// key, level with the cursor.
+    fn window_beside(&self, code: KeyCode) -> Option<Id> {
+        let rect = self.window_rect();
+        let (x, y) = self.pane().cursor_screen_pos();
+        let (x, y) = (rect.x + x, rect.y + y);
+        let status = self.windows.status_lines() as usize;
+        // Past the line between windows side by side, or onto the status line above
+        let (x, y) = match code {
+            KeyCode::Char('h') | KeyCode::Left => (rect.x.checked_sub(2)?, y),
+            KeyCode::Char('l') | KeyCode::Right => (rect.x + rect.cols + 1, y),
+            KeyCode::Char('k') | KeyCode::Up => (x, rect.y.checked_sub(1)?),
+            _ => (x, rect.y + rect.rows + status),
+        };
+        self.window_at(x, y).map(|(id, _)| id)
+    }
+
+    /// Sizes the current window after the windows or the terminal changed, keeping
+    /// the cursor in view.
+    pub(crate) fn layout(&mut self) {
+        let rect = self.window_rect();
+        self.screen_rows = rect.rows;
+        self.screen_cols = rect.cols.max(1);
+        self.clamp_cursor_to_line();
+        self.scroll_check();
+    }
+
+    /// Whether the current window has to stay as it is, saying so if it does: the
+    /// command-line window can't be left or made to show a file before it is closed.
+    pub(crate) fn window_locked(&mut self) -> bool {
+        if self.cmdwin.is_some() {
+            self.status_msg = cmdwin::INVALID.to_string();
+            return true;
+        }
+        false
+    }
+
+    /// Whether the focus may go to another window, saying why not if it may not. A
+    /// large file has to finish loading first, as the loader fills the buffer being
+    /// edited.
+    pub(crate) fn may_leave_window(&mut self) -> bool {
+        if self.window_locked() {
+            return false;
+        }
+        if self.loader.is_some() {
+            self.status_msg = "Cannot leave the window until the file is loaded".to_string();
+            return false;
+        }
+        true
+    }
+
+    /// Makes another window the current one. Returns false if the current window
+    /// can't be left.
+    pub(crate) fn focus_window(&mut self, id: Id) -> bool {
+        if id == self.windows.current_id() {
+            return true;
+        }
+        if !self.may_leave_window() {
+            return false;
+        }
+        self.enter_window(id);
+        true
+    }
+
+    /// The cursor and scroll position of the current window.
+    fn view(&self) -> View {
+        View {
+            cx: self.cx,
+            cy: self.cy,
+            row_offset: self.row_offset,
+            col_offset: self.col_offset,
+        }
+    }
+
+    /// Makes another window the current one, showing its tab page, and puts the buffer
+    /// being edited aside unless it shows that one too.
+    fn enter_window(&mut self, id: Id) {
+        self.buffer.seal_undo_step();
+        if matches!(self.mode, Mode::Insert(_) | Mode::Visual(_)) {
+            self.mode = Mode::Normal(NormalState::default());
+            self.status_msg.clear();
+        }
+        self.quickfix.set_focus(false);
+        let view = self.view();
+        let alternate_file = self.alternate_file.take();
+        let current = self.windows.current_id();
+        if let Some(window) = self.windows.windows.get_mut(&current) {
+            window.view = view;
+            window.alternate_file = alternate_file;
+        }
+        let window = self.windows.windows.get_mut(&id).unwrap();
+        let buffer = window.buffer;
+        View {
+            cx: self.cx,
+            cy: self.cy,
+            row_offset: self.row_offset,
+            col_offset: self.col_offset,
+        } = window.view;
+        self.alternate_file = window.alternate_file.take();
+        if buffer != self.windows.buffer {
+            let stored = self.store_buffer();
+            self.windows.buffers.insert(self.windows.buffer, stored);
+            let stored = self.windows.buffers.remove(&buffer).unwrap();
+            self.load_buffer(stored);
+            self.windows.buffer = buffer;
+        }
+        let index = self.windows.tab_of(id);
+        let tab = &mut self.windows.tabs[index];
+        if index == self.windows.tab {
+            tab.previous = Some(tab.current);
+        }
+        tab.current = id;
+        self.windows.tab = index;
+        self.layout();
+    }
+
+    /// Takes the buffer being edited out of the editor, with what goes with it.
+    fn store_buffer(&mut self) -> Stored {
+        Stored {
+            buffer: mem::replace(&mut self.buffer, Buffer::new()),
+            swap: self.swap.take(),
+            browser: self.browser.take(),
+            fileformat: self.options.fileformat.clone(),
+            fileencoding: self.options.fileencoding.clone(),
+            bomb: self.options.bomb,
+        }
+    }
+
+    /// Makes a buffer put aside the one being edited.
+    fn load_buffer(&mut self, stored: Stored) {
+        self.buffer = stored.buffer;
+        self.swap = stored.swap;
+        self.browser = stored.browser;
+        self.options.fileformat = stored.fileformat;
+        self.options.fileencoding = stored.fileencoding;
+        self.options.bomb = stored.bomb;
+    }
+
+    /// Makes the buffer being edited the one with that id, for the current window.
+    fn set_window_buffer(&mut self, id: Id) {
+        self.windows.buffer = id;
+        let current = self.windows.current_id();
+        if let Some(window) = self.windows.windows.get_mut(&current) {
+            window.buffer = id;
+        }
+    }
+
+    /// Gets the buffer being edited out of the way of another one: it is put aside if
+    /// another window shows it, else dropped along with its swap file. Returns the id
+    /// it is kept under; the current window gets a new one.
+    pub(crate) fn release_buffer(&mut self) -> Option<Id> {
+        let id = self.windows.buffer;
+        let kept = self.windows.shared();
+        if kept {
+            let stored = self.store_buffer();
+            self.windows.buffers.insert(id, stored);
+        } else {
+            self.remove_swap();
+        }
+        let new = self.windows.new_id();
+        self.set_window_buffer(new);
+        kept.then_some(id)
+    }
+
+    /// Shows a buffer put aside for another window in the current one, in place of the
+    /// buffer being edited.
+    pub(crate) fn show_buffer(&mut self, id: Id) {
+        self.release_buffer();
+        if let Some(stored) = self.windows.buffers.remove(&id) {
+            self.load_buffer(stored);
+            self.set_window_buffer(id);
+        }
+    }
+
+    /// Shows the buffer another window has of a file, if one has it. Returns false if
+    /// the file has to be read.
+    pub(crate) fn show_file(&mut self, filename: &str) -> bool {
+        match self.windows.buffer_of(filename) {
+            Some(id) => {
+                self.show_buffer(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The buffer a window shows.
+    fn window_buffer(&self, window: &Window) -> &Buffer {
+        self.windows.stored(window).unwrap_or(&self.buffer)
+    }
+
+    /// Whether closing the windows would lose changes: one of them shows a changed
+    /// buffer that no window left open shows.
+    pub(crate) fn loses_changes(&self, closing: &[Id]) -> bool {
+        closing.iter().any(|id| {
+            let window = &self.windows.windows[id];
+            self.window_buffer(window).dirty
+                && !self
+                    .windows
+                    .windows
+                    .iter()
+                    .any(|(other, shown)| !closing.contains(other) && shown.buffer == window.buffer)
+        })
+    }
+
+    /// Opens a window with a buffer of its own below the others and makes it current.
+    /// It keeps `height` text rows when there is room for them.
+    pub(crate) fn open_bottom_window(
+        &mut self,
+        buffer: Buffer,
+        height: usize,
+        title: &'static str,
+    ) {
+        let id = self.windows.add_bottom(buffer, height, title);
+        self.enter_window(id);
+    }
+
+    /// Splits the current window in two, one above the other or side by side, and
+    /// makes the new one, above or left, current. It edits the file in `args` if given,
+    /// as `:split file` does.
+    pub(crate) fn split_window(&mut self, vertical: bool, args: &[&str]) {
+        if !self.may_leave_window() {
+            return;
+        }
+        // Each half needs a row and a status line, or a column
+        let rect = self.window_rect();
+        let room = if vertical {
+            rect.cols >= 3
+        } else {
+            rect.rows + self.windows.status_lines() as usize >= 4
+        };
+        if !room {
+            self.status_msg = "Not enough room".to_string();
+            return;
+        }
+        let id = self
+            .windows
+            .split(vertical, self.view(), self.alternate_file.clone());
+        self.enter_window(id);
+        if !args.is_empty() {
+            self.edit(false, args);
+        }
+    }
+
+    /// Opens a tab page after the one shown, on an empty buffer or the file in `args`.
+    pub(crate) fn new_tab(&mut self, args: &[&str]) {
+        if !self.may_leave_window() {
+            return;
+        }
+        let id = self.windows.add_tab();
+        self.enter_window(id);
+        if !args.is_empty() {
+            self.edit(false, args);
+        }
+    }
+
+    /// Shows a tab page, with the window that was current there.
+    pub(crate) fn show_tab(&mut self, index: usize) {
+        if let Some(tab) = self.windows.tabs.get(index) {
+            self.focus_window(tab.current);
+        }
+    }
+
+    /// Shows the next tab page, or the one before, going round.
+    pub(crate) fn next_tab(&mut self, forward: bool) {
+        let count = self.windows.tabs.len();
+        let step = if forward { 1 } else { count - 1 };
+        self.show_tab((self.windows.tab + step) % count);
+    }
+
+    /// Closes a window, and its tab page if it is the last window there, but not the
+    /// last window of all. Closing the current one makes the one that was current
+    /// before it current again. A buffer no other window shows is dropped, and its
+    /// swap file deleted.
+    pub(crate) fn close_window(&mut self, id: Id) {
+        if id == self.windows.current_id() {
+            let Some(next) = self.next_window() else {
+                return;
+            };
+            self.enter_window(next);
+        }
+        let index = self.windows.tab_of(id);
+        let tab = &mut self.windows.tabs[index];
+        if tab.layout.ids() == [id] {
+            self.windows.tabs.remove(index);
+            if self.windows.tab > index {
+                self.windows.tab -= 1;
+            }
+        } else {
+            tab.layout.remove(id);
+            if tab.previous == Some(id) {
+                tab.previous = None;
+            }
+            if tab.current == id {
+                tab.current = tab.layout.ids()[0];
+            }
+        }
+        let Some(window) = self.windows.windows.remove(&id) else {
+            return;
+        };
+        if !self.windows.shown(window.buffer, id)
+            && let Some(stored) = self.windows.buffers.remove(&window.buffer)
+            && let Some(swap) = stored.swap
+        {
+            swap.remove();
+        }
+        self.layout();
+    }
+
+    /// The window to go to when the current one closes: the one that was current
+    /// before it, else another of the tab page, else the current window of the next
+    /// tab page or the one before.
+    fn next_window(&self) -> Option<Id> {
+        let windows = &self.windows;
+        let tab = &windows.tabs[windows.tab];
+        tab.previous
+            .filter(|previous| windows.windows.contains_key(previous))
+            .or_else(|| tab.layout.ids().into_iter().find(|&id| id != tab.current))
+            .or_else(|| {
+                let next = windows.tab + 1;
+                let next = if next < windows.tabs.len() {
+                    next
+                } else {
+                    windows.tab.checked_sub(1)?
+                };
+                Some(windows.tabs[next].current)
+            })
+    }
+
+    /// Closes the current window for `:q`, unless that loses changes. Returns false
+    /// when it is the last window, which quits the editor.
+    pub(crate) fn quit_window(&mut self, force: bool) -> bool {
+        let current = self.windows.current_id();
+        if !force && self.loses_changes(&[current]) {
+            self.status_msg = "No write since last change (use :q! to override)".to_string();
+            return true;
+        }
+        if self.windows.windows.len() == 1 {
+            return false;
+        }
+        if self.may_leave_window() {
+            self.close_window(current);
+        }
+        true
+    }
+
+    /// Closes the current window, unless it is the last one or that loses changes.
+    pub(crate) fn close_current_window(&mut self, force: bool) {
+        let current = self.windows.current_id();
+        if self.windows.windows.len() == 1 {
+            self.status_msg = "Cannot close last window".to_string();
+        } else if !force && self.loses_changes(&[current]) {
+            self.status_msg = "No write since last change (add ! to override)".to_string();
+        } else if self.may_leave_window() {
+            self.close_window(current);
+        }
+    }
+
+    /// Closes the other windows of the tab page, unless that loses changes.
+    pub(crate) fn only_window(&mut self, force: bool) {
+        let current = self.windows.current_id();
+        let others: Vec<Id> = self.windows.tabs[self.windows.tab]
+            .layout
+            .ids()
+            .into_iter()
+            .filter(|&id| id != current)
+            .collect();
+        if others.is_empty() {
+            self.status_msg = "Already only one window".to_string();
+        } else if !force && self.loses_changes(&others) {
+            self.status_msg = "No write since last change (add ! to override)".to_string();
+        } else {
+            for id in others {
+                self.close_window(id);
+            }
+        }
+    }
+
+    /// Closes the tab page shown and its windows, unless it is the last one or that
+    /// loses changes.
+    pub(crate) fn close_tab(&mut self, force: bool) {
+        if self.windows.tabs.len() == 1 {
+            self.status_msg = "Cannot close last tab page".to_string();
+            return;
+        }
+        let closing = self.windows.tabs[self.windows.tab].layout.ids();
+        if !force && self.loses_changes(&closing) {
+            self.status_msg = "No write since last change (add ! to override)".to_string();
+            return;
+        }
+        if !self.may_leave_window() {
+            return;
+        }
+        for id in closing {
+            self.close_window(id);
+        }
+    }
+
+    /// Quits for `:qall`, unless a buffer has changes that `force` doesn't drop.
+    /// Returns false to quit.
+    pub(crate) fn quit_all(&mut self, force: bool) -> bool {
+        let changed = std::iter::once(&self.buffer)
+            .chain(self.windows.buffers())
+            .find(|buffer| buffer.dirty);
+        match changed {
+            Some(buffer) if !force => {
+                self.status_msg = format!(
+                    "No write since last change for buffer \"{}\" (add ! to override)",
+                    buffer.filename.as_deref().unwrap_or("[No Name]")
+                );
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Carries out the key typed after Ctrl-w: `s` and `v` split the window, `c`, `q`
+    /// and `o` close windows, and `h`, `j`, `k`, `l`, `w`, `W` and `p` go to another,
+    /// the quickfix window below them included. Returns false when `q` closed the last
+    /// window, which quits.
+    pub(crate) fn window_command(&mut self, code: KeyCode) -> bool {
+        if self.window_locked() {
+            return true;
+        }
+        let quickfix = self.quickfix.window_rows(self.terminal_rows) > 0;
+        let ids: Vec<Id> = self
+            .windows
+            .rects(self.text_area())
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        let current = self.windows.current_id();
+        if self.quickfix.focused() {
+            // Going up or back leaves it for the window it was entered from
+            let target = match code {
+                KeyCode::Char('k' | 'p') | KeyCode::Up => Some(current),
+                KeyCode::Char('w') => ids.first().copied(),
+                KeyCode::Char('W') => ids.last().copied(),
+                _ => None,
+            };
+            if let Some(target) = target
+                && self.focus_window(target)
+            {
+                self.quickfix.set_focus(false);
+            }
+            return true;
+        }
+        let index = ids.iter().position(|&id| id == current).unwrap_or(0);
+        let target = match code {
+            KeyCode::Char('s' | 'S') => {
+                self.split_window(false, &[]);
+                return true;
+            }
+            KeyCode::Char('v') => {
+                self.split_window(true, &[]);
+                return true;
+            }
+            KeyCode::Char('c') => {
+                self.close_current_window(false);
+                return true;
+            }
+            KeyCode::Char('q') => return self.quit_window(false),
+            KeyCode::Char('o') => {
+                self.only_window(false);
+                return true;
+            }
+            KeyCode::Char('h' | 'j' | 'k' | 'l')
+            | KeyCode::Left
+            | KeyCode::Down
+            | KeyCode::Up
+            | KeyCode::Right => match self.window_beside(code) {
+                Some(id) => Some(id),
+                // The quickfix window is below all the others
+                None if matches!(code, KeyCode::Char('j') | KeyCode::Down) => None,
+                None => return true,
+            },
+            KeyCode::Char('w') => ids.get(index + 1).copied(),
+            KeyCode::Char('W') => index.checked_sub(1).map(|i| ids[i]),
+            KeyCode::Char('p') => self.windows.tabs[self.windows.tab].previous,
+            _ => return true,
+        };
+        match target {
+            Some(id) => {
+                self.focus_window(id);
+            }
+            None if quickfix => self.quickfix.set_focus(true),
+            // `w` and `W` go round
+            None if code == KeyCode::Char('w') => {
+                self.focus_window(ids[0]);
+            }
+            None if code == KeyCode::Char('W') => {
+                self.focus_window(ids[ids.len() - 1]);
+            }
+            None => {}
+        }
+        true
+    }
+
+    /// Draws the windows of the tab page shown, each with a status line of its own
+    /// when there are several, and the completion menu and language server popups over
+    /// the current one. The tab line goes above them when there are several tab pages.
+    pub(crate) fn draw_windows(&self, frame: &mut Frame) {
+        if self.windows.tab_line() {
+            self.draw_tab_line(frame);
+        }
+        let area = self.text_area();
+        let status = self.windows.status_lines();
+        let separator = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        for (id, rect) in self.windows.rects(area) {
+            let mut window = Frame::new(rect.cols, rect.rows);
+            if id == self.windows.current_id() {
+                self.pane().draw(&mut window);
+                self.draw_completion(&mut window);
+                self.draw_lsp_windows(&mut window);
+            } else {
+                self.window_pane(id, rect).draw(&mut window);
+            }
+            frame.blit(&window, rect.x, rect.y);
+            if status {
+                self.draw_window_status(frame, id, rect);
+            }
+            // A line between windows side by side
+            if rect.x + rect.cols < area.x + area.cols {
+                for y in rect.y..rect.y + rect.rows + status as usize {
+                    frame.put_str(rect.x + rect.cols, y, "│", separator);
+                }
+            }
+        }
+    }
+
+    /// Draws the status line below a window: the name of its file and whether it was
+    /// changed, in bold for the current window.
+    fn draw_window_status(&self, frame: &mut Frame, id: Id, rect: Rect) {
+        let window = &self.windows.windows[&id];
+        let buffer = self.window_buffer(window);
+        let mut bar = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        if id == self.windows.current_id() {
+            bar = bar.attr(Attribute::Bold);
+        }
+        let name = window
+            .title
+            .or(buffer.filename.as_deref())
+            .unwrap_or("[No Name]");
+        let dirty = if buffer.dirty { " [+]" } else { "" };
+        let text = format!("\"{}\"{}", name, dirty);
+        let y = rect.y + rect.rows;
+        frame.fill(rect.x, y, rect.cols, bar);
+        frame.put_str(rect.x, y, display::truncate(&text, rect.cols), bar);
+    }
+
+    /// The label of each tab page on the tab line: how many windows it has, if more
+    /// than one, the file name of its current window and `+` if that was changed.
+    fn tab_labels(&self) -> Vec<String> {
+        self.windows
+            .tabs
+            .iter()
+            .map(|tab| {
+                let window = &self.windows.windows[&tab.current];
+                let buffer = self.window_buffer(window);
+                let name = match (window.title, &buffer.filename) {
+                    (Some(title), _) => title.to_string(),
+                    (None, Some(filename)) => Path::new(filename)
+                        .file_name()
+                        .map_or(filename.clone(), |name| name.to_string_lossy().into_owned()),
+                    (None, None) => "[No Name]".to_string(),
+                };
+                let count = tab.layout.ids().len();
+                let count = if count > 1 {
+                    format!("{} ", count)
+                } else {
+                    String::new()
+                };
+                let dirty = if buffer.dirty { " +" } else { "" };
+                format!(" {}{}{} ", count, name, dirty)
+            })
+            .collect()
+    }
+
+    /// The tab page whose label is at column `x` of the tab line.
+    pub(crate) fn tab_at(&self, x: usize) -> Option<usize> {
+        let mut end = 0;
+        self.tab_labels().iter().position(|label| {
+            end += display::str_width(label);
+            x < end
+        })
+    }
+
+    /// Draws the tab line on the top row, with the tab page shown in bold.
+    fn draw_tab_line(&self, frame: &mut Frame) {
+        let bar = Style::DEFAULT.bg(Color::DarkGrey).fg(Color::Black);
+        frame.fill(0, 0, self.terminal_cols, bar);
+        let mut x = 0;
+        for (index, label) in self.tab_labels().iter().enumerate() {
+            let style = if index == self.windows.tab {
+                Style::DEFAULT.attr(Attribute::Bold)
+            } else {
+                bar
+            };
+            x = frame.put_str(x, 0, label, style);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::display::{self, Grapheme};
+use crate::options::Options;
+
+/// Characters after which `linebreak` may wrap a line (Vim's default `breakat`).
+const BREAKAT: &str = " \t!@*-+;:,./?";
+
+/// One screen row of a (possibly wrapped) line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    /// Index of the first char shown on this row.
+    pub start: usize,
+    /// Index one past the last char shown on this row.
+    pub end: usize,
+    /// Display column (within the whole line) of the first char on this row.
+    pub col: usize,
+    /// Columns used by `breakindent`/`showbreak` before the text.
+    pub prefix: usize,
+}
+
+/// Splits a laid-out line into the rows it occupies on screen.
+/// Every line, even an empty one, occupies at least one row.
+pub fn segments(graphemes: &[Grapheme], width: usize, opts: &Options) -> Vec<Segment> {
+    let width = width.max(1);
+    if !opts.wrap || display::line_width(graphemes) <= width {
+        return vec![Segment {
+            start: 0,
+            end: graphemes.last().map_or(0, |g| g.end),
+            col: 0,
+            prefix: 0,
+        }];
+    }
+    let prefix = continuation_prefix(graphemes, width, opts);
+    let mut result: Vec<Segment> = Vec::new();
+    let mut first = 0;
+    while first < graphemes.len() {
+        let row_prefix = if result.is_empty() { 0 } else { prefix };
+        let avail = width.saturating_sub(row_prefix).max(1);
+        let row_col = graphemes[first].col;
+        // Take as many whole graphemes as fit; a wide one at the edge moves down
+        let mut last = first;
+        while last < graphemes.len()
+            && graphemes[last].col + graphemes[last].width - row_col <= avail
+        {
+            last += 1;
+        }
+        if last == first {
+            last = first + 1;
+        }
+        if opts.linebreak && last < graphemes.len() {
+            // Break after the last `breakat` char that still fits on this row
+            if let Some(pos) = (first + 1..=last)
+                .rev()
+                .find(|&i| is_breakat(&graphemes[i - 1]))
+            {
+                last = pos;
+            }
+        }
+        result.push(Segment {
+            start: graphemes[first].start,
+            end: graphemes[last - 1].end,
+            col: row_col,
+            prefix: row_prefix,
+        });
+        first = last;
+    }
+    result
+}
+
+/// Whether `linebreak` may wrap the line right after this grapheme.
+fn is_breakat(grapheme: &Grapheme) -> bool {
+    grapheme.end - grapheme.start == 1
+        && grapheme
+            .text
+            .chars()
+            .next()
+            .is_some_and(|c| BREAKAT.contains(c))
+}
+
+/// Columns taken up by `breakindent` and `showbreak` on continuation rows.
+/// Falls back to no indent when it would leave too little room for text.
+fn continuation_prefix(graphemes: &[Grapheme], width: usize, opts: &Options) -> usize {
+    let showbreak = display::str_width(&opts.showbreak);
+    let mut prefix = showbreak;
+    if opts.breakindent {
+        prefix += graphemes
+            .iter()
+            .find(|g| !g.text.chars().all(char::is_whitespace))
+            .map_or(0, |g| g.col);
+    }
+    if prefix + 20 > width {
+        showbreak.min(width / 2)
+    } else {
+        prefix
+    }
+}
+
+/// The text drawn in front of a continuation row: the indent, then `showbreak`.
+pub fn prefix_text(prefix: usize, opts: &Options) -> String {
+    let mut showbreak = String::new();
+    for c in opts.showbreak.chars() {
+        let mut next = showbreak.clone();
+        next.push(c);
+        if display::str_width(&next) > prefix {
+            break;
+        }
+        showbreak = next;
+    }
+    let indent = prefix - display::str_width(&showbreak);
+    format!("{}{}", " ".repeat(indent), showbreak)
+}
+
+/// Returns the index of the segment that holds char `cx`.
+/// A cursor past the end of the line belongs to the last segment.
+pub fn segment_index(segments: &[Segment], cx: usize) -> usize {
+    segments
+        .iter()
+        .rposition(|segment| segment.start <= cx)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rows of a line as (first char, end char, column, prefix).
+    fn rows(line: &str, width: usize, opts: &Options) -> Vec<(usize, usize, usize, usize)> {
+        let chars: Vec<char> = line.chars().collect();
+        segments(&display::layout(&chars, opts.tabstop), width, opts)
+            .iter()
+            .map(|s| (s.start, s.end, s.col, s.prefix))
+            .collect()
+    }
+
+    fn wrapping() -> Options {
+        Options {
+            wrap: true,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn a_line_that_fits_takes_one_row() {
+        assert_eq!(rows("", 4, &wrapping()), [(0, 0, 0, 0)]);
+        assert_eq!(rows("abcd", 4, &wrapping()), [(0, 4, 0, 0)]);
+        // Without `wrap`, however long it is
+        assert_eq!(rows("abcdefgh", 4, &Options::default()), [(0, 8, 0, 0)]);
+    }
+
+    #[test]
+    fn wraps_at_the_last_cell() {
+        assert_eq!(
+            rows("abcdefghij", 4, &wrapping()),
+            [(0, 4, 0, 0), (4, 8, 4, 0), (8, 10, 8, 0)]
+        );
+        // A wide char that doesn't fit moves down whole
+        assert_eq!(rows("ab世", 3, &wrapping()), [(0, 2, 0, 0), (2, 3, 2, 0)]);
+    }
+
+    #[test]
+    fn linebreak_wraps_after_breakat_chars() {
+        let opts = Options {
+            linebreak: true,
+            ..wrapping()
+        };
+        assert_eq!(
+            rows("aaa bbb ccc", 6, &opts),
+            [(0, 4, 0, 0), (4, 8, 4, 0), (8, 11, 8, 0)]
+        );
+        // A word longer than the row is still cut
+        assert_eq!(rows("abcdefgh", 6, &opts), [(0, 6, 0, 0), (6, 8, 6, 0)]);
+    }
+
+    #[test]
+    fn continuation_rows_get_the_indent_and_showbreak() {
+        let opts = Options {
+            breakindent: true,
+            showbreak: "> ".to_string(),
+            ..wrapping()
+        };
+        let line = format!("    {}", "x".repeat(60));
+        assert_eq!(
+            rows(&line, 30, &opts),
+            [(0, 30, 0, 0), (30, 54, 30, 6), (54, 64, 54, 6)]
+        );
+        assert_eq!(prefix_text(6, &opts), "    > ");
+        // Too narrow a window for the indent keeps only `showbreak`
+        assert_eq!(rows(&line, 20, &opts)[1].3, 2);
+    }
+
+    #[test]
+    fn finds_the_row_of_a_char() {
+        let segments = segments(&display::layout(&['a'; 10], 8), 4, &wrapping());
+        assert_eq!(segment_index(&segments, 0), 0);
+        assert_eq!(segment_index(&segments, 5), 1);
+        // Past the end of the line is on the last row
+        assert_eq!(segment_index(&segments, 10), 2);
+    }
+}
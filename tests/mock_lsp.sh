@@ -0,0 +1,68 @@
+#!/bin/sh
+# A scripted language server for the tests in src/lsp.rs. It reads JSON-RPC
+# messages on standard input, appends each one to the log file given as its
+# argument, and answers with canned results for the file it is told about:
+#
+#     fn helper() {}
+#     fn main() {
+#         helper();
+#     }
+log=$1
+: > "$log"
+
+send() {
+    printf 'Content-Length: %d\r\n\r\n%s' "${#1}" "$1"
+}
+
+# The value of a string or number field in a message, found by name.
+field() {
+    printf '%s' "$2" | sed -n "s/.*\"$1\":\"\{0,1\}\([^\",}]*\).*/\1/p"
+}
+
+range() {
+    printf '{"start":{"line":%d,"character":%d},"end":{"line":%d,"character":%d}}' "$1" "$2" "$1" "$3"
+}
+
+while :; do
+    length=
+    while IFS= read -r header; do
+        header=$(printf '%s' "$header" | tr -d '\r')
+        [ -z "$header" ] && break
+        case $header in
+            Content-Length:*) length=${header#Content-Length: } ;;
+        esac
+    done
+    [ -n "$length" ] || exit 0
+    body=$(dd bs=1 count="$length" 2>/dev/null)
+    printf '%s\n' "$body" >> "$log"
+    method=$(field method "$body")
+    id=$(field id "$body")
+    uri=$(field uri "$body")
+    case $method in
+        initialize)
+            send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"capabilities\":{\"textDocumentSync\":2,\"definitionProvider\":true,\"referencesProvider\":true,\"renameProvider\":true}}}"
+            ;;
+        textDocument/didOpen)
+            send "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{\"uri\":\"$uri\",\"diagnostics\":[{\"range\":$(range 2 4 10),\"severity\":1,\"message\":\"mock error\"}]}}"
+            ;;
+        textDocument/didChange)
+            send "{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{\"uri\":\"$uri\",\"diagnostics\":[{\"range\":$(range 1 3 7),\"severity\":2,\"message\":\"mock warning\"}]}}"
+            ;;
+        textDocument/definition)
+            send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"uri\":\"$uri\",\"range\":$(range 0 3 9)}}"
+            ;;
+        textDocument/references)
+            send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":[{\"uri\":\"$uri\",\"range\":$(range 0 3 9)},{\"uri\":\"$uri\",\"range\":$(range 2 4 10)}]}"
+            ;;
+        textDocument/rename)
+            name=$(field newName "$body")
+            send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":{\"changes\":{\"$uri\":[{\"range\":$(range 0 3 9),\"newText\":\"$name\"},{\"range\":$(range 2 4 10),\"newText\":\"$name\"}]}}}"
+            ;;
+        exit)
+            exit 0
+            ;;
+        *)
+            [ -n "$id" ] && send "{\"jsonrpc\":\"2.0\",\"id\":$id,\"result\":null}"
+            ;;
+    esac
+done